@@ -0,0 +1,686 @@
+use rust_htslib::bam;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+use crate::bamutil::Region;
+use crate::error::Result;
+use crate::metric::{self, HeterogeneityMetric};
+use crate::{bamutil, readutil};
+
+/// Header line of the output table.
+pub const HEADER: &str = "chrom\tstart\tend\tname\tn_cpgs\tdepth\tpdr\tpm\tme\tmhl\tlpmd";
+
+/// Regions to aggregate over.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AggregateRegions {
+    /// Path to BED file of the regions.
+    Bed(String),
+    /// Windows of `size` basepairs every `step` basepairs, tiling the reference sequences in the
+    /// header of the input.
+    Windows { size: u64, step: u64 },
+}
+
+/// Parameters of region-level aggregation.
+#[derive(Clone)]
+pub struct AggregateParams {
+    /// Regions to aggregate over.
+    pub regions: AggregateRegions,
+    /// Minimum number of consecutive CpGs in a CpG stretch to consider (PDR and MHL).
+    pub min_cpgs: usize,
+    /// Minimum distance between CpG pairs to consider (LPMD).
+    pub min_distance: i32,
+    /// Maximum distance between CpG pairs to consider (LPMD).
+    pub max_distance: i32,
+    /// Minimum quality for a read to be considered.
+    pub min_qual: u8,
+    /// (Optional) Predefined set of CpGs (in BED file) to be analyzed.
+    pub cpg_set: Option<String>,
+    pub read_options: readutil::ReadOptions,
+}
+
+impl Default for AggregateParams {
+    fn default() -> Self {
+        Self {
+            regions: AggregateRegions::Bed(String::new()),
+            min_cpgs: 4,
+            min_distance: 2,
+            max_distance: 16,
+            min_qual: 10,
+            cpg_set: None,
+            read_options: readutil::ReadOptions::default(),
+        }
+    }
+}
+
+/// Measures of a region, computed from the counts pooled over its CpGs. Formatted as a line of
+/// the output table, with `NA` for the measures without any count in the region.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AggregateRecord {
+    pub chrom: String,
+    /// 0-based start of the region.
+    pub start: u64,
+    /// 0-based end (exclusive) of the region.
+    pub end: u64,
+    /// Name of the region in the BED file, or `.` if not given.
+    pub name: String,
+    /// Number of CpGs in the region covered by at least one read.
+    pub n_cpgs: u32,
+    /// Mean number of reads covering the CpGs counted in `n_cpgs`.
+    pub depth: f32,
+    pub pdr: Option<f32>,
+    pub pm: Option<f32>,
+    pub me: Option<f32>,
+    pub mhl: Option<f32>,
+    pub lpmd: Option<f32>,
+}
+
+impl fmt::Display for AggregateRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            self.chrom, self.start, self.end, self.name, self.n_cpgs, self.depth
+        )?;
+        for value in [self.pdr, self.pm, self.me, self.mhl, self.lpmd] {
+            match value {
+                Some(value) => write!(f, "\t{}", value)?,
+                None => write!(f, "\tNA")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Regions sorted by position, to find the regions containing a CpG.
+pub struct RegionIndex {
+    layout: Layout,
+}
+
+enum Layout {
+    /// Regions of a BED file. For each reference sequence, the indices of its regions and the
+    /// maximum end of the regions up to each of them.
+    Bed {
+        regions: Vec<(Region, Option<String>)>,
+        by_tid: HashMap<u32, Vec<(usize, u64)>>,
+    },
+    /// Windows tiling the reference sequences, located from their index rather than stored.
+    /// `offsets` holds the index of the first window of each reference sequence, followed by the
+    /// total number of windows.
+    Windows {
+        size: u64,
+        step: u64,
+        lengths: Vec<u64>,
+        offsets: Vec<usize>,
+    },
+}
+
+/// Number of windows of `size` basepairs every `step` basepairs in a sequence of `length`
+/// basepairs. The last window ends at the end of the sequence, and may be shorter than `size`.
+fn num_windows(length: u64, size: u64, step: u64) -> u64 {
+    match length {
+        0 => 0,
+        _ if length <= size => 1,
+        _ => (length - size + step - 1) / step + 1,
+    }
+}
+
+impl RegionIndex {
+    pub fn new(mut regions: Vec<(Region, Option<String>)>) -> Self {
+        regions.sort_by_key(|(region, _)| *region);
+
+        let mut by_tid: HashMap<u32, Vec<(usize, u64)>> = HashMap::new();
+        for (i, (region, _)) in regions.iter().enumerate() {
+            let tid_regions = by_tid.entry(region.tid).or_default();
+            let max_end = tid_regions.last().map_or(0, |&(_, max_end)| max_end);
+            tid_regions.push((i, max_end.max(region.end)));
+        }
+
+        Self {
+            layout: Layout::Bed { regions, by_tid },
+        }
+    }
+
+    /// Windows of `size` basepairs every `step` basepairs over the reference sequences in
+    /// `header`. Both `size` and `step` must be positive.
+    pub fn windows(header: &bam::HeaderView, size: u64, step: u64) -> Self {
+        let lengths: Vec<u64> = (0..header.target_count())
+            .map(|tid| header.target_len(tid).unwrap_or(0))
+            .collect();
+
+        let mut offsets: Vec<usize> = vec![0];
+        for length in lengths.iter() {
+            let offset = offsets.last().unwrap() + num_windows(*length, size, step) as usize;
+            offsets.push(offset);
+        }
+
+        Self {
+            layout: Layout::Windows {
+                size,
+                step,
+                lengths,
+                offsets,
+            },
+        }
+    }
+
+    fn len(&self) -> usize {
+        match &self.layout {
+            Layout::Bed { regions, .. } => regions.len(),
+            Layout::Windows { offsets, .. } => *offsets.last().unwrap(),
+        }
+    }
+
+    fn is_windows(&self) -> bool {
+        matches!(self.layout, Layout::Windows { .. })
+    }
+
+    fn region(&self, i: usize) -> Region {
+        match &self.layout {
+            Layout::Bed { regions, .. } => regions[i].0,
+            Layout::Windows {
+                size,
+                step,
+                lengths,
+                offsets,
+            } => {
+                let tid = offsets.partition_point(|&offset| offset <= i) - 1;
+                let start = (i - offsets[tid]) as u64 * step;
+                Region {
+                    tid: tid as u32,
+                    start,
+                    end: (start + size).min(lengths[tid]),
+                }
+            }
+        }
+    }
+
+    fn name(&self, i: usize) -> Option<&str> {
+        match &self.layout {
+            Layout::Bed { regions, .. } => regions[i].1.as_deref(),
+            Layout::Windows { .. } => None,
+        }
+    }
+
+    /// Indices of the regions containing `pos`.
+    fn containing(&self, pos: readutil::CpGPosition) -> Vec<usize> {
+        match &self.layout {
+            Layout::Bed { regions, by_tid } => {
+                let tid_regions = match by_tid.get(&(pos.tid as u32)) {
+                    Some(tid_regions) => tid_regions,
+                    None => return Vec::new(),
+                };
+
+                let upper =
+                    tid_regions.partition_point(|&(i, _)| regions[i].0.start <= pos.pos as u64);
+                tid_regions[..upper]
+                    .iter()
+                    .rev()
+                    .take_while(|&&(_, max_end)| max_end > pos.pos as u64)
+                    .map(|&(i, _)| i)
+                    .filter(|&i| self.contains(i, pos))
+                    .collect()
+            }
+            Layout::Windows {
+                size,
+                step,
+                offsets,
+                ..
+            } => {
+                let tid = pos.tid as usize;
+                if pos.tid < 0 || pos.pos < 0 || tid + 1 >= offsets.len() {
+                    return Vec::new();
+                }
+
+                // Windows k with k * step <= pos < k * step + size.
+                let n = offsets[tid + 1] - offsets[tid];
+                let pos = pos.pos as u64;
+                let first = match pos + 1 > *size {
+                    true => ((pos + 1 - size + step - 1) / step) as usize,
+                    false => 0,
+                };
+                let last = ((pos / step) as usize + 1).min(n);
+                (first..last).map(|k| offsets[tid] + k).collect()
+            }
+        }
+    }
+
+    fn contains(&self, i: usize, pos: readutil::CpGPosition) -> bool {
+        self.region(i).contains(pos.tid, pos.pos)
+    }
+}
+
+/// Counts pooled over the CpGs of a region.
+#[derive(Default)]
+struct RegionStats {
+    /// Number of reads covering each CpG.
+    cpg_depth: HashMap<readutil::CpGPosition, u32>,
+    n_concordant: u32,
+    n_discordant: u32,
+    quartet_pattern_counts: [u32; 16],
+    /// Number of fully methylated stretches of each length, summed over the reads.
+    stretch_info: HashMap<i32, i32>,
+    /// Number of CpGs of the reads, as a histogram.
+    num_cpgs: HashMap<i32, i32>,
+    max_num_cpgs: i32,
+    n_concordant_pairs: i32,
+    n_discordant_pairs: i32,
+}
+
+/// Measures of a region, along with the number and depth of its covered CpGs.
+#[derive(Clone, Copy, Default)]
+struct RegionValues {
+    n_cpgs: u32,
+    depth: f32,
+    pdr: Option<f32>,
+    pm: Option<f32>,
+    me: Option<f32>,
+    mhl: Option<f32>,
+    lpmd: Option<f32>,
+}
+
+impl RegionStats {
+    fn merge(&mut self, other: Self) {
+        for (cpg, depth) in other.cpg_depth {
+            *self.cpg_depth.entry(cpg).or_insert(0) += depth;
+        }
+        self.n_concordant += other.n_concordant;
+        self.n_discordant += other.n_discordant;
+        for (count, other_count) in self
+            .quartet_pattern_counts
+            .iter_mut()
+            .zip(other.quartet_pattern_counts)
+        {
+            *count += other_count;
+        }
+        for (l, count) in other.stretch_info {
+            *self.stretch_info.entry(l).or_insert(0) += count;
+        }
+        for (n, count) in other.num_cpgs {
+            *self.num_cpgs.entry(n).or_insert(0) += count;
+        }
+        self.max_num_cpgs = self.max_num_cpgs.max(other.max_num_cpgs);
+        self.n_concordant_pairs += other.n_concordant_pairs;
+        self.n_discordant_pairs += other.n_discordant_pairs;
+    }
+
+    fn compute_pdr(&self) -> Option<f32> {
+        let total = self.n_concordant + self.n_discordant;
+        match total {
+            0 => None,
+            _ => Some(self.n_discordant as f32 / total as f32),
+        }
+    }
+
+    fn compute_pm_me(&self) -> (Option<f32>, Option<f32>) {
+        let total: u32 = self.quartet_pattern_counts.iter().sum();
+        if total == 0 {
+            return (None, None);
+        }
+
+        let mut pm = 1.0;
+        let mut me = 0.0;
+        for count in self.quartet_pattern_counts.iter() {
+            let p = (*count as f32) / (total as f32);
+            pm -= p * p;
+            if *count > 0 {
+                me += p * p.log2();
+            }
+        }
+
+        (Some(pm), Some(me * -0.25))
+    }
+
+    /// MHL of the region, with the fully methylated stretches and the stretches of each length
+    /// pooled over the CpGs of the region.
+    fn compute_mhl(&self) -> Option<f32> {
+        if self.num_cpgs.is_empty() {
+            return None;
+        }
+
+        let l_sum: f32 = (1..self.max_num_cpgs + 1).map(|l| l as f32).sum();
+        let mut mhl = 0.0;
+        for (&l, &count) in self.stretch_info.iter() {
+            let denom: f32 = self
+                .num_cpgs
+                .iter()
+                .filter(|(&n, _)| n >= l)
+                .map(|(&n, &reads)| ((n - l + 1) * reads) as f32)
+                .sum();
+            mhl += (l as f32 * count as f32) / denom;
+        }
+
+        Some(mhl / l_sum)
+    }
+
+    fn compute_lpmd(&self) -> Option<f32> {
+        let total = self.n_concordant_pairs + self.n_discordant_pairs;
+        match total {
+            0 => None,
+            _ => Some(self.n_discordant_pairs as f32 / total as f32),
+        }
+    }
+
+    fn to_values(&self) -> RegionValues {
+        let n_cpgs = self.cpg_depth.len() as u32;
+        let depth = match n_cpgs {
+            0 => 0.0,
+            _ => self.cpg_depth.values().sum::<u32>() as f32 / n_cpgs as f32,
+        };
+        let (pm, me) = self.compute_pm_me();
+
+        RegionValues {
+            n_cpgs,
+            depth,
+            pdr: self.compute_pdr(),
+            pm,
+            me,
+            mhl: self.compute_mhl(),
+            lpmd: self.compute_lpmd(),
+        }
+    }
+}
+
+/// Region-level aggregation of all measures as a [`HeterogeneityMetric`].
+///
+/// The counts behind each measure are pooled over the CpGs of a region, rather than averaging
+/// the measures of the CpGs: concordant and discordant reads of the CpGs (PDR), patterns of the
+/// CpG quartets within the region (PM and ME), methylated stretches of the reads within the
+/// region (MHL), and concordant and discordant CpG pairs within the region (LPMD).
+pub struct AggregateMetric<'a> {
+    index: &'a RegionIndex,
+    min_cpgs: usize,
+    min_distance: i32,
+    max_distance: i32,
+    active: BTreeMap<usize, RegionStats>,
+    result: BTreeMap<usize, RegionValues>,
+}
+
+impl<'a> AggregateMetric<'a> {
+    pub fn new(index: &'a RegionIndex, params: &AggregateParams) -> Self {
+        Self {
+            index,
+            min_cpgs: params.min_cpgs,
+            min_distance: params.min_distance,
+            max_distance: params.max_distance,
+            active: BTreeMap::new(),
+            result: BTreeMap::new(),
+        }
+    }
+}
+
+impl HeterogeneityMetric for AggregateMetric<'_> {
+    type Output = Vec<AggregateRecord>;
+
+    fn observe_read(&mut self, br: &readutil::BismarkRead) {
+        let regions: BTreeSet<usize> = br
+            .get_cpg_positions()
+            .into_iter()
+            .flat_map(|cpg| self.index.containing(cpg))
+            .collect();
+        if regions.is_empty() {
+            return;
+        } // Read filtering: Ignore reads without CpGs in the regions.
+
+        // Each region counts the read once, with only the CpGs of the read in the region.
+        for i in regions {
+            let region_read = br.in_region(&self.index.region(i));
+            let stats = self.active.entry(i).or_default();
+            for cpg in region_read.get_cpg_positions() {
+                *stats.cpg_depth.entry(cpg).or_insert(0) += 1;
+            }
+
+            let num_cpgs = region_read.get_num_cpgs();
+            if num_cpgs < self.min_cpgs {
+                continue;
+            } // Read filtering: Ignore reads with few CpGs in the region for PDR and MHL.
+
+            match region_read.get_concordance_state() {
+                readutil::ReadConcordanceState::Concordant => stats.n_concordant += 1,
+                readutil::ReadConcordanceState::Discordant => stats.n_discordant += 1,
+            }
+            for (l, count) in region_read.get_stretch_info() {
+                *stats.stretch_info.entry(l).or_insert(0) += count;
+            }
+            *stats.num_cpgs.entry(num_cpgs as i32).or_insert(0) += 1;
+            stats.max_num_cpgs = stats.max_num_cpgs.max(num_cpgs as i32);
+        }
+
+        let (quartets, patterns) = br.get_cpg_quartets_and_patterns();
+        for (q, p) in quartets.iter().zip(patterns.iter()) {
+            for i in self.index.containing(q.pos1) {
+                if self.index.contains(i, q.pos4) {
+                    self.active.get_mut(&i).unwrap().quartet_pattern_counts[*p] += 1;
+                }
+            }
+        }
+
+        let (_, _, pairs) =
+            br.compute_pairwise_cpg_concordance_discordance(self.min_distance, self.max_distance);
+        for (cpg1, cpg2, concordance) in pairs.iter() {
+            for i in self.index.containing(*cpg1) {
+                if self.index.contains(i, *cpg2) {
+                    let stats = self.active.get_mut(&i).unwrap();
+                    match concordance {
+                        readutil::ReadConcordanceState::Concordant => stats.n_concordant_pairs += 1,
+                        readutil::ReadConcordanceState::Discordant => stats.n_discordant_pairs += 1,
+                    }
+                }
+            }
+        }
+    }
+
+    fn flush_before(&mut self, pos: readutil::CpGPosition) {
+        let index = self.index;
+        let result = &mut self.result;
+
+        self.active.retain(|&i, stats| {
+            let region = index.region(i);
+            let last = readutil::CpGPosition::new(region.tid as i32, region.end as i32 - 1);
+            if last.is_before(&pos, 150) {
+                result.insert(i, stats.to_values());
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn merge(&mut self, other: Self) {
+        for (i, stats) in other.active {
+            self.active.entry(i).or_default().merge(stats);
+        }
+        self.result.extend(other.result);
+    }
+
+    fn finalize(mut self, header: &bam::HeaderView) -> Vec<AggregateRecord> {
+        for (i, stats) in self.active.iter() {
+            self.result.insert(*i, stats.to_values());
+        }
+
+        // Windows without reads are omitted, as they would make up most of the genome.
+        let indices: Vec<usize> = match self.index.is_windows() {
+            true => self.result.keys().copied().collect(),
+            false => (0..self.index.len()).collect(),
+        };
+
+        indices
+            .into_iter()
+            .map(|i| {
+                let region = self.index.region(i);
+                let values = self.result.get(&i).copied().unwrap_or_default();
+                AggregateRecord {
+                    chrom: bamutil::tid2chrom(region.tid as i32, header),
+                    start: region.start,
+                    end: region.end,
+                    name: self.index.name(i).unwrap_or(".").to_string(),
+                    n_cpgs: values.n_cpgs,
+                    depth: values.depth,
+                    pdr: values.pdr,
+                    pm: values.pm,
+                    me: values.me,
+                    mhl: values.mhl,
+                    lpmd: values.lpmd,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Reads the regions of `params` using the header of `input`.
+fn get_region_index(input: &str, params: &AggregateParams) -> Result<RegionIndex> {
+    let reader = bamutil::get_reader(input)?;
+    let header = bamutil::get_header(&reader);
+
+    match &params.regions {
+        AggregateRegions::Bed(path) => Ok(RegionIndex::new(bamutil::read_named_regions_bed(
+            path, &header,
+        )?)),
+        AggregateRegions::Windows { size, step } => Ok(RegionIndex::windows(&header, *size, *step)),
+    }
+}
+
+/// Measures written as bedGraph tracks, with the name of each in the file name of its track.
+const TRACKS: [(&str, fn(&AggregateRecord) -> Option<f32>); 5] = [
+    ("pdr", |record| record.pdr),
+    ("pm", |record| record.pm),
+    ("me", |record| record.me),
+    ("mhl", |record| record.mhl),
+    ("lpmd", |record| record.lpmd),
+];
+
+/// Writes a bedGraph track of each measure to `<prefix>.<measure>.bedGraph`. Regions where the
+/// measure is `NA` are left out of its track.
+pub fn write_bedgraphs(prefix: &str, records: &[AggregateRecord]) -> Result<()> {
+    for (measure, value) in TRACKS.iter() {
+        let lines: Vec<String> = records
+            .iter()
+            .filter_map(|record| {
+                value(record).map(|value| {
+                    format!(
+                        "{}\t{}\t{}\t{}",
+                        record.chrom, record.start, record.end, value
+                    )
+                })
+            })
+            .collect();
+        metric::write_table(&format!("{}.{}.bedGraph", prefix, measure), None, &lines)?;
+    }
+
+    Ok(())
+}
+
+/// Computes the measures of each region and writes them to `output`, along with a bedGraph
+/// track of each measure with the `bedgraph` prefix if given. With `group_by_tag`, the tracks of
+/// each group are prefixed with `<bedgraph>.<group>`.
+pub fn compute(
+    input: &str,
+    output: &str,
+    bedgraph: &Option<String>,
+    params: &AggregateParams,
+) -> Result<()> {
+    if params.read_options.group_by_tag.is_some() {
+        let groups = compute_grouped_records(input, params)?;
+        if let Some(prefix) = bedgraph {
+            for (group, records) in groups.iter() {
+                write_bedgraphs(&format!("{}.{}", prefix, group), records)?;
+            }
+        }
+        return metric::write_grouped_table(output, Some(HEADER), &groups);
+    }
+
+    let records = compute_records(input, params)?;
+    if let Some(prefix) = bedgraph {
+        write_bedgraphs(prefix, &records)?;
+    }
+
+    metric::write_table(output, Some(HEADER), &records)
+}
+
+/// Computes the measures of each region of `params.regions` from the reads in `input`, sorted
+/// by position. Regions of a BED file without reads are included, with `NA` measures, while
+/// windows without reads are left out.
+pub fn compute_records(input: &str, params: &AggregateParams) -> Result<Vec<AggregateRecord>> {
+    let index = get_region_index(input, params)?;
+
+    metric::compute(
+        input,
+        params.min_qual,
+        &params.cpg_set,
+        &params.read_options,
+        || AggregateMetric::new(&index, params),
+    )
+}
+
+/// Computes the measures of each region separately for each group of reads sharing the value of
+/// the aux tag given by `group_by_tag` of `read_options`, sorted by the name of the group.
+pub fn compute_grouped_records(
+    input: &str,
+    params: &AggregateParams,
+) -> Result<Vec<(String, Vec<AggregateRecord>)>> {
+    let index = get_region_index(input, params)?;
+
+    metric::compute_grouped(
+        input,
+        params.min_qual,
+        &params.cpg_set,
+        &params.read_options,
+        || AggregateMetric::new(&index, params),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::Record;
+
+    #[test]
+    fn test_read_crossing_region_boundary() {
+        let mut header = bam::Header::new();
+        let mut sq = bam::header::HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", "chr1");
+        sq.push_tag(b"LN", 1000);
+        header.push_record(&sq);
+        let header = bam::HeaderView::from_header(&header);
+
+        // CpGs at 0, 2 and 4 are methylated, at 6 unmethylated and at 8 methylated.
+        let sam = "r\t0\tchr1\t1\t60\t10M\t*\t0\t0\tCGCGCGTGCG\tIIIIIIIIII\tXM:Z:Z.Z.Z.z.Z.";
+        let r = Record::from_sam(&header, sam.as_bytes()).unwrap();
+        let br = readutil::BismarkRead::new(&r).unwrap();
+
+        let index = RegionIndex::new(vec![
+            (
+                Region {
+                    tid: 0,
+                    start: 0,
+                    end: 5,
+                },
+                None,
+            ),
+            (
+                Region {
+                    tid: 0,
+                    start: 5,
+                    end: 10,
+                },
+                None,
+            ),
+        ]);
+        let params = AggregateParams {
+            min_cpgs: 3,
+            ..Default::default()
+        };
+        let mut metric = AggregateMetric::new(&index, &params);
+        metric.observe_read(&br);
+        let records = metric.finalize(&header);
+
+        // The CpGs of the read in the first region are all methylated, as a single read.
+        assert_eq!((records[0].n_cpgs, records[0].depth), (3, 1.0));
+        assert_eq!(records[0].pdr, Some(0.0));
+        assert_eq!(records[0].mhl, Some(1.0));
+        // The read has too few CpGs in the second region for PDR and MHL.
+        assert_eq!((records[1].n_cpgs, records[1].depth), (2, 1.0));
+        assert_eq!(records[1].pdr, None);
+        assert_eq!(records[1].mhl, None);
+    }
+}
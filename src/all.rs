@@ -0,0 +1,386 @@
+use rust_htslib::bam;
+
+use crate::error::Result;
+use crate::fdrp::{FdrpMetric, FdrpParams, FdrpRecord};
+use crate::lpmd::{LpmdMetric, LpmdParams, LpmdRecord};
+use crate::me::{MeMetric, MeParams, MeRecord};
+use crate::metric::{self, HeterogeneityMetric};
+use crate::mhl::{MhlMetric, MhlParams, MhlRecord};
+use crate::pdr::{PdrMetric, PdrParams, PdrRecord};
+use crate::pm::{PmMetric, PmParams, PmRecord};
+use crate::qfdrp::{QfdrpMetric, QfdrpParams, QfdrpRecord};
+use crate::{fdrp, lpmd, me, mhl, pdr, pm, qfdrp, readutil};
+
+/// Parameters of the computation of multiple metrics in a single pass.
+///
+/// Metrics without parameters are not computed. Reads are filtered by `min_qual`, `cpg_set` and
+/// `read_options` shared by all metrics, so those of the parameters of each metric are ignored.
+#[derive(Clone)]
+pub struct AllParams {
+    /// Minimum quality for a read to be considered.
+    pub min_qual: u8,
+    /// (Optional) Predefined set of CpGs (in BED file) to be analyzed.
+    pub cpg_set: Option<String>,
+    pub read_options: readutil::ReadOptions,
+    pub pdr: Option<PdrParams>,
+    pub pm: Option<PmParams>,
+    pub me: Option<MeParams>,
+    pub fdrp: Option<FdrpParams>,
+    pub qfdrp: Option<QfdrpParams>,
+    pub mhl: Option<MhlParams>,
+    pub lpmd: Option<LpmdParams>,
+}
+
+impl Default for AllParams {
+    fn default() -> Self {
+        Self {
+            min_qual: 10,
+            cpg_set: None,
+            read_options: readutil::ReadOptions::default(),
+            pdr: None,
+            pm: None,
+            me: None,
+            fdrp: None,
+            qfdrp: None,
+            mhl: None,
+            lpmd: None,
+        }
+    }
+}
+
+/// Results of the metrics computed in a single pass. Metrics not computed are `None`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AllRecords {
+    pub pdr: Option<Vec<PdrRecord>>,
+    pub pm: Option<Vec<PmRecord>>,
+    pub me: Option<Vec<MeRecord>>,
+    pub fdrp: Option<Vec<FdrpRecord>>,
+    pub qfdrp: Option<Vec<QfdrpRecord>>,
+    pub mhl: Option<Vec<MhlRecord>>,
+    pub lpmd: Option<LpmdRecord>,
+}
+
+/// Paths to output files of the metrics computed in a single pass.
+#[derive(Clone, Default)]
+pub struct AllOutputs {
+    pub pdr: Option<String>,
+    pub pm: Option<String>,
+    pub me: Option<String>,
+    pub fdrp: Option<String>,
+    pub qfdrp: Option<String>,
+    pub mhl: Option<String>,
+    pub lpmd: Option<String>,
+    /// Concordance information for all CpG pairs of LPMD.
+    pub lpmd_pairs: Option<String>,
+    /// Whether to write a header line to the tables of the metrics with a value for each CpG or
+    /// CpG quartet. Tables of LPMD always have a header line.
+    pub header: bool,
+}
+
+impl AllOutputs {
+    /// Header line of the table of a metric, if header lines are written.
+    fn header<'a>(&self, header: &'a str) -> Option<&'a str> {
+        self.header.then_some(header)
+    }
+}
+
+/// Multiple metrics as a single [`HeterogeneityMetric`], so that each read is observed once.
+pub struct AllMetric {
+    pdr: Option<PdrMetric>,
+    pm: Option<PmMetric>,
+    me: Option<MeMetric>,
+    fdrp: Option<FdrpMetric>,
+    qfdrp: Option<QfdrpMetric>,
+    mhl: Option<MhlMetric>,
+    lpmd: Option<LpmdMetric>,
+}
+
+impl AllMetric {
+    /// Creates the metrics for a sample, named `name` in the output of LPMD.
+    pub fn new(name: &str, params: &AllParams) -> Self {
+        Self {
+            pdr: params.pdr.as_ref().map(PdrMetric::new),
+            pm: params.pm.as_ref().map(PmMetric::new),
+            me: params.me.as_ref().map(MeMetric::new),
+            fdrp: params.fdrp.as_ref().map(FdrpMetric::new),
+            qfdrp: params.qfdrp.as_ref().map(QfdrpMetric::new),
+            mhl: params.mhl.as_ref().map(MhlMetric::new),
+            lpmd: params.lpmd.as_ref().map(|p| LpmdMetric::new(name, p)),
+        }
+    }
+}
+
+fn merge_metric<M: HeterogeneityMetric>(metric: &mut Option<M>, other: Option<M>) {
+    if let (Some(metric), Some(other)) = (metric, other) {
+        metric.merge(other);
+    }
+}
+
+impl HeterogeneityMetric for AllMetric {
+    type Output = AllRecords;
+
+    fn observe_read(&mut self, br: &readutil::BismarkRead) {
+        if let Some(m) = &mut self.pdr {
+            m.observe_read(br);
+        }
+        if let Some(m) = &mut self.pm {
+            m.observe_read(br);
+        }
+        if let Some(m) = &mut self.me {
+            m.observe_read(br);
+        }
+        if let Some(m) = &mut self.fdrp {
+            m.observe_read(br);
+        }
+        if let Some(m) = &mut self.qfdrp {
+            m.observe_read(br);
+        }
+        if let Some(m) = &mut self.mhl {
+            m.observe_read(br);
+        }
+        if let Some(m) = &mut self.lpmd {
+            m.observe_read(br);
+        }
+    }
+
+    fn flush_before(&mut self, pos: readutil::CpGPosition) {
+        if let Some(m) = &mut self.pdr {
+            m.flush_before(pos);
+        }
+        if let Some(m) = &mut self.pm {
+            m.flush_before(pos);
+        }
+        if let Some(m) = &mut self.me {
+            m.flush_before(pos);
+        }
+        if let Some(m) = &mut self.fdrp {
+            m.flush_before(pos);
+        }
+        if let Some(m) = &mut self.qfdrp {
+            m.flush_before(pos);
+        }
+        if let Some(m) = &mut self.mhl {
+            m.flush_before(pos);
+        }
+        if let Some(m) = &mut self.lpmd {
+            m.flush_before(pos);
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        merge_metric(&mut self.pdr, other.pdr);
+        merge_metric(&mut self.pm, other.pm);
+        merge_metric(&mut self.me, other.me);
+        merge_metric(&mut self.fdrp, other.fdrp);
+        merge_metric(&mut self.qfdrp, other.qfdrp);
+        merge_metric(&mut self.mhl, other.mhl);
+        merge_metric(&mut self.lpmd, other.lpmd);
+    }
+
+    fn finalize(self, header: &bam::HeaderView) -> AllRecords {
+        AllRecords {
+            pdr: self.pdr.map(|m| m.finalize(header)),
+            pm: self.pm.map(|m| m.finalize(header)),
+            me: self.me.map(|m| m.finalize(header)),
+            fdrp: self.fdrp.map(|m| m.finalize(header)),
+            qfdrp: self.qfdrp.map(|m| m.finalize(header)),
+            mhl: self.mhl.map(|m| m.finalize(header)),
+            lpmd: self.lpmd.map(|m| m.finalize(header)),
+        }
+    }
+}
+
+/// Computes the metrics in a single pass and writes the result of each metric to its output.
+/// Outputs of the metrics not computed are ignored.
+pub fn compute(input: &str, outputs: &AllOutputs, params: &AllParams) -> Result<()> {
+    if params.read_options.group_by_tag.is_some() {
+        let groups = compute_grouped_records(input, params)?;
+        return write_grouped_records(outputs, &groups);
+    }
+
+    let records = compute_records(input, params)?;
+
+    if let (Some(output), Some(records)) = (&outputs.pdr, &records.pdr) {
+        metric::write_table(output, outputs.header(pdr::HEADER), records)?;
+    }
+    if let (Some(output), Some(records)) = (&outputs.pm, &records.pm) {
+        metric::write_table(output, outputs.header(pm::HEADER), records)?;
+    }
+    if let (Some(output), Some(records)) = (&outputs.me, &records.me) {
+        metric::write_table(output, outputs.header(me::HEADER), records)?;
+    }
+    if let (Some(output), Some(records)) = (&outputs.fdrp, &records.fdrp) {
+        metric::write_table(output, outputs.header(fdrp::HEADER), records)?;
+    }
+    if let (Some(output), Some(records)) = (&outputs.qfdrp, &records.qfdrp) {
+        metric::write_table(output, outputs.header(qfdrp::HEADER), records)?;
+    }
+    if let (Some(output), Some(records)) = (&outputs.mhl, &records.mhl) {
+        metric::write_table(output, outputs.header(mhl::HEADER), records)?;
+    }
+    if let (Some(output), Some(record)) = (&outputs.lpmd, &records.lpmd) {
+        lpmd::write_record(output, &outputs.lpmd_pairs, record)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the results of the metrics for each group of reads to their outputs, with the name of
+/// the group in the first column.
+fn write_grouped_records(outputs: &AllOutputs, groups: &[(String, AllRecords)]) -> Result<()> {
+    fn write_metric<T: std::fmt::Display>(
+        output: &Option<String>,
+        header: Option<&str>,
+        groups: &[(String, AllRecords)],
+        records: impl Fn(&AllRecords) -> &Option<Vec<T>>,
+    ) -> Result<()> {
+        if let Some(output) = output {
+            let groups: Vec<(String, &[T])> = groups
+                .iter()
+                .filter_map(|(group, all)| {
+                    records(all)
+                        .as_ref()
+                        .map(|records| (group.clone(), records.as_slice()))
+                })
+                .collect();
+            metric::write_grouped_table(output, header, &groups)?;
+        }
+        Ok(())
+    }
+
+    write_metric(&outputs.pdr, outputs.header(pdr::HEADER), groups, |all| {
+        &all.pdr
+    })?;
+    write_metric(&outputs.pm, outputs.header(pm::HEADER), groups, |all| {
+        &all.pm
+    })?;
+    write_metric(&outputs.me, outputs.header(me::HEADER), groups, |all| {
+        &all.me
+    })?;
+    write_metric(&outputs.fdrp, outputs.header(fdrp::HEADER), groups, |all| {
+        &all.fdrp
+    })?;
+    write_metric(
+        &outputs.qfdrp,
+        outputs.header(qfdrp::HEADER),
+        groups,
+        |all| &all.qfdrp,
+    )?;
+    write_metric(&outputs.mhl, outputs.header(mhl::HEADER), groups, |all| {
+        &all.mhl
+    })?;
+    if let Some(output) = &outputs.lpmd {
+        let lpmd_groups: Vec<(String, LpmdRecord)> = groups
+            .iter()
+            .filter_map(|(group, all)| all.lpmd.clone().map(|record| (group.clone(), record)))
+            .collect();
+        lpmd::write_grouped_records(output, &outputs.lpmd_pairs, &lpmd_groups)?;
+    }
+
+    Ok(())
+}
+
+/// Computes the metrics with parameters in a single pass over the reads in `input`.
+pub fn compute_records(input: &str, params: &AllParams) -> Result<AllRecords> {
+    metric::compute(
+        input,
+        params.min_qual,
+        &params.cpg_set,
+        &params.read_options,
+        || AllMetric::new(input, params),
+    )
+}
+
+/// Computes the metrics separately for each group of reads sharing the value of the aux tag given
+/// by `group_by_tag` of `read_options`, sorted by the name of the group.
+pub fn compute_grouped_records(
+    input: &str,
+    params: &AllParams,
+) -> Result<Vec<(String, AllRecords)>> {
+    metric::compute_grouped(
+        input,
+        params.min_qual,
+        &params.cpg_set,
+        &params.read_options,
+        || AllMetric::new(input, params),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test4_same_as_individual_metrics() {
+        let input = "tests/test4.bam";
+        let fdrp_params = FdrpParams {
+            min_depth: 2,
+            min_overlap: 4,
+            ..Default::default()
+        };
+        let qfdrp_params = QfdrpParams {
+            min_depth: 2,
+            min_overlap: 4,
+            ..Default::default()
+        };
+        let params = AllParams {
+            pdr: Some(PdrParams::default()),
+            pm: Some(PmParams::default()),
+            me: Some(MeParams::default()),
+            fdrp: Some(fdrp_params.clone()),
+            qfdrp: Some(qfdrp_params.clone()),
+            mhl: Some(MhlParams::default()),
+            lpmd: Some(LpmdParams::default()),
+            ..Default::default()
+        };
+
+        let result = compute_records(input, &params).unwrap();
+
+        assert_eq!(
+            result.pdr.unwrap(),
+            pdr::compute_records(input, &PdrParams::default()).unwrap()
+        );
+        assert_eq!(
+            result.pm.unwrap(),
+            pm::compute_records(input, &PmParams::default()).unwrap()
+        );
+        assert_eq!(
+            result.me.unwrap(),
+            me::compute_records(input, &MeParams::default()).unwrap()
+        );
+        assert_eq!(
+            result.mhl.unwrap(),
+            mhl::compute_records(input, &MhlParams::default()).unwrap()
+        );
+        assert_eq!(
+            result.lpmd.unwrap(),
+            lpmd::compute_records(input, &LpmdParams::default()).unwrap()
+        );
+        // FDRP and qFDRP sample reads randomly beyond max_depth, but test4 has fewer reads.
+        let fdrp_records = result.fdrp.unwrap();
+        assert_eq!(fdrp_records.len(), 8);
+        assert_eq!(
+            fdrp_records,
+            fdrp::compute_records(input, &fdrp_params).unwrap()
+        );
+        assert_eq!(
+            result.qfdrp.unwrap(),
+            qfdrp::compute_records(input, &qfdrp_params).unwrap()
+        );
+    }
+
+    #[test]
+    fn test4_subset() {
+        let input = "tests/test4.bam";
+        let params = AllParams {
+            pm: Some(PmParams::default()),
+            ..Default::default()
+        };
+
+        let result = compute_records(input, &params).unwrap();
+
+        assert_eq!(result.pm.unwrap().len(), 2);
+        assert!(result.pdr.is_none());
+        assert!(result.lpmd.is_none());
+    }
+}
@@ -1,13 +1,46 @@
-use rust_htslib::{bam, bam::Read};
-use std::str;
+use rust_htslib::{bam, bam::Read, tpool};
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
 
-pub fn get_reader(input: &str) -> bam::Reader {
-    match bam::Reader::from_path(input) {
-        Ok(reader) => reader,
-        Err(error) => {
-            panic!("Error opening BAM file. {}", error);
-        }
+use crate::error::{Error, Result};
+use crate::readutil;
+
+/// Genomic region in 0-based, half-open coordinates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Region {
+    pub tid: u32,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Region {
+    pub fn contains(&self, tid: i32, pos: i32) -> bool {
+        self.tid as i32 == tid && self.start <= pos as u64 && (pos as u64) < self.end
+    }
+}
+
+pub fn get_reader(input: &str) -> Result<bam::Reader> {
+    bam::Reader::from_path(input).map_err(|source| Error::OpenAlignment {
+        path: input.to_string(),
+        source,
+    })
+}
+
+/// Opens the input like `get_reader`, using the reference genome to decode records of CRAM files.
+/// Without a reference, htslib looks up the reference given by the `UR`/`M5` fields of the CRAM header.
+pub fn get_reader_with_reference(input: &str, reference: &Option<String>) -> Result<bam::Reader> {
+    let mut reader = get_reader(input)?;
+    if let Some(reference) = reference {
+        reader
+            .set_reference(reference)
+            .map_err(|source| Error::Reference {
+                path: reference.to_string(),
+                source,
+            })?;
     }
+    Ok(reader)
 }
 
 pub fn get_header(reader: &bam::Reader) -> bam::HeaderView {
@@ -15,23 +48,366 @@ pub fn get_header(reader: &bam::Reader) -> bam::HeaderView {
 }
 
 pub fn tid2chrom(tid: i32, header: &bam::HeaderView) -> String {
-    str::from_utf8(header.tid2name(tid as u32))
-        .expect("Error parsing chromosome name.")
-        .to_string()
+    String::from_utf8_lossy(header.tid2name(tid as u32)).into_owned()
+}
+
+pub fn chrom2tid(chrom: &[u8], header: &bam::HeaderView) -> Result<u32> {
+    header
+        .tid(chrom)
+        .ok_or_else(|| Error::UnknownContig(String::from_utf8_lossy(chrom).into_owned()))
+}
+
+pub fn is_paired_end(input: &str, reference: &Option<String>) -> Result<bool> {
+    let mut reader = get_reader_with_reference(input, reference)?;
+
+    match reader.records().next() {
+        Some(r) => Ok(r.map_err(Error::ReadAlignment)?.is_paired()),
+        None => Ok(false),
+    }
+}
+
+/// Parses a samtools-style region string (`chr`, `chr:start` or `chr:start-end`, 1-based inclusive).
+pub fn parse_region(region: &str, header: &bam::HeaderView) -> Result<Region> {
+    // Contig names may contain ':', so try to interpret the whole string as a contig first.
+    if let Some(tid) = header.tid(region.as_bytes()) {
+        let end = header.target_len(tid).unwrap_or(0);
+        return Ok(Region { tid, start: 0, end });
+    }
+
+    let (chrom, interval) = match region.rsplit_once(':') {
+        Some((chrom, interval)) => (chrom, interval.replace(',', "")),
+        None => return Err(Error::UnknownContig(region.to_string())),
+    };
+    let tid = chrom2tid(chrom.as_bytes(), header)?;
+    let chrom_len = header.target_len(tid).unwrap_or(0);
+
+    let (start, end) = match interval.split_once('-') {
+        Some((start, end)) => (start.parse::<u64>(), end.parse::<u64>()),
+        None => (interval.parse::<u64>(), Ok(chrom_len)),
+    };
+    match (start, end) {
+        (Ok(start), Ok(end)) if start >= 1 && start <= end => Ok(Region {
+            tid,
+            start: start - 1,
+            end: end.min(chrom_len),
+        }),
+        _ => Err(Error::InvalidRegion(region.to_string())),
+    }
+}
+
+/// Reads regions from a BED file (0-based, half-open).
+pub fn read_regions_bed(path: &str, header: &bam::HeaderView) -> Result<Vec<Region>> {
+    Ok(read_named_regions_bed(path, header)?
+        .into_iter()
+        .map(|(region, _)| region)
+        .collect())
+}
+
+/// Reads regions from a BED file (0-based, half-open), along with their names in the optional
+/// fourth column.
+pub fn read_named_regions_bed(
+    path: &str,
+    header: &bam::HeaderView,
+) -> Result<Vec<(Region, Option<String>)>> {
+    let contents = fs::read_to_string(path).map_err(|source| Error::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    let malformed = |line: &str| Error::MalformedBed {
+        path: path.to_string(),
+        line: line.to_string(),
+    };
+
+    let mut regions: Vec<(Region, Option<String>)> = Vec::new();
+    for line in contents.lines() {
+        if line.is_empty() || line.starts_with('#') || line.starts_with("track") {
+            continue;
+        }
+        let tokens: Vec<&str> = line.split('\t').collect();
+        if tokens.len() < 3 {
+            return Err(malformed(line));
+        }
+
+        let tid = chrom2tid(tokens[0].as_bytes(), header)?;
+        let start = tokens[1].parse::<u64>();
+        let end = tokens[2].parse::<u64>();
+        match (start, end) {
+            (Ok(start), Ok(end)) => regions.push((
+                Region { tid, start, end },
+                tokens.get(3).map(|name| name.to_string()),
+            )),
+            _ => return Err(malformed(line)),
+        }
+    }
+
+    Ok(regions)
+}
+
+/// Collects the regions specified with `--region` and `--regions`, sorted and merged.
+/// Returns `None` if the whole input should be processed.
+pub fn get_regions(
+    read_options: &readutil::ReadOptions,
+    header: &bam::HeaderView,
+) -> Result<Option<Vec<Region>>> {
+    let mut regions: Vec<Region> = read_options
+        .region
+        .iter()
+        .map(|region| parse_region(region, header))
+        .collect::<Result<_>>()?;
+    if let Some(path) = &read_options.regions {
+        regions.extend(read_regions_bed(path, header)?);
+    }
+
+    if read_options.region.is_empty() && read_options.regions.is_none() {
+        return Ok(None);
+    }
+
+    regions.sort();
+    let mut merged: Vec<Region> = Vec::new();
+    for region in regions {
+        match merged.last_mut() {
+            Some(last) if last.tid == region.tid && region.start <= last.end => {
+                last.end = last.end.max(region.end);
+            }
+            _ => merged.push(region),
+        }
+    }
+
+    Ok(Some(merged))
+}
+
+/// Returns true if the position falls into any of the (sorted, merged) regions.
+pub fn in_regions(regions: &[Region], tid: i32, pos: i32) -> bool {
+    let i = regions.partition_point(|region| (region.tid as i32, region.end) <= (tid, pos as u64));
+    i < regions.len() && regions[i].contains(tid, pos)
 }
 
-pub fn chrom2tid(chrom: &[u8], header: &bam::HeaderView) -> u32 {
-    header.tid(chrom).unwrap()
+enum RecordSource {
+    Sequential(bam::Reader),
+    Indexed {
+        reader: bam::IndexedReader,
+        regions: Vec<Region>,
+        curr: Option<usize>,
+        next: usize,
+    },
 }
 
-pub fn is_paired_end(input: &str) -> bool {
-    let mut reader = get_reader(input);
-    let mut flag = false;
+/// Iterator over the alignment records of an input file,
+/// optionally restricted to the records overlapping a set of regions.
+pub struct Records {
+    source: RecordSource,
+}
 
-    if let Some(r) = reader.records().map(|r| r.unwrap()).next() {
-        if r.is_paired() {
-            flag = true;
+impl Records {
+    /// Decompresses the input with the threads of `thread_pool`.
+    pub fn set_thread_pool(&mut self, thread_pool: &tpool::ThreadPool) -> Result<()> {
+        match &mut self.source {
+            RecordSource::Sequential(reader) => reader.set_thread_pool(thread_pool),
+            RecordSource::Indexed { reader, .. } => reader.set_thread_pool(thread_pool),
         }
+        .map_err(Error::ReadAlignment)
     }
-    flag
+}
+
+impl Iterator for Records {
+    type Item = Result<bam::Record>;
+
+    fn next(&mut self) -> Option<Result<bam::Record>> {
+        let mut record = bam::Record::new();
+
+        match &mut self.source {
+            RecordSource::Sequential(reader) => match reader.read(&mut record) {
+                Some(Ok(())) => Some(Ok(record)),
+                Some(Err(error)) => Some(Err(Error::ReadAlignment(error))),
+                None => None,
+            },
+            RecordSource::Indexed {
+                reader,
+                regions,
+                curr,
+                next,
+            } => loop {
+                let i = match curr {
+                    Some(i) => *i,
+                    None => {
+                        if *next >= regions.len() {
+                            return None;
+                        }
+                        let region = regions[*next];
+                        if let Err(error) = reader.fetch((region.tid, region.start, region.end)) {
+                            return Some(Err(Error::ReadAlignment(error)));
+                        }
+                        *curr = Some(*next);
+                        *next += 1;
+                        *next - 1
+                    }
+                };
+
+                match reader.read(&mut record) {
+                    Some(Ok(())) => {
+                        // Records overlapping the previous region have already been visited.
+                        if i > 0 {
+                            let prev = regions[i - 1];
+                            if prev.tid as i32 == record.tid() && (record.pos() as u64) < prev.end {
+                                continue;
+                            }
+                        }
+                        return Some(Ok(record));
+                    }
+                    Some(Err(error)) => return Some(Err(Error::ReadAlignment(error))),
+                    None => *curr = None,
+                }
+            },
+        }
+    }
+}
+
+/// Opens the input and iterates over its records, using the index to visit only the records
+/// overlapping `regions` if they are given. CRAM records are decoded with `reference` if it is given.
+pub fn get_records(
+    input: &str,
+    regions: &Option<Vec<Region>>,
+    reference: &Option<String>,
+) -> Result<Records> {
+    let source = match regions {
+        None => RecordSource::Sequential(get_reader_with_reference(input, reference)?),
+        Some(regions) => {
+            let mut reader =
+                bam::IndexedReader::from_path(input).map_err(|source| Error::MissingIndex {
+                    path: input.to_string(),
+                    source,
+                })?;
+            if let Some(reference) = reference {
+                reader
+                    .set_reference(reference)
+                    .map_err(|source| Error::Reference {
+                        path: reference.to_string(),
+                        source,
+                    })?;
+            }
+            RecordSource::Indexed {
+                reader,
+                regions: regions.clone(),
+                curr: None,
+                next: 0,
+            }
+        }
+    };
+
+    Ok(Records { source })
+}
+
+/// Splits the work described by `read_options` into shards of one reference sequence each,
+/// so that they can be processed in parallel. Each shard is a copy of `read_options` restricted
+/// to the regions of a single reference sequence, in coordinate order.
+/// Returns `None` if the input should be processed serially, i.e., if a single thread is
+/// requested or the input is not indexed.
+pub fn get_shards(
+    input: &str,
+    read_options: &readutil::ReadOptions,
+) -> Result<Option<Vec<readutil::ReadOptions>>> {
+    if read_options.threads <= 1 {
+        return Ok(None);
+    }
+    if bam::IndexedReader::from_path(input).is_err() {
+        eprintln!(
+            "Warning: {} is not indexed. Processing it with a single thread.",
+            input
+        );
+        return Ok(None);
+    }
+
+    let shards = split_by_reference(input, read_options)?;
+    if shards.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(shards))
+}
+
+/// Splits the regions of `read_options` (or whole reference sequences, without regions) by
+/// reference sequence, using the header of `input`. Each part is a copy of `read_options`
+/// restricted to the regions of a single reference sequence, in coordinate order.
+pub fn split_by_reference(
+    input: &str,
+    read_options: &readutil::ReadOptions,
+) -> Result<Vec<readutil::ReadOptions>> {
+    Ok(reference_shards(input, read_options)?
+        .into_iter()
+        .map(|(_, shard)| shard)
+        .collect())
+}
+
+/// Splits the regions of `read_options` by reference sequence as done by [`split_by_reference`],
+/// with the name of the reference sequence of each part.
+pub fn reference_shards(
+    input: &str,
+    read_options: &readutil::ReadOptions,
+) -> Result<Vec<(String, readutil::ReadOptions)>> {
+    let reader = get_reader(input)?;
+    let header = get_header(&reader);
+    let regions = match get_regions(read_options, &header)? {
+        Some(regions) => regions,
+        None => (0..header.target_count())
+            .map(|tid| Region {
+                tid,
+                start: 0,
+                end: header.target_len(tid).unwrap_or(0),
+            })
+            .collect(),
+    };
+
+    let mut shards: Vec<(String, readutil::ReadOptions)> = Vec::new();
+    let mut prev_tid = None;
+    for region in regions.iter().filter(|region| region.start < region.end) {
+        let chrom = tid2chrom(region.tid as i32, &header);
+        if prev_tid != Some(region.tid) {
+            shards.push((
+                chrom.clone(),
+                readutil::ReadOptions {
+                    region: Vec::new(),
+                    regions: None,
+                    threads: 1,
+                    ..read_options.clone()
+                },
+            ));
+            prev_tid = Some(region.tid);
+        }
+        shards.last_mut().unwrap().1.region.push(format!(
+            "{}:{}-{}",
+            chrom,
+            region.start + 1,
+            region.end
+        ));
+    }
+
+    Ok(shards)
+}
+
+/// Applies `f` to every shard using `threads` worker threads (at least one), and returns the results in the order of the shards.
+pub fn map_shards<S, T, F>(shards: &[S], threads: usize, f: F) -> Vec<T>
+where
+    S: Sync,
+    T: Send,
+    F: Fn(&S) -> T + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<T>>> = shards.iter().map(|_| Mutex::new(None)).collect();
+
+    thread::scope(|scope| {
+        for _ in 0..threads.max(1).min(shards.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= shards.len() {
+                    break;
+                }
+                let result = f(&shards[i]);
+                *results[i].lock().unwrap() = Some(result);
+            });
+        }
+    });
+
+    results
+        .into_iter()
+        .map(|result| result.into_inner().unwrap().unwrap())
+        .collect()
 }
@@ -0,0 +1,554 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use crate::error::{Error, Result};
+use crate::{bamutil, tag};
+
+const BIGWIG_MAGIC: u32 = 0x888F_FC26;
+const CHROM_TREE_MAGIC: u32 = 0x78CA_8C91;
+const R_TREE_MAGIC: u32 = 0x2468_ACE0;
+const VERSION: u16 = 4;
+
+/// Maximum number of items in a node of the chromosome tree and of the R-tree indices.
+const BLOCK_SIZE: usize = 256;
+/// Maximum number of intervals (or zoom records) in a block of data.
+const ITEMS_PER_SLOT: usize = 1024;
+const MAX_ZOOM_LEVELS: usize = 10;
+/// Factor between the bin sizes of consecutive zoom levels.
+const ZOOM_INCREMENT: u32 = 4;
+
+const HEADER_SIZE: u64 = 64;
+const ZOOM_HEADER_SIZE: u64 = 24;
+/// Type of the data sections of bedGraph-like intervals.
+const SECTION_TYPE_BEDGRAPH: u8 = 1;
+
+/// Record of a measure written as an interval of a bigWig track.
+pub trait TrackRecord {
+    /// Chromosome, 0-based start, end (exclusive) and value of the record.
+    fn interval(&self) -> (&str, i32, i32, f32);
+}
+
+/// Interval of a track, on the chromosome with index `chrom` in the chromosome list.
+#[derive(Clone, Copy)]
+struct Interval {
+    chrom: u32,
+    start: u32,
+    end: u32,
+    value: f32,
+}
+
+/// Summary of the values of a track over a bin of a zoom level.
+#[derive(Clone, Copy)]
+struct ZoomRecord {
+    chrom: u32,
+    start: u32,
+    end: u32,
+    valid_count: u32,
+    min: f32,
+    max: f32,
+    sum: f32,
+    sum_squares: f32,
+}
+
+impl ZoomRecord {
+    fn new(chrom: u32, start: u32, end: u32, value: f32) -> Self {
+        let len = (end - start) as f32;
+        Self {
+            chrom,
+            start,
+            end,
+            valid_count: end - start,
+            min: value,
+            max: value,
+            sum: value * len,
+            sum_squares: value * value * len,
+        }
+    }
+
+    fn add(&mut self, start: u32, end: u32, value: f32) {
+        let len = (end - start) as f32;
+        self.start = self.start.min(start);
+        self.end = self.end.max(end);
+        self.valid_count += end - start;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value * len;
+        self.sum_squares += value * value * len;
+    }
+}
+
+/// Start (chromosome and position) and end of the data indexed by a node of an R-tree.
+#[derive(Clone, Copy, Default)]
+struct Bounds {
+    start: (u32, u32),
+    end: (u32, u32),
+}
+
+impl Bounds {
+    fn merge(bounds: &[Bounds]) -> Self {
+        match bounds.first() {
+            Some(first) => Self {
+                start: first.start,
+                end: bounds.iter().map(|b| b.end).max().unwrap(),
+            },
+            None => Self::default(),
+        }
+    }
+}
+
+/// Block of data in the file indexed by an R-tree.
+struct Block {
+    bounds: Bounds,
+    offset: u64,
+    size: u64,
+}
+
+/// Writes `records` as a bigWig track to `output`, with the names and sizes of the reference
+/// sequences in the header of `input`.
+pub fn write_records<T: TrackRecord>(output: &str, input: &str, records: &[T]) -> Result<()> {
+    let reader = bamutil::get_reader(input)?;
+    let header = bamutil::get_header(&reader);
+    let tid2size = tag::get_tid2size_from_bam(input)?;
+
+    let chroms: Vec<(String, u32)> = (0..header.target_count() as usize)
+        .map(|tid| {
+            let size = tid2size.get(&tid).copied().unwrap_or(0);
+            (bamutil::tid2chrom(tid as i32, &header), size as u32)
+        })
+        .collect();
+    let chrom2id: HashMap<&str, u32> = chroms
+        .iter()
+        .enumerate()
+        .map(|(id, (chrom, _))| (chrom.as_str(), id as u32))
+        .collect();
+
+    let mut intervals: Vec<Interval> = Vec::new();
+    for record in records.iter() {
+        let (chrom, start, end, value) = record.interval();
+        let chrom = *chrom2id
+            .get(chrom)
+            .ok_or_else(|| Error::UnknownContig(chrom.to_string()))?;
+        intervals.push(Interval {
+            chrom,
+            start: start as u32,
+            end: end as u32,
+            value,
+        });
+    }
+    intervals.sort_by_key(|interval| (interval.chrom, interval.start));
+
+    write(output, &chroms, &intervals).map_err(Error::io(output))
+}
+
+/// Writes `intervals` sorted by position as an uncompressed bigWig file, with zoom levels.
+fn write(output: &str, chroms: &[(String, u32)], intervals: &[Interval]) -> io::Result<()> {
+    let zoom_levels = get_zoom_levels(chroms, intervals);
+
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(output)?;
+    let mut out = BufWriter::new(file);
+
+    // The header and the zoom headers are written last, once the offsets are known.
+    out.seek(SeekFrom::Start(
+        HEADER_SIZE + ZOOM_HEADER_SIZE * zoom_levels.len() as u64,
+    ))?;
+    let summary_offset = out.stream_position()?;
+    write_summary(&mut out, intervals)?;
+
+    let chrom_tree_offset = out.stream_position()?;
+    write_chrom_tree(&mut out, chroms)?;
+
+    let data_offset = out.stream_position()?;
+    let blocks = write_intervals(&mut out, intervals)?;
+    let index_offset = out.stream_position()?;
+    write_r_tree(&mut out, &blocks, index_offset)?;
+
+    let mut zoom_headers: Vec<(u32, u64, u64)> = Vec::new();
+    for (reduction, records) in zoom_levels.iter() {
+        let zoom_data_offset = out.stream_position()?;
+        let blocks = write_zoom_records(&mut out, records)?;
+        let zoom_index_offset = out.stream_position()?;
+        write_r_tree(&mut out, &blocks, zoom_index_offset)?;
+        zoom_headers.push((*reduction, zoom_data_offset, zoom_index_offset));
+    }
+    out.write_all(&BIGWIG_MAGIC.to_le_bytes())?;
+
+    out.seek(SeekFrom::Start(0))?;
+    out.write_all(&BIGWIG_MAGIC.to_le_bytes())?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(&(zoom_levels.len() as u16).to_le_bytes())?;
+    out.write_all(&chrom_tree_offset.to_le_bytes())?;
+    out.write_all(&data_offset.to_le_bytes())?;
+    out.write_all(&index_offset.to_le_bytes())?;
+    out.write_all(&0u16.to_le_bytes())?; // Field count.
+    out.write_all(&0u16.to_le_bytes())?; // Defined field count.
+    out.write_all(&0u64.to_le_bytes())?; // AutoSql offset.
+    out.write_all(&summary_offset.to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?; // Uncompressed buffer size, 0 for uncompressed data.
+    out.write_all(&0u64.to_le_bytes())?; // Extension offset.
+
+    for (reduction, zoom_data_offset, zoom_index_offset) in zoom_headers.iter() {
+        out.write_all(&reduction.to_le_bytes())?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(&zoom_data_offset.to_le_bytes())?;
+        out.write_all(&zoom_index_offset.to_le_bytes())?;
+    }
+
+    out.flush()
+}
+
+/// Summarizes `intervals` in bins of increasing size, starting from 10 times the mean size of the
+/// intervals, up to the size of the longest chromosome. Returns the bin size and the records of
+/// each zoom level, skipping the bin sizes which do not reduce the number of records.
+fn get_zoom_levels(
+    chroms: &[(String, u32)],
+    intervals: &[Interval],
+) -> Vec<(u32, Vec<ZoomRecord>)> {
+    let mut zoom_levels: Vec<(u32, Vec<ZoomRecord>)> = Vec::new();
+    if intervals.is_empty() {
+        return zoom_levels;
+    }
+
+    let total_span: u64 = intervals.iter().map(|i| (i.end - i.start) as u64).sum();
+    let max_size = chroms.iter().map(|(_, size)| *size).max().unwrap_or(0);
+
+    let mut reduction =
+        ((total_span / intervals.len() as u64) * 10).clamp(1, u32::MAX as u64) as u32;
+    let mut n_records = intervals.len();
+    while zoom_levels.len() < MAX_ZOOM_LEVELS {
+        let records = zoom_records(intervals, reduction);
+        if records.len() < n_records {
+            n_records = records.len();
+            zoom_levels.push((reduction, records));
+        }
+
+        if reduction >= max_size {
+            break;
+        }
+        reduction = reduction.saturating_mul(ZOOM_INCREMENT);
+    }
+
+    zoom_levels
+}
+
+/// Summarizes `intervals` sorted by position in bins of `reduction` basepairs.
+fn zoom_records(intervals: &[Interval], reduction: u32) -> Vec<ZoomRecord> {
+    let mut records: Vec<ZoomRecord> = Vec::new();
+    // Bins of the current chromosome from `first_bin` on, which the following intervals may still
+    // cover, as they do not start before the current one.
+    let mut open: VecDeque<Option<ZoomRecord>> = VecDeque::new();
+    let mut first_bin = 0;
+    let mut chrom = None;
+    for interval in intervals.iter().filter(|i| i.end > i.start) {
+        let start_bin = interval.start / reduction;
+        if chrom != Some(interval.chrom) {
+            records.extend(open.drain(..).flatten());
+            chrom = Some(interval.chrom);
+            first_bin = start_bin;
+        }
+        while first_bin < start_bin {
+            match open.pop_front() {
+                Some(record) => {
+                    records.extend(record);
+                    first_bin += 1;
+                }
+                None => first_bin = start_bin,
+            }
+        }
+
+        for bin in start_bin..=(interval.end - 1) / reduction {
+            let bin_start = bin as u64 * reduction as u64;
+            let start = (interval.start as u64).max(bin_start) as u32;
+            let end = (interval.end as u64).min(bin_start + reduction as u64) as u32;
+            let i = (bin - first_bin) as usize;
+            if open.len() <= i {
+                open.resize(i + 1, None);
+            }
+            let slot = &mut open[i];
+            match slot {
+                Some(record) => record.add(start, end, interval.value),
+                None => *slot = Some(ZoomRecord::new(interval.chrom, start, end, interval.value)),
+            }
+        }
+    }
+    records.extend(open.into_iter().flatten());
+
+    records
+}
+
+fn write_summary<W: Write>(out: &mut W, intervals: &[Interval]) -> io::Result<()> {
+    let mut bases_covered = 0u64;
+    let mut min = f64::INFINITY;
+    let mut max = f64::NEG_INFINITY;
+    let mut sum = 0.0;
+    let mut sum_squares = 0.0;
+    for interval in intervals.iter() {
+        let len = (interval.end - interval.start) as f64;
+        let value = interval.value as f64;
+        bases_covered += interval.end as u64 - interval.start as u64;
+        min = min.min(value);
+        max = max.max(value);
+        sum += value * len;
+        sum_squares += value * value * len;
+    }
+    if intervals.is_empty() {
+        min = 0.0;
+        max = 0.0;
+    }
+
+    out.write_all(&bases_covered.to_le_bytes())?;
+    out.write_all(&min.to_le_bytes())?;
+    out.write_all(&max.to_le_bytes())?;
+    out.write_all(&sum.to_le_bytes())?;
+    out.write_all(&sum_squares.to_le_bytes())?;
+
+    Ok(())
+}
+
+/// Groups the nodes of each level of a tree over `n_items` items into nodes of up to
+/// [`BLOCK_SIZE`] items, from the leaves up to the root. Each node is the range of its items in
+/// the level below.
+fn get_tree_levels(n_items: usize) -> Vec<Vec<Range<usize>>> {
+    let mut levels: Vec<Vec<Range<usize>>> = Vec::new();
+    let mut n = n_items;
+    loop {
+        let nodes: Vec<Range<usize>> = (0..n.max(1))
+            .step_by(BLOCK_SIZE)
+            .map(|i| i..(i + BLOCK_SIZE).min(n))
+            .collect();
+        n = nodes.len();
+        levels.push(nodes);
+        if n == 1 {
+            return levels;
+        }
+    }
+}
+
+/// Offsets of the nodes of each level of a tree written from the root down, starting at
+/// `offset`, with nodes of `item_size` bytes per item in the leaves and `node_item_size` bytes per
+/// item in the other nodes.
+fn get_node_offsets(
+    levels: &[Vec<Range<usize>>],
+    offset: u64,
+    item_size: u64,
+    node_item_size: u64,
+) -> Vec<Vec<u64>> {
+    let mut offsets: Vec<Vec<u64>> = vec![Vec::new(); levels.len()];
+    let mut offset = offset;
+    for (level, nodes) in levels.iter().enumerate().rev() {
+        let size = match level {
+            0 => item_size,
+            _ => node_item_size,
+        };
+        for node in nodes.iter() {
+            offsets[level].push(offset);
+            offset += 4 + size * node.len() as u64;
+        }
+    }
+
+    offsets
+}
+
+fn write_node_header<W: Write>(out: &mut W, is_leaf: bool, count: usize) -> io::Result<()> {
+    out.write_all(&[is_leaf as u8, 0])?;
+    out.write_all(&(count as u16).to_le_bytes())
+}
+
+/// Writes the B+ tree mapping the names of the chromosomes to their index and size.
+fn write_chrom_tree<W: Write + Seek>(out: &mut W, chroms: &[(String, u32)]) -> io::Result<()> {
+    let mut items: Vec<(&[u8], u32, u32)> = chroms
+        .iter()
+        .enumerate()
+        .map(|(id, (chrom, size))| (chrom.as_bytes(), id as u32, *size))
+        .collect();
+    items.sort();
+    let key_size = items.iter().map(|(key, _, _)| key.len()).max().unwrap_or(1);
+
+    out.write_all(&CHROM_TREE_MAGIC.to_le_bytes())?;
+    out.write_all(&(BLOCK_SIZE as u32).to_le_bytes())?;
+    out.write_all(&(key_size as u32).to_le_bytes())?;
+    out.write_all(&8u32.to_le_bytes())?; // Value size.
+    out.write_all(&(items.len() as u64).to_le_bytes())?;
+    out.write_all(&0u64.to_le_bytes())?;
+
+    let levels = get_tree_levels(items.len());
+    let item_size = (key_size + 8) as u64;
+    let offsets = get_node_offsets(&levels, out.stream_position()?, item_size, item_size);
+
+    // Key of each node of a level, that is, the key of its first item.
+    let mut keys: Vec<Vec<&[u8]>> = vec![items.iter().map(|(key, _, _)| *key).collect()];
+    for nodes in levels.iter() {
+        let below = keys.last().unwrap();
+        let level_keys = nodes
+            .iter()
+            .map(|node| below.get(node.start).copied().unwrap_or(b""))
+            .collect();
+        keys.push(level_keys);
+    }
+
+    for (level, nodes) in levels.iter().enumerate().rev() {
+        for node in nodes.iter() {
+            write_node_header(out, level == 0, node.len())?;
+            for i in node.clone() {
+                out.write_all(keys[level][i])?;
+                out.write_all(&vec![0; key_size - keys[level][i].len()])?;
+                match level {
+                    0 => {
+                        out.write_all(&items[i].1.to_le_bytes())?;
+                        out.write_all(&items[i].2.to_le_bytes())?;
+                    }
+                    _ => out.write_all(&offsets[level - 1][i].to_le_bytes())?,
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the R-tree indexing `blocks`, which end at `end_offset` in the file.
+fn write_r_tree<W: Write + Seek>(out: &mut W, blocks: &[Block], end_offset: u64) -> io::Result<()> {
+    let levels = get_tree_levels(blocks.len());
+
+    // Bounds of each node of a level, merged from the bounds of its items.
+    let mut bounds: Vec<Vec<Bounds>> = vec![blocks.iter().map(|block| block.bounds).collect()];
+    for nodes in levels.iter() {
+        let below = bounds.last().unwrap();
+        let level_bounds = nodes
+            .iter()
+            .map(|node| Bounds::merge(&below[node.clone()]))
+            .collect();
+        bounds.push(level_bounds);
+    }
+    let root = bounds.last().unwrap()[0];
+
+    out.write_all(&R_TREE_MAGIC.to_le_bytes())?;
+    out.write_all(&(BLOCK_SIZE as u32).to_le_bytes())?;
+    out.write_all(&(blocks.len() as u64).to_le_bytes())?;
+    write_bounds(out, &root)?;
+    out.write_all(&end_offset.to_le_bytes())?;
+    out.write_all(&(ITEMS_PER_SLOT as u32).to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+
+    let offsets = get_node_offsets(&levels, out.stream_position()?, 32, 24);
+    for (level, nodes) in levels.iter().enumerate().rev() {
+        for node in nodes.iter() {
+            write_node_header(out, level == 0, node.len())?;
+            for i in node.clone() {
+                write_bounds(out, &bounds[level][i])?;
+                match level {
+                    0 => {
+                        out.write_all(&blocks[i].offset.to_le_bytes())?;
+                        out.write_all(&blocks[i].size.to_le_bytes())?;
+                    }
+                    _ => out.write_all(&offsets[level - 1][i].to_le_bytes())?,
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn write_bounds<W: Write>(out: &mut W, bounds: &Bounds) -> io::Result<()> {
+    out.write_all(&bounds.start.0.to_le_bytes())?;
+    out.write_all(&bounds.start.1.to_le_bytes())?;
+    out.write_all(&bounds.end.0.to_le_bytes())?;
+    out.write_all(&bounds.end.1.to_le_bytes())
+}
+
+/// Splits items sorted by chromosome into blocks of up to [`ITEMS_PER_SLOT`] items on a single
+/// chromosome.
+fn chunk_by_chrom<T>(items: &[T], chrom: impl Fn(&T) -> u32) -> Vec<&[T]> {
+    let mut chunks: Vec<&[T]> = Vec::new();
+    let mut rest = items;
+    while let Some(first) = rest.first() {
+        let same_chrom = rest
+            .iter()
+            .take_while(|item| chrom(item) == chrom(first))
+            .count();
+        let (chunk, remaining) = rest.split_at(same_chrom.min(ITEMS_PER_SLOT));
+        chunks.push(chunk);
+        rest = remaining;
+    }
+
+    chunks
+}
+
+/// Writes `intervals` in sections of bedGraph-like items, and returns the blocks of the sections.
+fn write_intervals<W: Write + Seek>(out: &mut W, intervals: &[Interval]) -> io::Result<Vec<Block>> {
+    let chunks = chunk_by_chrom(intervals, |interval| interval.chrom);
+    out.write_all(&(chunks.len() as u64).to_le_bytes())?;
+
+    let mut blocks: Vec<Block> = Vec::new();
+    for chunk in chunks.iter() {
+        let offset = out.stream_position()?;
+        let chrom = chunk[0].chrom;
+        let start = chunk[0].start;
+        let end = chunk.iter().map(|interval| interval.end).max().unwrap();
+
+        out.write_all(&chrom.to_le_bytes())?;
+        out.write_all(&start.to_le_bytes())?;
+        out.write_all(&end.to_le_bytes())?;
+        out.write_all(&0u32.to_le_bytes())?; // Item step.
+        out.write_all(&0u32.to_le_bytes())?; // Item span.
+        out.write_all(&[SECTION_TYPE_BEDGRAPH, 0])?;
+        out.write_all(&(chunk.len() as u16).to_le_bytes())?;
+        for interval in chunk.iter() {
+            out.write_all(&interval.start.to_le_bytes())?;
+            out.write_all(&interval.end.to_le_bytes())?;
+            out.write_all(&interval.value.to_le_bytes())?;
+        }
+
+        blocks.push(Block {
+            bounds: Bounds {
+                start: (chrom, start),
+                end: (chrom, end),
+            },
+            offset,
+            size: out.stream_position()? - offset,
+        });
+    }
+
+    Ok(blocks)
+}
+
+/// Writes the records of a zoom level, and returns the blocks of records.
+fn write_zoom_records<W: Write + Seek>(
+    out: &mut W,
+    records: &[ZoomRecord],
+) -> io::Result<Vec<Block>> {
+    out.write_all(&(records.len() as u32).to_le_bytes())?;
+
+    let mut blocks: Vec<Block> = Vec::new();
+    for chunk in chunk_by_chrom(records, |record| record.chrom) {
+        let offset = out.stream_position()?;
+        for record in chunk.iter() {
+            out.write_all(&record.chrom.to_le_bytes())?;
+            out.write_all(&record.start.to_le_bytes())?;
+            out.write_all(&record.end.to_le_bytes())?;
+            out.write_all(&record.valid_count.to_le_bytes())?;
+            out.write_all(&record.min.to_le_bytes())?;
+            out.write_all(&record.max.to_le_bytes())?;
+            out.write_all(&record.sum.to_le_bytes())?;
+            out.write_all(&record.sum_squares.to_le_bytes())?;
+        }
+
+        let chrom = chunk[0].chrom;
+        blocks.push(Block {
+            bounds: Bounds {
+                start: (chrom, chunk[0].start),
+                end: (chrom, chunk.iter().map(|record| record.end).max().unwrap()),
+            },
+            offset,
+            size: out.stream_position()? - offset,
+        });
+    }
+
+    Ok(blocks)
+}
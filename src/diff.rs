@@ -0,0 +1,901 @@
+use clap::ValueEnum;
+use rust_htslib::{bam, bgzf};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::io::{self, BufRead, BufReader};
+
+use crate::error::{Error, Result};
+use crate::lpmd::{LpmdMetric, LpmdParams};
+use crate::pdr::{PdrMetric, PdrParams};
+use crate::{bamutil, metric, readutil};
+
+/// Measure of heterogeneity compared between two groups of samples.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffMeasure {
+    /// Concordant and discordant reads of each CpG, as counted for PDR.
+    Pdr,
+    /// Concordant and discordant reads of each CpG pair, as counted for LPMD.
+    Lpmd,
+}
+
+/// Statistical test for the difference in the fraction of discordant reads.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffTest {
+    /// Fisher's exact test on the counts summed over the samples of each group, which ignores the
+    /// variation between the samples of a group.
+    Fisher,
+    /// Wald test on the pooled fractions, with the binomial variance scaled by the overdispersion
+    /// between the samples of each group (quasi-binomial). Requires replicates to be useful.
+    QuasiBinomial,
+}
+
+/// Parameters of differential heterogeneity testing.
+#[derive(Clone)]
+pub struct DiffParams {
+    pub measure: DiffMeasure,
+    pub test: DiffTest,
+    /// Minimum depth of a site in each group, summed over the samples of the group.
+    pub min_depth: u32,
+    /// Minimum number of consecutive CpGs in a CpG stretch to consider (PDR).
+    pub min_cpgs: usize,
+    /// Minimum distance between CpG pairs to consider (LPMD).
+    pub min_distance: i32,
+    /// Maximum distance between CpG pairs to consider (LPMD).
+    pub max_distance: i32,
+    /// Minimum quality for a read to be considered.
+    pub min_qual: u8,
+    /// (Optional) Predefined set of CpGs (in BED file) to be analyzed.
+    pub cpg_set: Option<String>,
+    pub read_options: readutil::ReadOptions,
+    /// Maximum false discovery rate (BH-adjusted p-value) of significant sites.
+    pub fdr: f64,
+    /// Maximum distance in bp between adjacent significant sites merged into a region.
+    pub max_gap: i32,
+    /// Minimum number of significant sites in a region.
+    pub min_sites: usize,
+}
+
+impl Default for DiffParams {
+    fn default() -> Self {
+        Self {
+            measure: DiffMeasure::Pdr,
+            test: DiffTest::Fisher,
+            min_depth: 10,
+            min_cpgs: 4,
+            min_distance: 2,
+            max_distance: 16,
+            min_qual: 10,
+            cpg_set: None,
+            read_options: readutil::ReadOptions::default(),
+            fdr: 0.05,
+            max_gap: 100,
+            min_sites: 3,
+        }
+    }
+}
+
+/// Result of the test of a site (a CpG for PDR, a CpG pair for LPMD). Formatted as a line of the
+/// output table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiffRecord {
+    pub chrom: String,
+    /// 0-based start of the CpG, or 0-based position of the first CpG of the pair.
+    pub start: i32,
+    /// 0-based end of the CpG, or 0-based position of the second CpG of the pair.
+    pub end: i32,
+    pub n_concordant1: u32,
+    pub n_discordant1: u32,
+    pub n_concordant2: u32,
+    pub n_discordant2: u32,
+    /// Fraction of discordant reads in the first group.
+    pub value1: f32,
+    /// Fraction of discordant reads in the second group.
+    pub value2: f32,
+    /// `value2 - value1`.
+    pub diff: f32,
+    pub pvalue: f64,
+    /// p-value adjusted for multiple testing by the Benjamini-Hochberg procedure.
+    pub qvalue: f64,
+}
+
+impl fmt::Display for DiffRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.4e}\t{:.4e}",
+            self.chrom,
+            self.start,
+            self.end,
+            self.n_concordant1,
+            self.n_discordant1,
+            self.n_concordant2,
+            self.n_discordant2,
+            self.value1,
+            self.value2,
+            self.diff,
+            self.pvalue,
+            self.qvalue
+        )
+    }
+}
+
+/// Differentially heterogeneous region, merged from adjacent significant sites. Formatted as a
+/// line of the region table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DhrRecord {
+    pub chrom: String,
+    /// 0-based start of the first site.
+    pub start: i32,
+    /// 0-based end of the last site.
+    pub end: i32,
+    pub n_sites: usize,
+    /// Mean of `diff` over the sites.
+    pub mean_diff: f32,
+    /// Minimum `qvalue` over the sites.
+    pub min_qvalue: f64,
+}
+
+impl fmt::Display for DhrRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{:.4e}",
+            self.chrom, self.start, self.end, self.n_sites, self.mean_diff, self.min_qvalue
+        )
+    }
+}
+
+/// Header line of the output table.
+fn header(measure: DiffMeasure) -> String {
+    let positions = match measure {
+        DiffMeasure::Pdr => "start\tend",
+        DiffMeasure::Lpmd => "cpg1\tcpg2",
+    };
+    format!(
+        "chrom\t{}\tn_concordant1\tn_discordant1\tn_concordant2\tn_discordant2\tvalue1\tvalue2\tdiff\tpvalue\tqvalue",
+        positions
+    )
+}
+
+/// Header line of the region table.
+const DHR_HEADER: &str = "chrom\tstart\tend\tn_sites\tmean_diff\tmin_qvalue";
+
+/// Counts of concordant and discordant reads of a site in a sample.
+struct SiteCounts {
+    chrom: String,
+    start: i32,
+    end: i32,
+    n_concordant: u32,
+    n_discordant: u32,
+}
+
+/// Whether `input` is an alignment file, rather than a metheor output table.
+fn is_alignment(input: &str) -> bool {
+    let input = input.to_lowercase();
+    input.ends_with(".bam") || input.ends_with(".sam") || input.ends_with(".cram")
+}
+
+/// Opens a table written by metheor, either plain text or compressed with BGZF (`.gz`).
+fn open_table(input: &str) -> Result<BufReader<bgzf::Reader>> {
+    let reader = bgzf::Reader::from_path(input).map_err(|source| Error::Io {
+        path: input.to_string(),
+        source: io::Error::other(source),
+    })?;
+
+    Ok(BufReader::new(reader))
+}
+
+/// Whether `line` of a table is empty or the header line, rather than a site.
+fn is_site(line: &str) -> bool {
+    !(line.trim().is_empty() || line.starts_with("chrom\t"))
+}
+
+/// Parses the counts of a site from a line of a PDR table or an LPMD CpG pair table, both with
+/// the position of the site in the second and third columns and the counts in the last two.
+fn parse_site(input: &str, line: String) -> Result<SiteCounts> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let site = match fields.as_slice() {
+        [chrom, start, end, _, n_concordant, n_discordant] => (|| {
+            Some(SiteCounts {
+                chrom: chrom.to_string(),
+                start: start.parse().ok()?,
+                end: end.parse().ok()?,
+                n_concordant: n_concordant.parse().ok()?,
+                n_discordant: n_discordant.parse().ok()?,
+            })
+        })(),
+        _ => None,
+    };
+
+    site.ok_or_else(|| Error::MalformedTable {
+        path: input.to_string(),
+        line,
+    })
+}
+
+/// Reference sequences of a table, in the order they appear. The sites of each reference sequence
+/// must be contiguous, as in the tables sorted by position written by metheor.
+fn table_chroms(input: &str) -> Result<Vec<String>> {
+    let mut chroms: Vec<String> = Vec::new();
+    for line in open_table(input)?.lines() {
+        let line = line.map_err(Error::io(input))?;
+        if !is_site(&line) {
+            continue;
+        }
+
+        let chrom = line.split('\t').next().unwrap_or_default();
+        if chroms.last().map(String::as_str) != Some(chrom) {
+            if chroms.iter().any(|c| c == chrom) {
+                return Err(Error::MalformedTable {
+                    path: input.to_string(),
+                    line,
+                });
+            } // The sites of the reference sequence are not contiguous.
+            chroms.push(chrom.to_string());
+        }
+    }
+
+    Ok(chroms)
+}
+
+/// Reader of the counts of a sample from a table, one reference sequence at a time.
+struct TableReader {
+    input: String,
+    lines: io::Lines<BufReader<bgzf::Reader>>,
+    /// The next site of the table.
+    next: Option<SiteCounts>,
+}
+
+impl TableReader {
+    fn open(input: &str) -> Result<Self> {
+        let mut table = Self {
+            input: input.to_string(),
+            lines: open_table(input)?.lines(),
+            next: None,
+        };
+        table.next = table.read_site()?;
+
+        Ok(table)
+    }
+
+    fn read_site(&mut self) -> Result<Option<SiteCounts>> {
+        for line in self.lines.by_ref() {
+            let line = line.map_err(Error::io(&self.input))?;
+            if is_site(&line) {
+                return parse_site(&self.input, line).map(Some);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Reads the sites of `chrom`, if they are next in the table.
+    fn read_chrom(&mut self, chrom: &str) -> Result<Vec<SiteCounts>> {
+        let mut sites = Vec::new();
+        while self.next.as_ref().is_some_and(|site| site.chrom == chrom) {
+            let next = self.read_site()?;
+            sites.extend(std::mem::replace(&mut self.next, next));
+        }
+
+        Ok(sites)
+    }
+}
+
+/// Merges the orders of the reference sequences of the samples into a single order consistent with
+/// each of them, taking the reference sequences in the order they first appear where the orders
+/// of the samples leave a choice.
+fn merge_chrom_orders(orders: &[Vec<String>]) -> Result<Vec<String>> {
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    let mut chroms: Vec<&str> = Vec::new();
+    for chrom in orders.iter().flatten() {
+        if !index.contains_key(chrom.as_str()) {
+            index.insert(chrom, chroms.len());
+            chroms.push(chrom);
+        }
+    }
+
+    // Reference sequences following each reference sequence in the order of a sample.
+    let mut next: Vec<HashSet<usize>> = vec![HashSet::new(); chroms.len()];
+    let mut n_prev = vec![0; chroms.len()];
+    for order in orders.iter() {
+        for pair in order.windows(2) {
+            let (prev, chrom) = (index[pair[0].as_str()], index[pair[1].as_str()]);
+            if next[prev].insert(chrom) {
+                n_prev[chrom] += 1;
+            }
+        }
+    }
+
+    let mut ready: BinaryHeap<Reverse<usize>> = (0..chroms.len())
+        .filter(|&i| n_prev[i] == 0)
+        .map(Reverse)
+        .collect();
+    let mut merged = Vec::with_capacity(chroms.len());
+    while let Some(Reverse(i)) = ready.pop() {
+        merged.push(chroms[i].to_string());
+        for &j in next[i].iter() {
+            n_prev[j] -= 1;
+            if n_prev[j] == 0 {
+                ready.push(Reverse(j));
+            }
+        }
+    }
+
+    match merged.len() == chroms.len() {
+        true => Ok(merged),
+        false => Err(Error::HeaderMismatch(
+            "reference sequences are in different orders in the samples".to_string(),
+        )),
+    }
+}
+
+/// Computes the counts of a sample from the reads in `input` selected by `read_options`.
+fn compute_counts(
+    input: &str,
+    read_options: &readutil::ReadOptions,
+    params: &DiffParams,
+) -> Result<Vec<SiteCounts>> {
+    match params.measure {
+        DiffMeasure::Pdr => {
+            // Depth is checked for each group, so that no sample is left out of its group.
+            let pdr_params = PdrParams {
+                min_depth: 1,
+                min_cpgs: params.min_cpgs,
+                ..Default::default()
+            };
+            let records = metric::compute(
+                input,
+                params.min_qual,
+                &params.cpg_set,
+                read_options,
+                || PdrMetric::new(&pdr_params),
+            )?;
+            let sites = records
+                .into_iter()
+                .map(|r| SiteCounts {
+                    chrom: r.chrom,
+                    start: r.start,
+                    end: r.end,
+                    n_concordant: r.n_concordant,
+                    n_discordant: r.n_discordant,
+                })
+                .collect();
+            Ok(sites)
+        }
+        DiffMeasure::Lpmd => {
+            let lpmd_params = LpmdParams {
+                min_distance: params.min_distance,
+                max_distance: params.max_distance,
+                ..Default::default()
+            };
+            let record = metric::compute(
+                input,
+                params.min_qual,
+                &params.cpg_set,
+                read_options,
+                || LpmdMetric::new(input, &lpmd_params),
+            )?;
+            let sites = record
+                .pairs
+                .into_iter()
+                .map(|r| SiteCounts {
+                    chrom: r.chrom,
+                    start: r.cpg1,
+                    end: r.cpg2,
+                    n_concordant: r.n_concordant as u32,
+                    n_discordant: r.n_discordant as u32,
+                })
+                .collect();
+            Ok(sites)
+        }
+    }
+}
+
+/// Counts of a site in each sample of the two groups.
+#[derive(Default)]
+struct Site {
+    groups: [Vec<(u32, u32)>; 2],
+}
+
+/// Source of the counts of a sample, read one reference sequence at a time.
+enum SampleSource {
+    /// Indexed alignment file, with the regions of each reference sequence.
+    Indexed {
+        input: String,
+        shards: HashMap<String, readutil::ReadOptions>,
+    },
+    /// Alignment file without index, with the counts of all reference sequences computed at once.
+    Computed(HashMap<String, Vec<SiteCounts>>),
+    /// Table written by metheor.
+    Table(TableReader),
+}
+
+/// Whether the alignment file `input` is indexed, so that its reference sequences can be read
+/// one at a time.
+fn is_indexed(input: &str) -> bool {
+    bam::IndexedReader::from_path(input).is_ok()
+}
+
+/// Tests the difference in the fraction of discordant reads between the samples in `group1` and
+/// `group2` for each site covered by at least `min_depth` reads in both groups. Each sample is
+/// either an alignment file (`.bam`, `.sam` or `.cram`) or a table written by `metheor pdr` (or
+/// the CpG pair table of `metheor lpmd`, for LPMD), optionally compressed with BGZF. Samples are
+/// read one reference sequence at a time, so that only the counts of a single reference sequence
+/// are kept in memory, except for alignment files without index. Returns the results sorted by
+/// position.
+pub fn compute_records(
+    group1: &[String],
+    group2: &[String],
+    params: &DiffParams,
+) -> Result<Vec<DiffRecord>> {
+    let read_options = readutil::ReadOptions {
+        threads: 1,
+        ..params.read_options.clone()
+    };
+    let inputs: Vec<(usize, &String, bool)> = group1
+        .iter()
+        .map(|input| (0, input))
+        .chain(group2.iter().map(|input| (1, input)))
+        .map(|(group, input)| (group, input, is_alignment(input) && is_indexed(input)))
+        .collect();
+
+    // Alignment files without index are computed at once, in parallel.
+    let unindexed: Vec<&String> = inputs
+        .iter()
+        .filter(|(_, input, indexed)| is_alignment(input) && !indexed)
+        .map(|(_, input, _)| *input)
+        .collect();
+    let mut unindexed_counts =
+        bamutil::map_shards(&unindexed, params.read_options.threads, |input| {
+            compute_counts(input, &read_options, params)
+        })
+        .into_iter();
+
+    let mut samples: Vec<(usize, SampleSource)> = Vec::new();
+    let mut orders: Vec<Vec<String>> = Vec::new();
+    for &(group, input, indexed) in inputs.iter() {
+        let source = if !is_alignment(input) {
+            orders.push(table_chroms(input)?);
+            SampleSource::Table(TableReader::open(input)?)
+        } else if indexed {
+            let shards = bamutil::reference_shards(input, &read_options)?;
+            orders.push(shards.iter().map(|(chrom, _)| chrom.clone()).collect());
+            SampleSource::Indexed {
+                input: input.clone(),
+                shards: shards.into_iter().collect(),
+            }
+        } else {
+            let sites = unindexed_counts.next().unwrap()?;
+            let mut order = Vec::new();
+            let mut chroms: HashMap<String, Vec<SiteCounts>> = HashMap::new();
+            for site in sites {
+                if !chroms.contains_key(&site.chrom) {
+                    order.push(site.chrom.clone());
+                }
+                chroms.entry(site.chrom.clone()).or_default().push(site);
+            }
+            orders.push(order);
+            SampleSource::Computed(chroms)
+        };
+        samples.push((group, source));
+    }
+
+    let mut records = Vec::new();
+    for chrom in merge_chrom_orders(&orders)? {
+        // Indexed alignment files are computed in parallel, one reference sequence at a time.
+        let indexed: Vec<(&str, Option<&readutil::ReadOptions>)> = samples
+            .iter()
+            .filter_map(|(_, source)| match source {
+                SampleSource::Indexed { input, shards } => {
+                    Some((input.as_str(), shards.get(&chrom)))
+                }
+                _ => None,
+            })
+            .collect();
+        let mut indexed_counts = bamutil::map_shards(
+            &indexed,
+            params.read_options.threads,
+            |(input, shard)| match shard {
+                Some(shard) => compute_counts(input, shard, params),
+                None => Ok(Vec::new()),
+            },
+        )
+        .into_iter();
+
+        let mut sites: BTreeMap<(i32, i32), Site> = BTreeMap::new();
+        for (group, source) in samples.iter_mut() {
+            let sample_sites = match source {
+                SampleSource::Indexed { .. } => indexed_counts.next().unwrap()?,
+                SampleSource::Computed(chroms) => chroms.remove(&chrom).unwrap_or_default(),
+                SampleSource::Table(table) => table.read_chrom(&chrom)?,
+            };
+
+            for site in sample_sites {
+                sites.entry((site.start, site.end)).or_default().groups[*group]
+                    .push((site.n_concordant, site.n_discordant));
+            }
+        }
+
+        for ((start, end), site) in sites {
+            if let Some(record) = test_site(&chrom, start, end, &site, params) {
+                records.push(record);
+            }
+        }
+    }
+
+    let pvalues: Vec<f64> = records.iter().map(|r| r.pvalue).collect();
+    for (record, qvalue) in records.iter_mut().zip(benjamini_hochberg(&pvalues)) {
+        record.qvalue = qvalue;
+    }
+
+    Ok(records)
+}
+
+/// Tests a site covered by at least `min_depth` reads in both groups, before the adjustment of the
+/// p-value for multiple testing.
+fn test_site(
+    chrom: &str,
+    start: i32,
+    end: i32,
+    site: &Site,
+    params: &DiffParams,
+) -> Option<DiffRecord> {
+    let [(n_concordant1, n_discordant1), (n_concordant2, n_discordant2)] =
+        [&site.groups[0], &site.groups[1]].map(|samples| {
+            samples
+                .iter()
+                .fold((0, 0), |(c, d), (sc, sd)| (c + sc, d + sd))
+        });
+    let depth1 = n_concordant1 + n_discordant1;
+    let depth2 = n_concordant2 + n_discordant2;
+    if depth1 < params.min_depth.max(1) || depth2 < params.min_depth.max(1) {
+        return None;
+    }
+
+    let pvalue = match params.test {
+        DiffTest::Fisher => {
+            fisher_exact(n_concordant1, n_discordant1, n_concordant2, n_discordant2)
+        }
+        DiffTest::QuasiBinomial => quasi_binomial_test(&site.groups[0], &site.groups[1]),
+    };
+    let value1 = n_discordant1 as f32 / depth1 as f32;
+    let value2 = n_discordant2 as f32 / depth2 as f32;
+    Some(DiffRecord {
+        chrom: chrom.to_string(),
+        start,
+        end,
+        n_concordant1,
+        n_discordant1,
+        n_concordant2,
+        n_discordant2,
+        value1,
+        value2,
+        diff: value2 - value1,
+        pvalue,
+        qvalue: pvalue,
+    })
+}
+
+/// Merges runs of consecutive significant sites (`qvalue` at most `fdr`) changing in the same
+/// direction, at most `max_gap` bp apart, into regions of at least `min_sites` sites.
+/// `records` must be sorted by position.
+pub fn merge_regions(records: &[DiffRecord], params: &DiffParams) -> Vec<DhrRecord> {
+    // A CpG pair of LPMD ends at the guanine of the second CpG.
+    let site_end = |r: &DiffRecord| match params.measure {
+        DiffMeasure::Pdr => r.end,
+        DiffMeasure::Lpmd => r.end + 2,
+    };
+
+    let mut regions = Vec::new();
+    let mut run: Vec<&DiffRecord> = Vec::new();
+    for record in records.iter() {
+        let significant = record.qvalue <= params.fdr && record.diff != 0.0;
+        let extends = run.last().is_some_and(|last| {
+            last.chrom == record.chrom
+                && (last.diff > 0.0) == (record.diff > 0.0)
+                && record.start - site_end(last) <= params.max_gap
+        });
+        if !(significant && extends) {
+            if run.len() >= params.min_sites.max(1) {
+                regions.push(to_region(&run, site_end(run.last().unwrap())));
+            }
+            run.clear();
+        }
+        if significant {
+            run.push(record);
+        }
+    }
+    if run.len() >= params.min_sites.max(1) {
+        regions.push(to_region(&run, site_end(run.last().unwrap())));
+    }
+
+    regions
+}
+
+fn to_region(run: &[&DiffRecord], end: i32) -> DhrRecord {
+    DhrRecord {
+        chrom: run[0].chrom.clone(),
+        start: run[0].start,
+        end,
+        n_sites: run.len(),
+        mean_diff: run.iter().map(|r| r.diff).sum::<f32>() / run.len() as f32,
+        min_qvalue: run.iter().map(|r| r.qvalue).fold(1.0, f64::min),
+    }
+}
+
+/// Writes the result of the test of each site to `output`, and the differentially heterogeneous
+/// regions to `dhr`, if given.
+pub fn compute(
+    group1: &[String],
+    group2: &[String],
+    output: &str,
+    dhr: &Option<String>,
+    params: &DiffParams,
+) -> Result<()> {
+    let records = compute_records(group1, group2, params)?;
+    metric::write_table(output, Some(&header(params.measure)), &records)?;
+
+    if let Some(dhr) = dhr {
+        let regions = merge_regions(&records, params);
+        metric::write_table(dhr, Some(DHR_HEADER), &regions)?;
+    }
+
+    Ok(())
+}
+
+/// Two-sided p-value of Fisher's exact test on the 2x2 table ((a, b), (c, d)).
+fn fisher_exact(a: u32, b: u32, c: u32, d: u32) -> f64 {
+    let (a, b, c, d) = (a as usize, b as usize, c as usize, d as usize);
+    let (row1, row2, col1, n) = (a + b, c + d, a + c, a + b + c + d);
+
+    let mut ln_factorial = vec![0.0; n + 1];
+    for i in 1..=n {
+        ln_factorial[i] = ln_factorial[i - 1] + (i as f64).ln();
+    }
+    // Log-probability of the table with x in the top left cell, given the margins.
+    let ln_p = |x: usize| {
+        ln_factorial[row1] + ln_factorial[row2] + ln_factorial[col1] + ln_factorial[n - col1]
+            - ln_factorial[n]
+            - ln_factorial[x]
+            - ln_factorial[row1 - x]
+            - ln_factorial[col1 - x]
+            - ln_factorial[row2 + x - col1]
+    };
+
+    let ln_p_observed = ln_p(a);
+    let pvalue: f64 = (col1.saturating_sub(row2)..=row1.min(col1))
+        .map(ln_p)
+        .filter(|&ln_p_x| ln_p_x <= ln_p_observed + 1e-7)
+        .map(f64::exp)
+        .sum();
+
+    pvalue.min(1.0)
+}
+
+/// Two-sided p-value of the difference in the fraction of discordant reads between two groups
+/// of samples, given as (concordant, discordant) counts, by a Wald test on the pooled fractions.
+/// The binomial variance is scaled by the overdispersion between the samples of each group,
+/// estimated by the method of moments (Pearson's chi-square over its degrees of freedom, pooled
+/// over the groups), as in a quasi-binomial model.
+fn quasi_binomial_test(group1: &[(u32, u32)], group2: &[(u32, u32)]) -> f64 {
+    let totals = |samples: &[(u32, u32)]| {
+        samples.iter().fold((0.0, 0.0), |(n, x), &(c, d)| {
+            (n + c as f64 + d as f64, x + d as f64)
+        })
+    };
+    let (n1, x1) = totals(group1);
+    let (n2, x2) = totals(group2);
+    if n1 == 0.0 || n2 == 0.0 {
+        return 1.0;
+    }
+
+    let (chi2_1, df1) = pearson_chi2(group1, x1 / n1);
+    let (chi2_2, df2) = pearson_chi2(group2, x2 / n2);
+    let dispersion = match df1 + df2 {
+        0 => 1.0,
+        df => ((chi2_1 + chi2_2) / df as f64).max(1.0),
+    };
+
+    let p = (x1 + x2) / (n1 + n2);
+    let variance = dispersion * p * (1.0 - p) * (1.0 / n1 + 1.0 / n2);
+    if variance <= 0.0 {
+        return 1.0;
+    }
+
+    let z = (x2 / n2 - x1 / n1) / variance.sqrt();
+    erfc(z.abs() / std::f64::consts::SQRT_2)
+}
+
+/// Pearson's chi-square of the discordant counts of `samples` around the fraction `p`, and its
+/// degrees of freedom. Samples whose fraction is 0 or 1 carry no information on the dispersion,
+/// and have no degrees of freedom.
+fn pearson_chi2(samples: &[(u32, u32)], p: f64) -> (f64, usize) {
+    let samples: Vec<(f64, f64)> = samples
+        .iter()
+        .filter(|&&(c, d)| c + d > 0)
+        .map(|&(c, d)| (c as f64 + d as f64, d as f64))
+        .collect();
+    if samples.len() < 2 || p <= 0.0 || p >= 1.0 {
+        return (0.0, 0);
+    }
+
+    let chi2 = samples
+        .iter()
+        .map(|(n, x)| (x - n * p).powi(2) / (n * p * (1.0 - p)))
+        .sum();
+    (chi2, samples.len() - 1)
+}
+
+/// Complementary error function, accurate to about 1e-15 relative to its value, so that small
+/// p-values are reliable. Computed by the power series of erf below 2 and by the continued fraction
+/// of erfc above.
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let r = if z < 2.0 {
+        // erf(z) = 2 / sqrt(pi) * exp(-z^2) * sum(2^n z^(2n + 1) / (1 * 3 * ... * (2n + 1)))
+        let mut term = z;
+        let mut sum = z;
+        let mut n = 0.0;
+        while term > sum * 1e-17 {
+            n += 1.0;
+            term *= 2.0 * z * z / (2.0 * n + 1.0);
+            sum += term;
+        }
+        1.0 - 2.0 / std::f64::consts::PI.sqrt() * (-z * z).exp() * sum
+    } else {
+        // erfc(z) = exp(-z^2) / sqrt(pi) / (z + (1/2) / (z + 1 / (z + (3/2) / (z + 2 / (z + ...)))))
+        let mut fraction = z;
+        for k in (1..=200).rev() {
+            fraction = z + (k as f64 / 2.0) / fraction;
+        }
+        (-z * z).exp() / std::f64::consts::PI.sqrt() / fraction
+    };
+
+    match x >= 0.0 {
+        true => r,
+        false => 2.0 - r,
+    }
+}
+
+/// Adjusts p-values for multiple testing by the Benjamini-Hochberg procedure.
+fn benjamini_hochberg(pvalues: &[f64]) -> Vec<f64> {
+    let m = pvalues.len();
+    let mut order: Vec<usize> = (0..m).collect();
+    order.sort_by(|&i, &j| pvalues[i].total_cmp(&pvalues[j]));
+
+    let mut qvalues = vec![1.0; m];
+    let mut min_qvalue: f64 = 1.0;
+    for (rank, &i) in order.iter().enumerate().rev() {
+        min_qvalue = min_qvalue.min(pvalues[i] * m as f64 / (rank + 1) as f64);
+        qvalues[i] = min_qvalue;
+    }
+
+    qvalues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_fisher_exact() {
+        assert_close(fisher_exact(1, 9, 11, 3), 0.002759);
+        assert_close(fisher_exact(3, 1, 1, 3), 0.4857);
+        assert_close(fisher_exact(5, 5, 5, 5), 1.0);
+    }
+
+    #[test]
+    fn test_erfc() {
+        assert_close(erfc(0.0), 1.0);
+        assert_close(erfc(1.959964 / std::f64::consts::SQRT_2), 0.05);
+        assert_close(erfc(-1.0), 1.842701);
+        for (x, expected) in [
+            (1.0, 0.157299207050285),
+            (3.0, 2.20904969985854e-5),
+            (5.0, 1.53745979442803e-12),
+        ] {
+            assert!(
+                ((erfc(x) - expected) / expected).abs() < 1e-12,
+                "erfc({}) = {}",
+                x,
+                erfc(x)
+            );
+        }
+    }
+
+    #[test]
+    fn test_quasi_binomial_overdispersion() {
+        let group1 = [(18, 2), (19, 1)];
+        let consistent = [(10, 10), (9, 11)];
+        let inconsistent = [(20, 0), (0, 20)];
+
+        assert!(quasi_binomial_test(&group1, &consistent) < 0.001);
+        assert!(quasi_binomial_test(&group1, &inconsistent) > 0.05);
+        assert_eq!(quasi_binomial_test(&[(10, 0)], &[(10, 0)]), 1.0);
+    }
+
+    #[test]
+    fn test_pearson_chi2_without_variation() {
+        // Samples without discordant reads add no degrees of freedom.
+        assert_eq!(pearson_chi2(&[(10, 0), (20, 0)], 0.0), (0.0, 0));
+        assert_eq!(pearson_chi2(&[(0, 10), (0, 20)], 1.0), (0.0, 0));
+        assert_eq!(pearson_chi2(&[(10, 10), (15, 5)], 15.0 / 40.0).1, 1);
+    }
+
+    #[test]
+    fn test_benjamini_hochberg() {
+        let qvalues = benjamini_hochberg(&[0.01, 0.04, 0.03, 0.2]);
+
+        assert_close(qvalues[0], 0.04);
+        assert_close(qvalues[1], 0.053333);
+        assert_close(qvalues[2], 0.053333);
+        assert_close(qvalues[3], 0.2);
+    }
+
+    fn record(chrom: &str, start: i32, diff: f32, qvalue: f64) -> DiffRecord {
+        DiffRecord {
+            chrom: chrom.to_string(),
+            start,
+            end: start + 2,
+            n_concordant1: 0,
+            n_discordant1: 0,
+            n_concordant2: 0,
+            n_discordant2: 0,
+            value1: 0.0,
+            value2: diff,
+            diff,
+            pvalue: qvalue,
+            qvalue,
+        }
+    }
+
+    #[test]
+    fn test_merge_chrom_orders() {
+        let order = |chroms: &[&str]| chroms.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+
+        let orders = [
+            order(&["chr1", "chr3"]),
+            order(&["chr2", "chr3", "chr4"]),
+            order(&["chr1", "chr2", "chr3"]),
+        ];
+        assert_eq!(
+            merge_chrom_orders(&orders).unwrap(),
+            order(&["chr1", "chr2", "chr3", "chr4"])
+        );
+
+        let orders = [order(&["chr1", "chr2"]), order(&["chr2", "chr1"])];
+        assert!(matches!(
+            merge_chrom_orders(&orders),
+            Err(Error::HeaderMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_merge_regions() {
+        let records = [
+            record("chr1", 0, 0.5, 0.01),
+            record("chr1", 10, 0.4, 0.02),
+            record("chr1", 20, 0.3, 0.01),
+            record("chr1", 30, 0.3, 0.5), // Not significant.
+            record("chr1", 40, 0.3, 0.01),
+            record("chr1", 50, -0.3, 0.01), // Opposite direction.
+            record("chr1", 60, -0.3, 0.01),
+            record("chr2", 0, -0.3, 0.01),
+        ];
+        let params = DiffParams {
+            min_sites: 2,
+            ..Default::default()
+        };
+
+        let regions = merge_regions(&records, &params);
+
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].to_string(), "chr1\t0\t22\t3\t0.4\t1.0000e-2");
+        assert_eq!((regions[1].start, regions[1].end), (50, 62));
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt;
+use std::io;
+
+use rust_htslib::errors::Error as HtslibError;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors that can occur while computing methylation heterogeneity metrics or tagging alignments.
+#[derive(Debug)]
+pub enum Error {
+    /// A (non-alignment) file could not be read or written.
+    Io { path: String, source: io::Error },
+    /// An alignment file could not be opened.
+    OpenAlignment { path: String, source: HtslibError },
+    /// An alignment file is not indexed, although the index is required (e.g., for region queries).
+    MissingIndex { path: String, source: HtslibError },
+    /// An alignment record could not be read, decoded or modified.
+    ReadAlignment(HtslibError),
+    /// An alignment record could not be written.
+    WriteAlignment(HtslibError),
+    /// The output directory does not exist.
+    MissingOutputDirectory(String),
+    /// The reference genome could not be opened or a sequence could not be fetched from it.
+    Reference { path: String, source: HtslibError },
+    /// The reference genome is required, but was not given.
+    MissingReference,
+    /// A read has no Bismark XM tag.
+    MissingXmTag(String),
+    /// A read has no MM/ML base modification tags.
+    MissingModificationTags(String),
+    /// A contig in a region, CpG set or BED file is not in the header of the alignment file.
+    UnknownContig(String),
+    /// A region string could not be parsed.
+    InvalidRegion(String),
+    /// A line of a BED file could not be parsed.
+    MalformedBed { path: String, line: String },
+    /// The header of the alignment file does not match the reference genome or the alignment records.
+    HeaderMismatch(String),
+    /// A line of a sample sheet could not be parsed.
+    MalformedSampleSheet { path: String, line: String },
+    /// A line of a metheor output table given as input could not be parsed.
+    MalformedTable { path: String, line: String },
+    /// A compressed output file could not be opened.
+    WriteOutput { path: String, source: HtslibError },
+    /// The tabix index of a compressed output file could not be built.
+    IndexOutput(String),
+}
+
+impl Error {
+    /// Returns a function wrapping an I/O error on the file at `path`, to be used with `map_err`.
+    pub fn io(path: &str) -> impl FnOnce(io::Error) -> Error + '_ {
+        move |source| Error::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    /// Exit code of the command line program for this kind of error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io { .. } => 3,
+            Error::OpenAlignment { .. } => 4,
+            Error::MissingIndex { .. } => 5,
+            Error::ReadAlignment(_) => 6,
+            Error::WriteAlignment(_) => 7,
+            Error::MissingOutputDirectory(_) => 8,
+            Error::Reference { .. } => 9,
+            Error::MissingReference => 10,
+            Error::MissingXmTag(_) => 11,
+            Error::MissingModificationTags(_) => 12,
+            Error::UnknownContig(_) => 13,
+            Error::InvalidRegion(_) => 14,
+            Error::MalformedBed { .. } => 15,
+            Error::HeaderMismatch(_) => 16,
+            Error::MalformedSampleSheet { .. } => 17,
+            Error::MalformedTable { .. } => 18,
+            Error::WriteOutput { .. } => 19,
+            Error::IndexOutput(_) => 20,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "Cannot access file {}. {}", path, source),
+            Error::OpenAlignment { path, source } => {
+                write!(f, "Cannot open BAM file {}. {}", path, source)
+            }
+            Error::MissingIndex { path, source } => write!(
+                f,
+                "Cannot open indexed BAM file {}. Region queries require an index. {}",
+                path, source
+            ),
+            Error::ReadAlignment(source) => write!(f, "Cannot read alignment record. {}", source),
+            Error::WriteAlignment(source) => {
+                write!(f, "Cannot write alignment record. {}", source)
+            }
+            Error::MissingOutputDirectory(dir) => {
+                write!(f, "No such directory for output file: {}", dir)
+            }
+            Error::Reference { path, source } => {
+                write!(f, "Cannot read reference genome file {}. {}", path, source)
+            }
+            Error::MissingReference => write!(
+                f,
+                "Reference genome is required to call methylation from reference."
+            ),
+            Error::MissingXmTag(qname) => write!(
+                f,
+                "Missing XM tag in BAM record {}. Make sure the reads are aligned using Bismark!",
+                qname
+            ),
+            Error::MissingModificationTags(qname) => write!(
+                f,
+                "Missing MM/ML tags in BAM record {}. Make sure the reads carry base modification calls!",
+                qname
+            ),
+            Error::UnknownContig(contig) => {
+                write!(f, "Contig not found in BAM header: {}", contig)
+            }
+            Error::InvalidRegion(region) => write!(f, "Invalid region: {}", region),
+            Error::MalformedBed { path, line } => {
+                write!(f, "Malformed line in BED file {}: {}", path, line)
+            }
+            Error::HeaderMismatch(message) => {
+                write!(f, "BAM header does not match: {}", message)
+            }
+            Error::MalformedSampleSheet { path, line } => {
+                write!(f, "Malformed line in sample sheet {}: {}", path, line)
+            }
+            Error::MalformedTable { path, line } => {
+                write!(f, "Malformed line in table {}: {}", path, line)
+            }
+            Error::WriteOutput { path, source } => {
+                write!(f, "Cannot open compressed output file {}. {}", path, source)
+            }
+            Error::IndexOutput(path) => write!(
+                f,
+                "Cannot build tabix index of {}. Make sure the table is sorted by position.",
+                path
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::OpenAlignment { source, .. }
+            | Error::MissingIndex { source, .. }
+            | Error::Reference { source, .. }
+            | Error::WriteOutput { source, .. } => Some(source),
+            Error::ReadAlignment(source) | Error::WriteAlignment(source) => Some(source),
+            _ => None,
+        }
+    }
+}
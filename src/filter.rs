@@ -0,0 +1,296 @@
+use rust_htslib::bam::record::Record;
+use std::fmt;
+
+use crate::readutil::ReadOptions;
+
+const FLAG_PROPER_PAIR: u16 = 0x2;
+const FLAG_SECONDARY: u16 = 0x100;
+const FLAG_QC_FAIL: u16 = 0x200;
+const FLAG_DUPLICATE: u16 = 0x400;
+const FLAG_SUPPLEMENTARY: u16 = 0x800;
+
+/// Parses SAM flags given in decimal (e.g., `1024`) or hexadecimal (e.g., `0x400`).
+pub fn parse_flags(s: &str) -> std::result::Result<u16, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse::<u16>(),
+    };
+    parsed.map_err(|_| format!("invalid SAM flags: {}", s))
+}
+
+/// Reason for a read to be filtered out. A read is counted for the first filter it fails,
+/// in the order listed here.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterReason {
+    Secondary,
+    Supplementary,
+    Duplicate,
+    QcFail,
+    ImproperPair,
+    IncludeFlags,
+    ExcludeFlags,
+    LowMapq,
+    /// Read without the aux tag used to group reads.
+    MissingGroupTag,
+}
+
+/// Read filters shared by all metrics, built from `ReadOptions` and the minimum mapping quality.
+#[derive(Clone, Debug)]
+pub struct ReadFilter {
+    skip_secondary: bool,
+    skip_supplementary: bool,
+    skip_duplicates: bool,
+    skip_qc_fail: bool,
+    require_proper_pair: bool,
+    include_flags: u16,
+    exclude_flags: u16,
+    min_qual: u8,
+}
+
+impl ReadFilter {
+    pub fn new(options: &ReadOptions, min_qual: u8) -> Self {
+        Self {
+            skip_secondary: options.skip_secondary,
+            skip_supplementary: options.skip_supplementary,
+            skip_duplicates: options.skip_duplicates,
+            skip_qc_fail: options.skip_qc_fail,
+            require_proper_pair: options.require_proper_pair,
+            include_flags: options.include_flags,
+            exclude_flags: options.exclude_flags,
+            min_qual,
+        }
+    }
+
+    /// Returns the reason for `r` to be filtered out, or `None` if it passes all filters.
+    pub fn check(&self, r: &Record) -> Option<FilterReason> {
+        let flags = r.flags();
+
+        if self.skip_secondary && flags & FLAG_SECONDARY != 0 {
+            Some(FilterReason::Secondary)
+        } else if self.skip_supplementary && flags & FLAG_SUPPLEMENTARY != 0 {
+            Some(FilterReason::Supplementary)
+        } else if self.skip_duplicates && flags & FLAG_DUPLICATE != 0 {
+            Some(FilterReason::Duplicate)
+        } else if self.skip_qc_fail && flags & FLAG_QC_FAIL != 0 {
+            Some(FilterReason::QcFail)
+        } else if self.require_proper_pair && r.is_paired() && flags & FLAG_PROPER_PAIR == 0 {
+            Some(FilterReason::ImproperPair)
+        } else if flags & self.include_flags != self.include_flags {
+            Some(FilterReason::IncludeFlags)
+        } else if flags & self.exclude_flags != 0 {
+            Some(FilterReason::ExcludeFlags)
+        } else if r.mapq() < self.min_qual {
+            Some(FilterReason::LowMapq)
+        } else {
+            None
+        }
+    }
+}
+
+/// Number of reads filtered out by each filter.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FilterCounts {
+    pub total: u64,
+    pub secondary: u64,
+    pub supplementary: u64,
+    pub duplicate: u64,
+    pub qc_fail: u64,
+    pub improper_pair: u64,
+    pub include_flags: u64,
+    pub exclude_flags: u64,
+    pub low_mapq: u64,
+    pub missing_group_tag: u64,
+}
+
+impl FilterCounts {
+    /// Counts a read with the result of `ReadFilter::check`.
+    pub fn add(&mut self, reason: Option<FilterReason>) {
+        self.total += 1;
+        match reason {
+            Some(FilterReason::Secondary) => self.secondary += 1,
+            Some(FilterReason::Supplementary) => self.supplementary += 1,
+            Some(FilterReason::Duplicate) => self.duplicate += 1,
+            Some(FilterReason::QcFail) => self.qc_fail += 1,
+            Some(FilterReason::ImproperPair) => self.improper_pair += 1,
+            Some(FilterReason::IncludeFlags) => self.include_flags += 1,
+            Some(FilterReason::ExcludeFlags) => self.exclude_flags += 1,
+            Some(FilterReason::LowMapq) => self.low_mapq += 1,
+            Some(FilterReason::MissingGroupTag) => self.missing_group_tag += 1,
+            None => {}
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.total += other.total;
+        self.secondary += other.secondary;
+        self.supplementary += other.supplementary;
+        self.duplicate += other.duplicate;
+        self.qc_fail += other.qc_fail;
+        self.improper_pair += other.improper_pair;
+        self.include_flags += other.include_flags;
+        self.exclude_flags += other.exclude_flags;
+        self.low_mapq += other.low_mapq;
+        self.missing_group_tag += other.missing_group_tag;
+    }
+
+    /// Number of reads passing all filters.
+    pub fn passed(&self) -> u64 {
+        self.total
+            - self.secondary
+            - self.supplementary
+            - self.duplicate
+            - self.qc_fail
+            - self.improper_pair
+            - self.include_flags
+            - self.exclude_flags
+            - self.low_mapq
+            - self.missing_group_tag
+    }
+}
+
+impl fmt::Display for FilterCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Processed {} reads, {} passed the read filters.",
+            self.total,
+            self.passed()
+        )?;
+
+        for (count, description) in [
+            (self.secondary, "secondary alignments"),
+            (self.supplementary, "supplementary alignments"),
+            (self.duplicate, "duplicates"),
+            (self.qc_fail, "reads failing quality checks"),
+            (self.improper_pair, "reads not mapped in a proper pair"),
+            (self.include_flags, "reads without --include-flags"),
+            (self.exclude_flags, "reads with --exclude-flags"),
+            (self.low_mapq, "reads with mapping quality below --min-qual"),
+            (self.missing_group_tag, "reads without --group-by-tag"),
+        ] {
+            if count > 0 {
+                write!(f, "\n  Filtered {} {}", count, description)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam;
+
+    fn record_with_flags(flags: u16, mapq: u8) -> Record {
+        let mut header = bam::Header::new();
+        let mut sq = bam::header::HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", "chr1");
+        sq.push_tag(b"LN", 100);
+        header.push_record(&sq);
+        let header = bam::HeaderView::from_header(&header);
+
+        let sam = format!(
+            "read\t{}\tchr1\t1\t{}\t4M\t*\t0\t0\tACGT\tJJJJ\tXM:Z:.Z..",
+            flags, mapq
+        );
+        Record::from_sam(&header, sam.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        assert_eq!(parse_flags("1024"), Ok(1024));
+        assert_eq!(parse_flags("0x400"), Ok(1024));
+        assert_eq!(parse_flags("0XF00"), Ok(3840));
+        assert!(parse_flags("dup").is_err());
+    }
+
+    #[test]
+    fn test_default_filter_only_checks_mapq() {
+        let filter = ReadFilter::new(&ReadOptions::default(), 10);
+
+        for flags in [0, 16, 99, 97, 256, 512, 1024, 2048, 3840] {
+            assert_eq!(filter.check(&record_with_flags(flags, 40)), None);
+        }
+        assert_eq!(
+            filter.check(&record_with_flags(0, 5)),
+            Some(FilterReason::LowMapq)
+        );
+    }
+
+    #[test]
+    fn test_skip_flags() {
+        let options = ReadOptions {
+            skip_duplicates: true,
+            skip_secondary: true,
+            skip_supplementary: true,
+            skip_qc_fail: true,
+            require_proper_pair: true,
+            ..Default::default()
+        };
+        let filter = ReadFilter::new(&options, 10);
+
+        assert_eq!(filter.check(&record_with_flags(0, 40)), None);
+        assert_eq!(filter.check(&record_with_flags(99, 40)), None);
+        assert_eq!(
+            filter.check(&record_with_flags(97, 40)),
+            Some(FilterReason::ImproperPair)
+        );
+        assert_eq!(
+            filter.check(&record_with_flags(1024 | 16, 40)),
+            Some(FilterReason::Duplicate)
+        );
+        assert_eq!(
+            filter.check(&record_with_flags(256, 40)),
+            Some(FilterReason::Secondary)
+        );
+        assert_eq!(
+            filter.check(&record_with_flags(2048 | 1024, 40)),
+            Some(FilterReason::Supplementary)
+        );
+        assert_eq!(
+            filter.check(&record_with_flags(512, 0)),
+            Some(FilterReason::QcFail)
+        );
+    }
+
+    #[test]
+    fn test_include_exclude_flags() {
+        let options = ReadOptions {
+            include_flags: 0x1 | 0x40,
+            exclude_flags: 0x10,
+            ..Default::default()
+        };
+        let filter = ReadFilter::new(&options, 10);
+
+        assert_eq!(filter.check(&record_with_flags(99, 40)), None);
+        assert_eq!(
+            filter.check(&record_with_flags(163, 40)),
+            Some(FilterReason::IncludeFlags)
+        );
+        assert_eq!(
+            filter.check(&record_with_flags(83, 40)),
+            Some(FilterReason::ExcludeFlags)
+        );
+    }
+
+    #[test]
+    fn test_filter_counts() {
+        let mut counts = FilterCounts::default();
+        counts.add(None);
+        counts.add(Some(FilterReason::Duplicate));
+        counts.add(Some(FilterReason::LowMapq));
+
+        let mut other = FilterCounts::default();
+        other.add(Some(FilterReason::Duplicate));
+        counts.merge(&other);
+
+        assert_eq!(counts.total, 4);
+        assert_eq!(counts.duplicate, 2);
+        assert_eq!(counts.passed(), 1);
+        assert_eq!(
+            counts.to_string(),
+            "Processed 4 reads, 1 passed the read filters.\n  Filtered 2 duplicates\n  Filtered 1 reads with mapping quality below --min-qual"
+        );
+    }
+}
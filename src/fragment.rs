@@ -0,0 +1,262 @@
+use rust_htslib::bam::record::Record;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::readutil::{BismarkRead, CpGPosition, MateConflict};
+
+struct PendingRead {
+    br: BismarkRead,
+    is_first: bool,
+    mate_pos: CpGPosition,
+}
+
+/// Assembles fragments from the overlapping mates of read pairs in coordinate-sorted input.
+///
+/// A primary read whose mate is aligned within its span is held until the primary alignment of
+/// the mate arrives, and the two are merged into a single fragment. Other reads (including
+/// secondary and supplementary alignments), and reads whose mate never arrives (e.g., because it
+/// was filtered out), are passed through as they are.
+pub struct MateBuffer {
+    conflict: MateConflict,
+    pending: HashMap<Vec<u8>, PendingRead>,
+    // Pending reads ordered by the position of their mates, to release reads whose mates
+    // were not seen at the expected position.
+    by_mate_pos: BTreeSet<(CpGPosition, Vec<u8>)>,
+    // Pending reads ordered by their first CpG, which bound the CpGs that can be finalized.
+    by_first_cpg: BTreeSet<(CpGPosition, Vec<u8>)>,
+}
+
+impl MateBuffer {
+    pub fn new(conflict: MateConflict) -> Self {
+        Self {
+            conflict,
+            pending: HashMap::new(),
+            by_mate_pos: BTreeSet::new(),
+            by_first_cpg: BTreeSet::new(),
+        }
+    }
+
+    /// Adds a read and returns the fragments that are ready to be observed.
+    pub fn push(&mut self, r: &Record, br: BismarkRead) -> Vec<BismarkRead> {
+        let pos = CpGPosition::new(r.tid(), r.pos() as i32);
+        let mut fragments = self.release_before(pos);
+
+        let qname = r.qname();
+        let is_primary = !r.is_secondary() && !r.is_supplementary();
+        let is_mate = self
+            .pending
+            .get(qname)
+            .is_some_and(|read| read.is_first != r.is_first_in_template());
+        if is_primary && is_mate {
+            let mate = self.remove(qname).expect("pending mate");
+            let merged = match mate.is_first {
+                true => BismarkRead::merge_mates(mate.br, br, self.conflict),
+                false => BismarkRead::merge_mates(br, mate.br, self.conflict),
+            };
+            fragments.push(merged);
+            return fragments;
+        }
+
+        let mate_pos = CpGPosition::new(r.mtid(), r.mpos() as i32);
+        let mate_overlaps = is_primary
+            && r.is_paired()
+            && !r.is_mate_unmapped()
+            && !self.pending.contains_key(qname)
+            && r.mtid() == r.tid()
+            && mate_pos >= pos
+            && mate_pos.pos <= br.get_end_pos();
+
+        if mate_overlaps {
+            let qname = qname.to_vec();
+            self.by_mate_pos.insert((mate_pos, qname.clone()));
+            if let Some(first_cpg) = br.get_first_cpg_position() {
+                self.by_first_cpg.insert((first_cpg, qname.clone()));
+            }
+            self.pending.insert(
+                qname,
+                PendingRead {
+                    br,
+                    is_first: r.is_first_in_template(),
+                    mate_pos,
+                },
+            );
+        } else {
+            fragments.push(br);
+        }
+
+        fragments
+    }
+
+    /// Position of the first CpG of the reads waiting for their mates, if any.
+    /// CpGs from this position on may still be covered by fragments released later.
+    pub fn first_pending_cpg(&self) -> Option<CpGPosition> {
+        self.by_first_cpg.iter().next().map(|(pos, _)| *pos)
+    }
+
+    /// Releases all reads waiting for their mates, at the end of the input.
+    pub fn finish(&mut self) -> Vec<BismarkRead> {
+        self.release_before(CpGPosition::new(i32::MAX, i32::MAX))
+    }
+
+    fn release_before(&mut self, pos: CpGPosition) -> Vec<BismarkRead> {
+        let mut released = Vec::new();
+
+        while let Some((mate_pos, qname)) = self.by_mate_pos.iter().next().cloned() {
+            if mate_pos >= pos {
+                break;
+            }
+            if let Some(read) = self.remove(&qname) {
+                released.push(read.br);
+            }
+        }
+
+        released
+    }
+
+    fn remove(&mut self, qname: &[u8]) -> Option<PendingRead> {
+        let read = self.pending.remove(qname)?;
+
+        self.by_mate_pos.remove(&(read.mate_pos, qname.to_vec()));
+        if let Some(first_cpg) = read.br.get_first_cpg_position() {
+            self.by_first_cpg.remove(&(first_cpg, qname.to_vec()));
+        }
+
+        Some(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam;
+
+    fn record_from_sam(sam: &str) -> Record {
+        let mut header = bam::Header::new();
+        let mut sq = bam::header::HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", "chr1");
+        sq.push_tag(b"LN", 1000);
+        header.push_record(&sq);
+        let header = bam::HeaderView::from_header(&header);
+
+        Record::from_sam(&header, sam.as_bytes()).unwrap()
+    }
+
+    fn push(buffer: &mut MateBuffer, sam: &str) -> Vec<BismarkRead> {
+        let r = record_from_sam(sam);
+        let br = BismarkRead::new(&r).unwrap();
+        buffer.push(&r, br)
+    }
+
+    fn calls(br: &BismarkRead) -> Vec<(i32, bool)> {
+        br.get_cpgs()
+            .iter()
+            .map(|cpg| (cpg.abspos.pos, cpg.methylated))
+            .collect()
+    }
+
+    #[test]
+    fn test_overlapping_mates_are_merged() {
+        let mut buffer = MateBuffer::new(MateConflict::Discard);
+
+        let released = push(
+            &mut buffer,
+            "pair\t99\tchr1\t1\t40\t8M\t=\t5\t12\tTGTGTGTG\tJJJJJJJJ\tXM:Z:Z.Z.Z.Z.",
+        );
+        assert!(released.is_empty());
+        assert_eq!(buffer.first_pending_cpg(), Some(CpGPosition::new(0, 0)));
+
+        let released = push(
+            &mut buffer,
+            "pair\t147\tchr1\t5\t40\t8M\t=\t1\t-12\tTGTGTGTG\tJJJJJJJJ\tXM:Z:Z.z.Z.Z.",
+        );
+        assert_eq!(released.len(), 1);
+        assert_eq!(
+            calls(&released[0]),
+            vec![(0, true), (2, true), (4, true), (8, true), (10, true)]
+        );
+        assert_eq!(released[0].get_start_pos(), 0);
+        assert_eq!(released[0].get_end_pos(), 11);
+        assert_eq!(buffer.first_pending_cpg(), None);
+    }
+
+    #[test]
+    fn test_supplementary_alignments_are_not_merged() {
+        let mut buffer = MateBuffer::new(MateConflict::Discard);
+
+        let released = push(
+            &mut buffer,
+            "pair\t99\tchr1\t1\t40\t8M\t=\t5\t12\tTGTGTGTG\tJJJJJJJJ\tXM:Z:Z.Z.Z.Z.",
+        );
+        assert!(released.is_empty());
+
+        // A supplementary alignment of read 1 is passed through, and read 1 keeps waiting.
+        let released = push(
+            &mut buffer,
+            "pair\t2147\tchr1\t3\t40\t4M\t=\t5\t8\tTGTG\tJJJJ\tXM:Z:z.z.",
+        );
+        assert_eq!(released.len(), 1);
+        assert_eq!(calls(&released[0]), vec![(2, false), (4, false)]);
+        assert_eq!(buffer.first_pending_cpg(), Some(CpGPosition::new(0, 0)));
+
+        let released = push(
+            &mut buffer,
+            "pair\t147\tchr1\t5\t40\t8M\t=\t1\t-12\tTGTGTGTG\tJJJJJJJJ\tXM:Z:Z.z.Z.Z.",
+        );
+        assert_eq!(released.len(), 1);
+        assert_eq!(
+            calls(&released[0]),
+            vec![(0, true), (2, true), (4, true), (8, true), (10, true)]
+        );
+        assert_eq!(buffer.first_pending_cpg(), None);
+    }
+
+    #[test]
+    fn test_mate_conflict_policy() {
+        for (conflict, expected) in [
+            (MateConflict::First, vec![(0, true), (2, true)]),
+            (MateConflict::Second, vec![(0, true), (2, false)]),
+        ] {
+            let mut buffer = MateBuffer::new(conflict);
+            push(
+                &mut buffer,
+                "pair\t99\tchr1\t1\t40\t4M\t=\t3\t6\tTGTG\tJJJJ\tXM:Z:Z.Z.",
+            );
+            let released = push(
+                &mut buffer,
+                "pair\t147\tchr1\t3\t40\t4M\t=\t1\t-6\tTGTG\tJJJJ\tXM:Z:z...",
+            );
+            assert_eq!(calls(&released[0]), expected);
+        }
+    }
+
+    #[test]
+    fn test_non_overlapping_and_orphan_mates_are_passed_through() {
+        let mut buffer = MateBuffer::new(MateConflict::Discard);
+
+        // Mate is aligned beyond the end of the read.
+        let released = push(
+            &mut buffer,
+            "far\t99\tchr1\t1\t40\t4M\t=\t101\t104\tTGTG\tJJJJ\tXM:Z:Z.Z.",
+        );
+        assert_eq!(released.len(), 1);
+
+        // Mate is expected at position 3, but never arrives.
+        let released = push(
+            &mut buffer,
+            "orphan\t99\tchr1\t1\t40\t4M\t=\t3\t6\tTGTG\tJJJJ\tXM:Z:Z.Z.",
+        );
+        assert!(released.is_empty());
+        let released = push(
+            &mut buffer,
+            "single\t0\tchr1\t4\t40\t4M\t*\t0\t0\tTGTG\tJJJJ\tXM:Z:.Z..",
+        );
+        assert_eq!(released.len(), 2);
+        assert_eq!(calls(&released[0]), vec![(0, true), (2, true)]);
+        assert_eq!(calls(&released[1]), vec![(4, true)]);
+
+        push(
+            &mut buffer,
+            "last\t99\tchr1\t11\t40\t4M\t=\t13\t6\tTGTG\tJJJJ\tXM:Z:Z.Z.",
+        );
+        assert_eq!(buffer.finish().len(), 1);
+    }
+}
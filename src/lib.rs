@@ -1,11 +1,32 @@
 use std::str;
 
-use clap::{Parser, Subcommand};
+use clap::{ArgGroup, Parser, Subcommand};
 
+pub mod aggregate;
+pub mod all;
 pub mod bamutil;
+pub mod bigwig;
+pub mod diff;
+pub mod error;
+pub mod fdrp;
+pub mod filter;
+pub mod fragment;
 pub mod lpmd;
+pub mod manifest;
+pub mod matrix;
+pub mod mbias;
+pub mod me;
+pub mod metric;
+pub mod mhl;
+pub mod output;
+pub mod pdr;
+pub mod pm;
 pub mod progressbar;
+pub mod qfdrp;
 pub mod readutil;
+pub mod tag;
+
+pub use error::{Error, Result};
 
 /// Summarizes the heterogeneity of DNA methylation states using BAM files.
 #[derive(Parser)]
@@ -19,6 +40,11 @@ pub mod readutil;
 pub struct Cli {
     #[clap(subcommand)]
     pub command: Commands,
+
+    /// (Optional) Path to a JSON manifest describing the run: version, command line, parameters,
+    /// inputs, read counts and wall time.
+    #[clap(long, global = true, display_order = 100)]
+    pub manifest: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -49,6 +75,22 @@ pub enum Commands {
         /// (Optional) Specify a predefined set of CpGs (in BED file) to be analyzed.
         #[clap(long, short = 'c', required = false, display_order = 6)]
         cpg_set: Option<String>,
+
+        /// Format of the output, a table or a bigWig track.
+        #[clap(
+            long,
+            value_enum,
+            default_value_t = output::OutputFormat::Auto,
+            display_order = 7
+        )]
+        output_format: output::OutputFormat,
+
+        /// Write a header line with the names of the columns to the output table.
+        #[clap(long, display_order = 8)]
+        header: bool,
+
+        #[clap(flatten)]
+        read_options: readutil::ReadOptions,
     },
     /// Compute epipolymorphism.
     #[clap(arg_required_else_help = true)]
@@ -72,6 +114,13 @@ pub enum Commands {
         /// (Optional) Specify a predefined set of CpGs (in BED file) to be analyzed.
         #[clap(long, short = 'c', required = false, display_order = 5)]
         cpg_set: Option<String>,
+
+        /// Write a header line with the names of the columns to the output table.
+        #[clap(long, display_order = 6)]
+        header: bool,
+
+        #[clap(flatten)]
+        read_options: readutil::ReadOptions,
     },
     /// Compute methylation entropy.
     #[clap(arg_required_else_help = true)]
@@ -95,6 +144,13 @@ pub enum Commands {
         /// (Optional) Specify a predefined set of CpGs (in BED file) to be analyzed.
         #[clap(long, short = 'c', required = false, display_order = 5)]
         cpg_set: Option<String>,
+
+        /// Write a header line with the names of the columns to the output table.
+        #[clap(long, display_order = 6)]
+        header: bool,
+
+        #[clap(flatten)]
+        read_options: readutil::ReadOptions,
     },
     /// Compute fraction of discordant read pairs (FDRP).
     #[clap(arg_required_else_help = true)]
@@ -126,6 +182,22 @@ pub enum Commands {
         /// (Optional) Specify a predefined set of CpGs (in BED file) to be analyzed.
         #[clap(long, short = 'c', required = false, display_order = 7)]
         cpg_set: Option<String>,
+
+        /// Format of the output, a table or a bigWig track.
+        #[clap(
+            long,
+            value_enum,
+            default_value_t = output::OutputFormat::Auto,
+            display_order = 8
+        )]
+        output_format: output::OutputFormat,
+
+        /// Write a header line with the names of the columns to the output table.
+        #[clap(long, display_order = 9)]
+        header: bool,
+
+        #[clap(flatten)]
+        read_options: readutil::ReadOptions,
     },
     /// Compute quantitative fraction of discordant read pairs (qFDRP).
     #[clap(arg_required_else_help = true)]
@@ -157,6 +229,22 @@ pub enum Commands {
         /// (Optional) Specify a predefined set of CpGs (in BED file) to be analyzed.
         #[clap(long, short = 'c', required = false, display_order = 7)]
         cpg_set: Option<String>,
+
+        /// Format of the output, a table or a bigWig track.
+        #[clap(
+            long,
+            value_enum,
+            default_value_t = output::OutputFormat::Auto,
+            display_order = 8
+        )]
+        output_format: output::OutputFormat,
+
+        /// Write a header line with the names of the columns to the output table.
+        #[clap(long, display_order = 9)]
+        header: bool,
+
+        #[clap(flatten)]
+        read_options: readutil::ReadOptions,
     },
     /// Compute methylation haplotype load (MHL).
     #[clap(arg_required_else_help = true)]
@@ -184,6 +272,22 @@ pub enum Commands {
         /// (Optional) Specify a predefined set of CpGs (in BED file) to be analyzed.
         #[clap(long, short = 'c', required = false, display_order = 6)]
         cpg_set: Option<String>,
+
+        /// Format of the output, a table or a bigWig track.
+        #[clap(
+            long,
+            value_enum,
+            default_value_t = output::OutputFormat::Auto,
+            display_order = 7
+        )]
+        output_format: output::OutputFormat,
+
+        /// Write a header line with the names of the columns to the output table.
+        #[clap(long, display_order = 8)]
+        header: bool,
+
+        #[clap(flatten)]
+        read_options: readutil::ReadOptions,
     },
     /// Compute local pairwise methylation discordance (LPMD).
     #[clap(arg_required_else_help = true)]
@@ -215,6 +319,291 @@ pub enum Commands {
         /// (Optional) Specify a predefined set of CpGs (in BED file) to be analyzed.
         #[clap(long, short = 'c', required = false, display_order = 7)]
         cpg_set: Option<String>,
+
+        #[clap(flatten)]
+        read_options: readutil::ReadOptions,
+    },
+    /// Report methylation levels by position in reads (M-bias) to choose the bases to ignore.
+    #[clap(arg_required_else_help = true)]
+    Mbias {
+        /// Path to input BAM file.
+        #[clap(long, short = 'i', required = true, display_order = 1)]
+        input: String,
+
+        /// Path to output table file summarizing methylation levels by position in reads.
+        #[clap(long, short = 'o', required = true, display_order = 2)]
+        output: String,
+
+        /// Minimum quality for a read to be considered.
+        #[clap(long, short = 'q', default_value_t = 10, display_order = 3)]
+        min_qual: u8,
+
+        /// (Optional) Specify a predefined set of CpGs (in BED file) to be analyzed.
+        #[clap(long, short = 'c', required = false, display_order = 4)]
+        cpg_set: Option<String>,
+
+        #[clap(flatten)]
+        read_options: readutil::ReadOptions,
+    },
+    /// Compute all measures at the level of regions, pooling the counts over the CpGs of each region.
+    #[clap(arg_required_else_help = true)]
+    #[clap(group(
+        ArgGroup::new("aggregate_regions")
+            .required(true)
+            .args(["bed", "window_size"])
+    ))]
+    Aggregate {
+        /// Input BAM file.
+        #[clap(long, short = 'i', required = true, display_order = 1)]
+        input: String,
+
+        /// BED file of regions (e.g., promoters or CpG islands) to aggregate over.
+        #[clap(long, short = 'b', display_order = 2)]
+        bed: Option<String>,
+
+        /// Size of windows tiling the reference sequences to aggregate over, instead of regions.
+        #[clap(
+            long,
+            short = 'w',
+            value_parser = clap::value_parser!(u64).range(1..),
+            display_order = 3
+        )]
+        window_size: Option<u64>,
+
+        /// Distance between the starts of consecutive windows. [default: window size]
+        #[clap(
+            long,
+            short = 's',
+            requires = "window_size",
+            value_parser = clap::value_parser!(u64).range(1..),
+            display_order = 4
+        )]
+        step: Option<u64>,
+
+        /// Path to output table file summarizing the measures of each region.
+        #[clap(long, short = 'o', required = true, display_order = 5)]
+        output: String,
+
+        /// (Optional) Prefix of output bedGraph files, one for each measure.
+        #[clap(long, display_order = 6)]
+        bedgraph: Option<String>,
+
+        /// Minimum number of consecutive CpGs in a CpG stretch to consider (PDR and MHL).
+        #[clap(long, short = 'p', default_value_t = 4, display_order = 7)]
+        min_cpgs: usize,
+
+        /// Minimum distance between CpG pairs to consider (LPMD).
+        #[clap(long, short = 'm', default_value_t = 2, display_order = 8)]
+        min_distance: i32,
+
+        /// Maximum distance between CpG pairs to consider (LPMD).
+        #[clap(long, short = 'M', default_value_t = 16, display_order = 9)]
+        max_distance: i32,
+
+        /// Minimum quality for a read to be considered.
+        #[clap(long, short = 'q', default_value_t = 10, display_order = 10)]
+        min_qual: u8,
+
+        /// (Optional) Specify a predefined set of CpGs (in BED file) to be analyzed.
+        #[clap(long, short = 'c', required = false, display_order = 11)]
+        cpg_set: Option<String>,
+
+        #[clap(flatten)]
+        read_options: readutil::ReadOptions,
+    },
+    /// Compute multiple measures in a single pass over the BAM file.
+    #[clap(arg_required_else_help = true)]
+    #[clap(group(
+        ArgGroup::new("outputs")
+            .required(true)
+            .multiple(true)
+            .args(["pdr", "pm", "me", "fdrp", "qfdrp", "mhl", "lpmd"])
+    ))]
+    All {
+        /// Input BAM file.
+        #[clap(long, short = 'i', required = true, display_order = 1)]
+        input: String,
+
+        /// (Optional) Path to output table file of PDR.
+        #[clap(long, display_order = 2)]
+        pdr: Option<String>,
+
+        /// (Optional) Path to output table file of PM.
+        #[clap(long, display_order = 3)]
+        pm: Option<String>,
+
+        /// (Optional) Path to output table file of ME.
+        #[clap(long, display_order = 4)]
+        me: Option<String>,
+
+        /// (Optional) Path to output table file of FDRP.
+        #[clap(long, display_order = 5)]
+        fdrp: Option<String>,
+
+        /// (Optional) Path to output table file of qFDRP.
+        #[clap(long, display_order = 6)]
+        qfdrp: Option<String>,
+
+        /// (Optional) Path to output table file of MHL.
+        #[clap(long, display_order = 7)]
+        mhl: Option<String>,
+
+        /// (Optional) Path to output table file of LPMD.
+        #[clap(long, display_order = 8)]
+        lpmd: Option<String>,
+
+        /// (Optional) Concordance information for all CpG pairs of LPMD.
+        #[clap(long, requires = "lpmd", display_order = 9)]
+        lpmd_pairs: Option<String>,
+
+        /// Minimum depth of CpGs (CpG quartets for PM and ME) to consider.
+        #[clap(long, short = 'd', default_value_t = 10, display_order = 10)]
+        min_depth: u32,
+
+        /// Minimum number of consecutive CpGs in a CpG stretch to consider (PDR and MHL).
+        #[clap(long, short = 'p', default_value_t = 4, display_order = 11)]
+        min_cpgs: usize,
+
+        /// Minimum quality for a read to be considered.
+        #[clap(long, short = 'q', default_value_t = 10, display_order = 12)]
+        min_qual: u8,
+
+        /// Maximum number of reads to consider (FDRP and qFDRP).
+        #[clap(long, short = 'D', default_value_t = 40, display_order = 13)]
+        max_depth: usize,
+
+        /// Minimum overlap between two reads to consider in bp (FDRP and qFDRP).
+        #[clap(long, short = 'l', default_value_t = 35, display_order = 14)]
+        min_overlap: i32,
+
+        /// Minimum distance between CpG pairs to consider (LPMD).
+        #[clap(long, short = 'm', default_value_t = 2, display_order = 15)]
+        min_distance: i32,
+
+        /// Maximum distance between CpG pairs to consider (LPMD).
+        #[clap(long, short = 'M', default_value_t = 16, display_order = 16)]
+        max_distance: i32,
+
+        /// (Optional) Specify a predefined set of CpGs (in BED file) to be analyzed.
+        #[clap(long, short = 'c', required = false, display_order = 17)]
+        cpg_set: Option<String>,
+
+        /// Write a header line with the names of the columns to the output tables.
+        #[clap(long, display_order = 18)]
+        header: bool,
+
+        #[clap(flatten)]
+        read_options: readutil::ReadOptions,
+    },
+    /// Compute a measure for multiple samples and write a CpG x sample matrix.
+    #[clap(arg_required_else_help = true)]
+    Matrix {
+        /// Tab-separated sample sheet with a sample name and the path to its sorted BAM file per line.
+        #[clap(long, short = 's', required = true, display_order = 1)]
+        samples: String,
+
+        /// Path to output matrix file, with a row for each CpG and a column for each sample.
+        #[clap(long, short = 'o', required = true, display_order = 2)]
+        output: String,
+
+        /// Measure to compute.
+        #[clap(long, value_enum, default_value_t = matrix::MatrixMetric::Pdr, display_order = 3)]
+        metric: matrix::MatrixMetric,
+
+        /// Minimum depth of CpGs (CpG quartets for PM and ME) to consider. Other values are NA.
+        #[clap(long, short = 'd', default_value_t = 10, display_order = 4)]
+        min_depth: u32,
+
+        /// Minimum number of consecutive CpGs in a CpG stretch to consider (PDR and MHL).
+        #[clap(long, short = 'p', default_value_t = 4, display_order = 5)]
+        min_cpgs: usize,
+
+        /// Minimum quality for a read to be considered.
+        #[clap(long, short = 'q', default_value_t = 10, display_order = 6)]
+        min_qual: u8,
+
+        /// Maximum number of reads to consider (FDRP and qFDRP).
+        #[clap(long, short = 'D', default_value_t = 40, display_order = 7)]
+        max_depth: usize,
+
+        /// Minimum overlap between two reads to consider in bp (FDRP and qFDRP).
+        #[clap(long, short = 'l', default_value_t = 35, display_order = 8)]
+        min_overlap: i32,
+
+        /// (Optional) Specify a predefined set of CpGs (in BED file) to be analyzed.
+        #[clap(long, short = 'c', required = false, display_order = 9)]
+        cpg_set: Option<String>,
+
+        #[clap(flatten)]
+        read_options: readutil::ReadOptions,
+    },
+    /// Test differential heterogeneity between two groups of samples.
+    #[clap(arg_required_else_help = true)]
+    Diff {
+        /// BAM files (or PDR tables, or LPMD CpG pair tables) of the samples in the first group.
+        #[clap(long, short = 'a', required = true, num_args = 1.., display_order = 1)]
+        group1: Vec<String>,
+
+        /// BAM files (or PDR tables, or LPMD CpG pair tables) of the samples in the second group.
+        #[clap(long, short = 'b', required = true, num_args = 1.., display_order = 2)]
+        group2: Vec<String>,
+
+        /// Path to output table file summarizing the test of each CpG (or CpG pair for LPMD).
+        #[clap(long, short = 'o', required = true, display_order = 3)]
+        output: String,
+
+        /// (Optional) Path to output table file of differentially heterogeneous regions.
+        #[clap(long, display_order = 4)]
+        dhr: Option<String>,
+
+        /// Measure of heterogeneity to compare.
+        #[clap(long, value_enum, default_value_t = diff::DiffMeasure::Pdr, display_order = 5)]
+        measure: diff::DiffMeasure,
+
+        /// Statistical test for the difference in the fraction of discordant reads. Fisher's exact
+        /// test sums the counts over the samples of each group, ignoring the variation between
+        /// replicates; use quasi-binomial when the groups have several samples.
+        #[clap(long, value_enum, default_value_t = diff::DiffTest::Fisher, display_order = 6)]
+        test: diff::DiffTest,
+
+        /// Maximum false discovery rate of significant CpGs merged into regions.
+        #[clap(long, default_value_t = 0.05, display_order = 7)]
+        fdr: f64,
+
+        /// Maximum distance between adjacent significant CpGs merged into a region in bp.
+        #[clap(long, default_value_t = 100, display_order = 8)]
+        max_gap: i32,
+
+        /// Minimum number of significant CpGs in a region.
+        #[clap(long, default_value_t = 3, display_order = 9)]
+        min_sites: usize,
+
+        /// Minimum depth of CpGs to consider in each group, summed over the samples of the group.
+        #[clap(long, short = 'd', default_value_t = 10, display_order = 10)]
+        min_depth: u32,
+
+        /// Minimum number of consecutive CpGs in a CpG stretch to consider (PDR).
+        #[clap(long, short = 'p', default_value_t = 4, display_order = 11)]
+        min_cpgs: usize,
+
+        /// Minimum distance between CpG pairs to consider (LPMD).
+        #[clap(long, short = 'm', default_value_t = 2, display_order = 12)]
+        min_distance: i32,
+
+        /// Maximum distance between CpG pairs to consider (LPMD).
+        #[clap(long, short = 'M', default_value_t = 16, display_order = 13)]
+        max_distance: i32,
+
+        /// Minimum quality for a read to be considered.
+        #[clap(long, short = 'q', default_value_t = 10, display_order = 14)]
+        min_qual: u8,
+
+        /// (Optional) Specify a predefined set of CpGs (in BED file) to be analyzed.
+        #[clap(long, short = 'c', required = false, display_order = 15)]
+        cpg_set: Option<String>,
+
+        #[clap(flatten)]
+        read_options: readutil::ReadOptions,
     },
     /// Add bismark XM tag to BAM file.
     #[clap(arg_required_else_help = true)]
@@ -227,5 +616,53 @@ pub enum Commands {
 
         #[clap(long, short = 'g', required = true, display_order = 3)]
         genome: String,
+
+        /// Path to reference genome fasta file used to decode CRAM input. Defaults to `--genome`.
+        #[clap(long, display_order = 4)]
+        reference: Option<String>,
+
+        /// Number of threads for decompressing the input and compressing the output.
+        #[clap(long, short = 't', default_value_t = 1, display_order = 5)]
+        threads: usize,
+
+        /// (Optional) Format of the output. Determined by the extension of the output path
+        /// (`.bam`, `.cram`, SAM otherwise) if not given.
+        #[clap(long, value_enum, display_order = 6)]
+        output_format: Option<tag::AlignmentFormat>,
+
+        /// (Optional) Compression level of BAM and CRAM output, from 0 (uncompressed) to 9.
+        #[clap(
+            long,
+            value_parser = clap::value_parser!(u32).range(0..=9),
+            display_order = 7
+        )]
+        compression_level: Option<u32>,
+
+        /// (Optional) Number of threads for compressing the output. Shares the threads of
+        /// `--threads` if not given.
+        #[clap(long, display_order = 8)]
+        writer_threads: Option<usize>,
+
+        /// (Optional) Type of the library. Strands of reads from non-directional libraries are
+        /// inferred from their C-to-T and G-to-A conversions.
+        #[clap(
+            long,
+            value_enum,
+            default_value_t = tag::Library::Directional,
+            display_order = 9
+        )]
+        library: tag::Library,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn verify_cli() {
+        // Catches conflicting ids of arguments and groups, e.g., with the flattened read options.
+        Cli::command().debug_assert();
+    }
+}
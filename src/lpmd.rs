@@ -1,13 +1,11 @@
-use rust_htslib::{bam, bam::Read};
-use std::{collections::HashMap, fs};
-use std::{io::Write, str, vec::Vec};
+use rust_htslib::bam;
+use std::{collections::HashMap, fmt, str, vec::Vec};
 
-use crate::{bamutil, progressbar, readutil};
+use crate::error::Result;
+use crate::metric::{self, HeterogeneityMetric};
+use crate::{bamutil, readutil};
 
-pub struct LPMDResult {
-    header: bam::HeaderView,
-    n_read: i32,
-    n_valid_read: i32,
+struct LPMDResult {
     n_concordant: i32,
     n_discordant: i32,
     pair2n_concordant: HashMap<(readutil::CpGPosition, readutil::CpGPosition), i32>,
@@ -15,16 +13,13 @@ pub struct LPMDResult {
 }
 
 impl LPMDResult {
-    fn new(header: bam::HeaderView) -> Self {
+    fn new() -> Self {
         let pair2n_concordant: HashMap<(readutil::CpGPosition, readutil::CpGPosition), i32> =
             HashMap::new();
         let pair2n_discordant: HashMap<(readutil::CpGPosition, readutil::CpGPosition), i32> =
             HashMap::new();
 
         Self {
-            header,
-            n_read: 0,
-            n_valid_read: 0,
             n_concordant: 0,
             n_discordant: 0,
             pair2n_concordant,
@@ -32,14 +27,6 @@ impl LPMDResult {
         }
     }
 
-    fn inc_n_read(&mut self, i: i32) {
-        self.n_read += i;
-    }
-
-    fn inc_n_valid_read(&mut self, i: i32) {
-        self.n_valid_read += i;
-    }
-
     fn inc_n_concordant(&mut self, i: i32) {
         self.n_concordant += i;
     }
@@ -54,17 +41,17 @@ impl LPMDResult {
         lpmd
     }
 
-    fn progress_string(&self) -> String {
-        let lpmd = self.compute_lpmd();
+    /// Adds the counts of another result, e.g., computed on a different reference sequence.
+    fn merge(&mut self, other: LPMDResult) {
+        self.n_concordant += other.n_concordant;
+        self.n_discordant += other.n_discordant;
 
-        format!(
-            "Processed {} reads, found {} valid reads. LPMD={:.4} ({}/{})",
-            self.n_read,
-            self.n_valid_read,
-            lpmd,
-            self.n_discordant,
-            self.n_concordant + self.n_discordant
-        )
+        for (pair, n) in other.pair2n_concordant {
+            *self.pair2n_concordant.entry(pair).or_insert(0) += n;
+        }
+        for (pair, n) in other.pair2n_discordant {
+            *self.pair2n_discordant.entry(pair).or_insert(0) += n;
+        }
     }
 
     fn add_pair_concordance(
@@ -86,119 +73,243 @@ impl LPMDResult {
         }
     }
 
-    fn print_pair_statistics(&self, output: &str) {
+    fn to_record(&self, name: &str, header: &bam::HeaderView) -> LpmdRecord {
         let mut pairs: Vec<&(readutil::CpGPosition, readutil::CpGPosition)> = self
             .pair2n_concordant
             .keys()
             .collect::<Vec<&(readutil::CpGPosition, readutil::CpGPosition)>>();
         pairs.sort();
 
-        let mut out = fs::OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .truncate(true)
-            .open(output)
-            .unwrap();
-
-        writeln!(out, "chrom\tcpg1\tcpg2\tlpmd\tn_concordant\tn_discordant")
-            .expect("Error writing to output file.");
-
-        for (cpg1, cpg2) in pairs {
-            let k = (*cpg1, *cpg2);
-            let n_concordant = self.pair2n_concordant[&k];
-            let n_discordant = self.pair2n_discordant[&k];
-            let lpmd = (n_discordant as f32) / (n_concordant as f32 + n_discordant as f32);
-
-            let chrom = bamutil::tid2chrom(cpg1.tid, &self.header);
-
-            writeln!(
-                out,
-                "{}\t{}\t{}\t{}\t{}\t{}",
-                chrom, cpg1.pos, cpg2.pos, lpmd, n_concordant, n_discordant
-            )
-            .expect("Error writing to output file.");
+        let pairs = pairs
+            .into_iter()
+            .map(|(cpg1, cpg2)| {
+                let k = (*cpg1, *cpg2);
+                let n_concordant = self.pair2n_concordant[&k];
+                let n_discordant = self.pair2n_discordant[&k];
+                let lpmd = (n_discordant as f32) / (n_concordant as f32 + n_discordant as f32);
+
+                LpmdPairRecord {
+                    chrom: bamutil::tid2chrom(cpg1.tid, header),
+                    cpg1: cpg1.pos,
+                    cpg2: cpg2.pos,
+                    lpmd,
+                    n_concordant,
+                    n_discordant,
+                }
+            })
+            .collect();
+
+        LpmdRecord {
+            name: name.to_string(),
+            lpmd: self.compute_lpmd(),
+            n_concordant: self.n_concordant,
+            n_discordant: self.n_discordant,
+            pairs,
         }
     }
 }
 
-pub fn compute(
-    input: &str,
-    output: &str,
-    min_distance: i32,
-    max_distance: i32,
-    min_qual: u8,
-    cpg_set: &Option<String>,
-    pairs: &Option<String>,
-) {
-    let result = compute_helper(input, min_distance, max_distance, min_qual, cpg_set);
-    let lpmd = result.compute_lpmd();
+/// Parameters of LPMD calculation.
+#[derive(Clone)]
+pub struct LpmdParams {
+    /// Minimum distance between CpG pairs to consider.
+    pub min_distance: i32,
+    /// Maximum distance between CpG pairs to consider.
+    pub max_distance: i32,
+    /// Minimum quality for a read to be considered.
+    pub min_qual: u8,
+    /// (Optional) Predefined set of CpGs (in BED file) to be analyzed.
+    pub cpg_set: Option<String>,
+    pub read_options: readutil::ReadOptions,
+}
+
+impl Default for LpmdParams {
+    fn default() -> Self {
+        Self {
+            min_distance: 2,
+            max_distance: 16,
+            min_qual: 10,
+            cpg_set: None,
+            read_options: readutil::ReadOptions::default(),
+        }
+    }
+}
 
-    let mut out = fs::OpenOptions::new()
-        .create(true)
-        .read(true)
-        .write(true)
-        .truncate(true)
-        .open(output)
-        .unwrap();
+/// LPMD of a sample. Formatted as a line of the output table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LpmdRecord {
+    pub name: String,
+    pub lpmd: f32,
+    pub n_concordant: i32,
+    pub n_discordant: i32,
+    /// Concordance information for all CpG pairs, sorted by position.
+    pub pairs: Vec<LpmdPairRecord>,
+}
 
-    writeln!(out, "name\tlpmd").expect("Error writing to output file.");
+impl fmt::Display for LpmdRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\t{}", self.name, self.lpmd)
+    }
+}
 
-    writeln!(out, "{}\t{}", input, lpmd).expect("Error writing to output file.");
+/// LPMD of a single CpG pair. Formatted as a line of the CpG pair table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LpmdPairRecord {
+    pub chrom: String,
+    /// 0-based position of the cytosine (C) in the first CpG.
+    pub cpg1: i32,
+    /// 0-based position of the cytosine (C) in the second CpG.
+    pub cpg2: i32,
+    pub lpmd: f32,
+    pub n_concordant: i32,
+    pub n_discordant: i32,
+}
 
-    if let Some(f) = pairs {
-        result.print_pair_statistics(f);
+impl fmt::Display for LpmdPairRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            self.chrom, self.cpg1, self.cpg2, self.lpmd, self.n_concordant, self.n_discordant
+        )
     }
 }
 
-fn compute_helper(
-    input: &str,
+/// LPMD as a [`HeterogeneityMetric`].
+pub struct LpmdMetric {
+    name: String,
     min_distance: i32,
     max_distance: i32,
-    min_qual: u8,
-    cpg_set: &Option<String>,
-) -> LPMDResult {
-    eprintln!(
-        "Computing subset-LPMD with parameters input={}, min_distance={}, max_distance={}",
-        input, min_distance, max_distance
-    );
-    let mut reader = bamutil::get_reader(input);
-    let header = bamutil::get_header(&reader);
-
-    eprint!("Processing target CpG set... ");
-    let target_cpgs = &readutil::get_target_cpgs(cpg_set, &header);
-
-    let mut res = LPMDResult::new(header);
-    let bar = progressbar::ProgressBar::new();
+    result: LPMDResult,
+}
 
-    // Iterate over reads and compute LPMD.
-    for r in reader.records().map(|r| r.unwrap()) {
-        res.inc_n_read(1);
-        if r.mapq() < min_qual {
-            continue;
+impl LpmdMetric {
+    /// Creates the metric for a sample, named `name` in the output.
+    pub fn new(name: &str, params: &LpmdParams) -> Self {
+        Self {
+            name: name.to_string(),
+            min_distance: params.min_distance,
+            max_distance: params.max_distance,
+            result: LPMDResult::new(),
         }
+    }
+}
 
-        let mut br = readutil::BismarkRead::new(&r);
-        if let Some(target_cpgs) = target_cpgs {
-            br.filter_isin(target_cpgs);
-        }
+impl HeterogeneityMetric for LpmdMetric {
+    type Output = LpmdRecord;
 
+    fn observe_read(&mut self, br: &readutil::BismarkRead) {
         let (c, d, pair2concordance) =
-            br.compute_pairwise_cpg_concordance_discordance(min_distance, max_distance);
+            br.compute_pairwise_cpg_concordance_discordance(self.min_distance, self.max_distance);
 
-        res.inc_n_valid_read(1);
-        res.inc_n_concordant(c);
-        res.inc_n_discordant(d);
+        self.result.inc_n_concordant(c);
+        self.result.inc_n_discordant(d);
         for (cpg1, cpg2, concordance) in &pair2concordance {
-            res.add_pair_concordance(cpg1, cpg2, concordance);
+            self.result.add_pair_concordance(cpg1, cpg2, concordance);
         }
+    }
 
-        if res.n_read % 10000 == 0 {
-            bar.update_lpmd(res.progress_string());
-        }
+    fn merge(&mut self, other: Self) {
+        self.result.merge(other.result);
     }
 
-    res
+    fn finalize(self, header: &bam::HeaderView) -> LpmdRecord {
+        self.result.to_record(&self.name, header)
+    }
+}
+
+/// Writes the LPMD of a sample to `output`, and the concordance information for all CpG pairs
+/// to `pairs`, if given.
+pub fn write_record(output: &str, pairs: &Option<String>, record: &LpmdRecord) -> Result<()> {
+    metric::write_table(output, Some("name\tlpmd"), std::slice::from_ref(record))?;
+
+    if let Some(f) = pairs {
+        metric::write_table(
+            f,
+            Some("chrom\tcpg1\tcpg2\tlpmd\tn_concordant\tn_discordant"),
+            &record.pairs,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Writes the LPMD of each group of reads to `output` as a single table, and the concordance
+/// information for all CpG pairs to `pairs`, if given, with the name of the group in the first column.
+pub fn write_grouped_records(
+    output: &str,
+    pairs: &Option<String>,
+    groups: &[(String, LpmdRecord)],
+) -> Result<()> {
+    let records: Vec<(String, &[LpmdRecord])> = groups
+        .iter()
+        .map(|(group, record)| (group.clone(), std::slice::from_ref(record)))
+        .collect();
+    metric::write_grouped_table(output, Some("name\tlpmd"), &records)?;
+
+    if let Some(f) = pairs {
+        let pair_records: Vec<(String, &[LpmdPairRecord])> = groups
+            .iter()
+            .map(|(group, record)| (group.clone(), record.pairs.as_slice()))
+            .collect();
+        metric::write_grouped_table(
+            f,
+            Some("chrom\tcpg1\tcpg2\tlpmd\tn_concordant\tn_discordant"),
+            &pair_records,
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn compute(
+    input: &str,
+    output: &str,
+    pairs: &Option<String>,
+    params: &LpmdParams,
+) -> Result<()> {
+    if params.read_options.group_by_tag.is_some() {
+        let groups = compute_grouped_records(input, params)?;
+        return write_grouped_records(output, pairs, &groups);
+    }
+
+    let record = compute_records(input, params)?;
+
+    write_record(output, pairs, &record)
+}
+
+/// Computes LPMD of the reads in `input`, along with the concordance information for all CpG pairs.
+pub fn compute_records(input: &str, params: &LpmdParams) -> Result<LpmdRecord> {
+    eprintln!(
+        "Computing subset-LPMD with parameters input={}, min_distance={}, max_distance={}",
+        input, params.min_distance, params.max_distance
+    );
+    metric::compute(
+        input,
+        params.min_qual,
+        &params.cpg_set,
+        &params.read_options,
+        || LpmdMetric::new(input, params),
+    )
+}
+
+/// Computes LPMD separately for each group of reads sharing the value of the aux tag given by
+/// `group_by_tag` of `read_options`, sorted by the name of the group.
+pub fn compute_grouped_records(
+    input: &str,
+    params: &LpmdParams,
+) -> Result<Vec<(String, LpmdRecord)>> {
+    eprintln!(
+        "Computing subset-LPMD with parameters input={}, min_distance={}, max_distance={}",
+        input, params.min_distance, params.max_distance
+    );
+    metric::compute_grouped(
+        input,
+        params.min_qual,
+        &params.cpg_set,
+        &params.read_options,
+        || LpmdMetric::new(input, params),
+    )
 }
 
 #[cfg(test)]
@@ -209,62 +320,68 @@ mod tests {
     fn test1() {
         let input = "tests/test1.bam";
 
-        let min_distance = 2;
-        let max_distance = 16;
-        let min_qual = 10;
-        let cpg_set = None;
+        let params = LpmdParams::default();
 
-        let result = compute_helper(input, min_distance, max_distance, min_qual, &cpg_set);
+        let record = compute_records(input, &params).unwrap();
 
-        assert_eq!(result.compute_lpmd(), 0.5);
+        assert_eq!(record.lpmd, 0.5);
     }
     #[test]
     fn test2() {
         let input = "tests/test2.bam";
-        let min_distance = 2;
-        let max_distance = 16;
-        let min_qual = 10;
-        let cpg_set = None;
+        let params = LpmdParams::default();
 
-        let result = compute_helper(input, min_distance, max_distance, min_qual, &cpg_set);
+        let record = compute_records(input, &params).unwrap();
 
-        assert_eq!(result.compute_lpmd(), 0.0);
+        assert_eq!(record.lpmd, 0.0);
     }
     #[test]
     fn test3() {
         let input = "tests/test3.bam";
-        let min_distance = 2;
-        let max_distance = 16;
-        let min_qual = 10;
-        let cpg_set = None;
+        let params = LpmdParams::default();
 
-        let result = compute_helper(input, min_distance, max_distance, min_qual, &cpg_set);
+        let record = compute_records(input, &params).unwrap();
 
-        assert_eq!(result.compute_lpmd(), 0.0);
+        assert_eq!(record.lpmd, 0.0);
     }
     #[test]
     fn test4() {
         let input = "tests/test4.bam";
-        let min_distance = 2;
-        let max_distance = 16;
-        let min_qual = 10;
-        let cpg_set = None;
+        let params = LpmdParams::default();
 
-        let result = compute_helper(input, min_distance, max_distance, min_qual, &cpg_set);
+        let record = compute_records(input, &params).unwrap();
 
-        assert_eq!(result.compute_lpmd(), 0.5);
+        assert_eq!(record.lpmd, 0.5);
     }
     #[test]
     fn test5() {
         // No reads pass quality cutoff.
         let input = "tests/test5.bam";
-        let min_distance = 2;
-        let max_distance = 16;
-        let min_qual = 10;
-        let cpg_set = None;
+        let params = LpmdParams::default();
 
-        let result = compute_helper(input, min_distance, max_distance, min_qual, &cpg_set);
+        let record = compute_records(input, &params).unwrap();
 
-        assert!(result.compute_lpmd().is_nan());
+        assert!(record.lpmd.is_nan());
+    }
+    #[test]
+    fn test9_threads() {
+        // Reads of test4.bam are duplicated on chr1 and chr2.
+        let input = "tests/test9.bam";
+        let params = LpmdParams {
+            read_options: readutil::ReadOptions {
+                threads: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let record = compute_records(input, &params).unwrap();
+        let serial_record = compute_records("tests/test4.bam", &LpmdParams::default()).unwrap();
+
+        assert_eq!(record.lpmd, 0.5);
+        assert_eq!(record.n_concordant, serial_record.n_concordant * 2);
+        assert_eq!(record.n_discordant, serial_record.n_discordant * 2);
+        // Six CpG pairs in each of the two read groups, on both chromosomes.
+        assert_eq!(record.pairs.len(), 24);
     }
 }
@@ -1,21 +1,46 @@
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches};
+use std::process;
+use std::time::Instant;
 
-mod bamutil;
-mod fdrp;
-mod lpmd;
-mod me;
-mod mhl;
-mod pdr;
-mod pm;
-mod progressbar;
-mod qfdrp;
-mod readutil;
-mod tag;
+use metheor::{
+    aggregate, all, diff, fdrp, lpmd, manifest, matrix, mbias, me, mhl, pdr, pm, qfdrp, tag,
+};
 
 fn main() {
-    let args = metheor::Cli::parse();
+    let start = Instant::now();
 
-    match &args.command {
+    let mut command = metheor::Cli::command();
+    let matches = command.get_matches_mut();
+    let args = metheor::Cli::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+
+    let result = run(&args.command).and_then(|_| {
+        report_filter_counts();
+        match &args.manifest {
+            Some(path) => manifest::write(path, &command, &matches, start.elapsed()),
+            None => Ok(()),
+        }
+    });
+
+    if let Err(error) = result {
+        eprintln!("Error: {}", error);
+        process::exit(error.exit_code())
+    }
+}
+
+/// Reports the number of reads filtered out by each filter to the standard error, for each input
+/// whose reads were filtered.
+fn report_filter_counts() {
+    let inputs = manifest::filter_counts();
+    for (path, counts) in inputs.iter() {
+        match inputs.len() {
+            1 => eprintln!("{}", counts),
+            _ => eprintln!("{}: {}", path, counts),
+        }
+    }
+}
+
+fn run(command: &metheor::Commands) -> metheor::Result<()> {
+    match command {
         metheor::Commands::Pdr {
             input,
             output,
@@ -23,27 +48,60 @@ fn main() {
             min_cpgs,
             min_qual,
             cpg_set,
-        } => {
-            pdr::compute(input, output, *min_depth, *min_cpgs, *min_qual, cpg_set);
-        }
+            output_format,
+            header,
+            read_options,
+        } => pdr::compute(
+            input,
+            output,
+            *output_format,
+            *header,
+            &pdr::PdrParams {
+                min_depth: *min_depth,
+                min_cpgs: *min_cpgs,
+                min_qual: *min_qual,
+                cpg_set: cpg_set.clone(),
+                read_options: read_options.clone(),
+            },
+        ),
         metheor::Commands::Pm {
             input,
             output,
             min_depth,
             min_qual,
             cpg_set,
-        } => {
-            pm::compute(input, output, *min_depth, *min_qual, cpg_set);
-        }
+            header,
+            read_options,
+        } => pm::compute(
+            input,
+            output,
+            *header,
+            &pm::PmParams {
+                min_depth: *min_depth,
+                min_qual: *min_qual,
+                cpg_set: cpg_set.clone(),
+                read_options: read_options.clone(),
+            },
+        ),
         metheor::Commands::Me {
             input,
             output,
             min_depth,
             min_qual,
             cpg_set,
-        } => {
-            me::compute(input, output, *min_depth, *min_qual, cpg_set);
-        }
+            header,
+            read_options,
+        } => me::compute(
+            input,
+            output,
+            *header,
+            &me::MeParams {
+                min_depth: *min_depth,
+                min_qual: *min_qual,
+                cpg_set: cpg_set.clone(),
+                read_options: read_options.clone(),
+            },
+        ),
         metheor::Commands::Fdrp {
             input,
             output,
@@ -52,17 +110,23 @@ fn main() {
             max_depth,
             min_overlap,
             cpg_set,
-        } => {
-            fdrp::compute(
-                input,
-                output,
-                *min_qual,
-                *min_depth,
-                *max_depth,
-                *min_overlap,
-                cpg_set,
-            );
-        }
+            output_format,
+            header,
+            read_options,
+        } => fdrp::compute(
+            input,
+            output,
+            *output_format,
+            *header,
+            &fdrp::FdrpParams {
+                min_qual: *min_qual,
+                min_depth: *min_depth,
+                max_depth: *max_depth,
+                min_overlap: *min_overlap,
+                cpg_set: cpg_set.clone(),
+                read_options: read_options.clone(),
+            },
+        ),
         metheor::Commands::Qfdrp {
             input,
             output,
@@ -71,17 +135,23 @@ fn main() {
             max_depth,
             min_overlap,
             cpg_set,
-        } => {
-            qfdrp::compute(
-                input,
-                output,
-                *min_qual,
-                *min_depth,
-                *max_depth,
-                *min_overlap,
-                cpg_set,
-            );
-        }
+            output_format,
+            header,
+            read_options,
+        } => qfdrp::compute(
+            input,
+            output,
+            *output_format,
+            *header,
+            &qfdrp::QfdrpParams {
+                min_qual: *min_qual,
+                min_depth: *min_depth,
+                max_depth: *max_depth,
+                min_overlap: *min_overlap,
+                cpg_set: cpg_set.clone(),
+                read_options: read_options.clone(),
+            },
+        ),
         metheor::Commands::Mhl {
             input,
             output,
@@ -89,9 +159,22 @@ fn main() {
             min_cpgs,
             min_qual,
             cpg_set,
-        } => {
-            mhl::compute(input, output, *min_depth, *min_cpgs, *min_qual, cpg_set);
-        }
+            output_format,
+            header,
+            read_options,
+        } => mhl::compute(
+            input,
+            output,
+            *output_format,
+            *header,
+            &mhl::MhlParams {
+                min_depth: *min_depth,
+                min_cpgs: *min_cpgs,
+                min_qual: *min_qual,
+                cpg_set: cpg_set.clone(),
+                read_options: read_options.clone(),
+            },
+        ),
         metheor::Commands::Lpmd {
             input,
             output,
@@ -100,23 +183,229 @@ fn main() {
             max_distance,
             min_qual,
             cpg_set,
+            read_options,
+        } => lpmd::compute(
+            input,
+            output,
+            pairs,
+            &lpmd::LpmdParams {
+                min_distance: *min_distance,
+                max_distance: *max_distance,
+                min_qual: *min_qual,
+                cpg_set: cpg_set.clone(),
+                read_options: read_options.clone(),
+            },
+        ),
+        metheor::Commands::Mbias {
+            input,
+            output,
+            min_qual,
+            cpg_set,
+            read_options,
+        } => mbias::compute(
+            input,
+            output,
+            &mbias::MbiasParams {
+                min_qual: *min_qual,
+                cpg_set: cpg_set.clone(),
+                read_options: read_options.clone(),
+            },
+        ),
+        metheor::Commands::Matrix {
+            samples,
+            output,
+            metric,
+            min_depth,
+            min_cpgs,
+            min_qual,
+            max_depth,
+            min_overlap,
+            cpg_set,
+            read_options,
+        } => matrix::compute(
+            &matrix::read_sample_sheet(samples)?,
+            output,
+            &matrix::MatrixParams {
+                metric: *metric,
+                min_depth: *min_depth,
+                min_cpgs: *min_cpgs,
+                min_qual: *min_qual,
+                max_depth: *max_depth,
+                min_overlap: *min_overlap,
+                cpg_set: cpg_set.clone(),
+                read_options: read_options.clone(),
+            },
+        ),
+        metheor::Commands::Diff {
+            group1,
+            group2,
+            output,
+            dhr,
+            measure,
+            test,
+            fdr,
+            max_gap,
+            min_sites,
+            min_depth,
+            min_cpgs,
+            min_distance,
+            max_distance,
+            min_qual,
+            cpg_set,
+            read_options,
+        } => diff::compute(
+            group1,
+            group2,
+            output,
+            dhr,
+            &diff::DiffParams {
+                measure: *measure,
+                test: *test,
+                min_depth: *min_depth,
+                min_cpgs: *min_cpgs,
+                min_distance: *min_distance,
+                max_distance: *max_distance,
+                min_qual: *min_qual,
+                cpg_set: cpg_set.clone(),
+                read_options: read_options.clone(),
+                fdr: *fdr,
+                max_gap: *max_gap,
+                min_sites: *min_sites,
+            },
+        ),
+        metheor::Commands::Aggregate {
+            input,
+            bed,
+            window_size,
+            step,
+            output,
+            bedgraph,
+            min_cpgs,
+            min_distance,
+            max_distance,
+            min_qual,
+            cpg_set,
+            read_options,
+        } => aggregate::compute(
+            input,
+            output,
+            bedgraph,
+            &aggregate::AggregateParams {
+                regions: match (bed, window_size) {
+                    (Some(bed), _) => aggregate::AggregateRegions::Bed(bed.clone()),
+                    (None, Some(size)) => aggregate::AggregateRegions::Windows {
+                        size: *size,
+                        step: step.unwrap_or(*size),
+                    },
+                    (None, None) => unreachable!("--bed or --window-size is required"),
+                },
+                min_cpgs: *min_cpgs,
+                min_distance: *min_distance,
+                max_distance: *max_distance,
+                min_qual: *min_qual,
+                cpg_set: cpg_set.clone(),
+                read_options: read_options.clone(),
+            },
+        ),
+        metheor::Commands::All {
+            input,
+            pdr: pdr_output,
+            pm: pm_output,
+            me: me_output,
+            fdrp: fdrp_output,
+            qfdrp: qfdrp_output,
+            mhl: mhl_output,
+            lpmd: lpmd_output,
+            lpmd_pairs,
+            min_depth,
+            min_cpgs,
+            min_qual,
+            max_depth,
+            min_overlap,
+            min_distance,
+            max_distance,
+            cpg_set,
+            header,
+            read_options,
         } => {
-            lpmd::compute(
-                input,
-                output,
-                *min_distance,
-                *max_distance,
-                *min_qual,
-                cpg_set,
-                pairs,
-            );
+            let outputs = all::AllOutputs {
+                pdr: pdr_output.clone(),
+                pm: pm_output.clone(),
+                me: me_output.clone(),
+                fdrp: fdrp_output.clone(),
+                qfdrp: qfdrp_output.clone(),
+                mhl: mhl_output.clone(),
+                lpmd: lpmd_output.clone(),
+                lpmd_pairs: lpmd_pairs.clone(),
+                header: *header,
+            };
+            // Read filters are shared by all metrics, so only metric-specific parameters are set.
+            let fdrp_params = fdrp::FdrpParams {
+                min_depth: *min_depth as usize,
+                max_depth: *max_depth,
+                min_overlap: *min_overlap,
+                ..Default::default()
+            };
+            let qfdrp_params = qfdrp::QfdrpParams {
+                min_depth: *min_depth as usize,
+                max_depth: *max_depth,
+                min_overlap: *min_overlap,
+                ..Default::default()
+            };
+            let params = all::AllParams {
+                min_qual: *min_qual,
+                cpg_set: cpg_set.clone(),
+                read_options: read_options.clone(),
+                pdr: pdr_output.as_ref().map(|_| pdr::PdrParams {
+                    min_depth: *min_depth,
+                    min_cpgs: *min_cpgs,
+                    ..Default::default()
+                }),
+                pm: pm_output.as_ref().map(|_| pm::PmParams {
+                    min_depth: *min_depth,
+                    ..Default::default()
+                }),
+                me: me_output.as_ref().map(|_| me::MeParams {
+                    min_depth: *min_depth,
+                    ..Default::default()
+                }),
+                fdrp: fdrp_output.as_ref().map(|_| fdrp_params),
+                qfdrp: qfdrp_output.as_ref().map(|_| qfdrp_params),
+                mhl: mhl_output.as_ref().map(|_| mhl::MhlParams {
+                    min_depth: *min_depth,
+                    min_cpgs: *min_cpgs,
+                    ..Default::default()
+                }),
+                lpmd: lpmd_output.as_ref().map(|_| lpmd::LpmdParams {
+                    min_distance: *min_distance,
+                    max_distance: *max_distance,
+                    ..Default::default()
+                }),
+            };
+            all::compute(input, &outputs, &params)
         }
         metheor::Commands::Tag {
             input,
             output,
             genome,
-        } => {
-            tag::run(input, output, genome);
-        }
+            reference,
+            threads,
+            output_format,
+            compression_level,
+            writer_threads,
+            library,
+        } => tag::run(
+            input,
+            output,
+            &tag::TagParams {
+                genome: genome.clone(),
+                reference: reference.clone(),
+                threads: *threads,
+                output_format: *output_format,
+                compression_level: *compression_level,
+                writer_threads: *writer_threads,
+                library: *library,
+            },
+        ),
     }
 }
@@ -0,0 +1,247 @@
+use clap::{ArgAction, ArgMatches, Command};
+use std::any::Any;
+use std::fmt::Write;
+use std::fs;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::error::{Error, Result};
+use crate::filter::FilterCounts;
+
+/// An input read during the run, with the number of reads filtered out by each filter if the
+/// reads were filtered.
+struct Input {
+    path: String,
+    counts: Option<FilterCounts>,
+}
+
+/// Inputs read during the run, in the order they were first read.
+fn inputs() -> &'static Mutex<Vec<Input>> {
+    static INPUTS: OnceLock<Mutex<Vec<Input>>> = OnceLock::new();
+    INPUTS.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+/// Records that `path` was read, with the number of reads filtered out by each filter. Counts of
+/// an input read multiple times are summed.
+pub fn record_input(path: &str, counts: Option<&FilterCounts>) {
+    let mut inputs = inputs().lock().unwrap();
+
+    match inputs.iter_mut().find(|input| input.path == path) {
+        Some(input) => match (&mut input.counts, counts) {
+            (Some(total), Some(counts)) => total.merge(counts),
+            (total, counts) => {
+                if total.is_none() {
+                    *total = counts.cloned();
+                }
+            }
+        },
+        None => inputs.push(Input {
+            path: path.to_string(),
+            counts: counts.cloned(),
+        }),
+    }
+}
+
+/// Inputs read during the run whose reads were filtered, with the number of reads filtered out by
+/// each filter.
+pub fn filter_counts() -> Vec<(String, FilterCounts)> {
+    let inputs = inputs().lock().unwrap();
+
+    inputs
+        .iter()
+        .filter_map(|input| Some((input.path.clone(), input.counts.clone()?)))
+        .collect()
+}
+
+/// Quotes `s` as a JSON string.
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Values of the argument `id` as JSON numbers or booleans, if its value parser (or its action,
+/// for flags) produces numbers or booleans.
+fn typed_values(id: &str, matches: &ArgMatches) -> Option<Vec<String>> {
+    fn values<T: Any + Clone + Send + Sync + ToString>(
+        id: &str,
+        matches: &ArgMatches,
+    ) -> Option<Vec<String>> {
+        let values = matches.try_get_many::<T>(id).ok()??;
+        Some(values.map(|value| value.to_string()).collect())
+    }
+
+    values::<bool>(id, matches)
+        .or_else(|| values::<u8>(id, matches))
+        .or_else(|| values::<u16>(id, matches))
+        .or_else(|| values::<u32>(id, matches))
+        .or_else(|| values::<u64>(id, matches))
+        .or_else(|| values::<usize>(id, matches))
+        .or_else(|| values::<i32>(id, matches))
+        .or_else(|| values::<i64>(id, matches))
+        .or_else(|| values::<f32>(id, matches).filter(|v| v.iter().all(|v| is_finite(v))))
+        .or_else(|| values::<f64>(id, matches).filter(|v| v.iter().all(|v| is_finite(v))))
+}
+
+/// Whether a formatted floating point number is valid in JSON, i.e., neither infinite nor NaN.
+fn is_finite(value: &str) -> bool {
+    value.parse::<f64>().is_ok_and(f64::is_finite)
+}
+
+/// Value of the argument `arg` of the subcommand as JSON: numbers and booleans as they were
+/// parsed, an array for arguments taking multiple values, a string otherwise and `null` if unset.
+fn parameter_value(arg: &clap::Arg, matches: &ArgMatches) -> String {
+    let id = arg.get_id().as_str();
+    let multiple = matches!(arg.get_action(), ArgAction::Append)
+        || arg
+            .get_num_args()
+            .is_some_and(|range| range.max_values() > 1);
+
+    let values: Vec<String> = match typed_values(id, matches) {
+        Some(values) => values,
+        None => match matches.try_get_raw(id) {
+            Ok(Some(values)) => values
+                .map(|value| quote(&value.to_string_lossy()))
+                .collect(),
+            _ => Vec::new(),
+        },
+    };
+
+    match (values.is_empty(), multiple) {
+        (true, _) => "null".to_string(),
+        (false, true) => format!("[{}]", values.join(", ")),
+        (false, false) => values[0].clone(),
+    }
+}
+
+/// Size in bytes and modification time in seconds since the Unix epoch of `path`, as JSON.
+fn file_stat(path: &str) -> (String, String) {
+    match fs::metadata(path) {
+        Ok(metadata) => (
+            metadata.len().to_string(),
+            metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or("null".to_string(), |modified| {
+                    modified.as_secs().to_string()
+                }),
+        ),
+        Err(_) => ("null".to_string(), "null".to_string()),
+    }
+}
+
+/// Number of reads processed, passing the read filters and filtered out by each filter, as JSON.
+fn read_counts(counts: &FilterCounts) -> String {
+    format!(
+        "{{\"total\": {}, \"passed\": {}, \"filtered\": {{\"secondary\": {}, \"supplementary\": {}, \
+         \"duplicate\": {}, \"qc_fail\": {}, \"improper_pair\": {}, \"include_flags\": {}, \
+         \"exclude_flags\": {}, \"low_mapq\": {}, \"missing_group_tag\": {}}}}}",
+        counts.total,
+        counts.passed(),
+        counts.secondary,
+        counts.supplementary,
+        counts.duplicate,
+        counts.qc_fail,
+        counts.improper_pair,
+        counts.include_flags,
+        counts.exclude_flags,
+        counts.low_mapq,
+        counts.missing_group_tag,
+    )
+}
+
+/// Writes a JSON manifest of the run to `output`: the version of metheor, the subcommand and the
+/// command line, every parameter of the subcommand parsed by `command` into `matches`, the inputs
+/// with their size, modification time and read counts, and the wall time.
+pub fn write(
+    output: &str,
+    command: &Command,
+    matches: &ArgMatches,
+    wall_time: Duration,
+) -> Result<()> {
+    let mut json = String::new();
+
+    writeln!(json, "{{").unwrap();
+    writeln!(
+        json,
+        "  \"metheor_version\": {},",
+        quote(env!("CARGO_PKG_VERSION"))
+    )
+    .unwrap();
+
+    let command_line: Vec<String> = std::env::args().map(|arg| quote(&arg)).collect();
+    if let Some((name, sub_matches)) = matches.subcommand() {
+        writeln!(json, "  \"command\": {},", quote(name)).unwrap();
+        writeln!(json, "  \"command_line\": [{}],", command_line.join(", ")).unwrap();
+
+        let parameters: Vec<String> = command
+            .find_subcommand(name)
+            .into_iter()
+            .flat_map(|subcommand| subcommand.get_arguments())
+            .filter(|arg| {
+                !matches!(
+                    arg.get_action(),
+                    ArgAction::Help
+                        | ArgAction::HelpShort
+                        | ArgAction::HelpLong
+                        | ArgAction::Version
+                )
+            })
+            .map(|arg| {
+                format!(
+                    "    {}: {}",
+                    quote(arg.get_id().as_str()),
+                    parameter_value(arg, sub_matches)
+                )
+            })
+            .collect();
+        writeln!(
+            json,
+            "  \"parameters\": {{\n{}\n  }},",
+            parameters.join(",\n")
+        )
+        .unwrap();
+    }
+
+    let inputs: Vec<String> = inputs()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|input| {
+            let (size, modified) = file_stat(&input.path);
+            format!(
+                "    {{\"path\": {}, \"size\": {}, \"modified\": {}, \"reads\": {}}}",
+                quote(&input.path),
+                size,
+                modified,
+                input
+                    .counts
+                    .as_ref()
+                    .map_or("null".to_string(), read_counts)
+            )
+        })
+        .collect();
+    writeln!(json, "  \"inputs\": [\n{}\n  ],", inputs.join(",\n")).unwrap();
+    writeln!(
+        json,
+        "  \"wall_time_seconds\": {:.3}",
+        wall_time.as_secs_f64()
+    )
+    .unwrap();
+    writeln!(json, "}}").unwrap();
+
+    fs::write(output, json).map_err(Error::io(output))
+}
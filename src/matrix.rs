@@ -0,0 +1,515 @@
+use clap::ValueEnum;
+use rust_htslib::{bam, tpool};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+
+use crate::error::{Error, Result};
+use crate::fdrp::{FdrpMetric, FdrpParams, FdrpRecord};
+use crate::me::{MeMetric, MeParams, MeRecord};
+use crate::metric::{self, HeterogeneityMetric};
+use crate::mhl::{MhlMetric, MhlParams, MhlRecord};
+use crate::pdr::{PdrMetric, PdrParams, PdrRecord};
+use crate::pm::{PmMetric, PmParams, PmRecord};
+use crate::qfdrp::{QfdrpMetric, QfdrpParams, QfdrpRecord};
+use crate::{bamutil, output, progressbar, readutil};
+
+/// Measure summarized in a CpG x sample matrix.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatrixMetric {
+    Pdr,
+    Pm,
+    Me,
+    Fdrp,
+    Qfdrp,
+    Mhl,
+}
+
+impl MatrixMetric {
+    /// Number of the columns locating a row, after the chromosome.
+    fn n_positions(&self) -> usize {
+        match self {
+            MatrixMetric::Pm | MatrixMetric::Me => 4,
+            _ => 2,
+        }
+    }
+
+    /// Names of the columns locating a row, after the chromosome.
+    fn position_columns(&self) -> &'static str {
+        match self {
+            MatrixMetric::Pm | MatrixMetric::Me => "cpg1\tcpg2\tcpg3\tcpg4",
+            _ => "start\tend",
+        }
+    }
+}
+
+/// A sample of the sample sheet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    /// Path to the (coordinate-sorted) BAM file of the sample.
+    pub path: String,
+}
+
+/// Reads a tab-separated sample sheet with the name of a sample and the path to its BAM file
+/// in each line. Empty lines and lines starting with `#` are ignored.
+pub fn read_sample_sheet(path: &str) -> Result<Vec<Sample>> {
+    let file = fs::File::open(path).map_err(Error::io(path))?;
+
+    let mut samples = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(Error::io(path))?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            [name, sample_path] if !name.is_empty() && !sample_path.is_empty() => {
+                samples.push(Sample {
+                    name: name.to_string(),
+                    path: sample_path.to_string(),
+                })
+            }
+            _ => {
+                return Err(Error::MalformedSampleSheet {
+                    path: path.to_string(),
+                    line,
+                })
+            }
+        }
+    }
+
+    Ok(samples)
+}
+
+/// Parameters of a CpG x sample matrix.
+///
+/// Parameters irrelevant to `metric` are ignored, e.g., `min_cpgs` is only used by PDR and MHL.
+#[derive(Clone)]
+pub struct MatrixParams {
+    pub metric: MatrixMetric,
+    /// Minimum depth of CpGs (CpG quartets for PM and ME) to consider. Sites below are `NA`.
+    pub min_depth: u32,
+    /// Minimum number of consecutive CpGs in a CpG stretch to consider (PDR and MHL).
+    pub min_cpgs: usize,
+    /// Maximum number of reads to consider (FDRP and qFDRP).
+    pub max_depth: usize,
+    /// Minimum overlap between two reads to consider in bp (FDRP and qFDRP).
+    pub min_overlap: i32,
+    /// Minimum quality for a read to be considered.
+    pub min_qual: u8,
+    /// (Optional) Predefined set of CpGs (in BED file) to be analyzed.
+    pub cpg_set: Option<String>,
+    pub read_options: readutil::ReadOptions,
+}
+
+impl Default for MatrixParams {
+    fn default() -> Self {
+        Self {
+            metric: MatrixMetric::Pdr,
+            min_depth: 10,
+            min_cpgs: 4,
+            max_depth: 40,
+            min_overlap: 35,
+            min_qual: 10,
+            cpg_set: None,
+            read_options: readutil::ReadOptions::default(),
+        }
+    }
+}
+
+/// A row of the matrix: a CpG (a CpG quartet for PM and ME) and the value of each sample, `None`
+/// if the sample does not pass `min_depth`. Formatted as a line of the output table, with `NA`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatrixRow {
+    pub chrom: String,
+    /// 0-based start and end of the CpG, or 0-based positions of the CpGs of the quartet.
+    pub positions: Vec<i32>,
+    pub values: Vec<Option<f32>>,
+}
+
+impl fmt::Display for MatrixRow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.chrom)?;
+        for pos in self.positions.iter() {
+            write!(f, "\t{}", pos)?;
+        }
+        for value in self.values.iter() {
+            match value {
+                Some(value) => write!(f, "\t{}", value)?,
+                None => write!(f, "\tNA")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Header line of the matrix of `samples`.
+pub fn header(metric: MatrixMetric, samples: &[Sample]) -> String {
+    let mut header = format!("chrom\t{}", metric.position_columns());
+    for sample in samples.iter() {
+        header.push('\t');
+        header.push_str(&sample.name);
+    }
+
+    header
+}
+
+/// Number of reads read from a sample at a time, before its sites are merged with the other samples.
+const READS_PER_STEP: u64 = 1000;
+
+/// Value of a site in a sample.
+struct Site {
+    /// Id of the reference sequence of the site in the header of the first sample.
+    tid: i32,
+    /// 0-based start and end of the CpG followed by zeros, or 0-based positions of the CpGs of the
+    /// quartet.
+    positions: [i32; 4],
+    value: f32,
+}
+
+/// Sites of a sample, computed as its reads are read.
+struct SampleSites<'a, M> {
+    path: &'a str,
+    stream: metric::MetricStream<'a, M>,
+    /// Reference sequence of the last site, and its id in the header of the first sample.
+    chrom: Option<(String, Option<i32>)>,
+    /// Position of the last site.
+    last: Option<(i32, [i32; 4])>,
+    sites: VecDeque<Site>,
+}
+
+impl<'a, M> SampleSites<'a, M>
+where
+    M: HeterogeneityMetric,
+    M::Output: IntoIterator + Default,
+{
+    /// Reads the sample until the next sites are available or all reads are read, and extracts the
+    /// reference sequence, the positions and the value of each record with `site`. Sites on
+    /// reference sequences missing from the header of the first sample (`first`) are ignored.
+    fn fill<S>(&mut self, first: &Sample, first_header: &bam::HeaderView, site: &S) -> Result<()>
+    where
+        S: Fn(<M::Output as IntoIterator>::Item) -> (String, [i32; 4], f32),
+    {
+        while self.sites.is_empty() {
+            let records = match self.stream.next_results(READS_PER_STEP)? {
+                Some(records) => records,
+                None => return Ok(()),
+            };
+
+            for record in records {
+                let (chrom, positions, value) = site(record);
+                let tid = match &self.chrom {
+                    Some((last_chrom, tid)) if *last_chrom == chrom => *tid,
+                    _ => {
+                        let tid = first_header.tid(chrom.as_bytes()).map(|tid| tid as i32);
+                        self.chrom = Some((chrom, tid));
+                        tid
+                    }
+                };
+                let tid = match tid {
+                    Some(tid) => tid,
+                    None => continue,
+                };
+
+                if self.last.is_some_and(|last| (tid, positions) <= last) {
+                    return Err(Error::HeaderMismatch(format!(
+                        "{} is sorted in a different order of reference sequences than {}",
+                        self.path, first.path
+                    )));
+                }
+                self.last = Some((tid, positions));
+                self.sites.push_back(Site {
+                    tid,
+                    positions,
+                    value,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Computes the metric created by `new_metric` for all samples, reading their BAM files together in
+/// coordinate order, and passes each row of the matrix to `f`. The value of each record is
+/// extracted, along with its reference sequence and positions, with `site`.
+fn merge_samples<M, N, S, F>(
+    samples: &[Sample],
+    params: &MatrixParams,
+    new_metric: N,
+    site: S,
+    mut f: F,
+) -> Result<()>
+where
+    M: HeterogeneityMetric,
+    M::Output: IntoIterator + Default,
+    N: Fn() -> M,
+    S: Fn(<M::Output as IntoIterator>::Item) -> (String, [i32; 4], f32),
+    F: FnMut(MatrixRow) -> Result<()>,
+{
+    let first = match samples.first() {
+        Some(first) => first,
+        None => return Ok(()),
+    };
+    let first_header = bamutil::get_header(&bamutil::get_reader(&first.path)?);
+
+    // A single progress bar, and a single thread pool decompressing the BAM files.
+    let bar = progressbar::ProgressBar::new();
+    let thread_pool = match params.read_options.threads {
+        threads if threads > 1 => {
+            Some(tpool::ThreadPool::new(threads as u32).map_err(Error::ReadAlignment)?)
+        }
+        _ => None,
+    };
+    let mut streams = samples
+        .iter()
+        .map(|sample| {
+            Ok(SampleSites {
+                path: &sample.path,
+                stream: metric::MetricStream::new(
+                    &sample.path,
+                    params.min_qual,
+                    &params.cpg_set,
+                    &params.read_options,
+                    new_metric(),
+                    thread_pool.as_ref(),
+                    &bar,
+                )?,
+                chrom: None,
+                last: None,
+                sites: VecDeque::new(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let n_positions = params.metric.n_positions();
+    loop {
+        for sample in streams.iter_mut() {
+            sample.fill(first, &first_header, &site)?;
+        }
+
+        let (tid, positions) = match streams
+            .iter()
+            .filter_map(|sample| sample.sites.front())
+            .map(|site| (site.tid, site.positions))
+            .min()
+        {
+            Some(position) => position,
+            None => break,
+        };
+
+        let mut values = vec![None; samples.len()];
+        for (sample, value) in streams.iter_mut().zip(values.iter_mut()) {
+            if let Some(site) = sample
+                .sites
+                .front()
+                .filter(|site| (site.tid, site.positions) == (tid, positions))
+            {
+                *value = Some(site.value);
+                sample.sites.pop_front();
+            }
+        }
+
+        f(MatrixRow {
+            chrom: bamutil::tid2chrom(tid, &first_header),
+            positions: positions[..n_positions].to_vec(),
+            values,
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Computes the rows of the matrix and passes each row to `f` in coordinate order. The BAM files of
+/// the samples are read together, one step of reads at a time, and the sites of the samples are
+/// merged by position as they are finalized, so that only the sites around the current position
+/// are kept in memory. The reference sequences are taken from the header of the first sample.
+fn for_each_row<F>(samples: &[Sample], params: &MatrixParams, f: F) -> Result<()>
+where
+    F: FnMut(MatrixRow) -> Result<()>,
+{
+    match params.metric {
+        MatrixMetric::Pdr => {
+            let pdr_params = PdrParams {
+                min_depth: params.min_depth,
+                min_cpgs: params.min_cpgs,
+                ..Default::default()
+            };
+            merge_samples(
+                samples,
+                params,
+                || PdrMetric::new(&pdr_params),
+                |r: PdrRecord| (r.chrom, [r.start, r.end, 0, 0], r.pdr),
+                f,
+            )
+        }
+        MatrixMetric::Mhl => {
+            let mhl_params = MhlParams {
+                min_depth: params.min_depth,
+                min_cpgs: params.min_cpgs,
+                ..Default::default()
+            };
+            merge_samples(
+                samples,
+                params,
+                || MhlMetric::new(&mhl_params),
+                |r: MhlRecord| (r.chrom, [r.start, r.end, 0, 0], r.mhl),
+                f,
+            )
+        }
+        MatrixMetric::Pm => {
+            let pm_params = PmParams {
+                min_depth: params.min_depth,
+                ..Default::default()
+            };
+            merge_samples(
+                samples,
+                params,
+                || PmMetric::new(&pm_params),
+                |r: PmRecord| (r.chrom, [r.cpg1, r.cpg2, r.cpg3, r.cpg4], r.pm),
+                f,
+            )
+        }
+        MatrixMetric::Me => {
+            let me_params = MeParams {
+                min_depth: params.min_depth,
+                ..Default::default()
+            };
+            merge_samples(
+                samples,
+                params,
+                || MeMetric::new(&me_params),
+                |r: MeRecord| (r.chrom, [r.cpg1, r.cpg2, r.cpg3, r.cpg4], r.me),
+                f,
+            )
+        }
+        MatrixMetric::Fdrp => {
+            let fdrp_params = FdrpParams {
+                min_depth: params.min_depth as usize,
+                max_depth: params.max_depth,
+                min_overlap: params.min_overlap,
+                ..Default::default()
+            };
+            merge_samples(
+                samples,
+                params,
+                || FdrpMetric::new(&fdrp_params),
+                |r: FdrpRecord| (r.chrom, [r.start, r.end, 0, 0], r.fdrp),
+                f,
+            )
+        }
+        MatrixMetric::Qfdrp => {
+            let qfdrp_params = QfdrpParams {
+                min_depth: params.min_depth as usize,
+                max_depth: params.max_depth,
+                min_overlap: params.min_overlap,
+                ..Default::default()
+            };
+            merge_samples(
+                samples,
+                params,
+                || QfdrpMetric::new(&qfdrp_params),
+                |r: QfdrpRecord| (r.chrom, [r.start, r.end, 0, 0], r.qfdrp),
+                f,
+            )
+        }
+    }
+}
+
+/// Writes the CpG x sample matrix of `params.metric` for `samples` to `output`, with `NA` for the
+/// sites not passing `min_depth` in a sample. All samples must be sorted by coordinate.
+pub fn compute(samples: &[Sample], output: &str, params: &MatrixParams) -> Result<()> {
+    let mut out = output::create(output)?;
+
+    writeln!(out, "{}", header(params.metric, samples)).map_err(Error::io(output))?;
+    for_each_row(samples, params, |row| {
+        writeln!(out, "{}", row).map_err(Error::io(output))
+    })?;
+    drop(out);
+
+    output::index(output, 1, 1)
+}
+
+/// Computes the rows of the CpG x sample matrix of `params.metric` for `samples`, sorted by
+/// position.
+pub fn compute_rows(samples: &[Sample], params: &MatrixParams) -> Result<Vec<MatrixRow>> {
+    let mut rows = Vec::new();
+    for_each_row(samples, params, |row| {
+        rows.push(row);
+        Ok(())
+    })?;
+
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_sample_sheet() {
+        let samples = read_sample_sheet("tests/samples.tsv").unwrap();
+
+        let names: Vec<&str> = samples.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["A", "B", "C"]);
+        assert_eq!(samples[0].path, "tests/test1.bam");
+
+        match read_sample_sheet("tests/samples.malformed.tsv") {
+            Err(Error::MalformedSampleSheet { line, .. }) => assert_eq!(line, "B tests/test2.bam"),
+            _ => panic!("expected a malformed sample sheet"),
+        }
+    }
+
+    #[test]
+    fn test_rows_match_samples() {
+        let samples = read_sample_sheet("tests/samples.tsv").unwrap();
+        let params = MatrixParams {
+            min_depth: 1,
+            min_cpgs: 1,
+            ..Default::default()
+        };
+        let pdr_params = PdrParams {
+            min_depth: 1,
+            min_cpgs: 1,
+            ..Default::default()
+        };
+
+        let rows = compute_rows(&samples, &params).unwrap();
+
+        for (i, sample) in samples.iter().enumerate() {
+            let records = crate::pdr::compute_records(&sample.path, &pdr_params).unwrap();
+            let values: Vec<(String, Vec<i32>, f32)> = rows
+                .iter()
+                .filter_map(|row| {
+                    row.values[i].map(|v| (row.chrom.clone(), row.positions.clone(), v))
+                })
+                .collect();
+            let expected: Vec<(String, Vec<i32>, f32)> = records
+                .into_iter()
+                .map(|r| (r.chrom, vec![r.start, r.end], r.pdr))
+                .collect();
+            assert_eq!(values, expected);
+        }
+    }
+
+    #[test]
+    fn test_pm_rows() {
+        let samples = read_sample_sheet("tests/samples.tsv").unwrap();
+        let params = MatrixParams {
+            metric: MatrixMetric::Pm,
+            min_depth: 1,
+            ..Default::default()
+        };
+
+        let rows = compute_rows(&samples, &params).unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].positions, vec![0, 2, 4, 6]);
+        assert_eq!(rows[0].values, vec![Some(0.9375), Some(0.5), Some(0.9375)]);
+        assert_eq!(rows[1].to_string(), "chr1\t13\t15\t17\t19\tNA\tNA\t0.9375");
+    }
+}
@@ -0,0 +1,249 @@
+use rust_htslib::bam;
+use std::fmt;
+
+use crate::error::Result;
+use crate::metric::{self, HeterogeneityMetric};
+use crate::readutil;
+
+/// Header line of the output table.
+pub const HEADER: &str = "read\tposition\tn_methylated\tn_unmethylated\tbeta";
+
+/// Parameters of M-bias calculation.
+#[derive(Clone)]
+pub struct MbiasParams {
+    /// Minimum quality for a read to be considered.
+    pub min_qual: u8,
+    /// (Optional) Predefined set of CpGs (in BED file) to be analyzed.
+    pub cpg_set: Option<String>,
+    pub read_options: readutil::ReadOptions,
+}
+
+impl Default for MbiasParams {
+    fn default() -> Self {
+        Self {
+            min_qual: 10,
+            cpg_set: None,
+            read_options: readutil::ReadOptions::default(),
+        }
+    }
+}
+
+/// Methylation level of CpGs called at a position of reads. Formatted as a line of the output table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MbiasRecord {
+    /// `R1` for single-end reads and read 1 of read pairs, `R2` for read 2.
+    pub read: String,
+    /// 1-based position from the 5' end of the read.
+    pub position: usize,
+    pub n_methylated: u32,
+    pub n_unmethylated: u32,
+    /// Fraction of methylated calls.
+    pub beta: f32,
+}
+
+impl fmt::Display for MbiasRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            self.read, self.position, self.n_methylated, self.n_unmethylated, self.beta
+        )
+    }
+}
+
+/// Counts of (methylated, unmethylated) calls for each position of reads.
+#[derive(Default)]
+struct PositionCounts {
+    counts: Vec<(u32, u32)>,
+}
+
+impl PositionCounts {
+    fn add(&mut self, cycle: usize, methylated: bool) {
+        if self.counts.len() <= cycle {
+            self.counts.resize(cycle + 1, (0, 0));
+        }
+        match methylated {
+            true => self.counts[cycle].0 += 1,
+            false => self.counts[cycle].1 += 1,
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (cycle, (n_methylated, n_unmethylated)) in other.counts.iter().enumerate() {
+            if self.counts.len() <= cycle {
+                self.counts.resize(cycle + 1, (0, 0));
+            }
+            self.counts[cycle].0 += n_methylated;
+            self.counts[cycle].1 += n_unmethylated;
+        }
+    }
+
+    fn to_records(&self, read: &str) -> Vec<MbiasRecord> {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, (n_methylated, n_unmethylated))| n_methylated + n_unmethylated > 0)
+            .map(|(cycle, &(n_methylated, n_unmethylated))| MbiasRecord {
+                read: read.to_string(),
+                position: cycle + 1,
+                n_methylated,
+                n_unmethylated,
+                beta: n_methylated as f32 / (n_methylated + n_unmethylated) as f32,
+            })
+            .collect()
+    }
+}
+
+/// M-bias as a [`HeterogeneityMetric`].
+#[derive(Default)]
+pub struct MbiasMetric {
+    r1: PositionCounts,
+    r2: PositionCounts,
+}
+
+impl MbiasMetric {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl HeterogeneityMetric for MbiasMetric {
+    type Output = Vec<MbiasRecord>;
+
+    fn observe_read(&mut self, br: &readutil::BismarkRead) {
+        let counts = match br.is_second_mate() {
+            true => &mut self.r2,
+            false => &mut self.r1,
+        };
+        for cpg in br.get_cpgs().iter() {
+            counts.add(cpg.cycle as usize, cpg.methylated);
+        }
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.r1.merge(&other.r1);
+        self.r2.merge(&other.r2);
+    }
+
+    fn finalize(self, _header: &bam::HeaderView) -> Vec<MbiasRecord> {
+        let mut records = self.r1.to_records("R1");
+        records.extend(self.r2.to_records("R2"));
+
+        records
+    }
+}
+
+pub fn compute(input: &str, output: &str, params: &MbiasParams) -> Result<()> {
+    if params.read_options.group_by_tag.is_some() {
+        let groups = compute_grouped_records(input, params)?;
+        return metric::write_grouped_table(output, Some(HEADER), &groups);
+    }
+
+    let records = compute_records(input, params)?;
+
+    metric::write_table(output, Some(HEADER), &records)
+}
+
+/// Computes the methylation level of CpGs called at each position of read 1 and read 2.
+/// Mates are never merged, so that the positions are those of the sequenced reads.
+pub fn compute_records(input: &str, params: &MbiasParams) -> Result<Vec<MbiasRecord>> {
+    let read_options = readutil::ReadOptions {
+        merge_mates: false,
+        ..params.read_options.clone()
+    };
+
+    metric::compute(
+        input,
+        params.min_qual,
+        &params.cpg_set,
+        &read_options,
+        MbiasMetric::new,
+    )
+}
+
+/// Computes M-bias separately for each group of reads sharing the value of the aux tag given by
+/// `group_by_tag` of `read_options`, sorted by the name of the group.
+pub fn compute_grouped_records(
+    input: &str,
+    params: &MbiasParams,
+) -> Result<Vec<(String, Vec<MbiasRecord>)>> {
+    let read_options = readutil::ReadOptions {
+        merge_mates: false,
+        ..params.read_options.clone()
+    };
+
+    metric::compute_grouped(
+        input,
+        params.min_qual,
+        &params.cpg_set,
+        &read_options,
+        MbiasMetric::new,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test1() {
+        let input = "tests/test1.bam";
+        let params = MbiasParams::default();
+
+        let records = compute_records(input, &params).unwrap();
+
+        let n_calls: u32 = records
+            .iter()
+            .map(|record| record.n_methylated + record.n_unmethylated)
+            .sum();
+        assert_eq!(n_calls, 64);
+        assert!(records.iter().all(|record| record.read == "R1"));
+    }
+
+    #[test]
+    fn test11_paired() {
+        let input = "tests/test11.sam";
+        let params = MbiasParams::default();
+
+        let records = compute_records(input, &params).unwrap();
+
+        // Read 2 is aligned to the reverse strand, so its 5' end is the last base of the alignment.
+        let r2: Vec<(usize, u32, u32)> = records
+            .iter()
+            .filter(|record| record.read == "R2")
+            .map(|record| (record.position, record.n_methylated, record.n_unmethylated))
+            .collect();
+        assert_eq!(r2, vec![(2, 3, 0), (4, 3, 0), (6, 2, 1), (8, 3, 0)]);
+    }
+
+    #[test]
+    fn test11_ignore_ends() {
+        let input = "tests/test11.sam";
+        let params = MbiasParams {
+            read_options: readutil::ReadOptions {
+                ignore_5p: 2,
+                ignore_3p_r2: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let records = compute_records(input, &params).unwrap();
+
+        let positions: Vec<(&str, usize)> = records
+            .iter()
+            .map(|record| (record.read.as_str(), record.position))
+            .collect();
+        assert_eq!(
+            positions,
+            vec![
+                ("R1", 3),
+                ("R1", 5),
+                ("R1", 7),
+                ("R2", 2),
+                ("R2", 4),
+                ("R2", 6)
+            ]
+        );
+    }
+}
@@ -1,12 +1,13 @@
-use rust_htslib::{bam, bam::Read};
+use rust_htslib::bam;
 use std::collections::HashMap;
-use std::fs;
-use std::io::Write;
+use std::fmt;
 use std::str;
 
-use crate::{bamutil, progressbar, readutil};
+use crate::error::Result;
+use crate::metric::{self, HeterogeneityMetric};
+use crate::{bamutil, readutil};
 
-pub struct QuartetStat {
+struct QuartetStat {
     pos1: readutil::CpGPosition,
     pos2: readutil::CpGPosition,
     pos3: readutil::CpGPosition,
@@ -54,81 +55,182 @@ impl QuartetStat {
         me
     }
 
-    fn to_bedgraph_field(&self, header: &bam::HeaderView) -> String {
-        let chrom = bamutil::tid2chrom(self.pos1.tid, header);
-        let me = self.compute_me();
-
-        format!(
-            "{}\t{}\t{}\t{}\t{}\t{}",
-            chrom, self.pos1.pos, self.pos2.pos, self.pos3.pos, self.pos4.pos, me
-        )
+    fn to_record(&self, header: &bam::HeaderView) -> MeRecord {
+        MeRecord {
+            chrom: bamutil::tid2chrom(self.pos1.tid, header),
+            cpg1: self.pos1.pos,
+            cpg2: self.pos2.pos,
+            cpg3: self.pos3.pos,
+            cpg4: self.pos4.pos,
+            me: self.compute_me(),
+            depth: self.get_read_depth(),
+        }
     }
 }
 
-pub fn compute(input: &str, output: &str, min_depth: u32, min_qual: u8, cpg_set: &Option<String>) {
-    let reader = bamutil::get_reader(input);
-    let header = bamutil::get_header(&reader);
-
-    let result = compute_helper(input, min_qual, cpg_set);
-
-    let mut out = fs::OpenOptions::new()
-        .create(true)
-        .read(true)
-        .write(true)
-        .truncate(true)
-        .open(output)
-        .unwrap();
-    for stat in result.values() {
-        if stat.get_read_depth() < min_depth {
-            continue;
+/// Header line of the output table of ME, written with `--header`.
+pub const HEADER: &str = "chrom\tcpg1\tcpg2\tcpg3\tcpg4\tme";
+
+/// Parameters of ME calculation.
+#[derive(Clone)]
+pub struct MeParams {
+    /// Minimum depth of CpG quartets to consider.
+    pub min_depth: u32,
+    /// Minimum quality for a read to be considered.
+    pub min_qual: u8,
+    /// (Optional) Predefined set of CpGs (in BED file) to be analyzed.
+    pub cpg_set: Option<String>,
+    pub read_options: readutil::ReadOptions,
+}
+
+impl Default for MeParams {
+    fn default() -> Self {
+        Self {
+            min_depth: 10,
+            min_qual: 10,
+            cpg_set: None,
+            read_options: readutil::ReadOptions::default(),
         }
-        writeln!(out, "{}", stat.to_bedgraph_field(&header))
-            .expect("Error writing to output file.");
     }
 }
 
-pub fn compute_helper(
-    input: &str,
-    min_qual: u8,
-    cpg_set: &Option<String>,
-) -> HashMap<readutil::Quartet, QuartetStat> {
-    let mut reader = bamutil::get_reader(input);
-    let header = bamutil::get_header(&reader);
-
-    let target_cpgs = &readutil::get_target_cpgs(cpg_set, &header);
-    let mut quartet2stat: HashMap<readutil::Quartet, QuartetStat> = HashMap::new();
-
-    let mut readcount = 0;
-    let mut valid_readcount = 0;
+/// ME of a CpG quartet. Formatted as a line of the output table (without `depth`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct MeRecord {
+    pub chrom: String,
+    /// 0-based position of the cytosine (C) in the first CpG.
+    pub cpg1: i32,
+    /// 0-based position of the cytosine (C) in the second CpG.
+    pub cpg2: i32,
+    /// 0-based position of the cytosine (C) in the third CpG.
+    pub cpg3: i32,
+    /// 0-based position of the cytosine (C) in the fourth CpG.
+    pub cpg4: i32,
+    pub me: f32,
+    /// Number of reads covering all the four CpGs.
+    pub depth: u32,
+}
 
-    let bar = progressbar::ProgressBar::new();
+impl fmt::Display for MeRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            self.chrom, self.cpg1, self.cpg2, self.cpg3, self.cpg4, self.me
+        )
+    }
+}
 
-    for r in reader.records().map(|r| r.unwrap()) {
-        let mut br = readutil::BismarkRead::new(&r);
+/// ME as a [`HeterogeneityMetric`].
+pub struct MeMetric {
+    min_depth: u32,
+    quartet2stat: HashMap<readutil::Quartet, QuartetStat>,
+}
 
-        if let Some(target_cpgs) = target_cpgs {
-            br.filter_isin(target_cpgs);
+impl MeMetric {
+    pub fn new(params: &MeParams) -> Self {
+        Self {
+            min_depth: params.min_depth,
+            quartet2stat: HashMap::new(),
         }
+    }
 
-        readcount += 1;
+    /// Records of the quartets of `stats` passing `min_depth`, sorted by position.
+    fn to_records<'a>(
+        &self,
+        stats: impl Iterator<Item = &'a QuartetStat>,
+        header: &bam::HeaderView,
+    ) -> Vec<MeRecord> {
+        let mut stats: Vec<&QuartetStat> = stats
+            .filter(|stat| stat.get_read_depth() >= self.min_depth)
+            .collect();
+        stats.sort_by_key(|stat| (stat.pos1, stat.pos2, stat.pos3, stat.pos4));
+
+        stats.iter().map(|stat| stat.to_record(header)).collect()
+    }
+}
 
-        if r.mapq() < min_qual {
-            continue;
-        }
-        valid_readcount += 1;
+impl HeterogeneityMetric for MeMetric {
+    type Output = Vec<MeRecord>;
 
+    fn observe_read(&mut self, br: &readutil::BismarkRead) {
         let (quartets, patterns) = br.get_cpg_quartets_and_patterns();
         for (q, p) in quartets.iter().zip(patterns.iter()) {
-            let stat = quartet2stat.entry(*q).or_insert(QuartetStat::new(*q));
+            let stat = self.quartet2stat.entry(*q).or_insert(QuartetStat::new(*q));
 
             stat.add_quartet_pattern(*p);
         }
+    }
 
-        if readcount % 10000 == 0 {
-            bar.update(readcount, valid_readcount)
-        };
+    fn take_before(
+        &mut self,
+        pos: readutil::CpGPosition,
+        header: &bam::HeaderView,
+    ) -> Option<Vec<MeRecord>> {
+        let quartets: Vec<readutil::Quartet> = self
+            .quartet2stat
+            .keys()
+            .filter(|q| q.pos1 < pos)
+            .copied()
+            .collect();
+        let stats: Vec<QuartetStat> = quartets
+            .iter()
+            .filter_map(|q| self.quartet2stat.remove(q))
+            .collect();
+
+        Some(self.to_records(stats.iter(), header))
+    }
+
+    fn merge(&mut self, other: Self) {
+        debug_assert!(other
+            .quartet2stat
+            .keys()
+            .all(|key| !self.quartet2stat.contains_key(key)));
+        self.quartet2stat.extend(other.quartet2stat);
     }
-    quartet2stat
+
+    fn finalize(self, header: &bam::HeaderView) -> Vec<MeRecord> {
+        self.to_records(self.quartet2stat.values(), header)
+    }
+}
+
+pub fn compute(input: &str, output: &str, header: bool, params: &MeParams) -> Result<()> {
+    let header = header.then_some(HEADER);
+
+    if params.read_options.group_by_tag.is_some() {
+        let groups = compute_grouped_records(input, params)?;
+        return metric::write_grouped_table(output, header, &groups);
+    }
+
+    let records = compute_records(input, params)?;
+
+    metric::write_table(output, header, &records)
+}
+
+/// Computes ME of the CpG quartets covered by the reads in `input`, sorted by position.
+pub fn compute_records(input: &str, params: &MeParams) -> Result<Vec<MeRecord>> {
+    metric::compute(
+        input,
+        params.min_qual,
+        &params.cpg_set,
+        &params.read_options,
+        || MeMetric::new(params),
+    )
+}
+
+/// Computes ME separately for each group of reads sharing the value of the aux tag given by
+/// `group_by_tag` of `read_options`, sorted by the name of the group.
+pub fn compute_grouped_records(
+    input: &str,
+    params: &MeParams,
+) -> Result<Vec<(String, Vec<MeRecord>)>> {
+    metric::compute_grouped(
+        input,
+        params.min_qual,
+        &params.cpg_set,
+        &params.read_options,
+        || MeMetric::new(params),
+    )
 }
 
 #[cfg(test)]
@@ -138,59 +240,83 @@ mod tests {
     #[test]
     fn test1() {
         let input = "tests/test1.bam";
-        let min_qual = 10;
-        let cpg_set = None;
+        let params = MeParams {
+            min_depth: 0,
+            ..Default::default()
+        };
 
-        let quartet2stat = compute_helper(input, min_qual, &cpg_set);
+        let records = compute_records(input, &params).unwrap();
 
-        assert_eq!(quartet2stat.len(), 1);
+        assert_eq!(records.len(), 1);
 
-        for (_, reads) in quartet2stat.iter() {
-            assert_eq!(reads.get_read_depth(), 16);
-            assert_eq!(reads.compute_me(), 1.0);
+        for record in records.iter() {
+            assert_eq!(record.depth, 16);
+            assert_eq!(record.me, 1.0);
         }
     }
 
     #[test]
     fn test2() {
         let input = "tests/test2.bam";
-        let min_qual = 10;
-        let cpg_set = None;
+        let params = MeParams {
+            min_depth: 0,
+            ..Default::default()
+        };
 
-        let quartet2stat = compute_helper(input, min_qual, &cpg_set);
+        let records = compute_records(input, &params).unwrap();
 
-        assert_eq!(quartet2stat.len(), 1);
+        assert_eq!(records.len(), 1);
 
-        for (_, reads) in quartet2stat.iter() {
-            assert_eq!(reads.compute_me(), 0.25);
+        for record in records.iter() {
+            assert_eq!(record.me, 0.25);
         }
     }
     #[test]
     fn test3() {
         let input = "tests/test3.bam";
-        let min_qual = 10;
-        let cpg_set = None;
+        let params = MeParams {
+            min_depth: 0,
+            ..Default::default()
+        };
 
-        let quartet2stat = compute_helper(input, min_qual, &cpg_set);
+        let records = compute_records(input, &params).unwrap();
 
-        assert_eq!(quartet2stat.len(), 1);
+        assert_eq!(records.len(), 1);
 
-        for (_, reads) in quartet2stat.iter() {
-            assert_eq!(reads.compute_me(), 0.25);
+        for record in records.iter() {
+            assert_eq!(record.me, 0.25);
         }
     }
     #[test]
     fn test4() {
         let input = "tests/test4.bam";
-        let min_qual = 10;
-        let cpg_set = None;
+        let params = MeParams {
+            min_depth: 0,
+            ..Default::default()
+        };
+
+        let records = compute_records(input, &params).unwrap();
+
+        assert_eq!(records.len(), 2);
 
-        let quartet2stat = compute_helper(input, min_qual, &cpg_set);
+        for record in records.iter() {
+            assert_eq!(record.me, 1.0);
+        }
+    }
+    #[test]
+    fn test7_mm_ml() {
+        let input = "tests/test7.sam";
+        let params = MeParams {
+            min_depth: 0,
+            ..Default::default()
+        };
+
+        let records = compute_records(input, &params).unwrap();
 
-        assert_eq!(quartet2stat.len(), 2);
+        assert_eq!(records.len(), 1);
 
-        for (_, reads) in quartet2stat.iter() {
-            assert_eq!(reads.compute_me(), 1.0);
+        for record in records.iter() {
+            assert_eq!(record.me, 1.0);
         }
     }
     #[test]
@@ -198,11 +324,13 @@ mod tests {
         // No reads pass quality cutoff.
         let input = "tests/test5.bam";
 
-        let min_qual = 10;
-        let cpg_set = None;
+        let params = MeParams {
+            min_depth: 0,
+            ..Default::default()
+        };
 
-        let quartet2stat = compute_helper(input, min_qual, &cpg_set);
+        let records = compute_records(input, &params).unwrap();
 
-        assert_eq!(quartet2stat.len(), 0);
+        assert_eq!(records.len(), 0);
     }
 }
@@ -0,0 +1,582 @@
+use rust_htslib::bam::record::Aux;
+use rust_htslib::{bam, tpool};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io::Write;
+use std::ops::Deref;
+
+use crate::bigwig::{self, TrackRecord};
+use crate::error::{Error, Result};
+use crate::filter::{FilterCounts, FilterReason, ReadFilter};
+use crate::fragment::MateBuffer;
+use crate::output::{self, OutputFormat};
+use crate::{bamutil, manifest, progressbar, readutil};
+
+/// A measure of methylation heterogeneity, computed over reads sorted by position.
+pub trait HeterogeneityMetric: Sized {
+    /// Result of the metric, e.g., a record for each CpG.
+    type Output;
+
+    /// Adds a read that passed the read filters shared by all metrics.
+    fn observe_read(&mut self, br: &readutil::BismarkRead);
+
+    /// Finalizes the CpGs before `pos`, which will not be covered by the reads observed from now on.
+    /// Called with the position of the first CpG of each read, before the read is observed.
+    fn flush_before(&mut self, _pos: readutil::CpGPosition) {}
+
+    /// Removes and returns the results of the CpGs (CpG quartets, starting with their first CpG)
+    /// before `pos`, sorted by position. The reads observed from now on must not cover them.
+    /// Returns `None` for metrics whose results are only available from [`finalize`](Self::finalize).
+    fn take_before(
+        &mut self,
+        _pos: readutil::CpGPosition,
+        _header: &bam::HeaderView,
+    ) -> Option<Self::Output> {
+        None
+    }
+
+    /// Adds the state of the metric computed on the reads of another reference sequence.
+    ///
+    /// The shards must be disjoint: a CpG (or quartet) observed in both `self` and `other` would
+    /// keep the state of only one of them.
+    fn merge(&mut self, other: Self);
+
+    /// Finalizes the remaining CpGs and returns the result.
+    fn finalize(self, header: &bam::HeaderView) -> Self::Output;
+}
+
+/// Computes a metric in a single pass over the reads in `input`.
+///
+/// Reads failing the read filters of `read_options` or with mapping quality lower than `min_qual`
+/// are ignored, and the number of reads filtered out by each filter is recorded for the run (see
+/// [`manifest::filter_counts`]).
+/// The CpGs of each read are restricted to `cpg_set` and the regions of `read_options` before
+/// the read is observed, and overlapping mates are merged into a fragment with `merge_mates`.
+/// With multiple threads, a metric is created by `new_metric` for each reference sequence and
+/// the metrics are merged before finalization.
+pub fn compute<M, F>(
+    input: &str,
+    min_qual: u8,
+    cpg_set: &Option<String>,
+    read_options: &readutil::ReadOptions,
+    new_metric: F,
+) -> Result<M::Output>
+where
+    M: HeterogeneityMetric + Send,
+    F: Fn() -> M + Sync,
+{
+    let (output, _) = compute_with_counts(input, min_qual, cpg_set, read_options, new_metric)?;
+
+    Ok(output)
+}
+
+/// Computes a metric as done by [`compute`], and also returns the number of reads filtered out by
+/// each filter.
+pub fn compute_with_counts<M, F>(
+    input: &str,
+    min_qual: u8,
+    cpg_set: &Option<String>,
+    read_options: &readutil::ReadOptions,
+    new_metric: F,
+) -> Result<(M::Output, FilterCounts)>
+where
+    M: HeterogeneityMetric + Send,
+    F: Fn() -> M + Sync,
+{
+    let (groups, counts) =
+        observe_reads(input, min_qual, cpg_set, read_options, None, &new_metric)?;
+    manifest::record_input(input, Some(&counts));
+
+    let reader = bamutil::get_reader(input)?;
+    let header = bamutil::get_header(&reader);
+
+    let metric = groups.into_values().next().unwrap_or_else(&new_metric);
+    Ok((metric.finalize(&header), counts))
+}
+
+/// Computes a metric separately for each group of reads sharing the value of the aux tag given by
+/// `group_by_tag` of `read_options` (e.g., `CB` for cell barcodes), as done by [`compute`].
+/// Reads without the tag are ignored. Returns the results sorted by the name of the group.
+/// Without `group_by_tag`, all reads are in a single group named "".
+pub fn compute_grouped<M, F>(
+    input: &str,
+    min_qual: u8,
+    cpg_set: &Option<String>,
+    read_options: &readutil::ReadOptions,
+    new_metric: F,
+) -> Result<Vec<(String, M::Output)>>
+where
+    M: HeterogeneityMetric + Send,
+    F: Fn() -> M + Sync,
+{
+    let (groups, counts) = observe_reads(
+        input,
+        min_qual,
+        cpg_set,
+        read_options,
+        read_options.group_by_tag.as_deref(),
+        &new_metric,
+    )?;
+    manifest::record_input(input, Some(&counts));
+
+    let reader = bamutil::get_reader(input)?;
+    let header = bamutil::get_header(&reader);
+
+    Ok(groups
+        .into_iter()
+        .map(|(group, metric)| (group, metric.finalize(&header)))
+        .collect())
+}
+
+/// State of a group of reads: the metric and the reads waiting for their mates.
+struct Group<M> {
+    metric: M,
+    mates: Option<MateBuffer>,
+}
+
+impl<M: HeterogeneityMetric> Group<M> {
+    fn new(metric: M, read_options: &readutil::ReadOptions) -> Self {
+        let mates = match read_options.merge_mates {
+            true => Some(MateBuffer::new(read_options.mate_conflict)),
+            false => None,
+        };
+
+        Self { metric, mates }
+    }
+
+    fn observe(&mut self, r: &bam::Record, br: readutil::BismarkRead) {
+        let fragments = match &mut self.mates {
+            Some(mates) => mates.push(r, br),
+            None => vec![br],
+        };
+        let pending = self.first_pending_cpg();
+        observe_fragments(&mut self.metric, &fragments, pending);
+    }
+
+    /// Position of the first CpG of the reads waiting for their mates.
+    fn first_pending_cpg(&self) -> Option<readutil::CpGPosition> {
+        self.mates
+            .as_ref()
+            .and_then(|mates| mates.first_pending_cpg())
+    }
+
+    fn finish(mut self) -> M {
+        if let Some(mates) = &mut self.mates {
+            observe_fragments(&mut self.metric, &mates.finish(), None);
+        }
+
+        self.metric
+    }
+}
+
+/// Value of the aux tag `tag` of `r` as the name of a group.
+fn get_group(r: &bam::Record, tag: &str) -> Option<String> {
+    match r.aux(tag.as_bytes()).ok()? {
+        Aux::String(s) => Some(s.to_string()),
+        Aux::Char(c) => Some((c as char).to_string()),
+        Aux::I8(v) => Some(v.to_string()),
+        Aux::U8(v) => Some(v.to_string()),
+        Aux::I16(v) => Some(v.to_string()),
+        Aux::U16(v) => Some(v.to_string()),
+        Aux::I32(v) => Some(v.to_string()),
+        Aux::U32(v) => Some(v.to_string()),
+        _ => None,
+    }
+}
+
+/// Observes the reads in `input`, routed to a metric for each group by the aux tag `group_by`.
+/// Without `group_by`, all reads are observed by a single metric in the group named "".
+fn observe_reads<M, F>(
+    input: &str,
+    min_qual: u8,
+    cpg_set: &Option<String>,
+    read_options: &readutil::ReadOptions,
+    group_by: Option<&str>,
+    new_metric: &F,
+) -> Result<(BTreeMap<String, M>, FilterCounts)>
+where
+    M: HeterogeneityMetric + Send,
+    F: Fn() -> M + Sync,
+{
+    // A single progress bar is shared by all shards of the run.
+    let bar = progressbar::ProgressBar::new();
+
+    if let Some(shards) = bamutil::get_shards(input, read_options)? {
+        let mut shard_groups = bamutil::map_shards(&shards, read_options.threads, |shard| {
+            observe_shard(input, min_qual, cpg_set, shard, group_by, new_metric, &bar)
+        })
+        .into_iter();
+
+        let (mut groups, mut counts) = shard_groups.next().unwrap()?;
+        for shard in shard_groups {
+            let (shard_groups, shard_counts) = shard?;
+            for (group, shard_metric) in shard_groups {
+                match groups.entry(group) {
+                    Entry::Occupied(mut entry) => entry.get_mut().merge(shard_metric),
+                    Entry::Vacant(entry) => {
+                        entry.insert(shard_metric);
+                    }
+                }
+            }
+            counts.merge(&shard_counts);
+        }
+        return Ok((groups, counts));
+    }
+
+    observe_shard(
+        input,
+        min_qual,
+        cpg_set,
+        read_options,
+        group_by,
+        new_metric,
+        &bar,
+    )
+}
+
+/// Observes the reads in the regions of `read_options` as done by [`observe_reads`], in a single
+/// thread, and adds them to the progress shown by `bar`.
+fn observe_shard<M, F>(
+    input: &str,
+    min_qual: u8,
+    cpg_set: &Option<String>,
+    read_options: &readutil::ReadOptions,
+    group_by: Option<&str>,
+    new_metric: &F,
+    bar: &progressbar::ProgressBar,
+) -> Result<(BTreeMap<String, M>, FilterCounts)>
+where
+    M: HeterogeneityMetric,
+    F: Fn() -> M,
+{
+    let reader = bamutil::get_reader(input)?;
+    let header = bamutil::get_header(&reader);
+    let mut reads = ReadCaller::new(min_qual, cpg_set, read_options, &header)?;
+
+    let mut groups: BTreeMap<String, Group<M>> = BTreeMap::new();
+    if group_by.is_none() {
+        groups.insert(String::new(), Group::new(new_metric(), read_options));
+    }
+
+    // Reads processed since the last update of the progress bar.
+    let mut readcount = 0;
+    let mut valid_readcount = 0;
+
+    for r in bamutil::get_records(input, &reads.regions, read_options.cram_reference())? {
+        let r = r?;
+
+        readcount += 1;
+        let group = match group_by {
+            Some(tag) => get_group(&r, tag),
+            None => Some(String::new()),
+        };
+        let br = match reads.call(&r, group.is_some())? {
+            Some(br) => br,
+            None => continue,
+        };
+
+        groups
+            .entry(group.unwrap_or_default())
+            .or_insert_with(|| Group::new(new_metric(), read_options))
+            .observe(&r, br);
+
+        valid_readcount += 1;
+        if readcount >= 10000 {
+            bar.add_reads(readcount, valid_readcount);
+            readcount = 0;
+            valid_readcount = 0;
+        };
+    }
+    bar.add_reads(readcount, valid_readcount);
+
+    let groups = groups
+        .into_iter()
+        .map(|(name, group)| (name, group.finish()))
+        .collect();
+
+    Ok((groups, reads.counts))
+}
+
+/// Read filters and methylation calls applied to the reads of an input before they are observed
+/// by a metric, along with the number of reads filtered out by each filter.
+struct ReadCaller {
+    target_cpgs: Option<HashSet<readutil::CpGPosition>>,
+    caller: readutil::MethylationCaller,
+    regions: Option<Vec<bamutil::Region>>,
+    filter: ReadFilter,
+    counts: FilterCounts,
+}
+
+impl ReadCaller {
+    fn new(
+        min_qual: u8,
+        cpg_set: &Option<String>,
+        read_options: &readutil::ReadOptions,
+        header: &bam::HeaderView,
+    ) -> Result<Self> {
+        Ok(Self {
+            target_cpgs: readutil::get_target_cpgs(cpg_set, header)?,
+            caller: readutil::MethylationCaller::new(read_options, header)?,
+            regions: bamutil::get_regions(read_options, header)?,
+            filter: ReadFilter::new(read_options, min_qual),
+            counts: FilterCounts::default(),
+        })
+    }
+
+    /// Calls the methylation of the CpGs of `r` in the CpG set and the regions, or returns `None`
+    /// if `r` fails the read filters (flags and minimum mapping quality) or is not in a group.
+    fn call(&mut self, r: &bam::Record, in_group: bool) -> Result<Option<readutil::BismarkRead>> {
+        let reason = self.filter.check(r).or(match in_group {
+            true => None,
+            false => Some(FilterReason::MissingGroupTag),
+        });
+        self.counts.add(reason);
+        if reason.is_some() {
+            return Ok(None);
+        }
+
+        let mut br = self.caller.call(r)?;
+
+        if let Some(target_cpgs) = &self.target_cpgs {
+            br.filter_isin(target_cpgs);
+        }
+
+        if let Some(regions) = &self.regions {
+            br.filter_in_regions(regions);
+        }
+
+        Ok(Some(br))
+    }
+}
+
+/// A metric computed over the reads of an input as they are read, so that the results of the
+/// CpGs that the next reads cannot cover are available before all reads are observed (see
+/// [`HeterogeneityMetric::take_before`]). The reads are processed as done by [`compute`], in a
+/// single thread.
+pub struct MetricStream<'a, M> {
+    input: String,
+    header: bam::HeaderView,
+    records: bamutil::Records,
+    reads: ReadCaller,
+    /// `None` once all reads are observed.
+    group: Option<Group<M>>,
+    /// Start of the last read, before which the next reads have no CpG.
+    position: Option<readutil::CpGPosition>,
+    bar: &'a progressbar::ProgressBar,
+}
+
+impl<'a, M> MetricStream<'a, M>
+where
+    M: HeterogeneityMetric,
+    M::Output: Default,
+{
+    /// Opens `input` to compute `metric` over its reads, adding them to the progress shown by `bar`.
+    /// With `thread_pool`, the input is decompressed by the threads of the pool.
+    pub fn new(
+        input: &str,
+        min_qual: u8,
+        cpg_set: &Option<String>,
+        read_options: &readutil::ReadOptions,
+        metric: M,
+        thread_pool: Option<&tpool::ThreadPool>,
+        bar: &'a progressbar::ProgressBar,
+    ) -> Result<Self> {
+        let reader = bamutil::get_reader(input)?;
+        let header = bamutil::get_header(&reader);
+        let reads = ReadCaller::new(min_qual, cpg_set, read_options, &header)?;
+
+        let mut records =
+            bamutil::get_records(input, &reads.regions, read_options.cram_reference())?;
+        if let Some(thread_pool) = thread_pool {
+            records.set_thread_pool(thread_pool)?;
+        }
+
+        Ok(Self {
+            input: input.to_string(),
+            header,
+            records,
+            reads,
+            group: Some(Group::new(metric, read_options)),
+            position: None,
+            bar,
+        })
+    }
+
+    /// Header of the input.
+    pub fn header(&self) -> &bam::HeaderView {
+        &self.header
+    }
+
+    /// Number of reads filtered out by each filter so far.
+    pub fn counts(&self) -> &FilterCounts {
+        &self.reads.counts
+    }
+
+    /// Observes up to `n_reads` reads, and returns the results that the next reads cannot change,
+    /// sorted by position. Once all reads are observed, returns the remaining results, and then
+    /// `None`.
+    pub fn next_results(&mut self, n_reads: u64) -> Result<Option<M::Output>> {
+        let group = match &mut self.group {
+            Some(group) => group,
+            None => return Ok(None),
+        };
+
+        let mut readcount = 0;
+        let mut valid_readcount = 0;
+        let mut is_done = false;
+        while readcount < n_reads {
+            let r = match self.records.next() {
+                Some(r) => r?,
+                None => {
+                    is_done = true;
+                    break;
+                }
+            };
+
+            readcount += 1;
+            if r.tid() >= 0 {
+                // The C of a CpG whose G is the first base of a reverse read is before the read.
+                self.position = Some(readutil::CpGPosition::new(r.tid(), r.pos() as i32 - 1));
+            }
+            if let Some(br) = self.reads.call(&r, true)? {
+                group.observe(&r, br);
+                valid_readcount += 1;
+            }
+        }
+        self.bar.add_reads(readcount, valid_readcount);
+
+        if is_done {
+            let metric = self.group.take().expect("not done yet").finish();
+            manifest::record_input(&self.input, Some(&self.reads.counts));
+            return Ok(Some(metric.finalize(&self.header)));
+        }
+
+        // The CpGs of the reads waiting for their mates are observed with the mates.
+        let pos = match (self.position, group.first_pending_cpg()) {
+            (Some(position), Some(pending)) => Some(position.min(pending)),
+            (position, pending) => position.or(pending),
+        };
+        Ok(Some(
+            pos.and_then(|pos| group.metric.take_before(pos, &self.header))
+                .unwrap_or_default(),
+        ))
+    }
+}
+
+/// Observes reads (or fragments of merged mates), after finalizing the CpGs before the first CpG
+/// of the reads. CpGs of the reads still waiting for their mates, from `pending` on, are kept.
+fn observe_fragments<M: HeterogeneityMetric>(
+    metric: &mut M,
+    fragments: &[readutil::BismarkRead],
+    pending: Option<readutil::CpGPosition>,
+) {
+    let first_cpg_position = fragments
+        .iter()
+        .filter_map(|br| br.get_first_cpg_position())
+        .min();
+
+    if let Some(first_cpg_position) = first_cpg_position {
+        let pos = match pending {
+            Some(pending) => pending.min(first_cpg_position),
+            None => first_cpg_position,
+        };
+        metric.flush_before(pos);
+    }
+
+    for br in fragments.iter() {
+        metric.observe_read(br);
+    }
+}
+
+/// Writes `records` to `output`, one line per record, after an optional header line.
+///
+/// Outputs ending with `.gz` are compressed with BGZF. Tables of sites, without a header line or
+/// with one starting with the `chrom` column, are then indexed with tabix, using the second and
+/// third columns as start and end.
+pub fn write_table<T: fmt::Display>(
+    output: &str,
+    header: Option<&str>,
+    records: &[T],
+) -> Result<()> {
+    let mut out = output::create(output)?;
+
+    if let Some(header) = header {
+        writeln!(out, "{}", header).map_err(Error::io(output))?;
+    }
+    for record in records.iter() {
+        writeln!(out, "{}", record).map_err(Error::io(output))?;
+    }
+    drop(out);
+
+    match header {
+        None => output::index(output, 1, 0),
+        Some(header) if header.starts_with("chrom\t") => output::index(output, 1, 1),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Writes the records of a measure with a value for each CpG to `output` in `format`, either as a
+/// table with an optional header line or as a bigWig track with the reference sequences in the
+/// header of `input`.
+pub fn write_records<T: fmt::Display + TrackRecord>(
+    input: &str,
+    output: &str,
+    format: OutputFormat,
+    header: Option<&str>,
+    records: &[T],
+) -> Result<()> {
+    match format.is_bigwig(output) {
+        true => bigwig::write_records(output, input, records),
+        false => write_table(output, header, records),
+    }
+}
+
+/// Writes the records of each group as done by [`write_records`]: a single table, or a bigWig
+/// track for each group with the name of the group inserted in the path (see
+/// [`output::group_path`]).
+pub fn write_grouped_records<T: fmt::Display + TrackRecord>(
+    input: &str,
+    output: &str,
+    format: OutputFormat,
+    header: Option<&str>,
+    groups: &[(String, Vec<T>)],
+) -> Result<()> {
+    if !format.is_bigwig(output) {
+        return write_grouped_table(output, header, groups);
+    }
+
+    for (group, records) in groups.iter() {
+        bigwig::write_records(&output::group_path(output, group), input, records)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the records of each group to `output` as a single table, with the name of the group
+/// in the first column of each line and of the optional header line.
+///
+/// Outputs ending with `.gz` are compressed with BGZF, but not indexed, as the lines are sorted by
+/// the name of the group rather than by position.
+pub fn write_grouped_table<T, R>(
+    output: &str,
+    header: Option<&str>,
+    groups: &[(String, R)],
+) -> Result<()>
+where
+    T: fmt::Display,
+    R: Deref<Target = [T]>,
+{
+    let mut out = output::create(output)?;
+
+    if let Some(header) = header {
+        writeln!(out, "group\t{}", header).map_err(Error::io(output))?;
+    }
+    for (group, records) in groups.iter() {
+        for record in records.iter() {
+            writeln!(out, "{}\t{}", group, record).map_err(Error::io(output))?;
+        }
+    }
+
+    Ok(())
+}
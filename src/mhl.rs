@@ -1,12 +1,15 @@
-use rust_htslib::bam::Read;
+use rust_htslib::bam;
 use std::cmp::Ordering;
 use std::collections::{BTreeMap, HashMap};
-use std::fs;
-use std::io::Write;
+use std::fmt;
 use std::str;
 use std::vec::Vec;
 
-use crate::{bamutil, progressbar, readutil};
+use crate::bigwig::TrackRecord;
+use crate::error::Result;
+use crate::metric::{self, HeterogeneityMetric};
+use crate::output::OutputFormat;
+use crate::{bamutil, readutil};
 
 #[derive(Eq)]
 struct AssociatedReads {
@@ -98,122 +101,222 @@ impl Ord for AssociatedReads {
     }
 }
 
-pub fn compute(
-    input: &str,
-    output: &str,
-    min_depth: u32,
-    min_cpgs: usize,
-    min_qual: u8,
-    cpg_set: &Option<String>,
-) {
-    let reader = bamutil::get_reader(input);
-    let header = bamutil::get_header(&reader);
-
-    let result = compute_helper(input, min_depth, min_cpgs, min_qual, cpg_set);
-
-    let mut out = fs::OpenOptions::new()
-        .create(true)
-        .read(true)
-        .write(true)
-        .truncate(true)
-        .open(output)
-        .unwrap();
-
-    for (cpg, mhl) in result.iter() {
-        writeln!(
-            out,
+/// Header line of the output table of MHL, written with `--header`.
+pub const HEADER: &str = "chrom\tstart\tend\tmhl";
+
+/// Parameters of MHL calculation.
+#[derive(Clone)]
+pub struct MhlParams {
+    /// Minimum depth of CpG stretches to consider.
+    pub min_depth: u32,
+    /// Minimum number of consecutive CpGs in a CpG stretch to consider.
+    pub min_cpgs: usize,
+    /// Minimum quality for a read to be considered.
+    pub min_qual: u8,
+    /// (Optional) Predefined set of CpGs (in BED file) to be analyzed.
+    pub cpg_set: Option<String>,
+    pub read_options: readutil::ReadOptions,
+}
+
+impl Default for MhlParams {
+    fn default() -> Self {
+        Self {
+            min_depth: 10,
+            min_cpgs: 4,
+            min_qual: 10,
+            cpg_set: None,
+            read_options: readutil::ReadOptions::default(),
+        }
+    }
+}
+
+/// MHL of a single CpG. Formatted as a line of the output table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MhlRecord {
+    pub chrom: String,
+    /// 0-based position of the cytosine (C) in CpG.
+    pub start: i32,
+    /// 0-based position + 1 of the guanine (G) in CpG.
+    pub end: i32,
+    pub mhl: f32,
+}
+
+impl fmt::Display for MhlRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
             "{}\t{}\t{}\t{}",
-            bamutil::tid2chrom(cpg.tid, &header),
-            cpg.pos,
-            cpg.pos + 2,
-            mhl
+            self.chrom, self.start, self.end, self.mhl
         )
-        .expect("Error writing to output file.");
     }
 }
 
-pub fn compute_helper(
-    input: &str,
+impl TrackRecord for MhlRecord {
+    fn interval(&self) -> (&str, i32, i32, f32) {
+        (&self.chrom, self.start, self.end, self.mhl)
+    }
+}
+
+/// MHL as a [`HeterogeneityMetric`].
+pub struct MhlMetric {
     min_depth: u32,
     min_cpgs: usize,
-    min_qual: u8,
-    cpg_set: &Option<String>,
-) -> BTreeMap<readutil::CpGPosition, f32> {
-    let mut reader = bamutil::get_reader(input);
-    let header = bamutil::get_header(&reader);
-
-    let target_cpgs = &readutil::get_target_cpgs(cpg_set, &header);
-
-    let mut cpg2reads: HashMap<readutil::CpGPosition, AssociatedReads> = HashMap::new();
-    let mut result: BTreeMap<readutil::CpGPosition, f32> = BTreeMap::new();
-
-    let mut readcount = 0;
-    let mut valid_readcount = 0;
-
-    let bar = progressbar::ProgressBar::new();
-
-    for r in reader.records().map(|r| r.unwrap()) {
-        let mut br = readutil::BismarkRead::new(&r);
-
-        if let Some(target_cpgs) = target_cpgs {
-            br.filter_isin(target_cpgs);
-        }
+    cpg2reads: HashMap<readutil::CpGPosition, AssociatedReads>,
+    result: BTreeMap<readutil::CpGPosition, f32>,
+}
 
-        if let Some(first_cpg_position) = br.get_first_cpg_position() {
-            cpg2reads.retain(|&cpg, reads| {
-                if cpg < first_cpg_position {
-                    if reads.get_coverage() >= min_depth {
-                        result.insert(cpg, reads.compute_mhl());
-                    }
-                    false
-                } else {
-                    true
-                }
-            }); // Finalize and compute metric for the CpGs before the first CpG in this read.
+impl MhlMetric {
+    pub fn new(params: &MhlParams) -> Self {
+        Self {
+            min_depth: params.min_depth,
+            min_cpgs: params.min_cpgs,
+            cpg2reads: HashMap::new(),
+            result: BTreeMap::new(),
         }
+    }
+}
 
-        readcount += 1;
-        if r.mapq() < min_qual {
-            continue;
-        } // Read filtering: Minimum quality should be >= min_qual.
+impl HeterogeneityMetric for MhlMetric {
+    type Output = Vec<MhlRecord>;
 
-        let mut cpg_positions = br.get_cpg_positions();
-        if br.get_num_cpgs() < min_cpgs {
-            continue;
+    fn observe_read(&mut self, br: &readutil::BismarkRead) {
+        if br.get_num_cpgs() < self.min_cpgs {
+            return;
         } // Read filtering: Ignore reads with few CpGs.
 
-        for cpg_position in cpg_positions.iter_mut() {
-            let r = cpg2reads
+        for cpg_position in br.get_cpg_positions().iter() {
+            let r = self
+                .cpg2reads
                 .entry(*cpg_position)
                 .or_insert(AssociatedReads::new(*cpg_position));
 
             r.add_num_cpgs(br.get_num_cpgs());
             r.add_stretch_info(br.get_stretch_info());
         }
+    }
 
-        valid_readcount += 1;
-        if readcount % 10000 == 0 {
-            bar.update(readcount, valid_readcount)
-        };
+    fn flush_before(&mut self, pos: readutil::CpGPosition) {
+        let min_depth = self.min_depth;
+        let result = &mut self.result;
+
+        self.cpg2reads.retain(|&cpg, reads| {
+            if cpg < pos {
+                if reads.get_coverage() >= min_depth {
+                    result.insert(cpg, reads.compute_mhl());
+                }
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn take_before(
+        &mut self,
+        pos: readutil::CpGPosition,
+        header: &bam::HeaderView,
+    ) -> Option<Vec<MhlRecord>> {
+        self.flush_before(pos);
+
+        let rest = self.result.split_off(&pos);
+        let taken = std::mem::replace(&mut self.result, rest);
+        Some(to_records(&taken, header))
+    }
+
+    fn merge(&mut self, other: Self) {
+        debug_assert!(other
+            .cpg2reads
+            .keys()
+            .all(|key| !self.cpg2reads.contains_key(key)));
+        debug_assert!(other
+            .result
+            .keys()
+            .all(|key| !self.result.contains_key(key)));
+        self.cpg2reads.extend(other.cpg2reads);
+        self.result.extend(other.result);
     }
 
-    // Flush remaining CpGs.
-    for (&cpg, reads) in cpg2reads.iter_mut() {
-        if reads.get_coverage() >= min_depth {
-            result.insert(cpg, reads.compute_mhl());
+    fn finalize(mut self, header: &bam::HeaderView) -> Vec<MhlRecord> {
+        // Flush remaining CpGs.
+        for (cpg, reads) in self.cpg2reads.iter() {
+            if reads.get_coverage() >= self.min_depth {
+                self.result.insert(*cpg, reads.compute_mhl());
+            }
         }
+
+        to_records(&self.result, header)
     }
+}
 
+/// Records of the MHL of the CpGs in `result`.
+fn to_records(
+    result: &BTreeMap<readutil::CpGPosition, f32>,
+    header: &bam::HeaderView,
+) -> Vec<MhlRecord> {
     result
+        .iter()
+        .map(|(cpg, mhl)| MhlRecord {
+            chrom: bamutil::tid2chrom(cpg.tid, header),
+            start: cpg.pos,
+            end: cpg.pos + 2,
+            mhl: *mhl,
+        })
+        .collect()
+}
+
+pub fn compute(
+    input: &str,
+    output: &str,
+    format: OutputFormat,
+    header: bool,
+    params: &MhlParams,
+) -> Result<()> {
+    let header = header.then_some(HEADER);
+
+    if params.read_options.group_by_tag.is_some() {
+        let groups = compute_grouped_records(input, params)?;
+        return metric::write_grouped_records(input, output, format, header, &groups);
+    }
+
+    let records = compute_records(input, params)?;
+
+    metric::write_records(input, output, format, header, &records)
+}
+
+/// Computes MHL of the CpGs covered by the reads in `input`, sorted by position.
+pub fn compute_records(input: &str, params: &MhlParams) -> Result<Vec<MhlRecord>> {
+    metric::compute(
+        input,
+        params.min_qual,
+        &params.cpg_set,
+        &params.read_options,
+        || MhlMetric::new(params),
+    )
+}
+
+/// Computes MHL separately for each group of reads sharing the value of the aux tag given by
+/// `group_by_tag` of `read_options`, sorted by the name of the group.
+pub fn compute_grouped_records(
+    input: &str,
+    params: &MhlParams,
+) -> Result<Vec<(String, Vec<MhlRecord>)>> {
+    metric::compute_grouped(
+        input,
+        params.min_qual,
+        &params.cpg_set,
+        &params.read_options,
+        || MhlMetric::new(params),
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::super::bamutil;
     use super::*;
+    use rust_htslib::bam::Read;
 
     fn startup(input: &str) -> HashMap<readutil::CpGPosition, AssociatedReads> {
-        let mut reader = bamutil::get_reader(input);
+        let mut reader = bamutil::get_reader(input).unwrap();
         // let header = bamutil::get_header(&reader);
 
         let min_qual = 10;
@@ -221,7 +324,7 @@ mod tests {
         let mut cpg2reads: HashMap<readutil::CpGPosition, AssociatedReads> = HashMap::new();
 
         for r in reader.records().map(|r| r.unwrap()) {
-            let br = readutil::BismarkRead::new(&r);
+            let br = readutil::BismarkRead::new(&r).unwrap();
             if r.mapq() < min_qual {
                 continue;
             } // Read filtering: Minimum quality should be >= min_qual.
@@ -293,4 +396,25 @@ mod tests {
 
         assert_eq!(cpg2reads.len(), 0);
     }
+
+    #[test]
+    fn test1_records() {
+        let input = "tests/test1.bam";
+        let params = MhlParams {
+            min_depth: 0,
+            min_cpgs: 0,
+            ..Default::default()
+        };
+
+        let cpg_positions = [0, 2, 4, 6];
+
+        let result = compute_records(input, &params).unwrap();
+
+        assert_eq!(result.len(), 4);
+        for (i, record) in result.iter().enumerate() {
+            assert_eq!(record.start, cpg_positions[i]);
+            assert_eq!(record.end, cpg_positions[i] + 2);
+            assert_eq!(record.mhl, 0.1625);
+        }
+    }
 }
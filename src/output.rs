@@ -0,0 +1,114 @@
+use clap::ValueEnum;
+use rust_htslib::{bgzf, htslib};
+use std::ffi::CString;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+/// `preset` of tabix for tables with generic columns and 0-based, half-open coordinates.
+const TBX_GENERIC_UCSC: i32 = 0x10000;
+
+/// Minimum interval size of CSI indices (2^14), used for positions beyond the limit of TBI.
+const CSI_MIN_SHIFT: i32 = 14;
+
+/// Format of the output of a measure with a value for each CpG.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// bigWig if the output path ends with `.bw` or `.bigwig`, table otherwise.
+    Auto,
+    /// Tab-separated table.
+    Tsv,
+    /// bigWig track of the value at each CpG.
+    Bigwig,
+}
+
+impl OutputFormat {
+    /// Whether `output` is written as a bigWig track in this format.
+    pub fn is_bigwig(self, output: &str) -> bool {
+        match self {
+            OutputFormat::Auto => {
+                let lower = output.to_lowercase();
+                lower.ends_with(".bw") || lower.ends_with(".bigwig")
+            }
+            OutputFormat::Tsv => false,
+            OutputFormat::Bigwig => true,
+        }
+    }
+}
+
+/// Path of the output of the group of reads `group`, with the name of the group inserted before
+/// the extension of `output` (e.g., `pdr.CB1.bw` for `pdr.bw`).
+pub fn group_path(output: &str, group: &str) -> String {
+    let path = Path::new(output);
+    match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) => path
+            .with_file_name(format!(
+                "{}.{}.{}",
+                stem.to_string_lossy(),
+                group,
+                extension.to_string_lossy()
+            ))
+            .to_string_lossy()
+            .into_owned(),
+        _ => format!("{}.{}", output, group),
+    }
+}
+
+/// Whether `output` is written compressed with BGZF, i.e., its path ends with `.gz`.
+pub fn is_bgzf(output: &str) -> bool {
+    output.ends_with(".gz")
+}
+
+/// Opens `output` for writing, truncating it if it exists. Outputs ending with `.gz` are
+/// compressed with BGZF, so that they can be indexed with tabix.
+pub fn create(output: &str) -> Result<Box<dyn Write>> {
+    if is_bgzf(output) {
+        let writer = bgzf::Writer::from_path(output).map_err(|source| Error::WriteOutput {
+            path: output.to_string(),
+            source,
+        })?;
+        return Ok(Box::new(writer));
+    }
+
+    let out = fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .truncate(true)
+        .open(output)
+        .map_err(Error::io(output))?;
+
+    Ok(Box::new(out))
+}
+
+/// Builds a tabix index of `output` if it is compressed with BGZF, for a table sorted by position
+/// with the chromosome, start and end in the three columns from `first_column` (1-based), after
+/// `line_skip` header lines. A TBI index (`.tbi`) is built, or a CSI index (`.csi`) if positions
+/// exceed the limit of TBI. Uncompressed outputs are left as they are.
+pub fn index(output: &str, first_column: i32, line_skip: i32) -> Result<()> {
+    if !is_bgzf(output) {
+        return Ok(());
+    }
+
+    let conf = htslib::tbx_conf_t {
+        preset: TBX_GENERIC_UCSC,
+        sc: first_column,
+        bc: first_column + 1,
+        ec: first_column + 2,
+        meta_char: '#' as i32,
+        line_skip,
+    };
+    let path = CString::new(output).map_err(|_| Error::IndexOutput(output.to_string()))?;
+
+    let mut ret = unsafe { htslib::tbx_index_build(path.as_ptr(), 0, &conf) };
+    if ret != 0 {
+        ret = unsafe { htslib::tbx_index_build(path.as_ptr(), CSI_MIN_SHIFT, &conf) };
+    }
+
+    match ret {
+        0 => Ok(()),
+        _ => Err(Error::IndexOutput(output.to_string())),
+    }
+}
@@ -1,14 +1,16 @@
-use rust_htslib::bam::Read;
+use rust_htslib::bam;
 use std::cmp::Ordering;
 use std::collections::{BTreeMap, HashMap};
 use std::fmt;
-use std::fs;
-use std::io::Write;
 use std::str;
 
-use crate::{bamutil, progressbar, readutil};
+use crate::bigwig::TrackRecord;
+use crate::error::Result;
+use crate::metric::{self, HeterogeneityMetric};
+use crate::output::OutputFormat;
+use crate::{bamutil, readutil};
 
-#[derive(Eq)]
+#[derive(Eq, Clone, Copy)]
 struct PDRResult {
     pos: readutil::CpGPosition,
     n_concordant: u32,
@@ -47,6 +49,17 @@ impl PDRResult {
     fn compute_pdr(&self) -> f32 {
         (self.n_discordant as f32) / (self.n_concordant as f32 + self.n_discordant as f32)
     }
+
+    fn to_record(self, header: &bam::HeaderView) -> PdrRecord {
+        PdrRecord {
+            chrom: bamutil::tid2chrom(self.pos.tid, header),
+            start: self.pos.pos,
+            end: self.pos.pos + 2,
+            pdr: self.compute_pdr(),
+            n_concordant: self.get_n_concordant(),
+            n_discordant: self.get_n_discordant(),
+        }
+    }
 }
 
 impl fmt::Display for PDRResult {
@@ -79,136 +92,225 @@ impl Ord for PDRResult {
     }
 }
 
-pub fn compute(
-    input: &str,
-    output: &str,
-    min_depth: u32,
-    min_cpgs: usize,
-    min_qual: u8,
-    cpg_set: &Option<String>,
-) {
-    let reader = bamutil::get_reader(input);
-    let header = bamutil::get_header(&reader);
-
-    let result = compute_helper(input, min_depth, min_cpgs, min_qual, cpg_set);
-
-    let mut out = fs::OpenOptions::new()
-        .create(true)
-        .read(true)
-        .write(true)
-        .truncate(true)
-        .open(output)
-        .unwrap();
-    for (cpg, (pdr, n_concordant, n_discordant)) in result.iter() {
-        let chrom = bamutil::tid2chrom(cpg.tid, &header);
-
-        writeln!(
-            out,
+/// Header line of the output table of PDR, written with `--header`.
+pub const HEADER: &str = "chrom\tstart\tend\tpdr\tn_concordant\tn_discordant";
+
+/// Parameters of PDR calculation.
+#[derive(Clone)]
+pub struct PdrParams {
+    /// Minimum depth of CpG stretches to consider.
+    pub min_depth: u32,
+    /// Minimum number of consecutive CpGs in a CpG stretch to consider.
+    pub min_cpgs: usize,
+    /// Minimum quality for a read to be considered.
+    pub min_qual: u8,
+    /// (Optional) Predefined set of CpGs (in BED file) to be analyzed.
+    pub cpg_set: Option<String>,
+    pub read_options: readutil::ReadOptions,
+}
+
+impl Default for PdrParams {
+    fn default() -> Self {
+        Self {
+            min_depth: 10,
+            min_cpgs: 4,
+            min_qual: 10,
+            cpg_set: None,
+            read_options: readutil::ReadOptions::default(),
+        }
+    }
+}
+
+/// PDR of a single CpG. Formatted as a line of the output table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PdrRecord {
+    pub chrom: String,
+    /// 0-based position of the cytosine (C) in CpG.
+    pub start: i32,
+    /// 0-based position + 1 of the guanine (G) in CpG.
+    pub end: i32,
+    pub pdr: f32,
+    pub n_concordant: u32,
+    pub n_discordant: u32,
+}
+
+impl fmt::Display for PdrRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
             "{}\t{}\t{}\t{}\t{}\t{}",
-            chrom,
-            cpg.pos,
-            cpg.pos + 2,
-            pdr,
-            n_concordant,
-            n_discordant
+            self.chrom, self.start, self.end, self.pdr, self.n_concordant, self.n_discordant
         )
-        .expect("Error writing to output file.");
     }
 }
 
-pub fn compute_helper(
-    input: &str,
+impl TrackRecord for PdrRecord {
+    fn interval(&self) -> (&str, i32, i32, f32) {
+        (&self.chrom, self.start, self.end, self.pdr)
+    }
+}
+
+/// PDR as a [`HeterogeneityMetric`].
+pub struct PdrMetric {
     min_depth: u32,
     min_cpgs: usize,
-    min_qual: u8,
-    cpg_set: &Option<String>,
-) -> BTreeMap<readutil::CpGPosition, (f32, u32, u32)> {
-    let mut reader = bamutil::get_reader(input);
-    let header = bamutil::get_header(&reader);
-
-    let target_cpgs = &readutil::get_target_cpgs(cpg_set, &header);
-
-    let mut cpg2reads: HashMap<readutil::CpGPosition, PDRResult> = HashMap::new();
-
-    let mut readcount = 0;
-    let mut valid_readcount = 0;
-
-    let mut result: BTreeMap<readutil::CpGPosition, (f32, u32, u32)> = BTreeMap::new();
-    let bar = progressbar::ProgressBar::new();
-
-    for r in reader.records().map(|r| r.unwrap()) {
-        let mut br = readutil::BismarkRead::new(&r);
+    cpg2reads: HashMap<readutil::CpGPosition, PDRResult>,
+    result: BTreeMap<readutil::CpGPosition, PDRResult>,
+}
 
-        if let Some(target_cpgs) = target_cpgs {
-            br.filter_isin(target_cpgs); // cpg_set is specified
+impl PdrMetric {
+    pub fn new(params: &PdrParams) -> Self {
+        Self {
+            min_depth: params.min_depth,
+            min_cpgs: params.min_cpgs,
+            cpg2reads: HashMap::new(),
+            result: BTreeMap::new(),
         }
+    }
+}
 
-        readcount += 1;
-        if br.get_num_cpgs() < min_cpgs {
-            continue;
-        }
-        if r.mapq() < min_qual {
-            continue;
-        } // Read filtering: Minimum quality should be >= min_qual.
+impl HeterogeneityMetric for PdrMetric {
+    type Output = Vec<PdrRecord>;
+
+    fn observe_read(&mut self, br: &readutil::BismarkRead) {
+        if br.get_num_cpgs() < self.min_cpgs {
+            return;
+        } // Read filtering: Ignore reads with few CpGs.
 
-        let mut cpg_positions = br.get_cpg_positions();
+        let cpg_positions = br.get_cpg_positions();
         if cpg_positions.is_empty() {
-            continue;
+            return;
         } // Read filtering: Ignore reads without CpGs.
 
-        if let Some(first_cpg_position) = br.get_first_cpg_position() {
-            cpg2reads.retain(|&cpg, reads| {
-                // if cpg < first_cpg_position {
-                if cpg.is_before(&first_cpg_position, 150) {
-                    if reads.get_coverage() >= min_depth {
-                        result.insert(
-                            cpg,
-                            (
-                                reads.compute_pdr(),
-                                reads.get_n_concordant(),
-                                reads.get_n_discordant(),
-                            ),
-                        );
-                    }
-                    false
-                } else {
-                    true
-                }
-            }); // Finalize and compute metric for the CpGs before the first CpG in this read.
-        }
-
-        for cpg_position in cpg_positions.iter_mut() {
-            let r = cpg2reads
+        let concordance_state = br.get_concordance_state();
+        for cpg_position in cpg_positions.iter() {
+            let r = self
+                .cpg2reads
                 .entry(*cpg_position)
                 .or_insert(PDRResult::new(*cpg_position));
 
-            let concordance_state = br.get_concordance_state();
-
             match concordance_state {
                 readutil::ReadConcordanceState::Concordant => r.inc_concordant(),
                 readutil::ReadConcordanceState::Discordant => r.inc_discordant(),
             }
         }
+    }
 
-        valid_readcount += 1;
-        if readcount % 10000 == 0 {
-            bar.update(readcount, valid_readcount)
-        };
+    fn flush_before(&mut self, pos: readutil::CpGPosition) {
+        let min_depth = self.min_depth;
+        let result = &mut self.result;
+
+        self.cpg2reads.retain(|&cpg, reads| {
+            if cpg.is_before(&pos, 150) {
+                if reads.get_coverage() >= min_depth {
+                    result.insert(cpg, *reads);
+                }
+                false
+            } else {
+                true
+            }
+        });
     }
 
-    for (&cpg, reads) in cpg2reads.iter() {
-        if reads.get_coverage() >= min_depth {
-            result.insert(
-                cpg,
-                (
-                    reads.compute_pdr(),
-                    reads.get_n_concordant(),
-                    reads.get_n_discordant(),
-                ),
-            );
+    fn take_before(
+        &mut self,
+        pos: readutil::CpGPosition,
+        header: &bam::HeaderView,
+    ) -> Option<Vec<PdrRecord>> {
+        let min_depth = self.min_depth;
+        let result = &mut self.result;
+
+        self.cpg2reads.retain(|&cpg, reads| {
+            if cpg < pos {
+                if reads.get_coverage() >= min_depth {
+                    result.insert(cpg, *reads);
+                }
+                false
+            } else {
+                true
+            }
+        });
+
+        let rest = self.result.split_off(&pos);
+        let taken = std::mem::replace(&mut self.result, rest);
+        Some(
+            taken
+                .values()
+                .map(|reads| reads.to_record(header))
+                .collect(),
+        )
+    }
+
+    fn merge(&mut self, other: Self) {
+        debug_assert!(other
+            .cpg2reads
+            .keys()
+            .all(|key| !self.cpg2reads.contains_key(key)));
+        debug_assert!(other
+            .result
+            .keys()
+            .all(|key| !self.result.contains_key(key)));
+        self.cpg2reads.extend(other.cpg2reads);
+        self.result.extend(other.result);
+    }
+
+    fn finalize(mut self, header: &bam::HeaderView) -> Vec<PdrRecord> {
+        for (cpg, reads) in self.cpg2reads.into_iter() {
+            if reads.get_coverage() >= self.min_depth {
+                self.result.insert(cpg, reads);
+            }
         }
+
+        self.result
+            .values()
+            .map(|reads| reads.to_record(header))
+            .collect()
     }
-    result
+}
+
+pub fn compute(
+    input: &str,
+    output: &str,
+    format: OutputFormat,
+    header: bool,
+    params: &PdrParams,
+) -> Result<()> {
+    let header = header.then_some(HEADER);
+
+    if params.read_options.group_by_tag.is_some() {
+        let groups = compute_grouped_records(input, params)?;
+        return metric::write_grouped_records(input, output, format, header, &groups);
+    }
+
+    let records = compute_records(input, params)?;
+
+    metric::write_records(input, output, format, header, &records)
+}
+
+/// Computes PDR of the CpGs covered by the reads in `input`, sorted by position.
+pub fn compute_records(input: &str, params: &PdrParams) -> Result<Vec<PdrRecord>> {
+    metric::compute(
+        input,
+        params.min_qual,
+        &params.cpg_set,
+        &params.read_options,
+        || PdrMetric::new(params),
+    )
+}
+
+/// Computes PDR separately for each group of reads sharing the value of the aux tag given by
+/// `group_by_tag` of `read_options`, sorted by the name of the group.
+pub fn compute_grouped_records(
+    input: &str,
+    params: &PdrParams,
+) -> Result<Vec<(String, Vec<PdrRecord>)>> {
+    metric::compute_grouped(
+        input,
+        params.min_qual,
+        &params.cpg_set,
+        &params.read_options,
+        || PdrMetric::new(params),
+    )
 }
 
 #[cfg(test)]
@@ -218,44 +320,46 @@ mod tests {
     #[test]
     fn test1() {
         let input = "tests/test1.bam";
-        let min_depth = 0;
-        let min_cpgs = 0;
-        let min_qual = 10;
-        let cpg_set = None;
+        let params = PdrParams {
+            min_depth: 0,
+            min_cpgs: 0,
+            ..Default::default()
+        };
 
         let target_pdrs = [14.0 / 16.0; 4];
         let target_n_concordant = [2; 4];
         let target_n_discordant = [14; 4];
 
-        let result = compute_helper(input, min_depth, min_cpgs, min_qual, &cpg_set);
+        let result = compute_records(input, &params).unwrap();
 
         assert_eq!(result.len(), 4);
-        for (i, (_, (pdr, n_concordant, n_discordant))) in result.iter().enumerate() {
-            assert_eq!(*pdr, target_pdrs[i]);
-            assert_eq!(*n_concordant, target_n_concordant[i]);
-            assert_eq!(*n_discordant, target_n_discordant[i]);
+        for (i, record) in result.iter().enumerate() {
+            assert_eq!(record.pdr, target_pdrs[i]);
+            assert_eq!(record.n_concordant, target_n_concordant[i]);
+            assert_eq!(record.n_discordant, target_n_discordant[i]);
         }
     }
 
     #[test]
     fn test2() {
         let input = "tests/test2.bam";
-        let min_depth = 0;
-        let min_cpgs = 0;
-        let min_qual = 10;
-        let cpg_set = None;
+        let params = PdrParams {
+            min_depth: 0,
+            min_cpgs: 0,
+            ..Default::default()
+        };
 
         let target_pdrs = [0.0; 4];
         let target_n_concordant = [16; 4];
         let target_n_discordant = [0; 4];
 
-        let result = compute_helper(input, min_depth, min_cpgs, min_qual, &cpg_set);
+        let result = compute_records(input, &params).unwrap();
 
         assert_eq!(result.len(), 4);
-        for (i, (_, (pdr, n_concordant, n_discordant))) in result.iter().enumerate() {
-            assert_eq!(*pdr, target_pdrs[i]);
-            assert_eq!(*n_concordant, target_n_concordant[i]);
-            assert_eq!(*n_discordant, target_n_discordant[i]);
+        for (i, record) in result.iter().enumerate() {
+            assert_eq!(record.pdr, target_pdrs[i]);
+            assert_eq!(record.n_concordant, target_n_concordant[i]);
+            assert_eq!(record.n_discordant, target_n_discordant[i]);
         }
     }
 
@@ -263,22 +367,23 @@ mod tests {
     fn test3() {
         let input = "tests/test3.bam";
 
-        let min_depth = 0;
-        let min_cpgs = 0;
-        let min_qual = 10;
-        let cpg_set = None;
+        let params = PdrParams {
+            min_depth: 0,
+            min_cpgs: 0,
+            ..Default::default()
+        };
 
         let target_pdrs = [0.0; 4];
         let target_n_concordant = [2; 4];
         let target_n_discordant = [0; 4];
 
-        let result = compute_helper(input, min_depth, min_cpgs, min_qual, &cpg_set);
+        let result = compute_records(input, &params).unwrap();
 
         assert_eq!(result.len(), 4);
-        for (i, (_, (pdr, n_concordant, n_discordant))) in result.iter().enumerate() {
-            assert_eq!(*pdr, target_pdrs[i]);
-            assert_eq!(*n_concordant, target_n_concordant[i]);
-            assert_eq!(*n_discordant, target_n_discordant[i]);
+        for (i, record) in result.iter().enumerate() {
+            assert_eq!(record.pdr, target_pdrs[i]);
+            assert_eq!(record.n_concordant, target_n_concordant[i]);
+            assert_eq!(record.n_discordant, target_n_discordant[i]);
         }
     }
 
@@ -288,22 +393,102 @@ mod tests {
         // Reads have four CpGs, all the 16 methylation patterns are present. (Max entropy)
         let input = "tests/test4.bam";
 
-        let min_depth = 0;
-        let min_cpgs = 0;
-        let min_qual = 10;
-        let cpg_set = None;
+        let params = PdrParams {
+            min_depth: 0,
+            min_cpgs: 0,
+            ..Default::default()
+        };
 
         let target_pdrs = [14.0 / 16.0; 8]; // Two concordant patterns (0000, 1111)
         let target_n_concordant = [2; 8];
         let target_n_discordant = [14; 8];
 
-        let result = compute_helper(input, min_depth, min_cpgs, min_qual, &cpg_set);
+        let result = compute_records(input, &params).unwrap();
 
         assert_eq!(result.len(), 8);
-        for (i, (_, (pdr, n_concordant, n_discordant))) in result.iter().enumerate() {
-            assert_eq!(*pdr, target_pdrs[i]);
-            assert_eq!(*n_concordant, target_n_concordant[i]);
-            assert_eq!(*n_discordant, target_n_discordant[i]);
+        for (i, record) in result.iter().enumerate() {
+            assert_eq!(record.pdr, target_pdrs[i]);
+            assert_eq!(record.n_concordant, target_n_concordant[i]);
+            assert_eq!(record.n_discordant, target_n_discordant[i]);
+        }
+    }
+
+    #[test]
+    fn test7_mm_ml() {
+        // Same methylation patterns as test1, but encoded in MM/ML tags.
+        let input = "tests/test7.sam";
+        let params = PdrParams {
+            min_depth: 0,
+            min_cpgs: 0,
+            ..Default::default()
+        };
+
+        let target_pdrs = [14.0 / 16.0; 4];
+        let target_n_concordant = [2; 4];
+        let target_n_discordant = [14; 4];
+
+        let result = compute_records(input, &params).unwrap();
+
+        assert_eq!(result.len(), 4);
+        for (i, record) in result.iter().enumerate() {
+            assert_eq!(record.pdr, target_pdrs[i]);
+            assert_eq!(record.n_concordant, target_n_concordant[i]);
+            assert_eq!(record.n_discordant, target_n_discordant[i]);
+        }
+    }
+
+    #[test]
+    fn test8_reference_caller() {
+        // Same methylation patterns as test1, but aligned with bwa-meth (no XM tag).
+        let input = "tests/test8.sam";
+        let params = PdrParams {
+            min_depth: 0,
+            min_cpgs: 0,
+            read_options: readutil::ReadOptions {
+                caller: readutil::CallerKind::Reference,
+                genome: Some("tests/test8.fa".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let target_pdrs = [14.0 / 16.0; 4];
+        let target_n_concordant = [2; 4];
+        let target_n_discordant = [14; 4];
+
+        let result = compute_records(input, &params).unwrap();
+
+        assert_eq!(result.len(), 4);
+        for (i, record) in result.iter().enumerate() {
+            assert_eq!(record.pdr, target_pdrs[i]);
+            assert_eq!(record.n_concordant, target_n_concordant[i]);
+            assert_eq!(record.n_discordant, target_n_discordant[i]);
+        }
+    }
+
+    #[test]
+    fn test4_region() {
+        // Only the second read group is covered by the region.
+        let input = "tests/test4.bam";
+
+        let params = PdrParams {
+            min_depth: 0,
+            min_cpgs: 0,
+            read_options: readutil::ReadOptions {
+                region: vec!["chr1:14-21".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let cpg_positions = [13, 15, 17, 19];
+
+        let result = compute_records(input, &params).unwrap();
+
+        assert_eq!(result.len(), 4);
+        for (i, record) in result.iter().enumerate() {
+            assert_eq!(record.start, cpg_positions[i]);
+            assert_eq!(record.pdr, 14.0 / 16.0);
         }
     }
 
@@ -312,12 +497,13 @@ mod tests {
         // No reads pass quality cutoff.
         let input = "tests/test5.bam";
 
-        let min_depth = 0;
-        let min_cpgs = 0;
-        let min_qual = 10;
-        let cpg_set = None;
+        let params = PdrParams {
+            min_depth: 0,
+            min_cpgs: 0,
+            ..Default::default()
+        };
 
-        let result = compute_helper(input, min_depth, min_cpgs, min_qual, &cpg_set);
+        let result = compute_records(input, &params).unwrap();
 
         assert_eq!(result.len(), 0);
     }
@@ -329,22 +515,23 @@ mod tests {
         // Here we set min_cpgs to 1, so every read passes the filter.
         let input = "tests/test6.bam";
 
-        let min_depth = 0;
-        let min_cpgs = 1;
-        let min_qual = 10;
-        let cpg_set = None;
+        let params = PdrParams {
+            min_depth: 0,
+            min_cpgs: 1,
+            ..Default::default()
+        };
 
         let target_pdrs = [0.0; 2];
         let target_n_concordant = [16; 2];
         let target_n_discordant = [0; 2];
 
-        let result = compute_helper(input, min_depth, min_cpgs, min_qual, &cpg_set);
+        let result = compute_records(input, &params).unwrap();
 
         assert_eq!(result.len(), 2);
-        for (i, (_, (pdr, n_concordant, n_discordant))) in result.iter().enumerate() {
-            assert_eq!(*pdr, target_pdrs[i]);
-            assert_eq!(*n_concordant, target_n_concordant[i]);
-            assert_eq!(*n_discordant, target_n_discordant[i]);
+        for (i, record) in result.iter().enumerate() {
+            assert_eq!(record.pdr, target_pdrs[i]);
+            assert_eq!(record.n_concordant, target_n_concordant[i]);
+            assert_eq!(record.n_discordant, target_n_discordant[i]);
         }
     }
 
@@ -355,12 +542,38 @@ mod tests {
         // Here we set min_cpgs to 2, so no reads pass the filter.
         let input = "tests/test6.bam";
 
-        let min_depth = 0;
-        let min_cpgs = 2;
-        let min_qual = 10;
-        let cpg_set = None;
+        let params = PdrParams {
+            min_depth: 0,
+            min_cpgs: 2,
+            ..Default::default()
+        };
 
-        let result = compute_helper(input, min_depth, min_cpgs, min_qual, &cpg_set);
+        let result = compute_records(input, &params).unwrap();
         assert_eq!(result.len(), 0); // No CpGs participate in the PDR calculation.
     }
+
+    #[test]
+    fn test9_threads() {
+        // Reads of test4.bam are duplicated on chr1 and chr2.
+        let input = "tests/test9.bam";
+
+        let params = PdrParams {
+            min_depth: 0,
+            min_cpgs: 0,
+            ..Default::default()
+        };
+        let threaded_params = PdrParams {
+            read_options: readutil::ReadOptions {
+                threads: 2,
+                ..Default::default()
+            },
+            ..params.clone()
+        };
+
+        let result = compute_records(input, &params).unwrap();
+        let threaded_result = compute_records(input, &threaded_params).unwrap();
+
+        assert_eq!(result.len(), 16);
+        assert_eq!(result, threaded_result);
+    }
 }
@@ -1,5 +1,17 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+/// All progress bars are drawn through a single `MultiProgress`, so that the bars of
+/// samples processed in parallel are shown on separate lines.
+fn multi_progress() -> &'static indicatif::MultiProgress {
+    static MULTI_PROGRESS: OnceLock<indicatif::MultiProgress> = OnceLock::new();
+    MULTI_PROGRESS.get_or_init(indicatif::MultiProgress::new)
+}
+
 pub struct ProgressBar {
     bar: indicatif::ProgressBar,
+    readcount: AtomicU64,
+    valid_readcount: AtomicU64,
 }
 
 impl Default for ProgressBar {
@@ -10,14 +22,18 @@ impl Default for ProgressBar {
 
 impl ProgressBar {
     pub fn new() -> Self {
-        let bar = indicatif::ProgressBar::new(1);
+        let bar = multi_progress().add(indicatif::ProgressBar::new(1));
         bar.set_style(
             indicatif::ProgressStyle::default_bar()
                 .template("{spinner} {elapsed_precise} {msg}")
                 .expect("Invalid progress bar template"),
         );
 
-        Self { bar }
+        Self {
+            bar,
+            readcount: AtomicU64::new(0),
+            valid_readcount: AtomicU64::new(0),
+        }
     }
 
     pub fn inc_length(&self, i: u64) {
@@ -41,6 +57,23 @@ impl ProgressBar {
         ));
     }
 
+    /// Adds reads processed since the last call, e.g., by one of the shards processed in parallel,
+    /// and shows the number of reads processed so far by the whole run.
+    pub fn add_reads(&self, readcount: u64, valid_readcount: u64) {
+        let readcount = self.readcount.fetch_add(readcount, Ordering::Relaxed) + readcount;
+        let valid_readcount = self
+            .valid_readcount
+            .fetch_add(valid_readcount, Ordering::Relaxed)
+            + valid_readcount;
+
+        self.inc_length(10000);
+        self.inc(10000);
+        self.set_message(format!(
+            "Processed {} reads, found {} valid reads.",
+            readcount, valid_readcount
+        ));
+    }
+
     pub fn update_lpmd(&self, progress_string: String) {
         self.inc_length(10000);
         self.inc(10000);
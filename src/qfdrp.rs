@@ -1,11 +1,14 @@
 use itertools::Itertools;
 use rand::Rng;
-use rust_htslib::bam::Read;
+use rust_htslib::bam;
 use std::collections::BTreeMap;
-use std::fs;
-use std::io::Write;
+use std::fmt;
 
-use crate::{bamutil, progressbar, readutil};
+use crate::bigwig::TrackRecord;
+use crate::error::Result;
+use crate::metric::{self, HeterogeneityMetric};
+use crate::output::OutputFormat;
+use crate::{bamutil, readutil};
 
 const MAX_READ_LEN: i32 = 201;
 
@@ -134,7 +137,7 @@ impl AssociatedReads {
         dist
     }
 
-    fn compute_qfdrp(&mut self, min_overlap: i32) -> f32 {
+    fn compute_qfdrp(&self, min_overlap: i32) -> f32 {
         let num_reads = self.get_num_reads();
 
         let mut qfdrp = 0.0;
@@ -157,110 +160,225 @@ impl AssociatedReads {
     }
 }
 
-pub fn compute(
-    input: &str,
-    output: &str,
-    min_qual: u8,
-    min_depth: usize,
-    max_depth: usize,
-    min_overlap: i32,
-    cpg_set: &Option<String>,
-) {
-    let result = compute_helper(input, min_qual, min_depth, max_depth, min_overlap, cpg_set);
-
-    let reader = bamutil::get_reader(input);
-    let header = bamutil::get_header(&reader);
-
-    let mut out = fs::OpenOptions::new()
-        .create(true)
-        .read(true)
-        .write(true)
-        .truncate(true)
-        .open(output)
-        .unwrap();
-    for (cpg, fdrp) in result.iter() {
-        let chrom = bamutil::tid2chrom(cpg.tid, &header);
-        writeln!(out, "{}\t{}\t{}\t{}", chrom, cpg.pos, cpg.pos + 2, fdrp)
-            .expect("Error writing to output file.");
+/// Header line of the output table of qFDRP, written with `--header`.
+pub const HEADER: &str = "chrom\tstart\tend\tqfdrp";
+
+/// Parameters of qFDRP calculation.
+#[derive(Clone)]
+pub struct QfdrpParams {
+    /// Minimum quality for a read to be considered.
+    pub min_qual: u8,
+    /// Minimum number of reads mapped to a CpG in order to be considered.
+    pub min_depth: usize,
+    /// Maximum number of reads to consider.
+    pub max_depth: usize,
+    /// Minimum overlap between two reads to consider in bp.
+    pub min_overlap: i32,
+    /// (Optional) Predefined set of CpGs (in BED file) to be analyzed.
+    pub cpg_set: Option<String>,
+    pub read_options: readutil::ReadOptions,
+}
+
+impl Default for QfdrpParams {
+    fn default() -> Self {
+        Self {
+            min_qual: 10,
+            min_depth: 10,
+            max_depth: 40,
+            min_overlap: 35,
+            cpg_set: None,
+            read_options: readutil::ReadOptions::default(),
+        }
     }
 }
 
-fn compute_helper(
-    input: &str,
-    min_qual: u8,
+/// qFDRP of a single CpG. Formatted as a line of the output table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QfdrpRecord {
+    pub chrom: String,
+    /// 0-based position of the cytosine (C) in CpG.
+    pub start: i32,
+    /// 0-based position + 1 of the guanine (G) in CpG.
+    pub end: i32,
+    pub qfdrp: f32,
+}
+
+impl fmt::Display for QfdrpRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}",
+            self.chrom, self.start, self.end, self.qfdrp
+        )
+    }
+}
+
+impl TrackRecord for QfdrpRecord {
+    fn interval(&self) -> (&str, i32, i32, f32) {
+        (&self.chrom, self.start, self.end, self.qfdrp)
+    }
+}
+
+/// qFDRP as a [`HeterogeneityMetric`].
+pub struct QfdrpMetric {
     min_depth: usize,
     max_depth: usize,
     min_overlap: i32,
-    cpg_set: &Option<String>,
-) -> BTreeMap<readutil::CpGPosition, f32> {
-    let mut reader = bamutil::get_reader(input);
-    let header = bamutil::get_header(&reader);
+    cpg2reads: BTreeMap<readutil::CpGPosition, AssociatedReads>,
+    result: BTreeMap<readutil::CpGPosition, f32>,
+}
 
-    let mut readcount = 0;
-    let mut valid_readcount = 0;
+impl QfdrpMetric {
+    pub fn new(params: &QfdrpParams) -> Self {
+        Self {
+            min_depth: params.min_depth,
+            max_depth: params.max_depth,
+            min_overlap: params.min_overlap,
+            cpg2reads: BTreeMap::new(),
+            result: BTreeMap::new(),
+        }
+    }
+}
 
-    let bar = progressbar::ProgressBar::new();
+impl HeterogeneityMetric for QfdrpMetric {
+    type Output = Vec<QfdrpRecord>;
 
-    let target_cpgs = &readutil::get_target_cpgs(cpg_set, &header);
+    fn observe_read(&mut self, br: &readutil::BismarkRead) {
+        if br.get_num_cpgs() == 0 {
+            return;
+        }
 
-    let mut cpg2reads: BTreeMap<readutil::CpGPosition, AssociatedReads> = BTreeMap::new();
-    let mut result: BTreeMap<readutil::CpGPosition, f32> = BTreeMap::new();
+        for cpg_position in br.get_cpg_positions().iter() {
+            let r = self
+                .cpg2reads
+                .entry(*cpg_position)
+                .or_insert(AssociatedReads::new(*cpg_position, self.max_depth));
 
-    for r in reader.records().map(|r| r.unwrap()) {
-        let mut br = readutil::BismarkRead::new(&r);
-        if let Some(target_cpgs) = target_cpgs {
-            br.filter_isin(target_cpgs);
+            r.add_read(br);
         }
+    }
 
-        readcount += 1;
-        if r.mapq() < min_qual {
-            continue;
-        }
-        if br.get_num_cpgs() == 0 {
-            continue;
-        }
+    fn flush_before(&mut self, pos: readutil::CpGPosition) {
+        let min_depth = self.min_depth;
+        let min_overlap = self.min_overlap;
+        let result = &mut self.result;
 
-        if let Some(first_cpg_position) = br.get_first_cpg_position() {
-            cpg2reads.retain(|&cpg, reads| {
-                if cpg < first_cpg_position {
-                    if reads.get_num_reads() >= min_depth {
-                        result.insert(cpg, reads.compute_qfdrp(min_overlap));
-                    }
-                    false
-                } else {
-                    true
+        self.cpg2reads.retain(|&cpg, reads| {
+            if cpg < pos {
+                if reads.get_num_reads() >= min_depth {
+                    result.insert(cpg, reads.compute_qfdrp(min_overlap));
                 }
-            }); // Finalize and compute metric for the CpGs before the first CpG in this read.
-        }
+                false
+            } else {
+                true
+            }
+        });
+    }
 
-        for cpg_position in br.get_cpg_positions().iter() {
-            let r = cpg2reads
-                .entry(*cpg_position)
-                .or_insert(AssociatedReads::new(*cpg_position, max_depth));
+    fn take_before(
+        &mut self,
+        pos: readutil::CpGPosition,
+        header: &bam::HeaderView,
+    ) -> Option<Vec<QfdrpRecord>> {
+        self.flush_before(pos);
 
-            r.add_read(&br);
-        }
+        let rest = self.result.split_off(&pos);
+        let taken = std::mem::replace(&mut self.result, rest);
+        Some(to_records(&taken, header))
+    }
 
-        valid_readcount += 1;
-        if readcount % 10000 == 0 {
-            bar.update(readcount, valid_readcount)
-        };
+    fn merge(&mut self, other: Self) {
+        debug_assert!(other
+            .cpg2reads
+            .keys()
+            .all(|key| !self.cpg2reads.contains_key(key)));
+        debug_assert!(other
+            .result
+            .keys()
+            .all(|key| !self.result.contains_key(key)));
+        self.cpg2reads.extend(other.cpg2reads);
+        self.result.extend(other.result);
     }
 
-    // Flush remaining CpGs.
-    for (cpg, reads) in cpg2reads.iter_mut() {
-        if reads.get_num_reads() >= min_depth {
-            result.insert(*cpg, reads.compute_qfdrp(min_overlap));
+    fn finalize(mut self, header: &bam::HeaderView) -> Vec<QfdrpRecord> {
+        // Flush remaining CpGs.
+        for (cpg, reads) in self.cpg2reads.iter() {
+            if reads.get_num_reads() >= self.min_depth {
+                self.result
+                    .insert(*cpg, reads.compute_qfdrp(self.min_overlap));
+            }
         }
+
+        to_records(&self.result, header)
     }
+}
 
+/// Records of the qFDRP of the CpGs in `result`.
+fn to_records(
+    result: &BTreeMap<readutil::CpGPosition, f32>,
+    header: &bam::HeaderView,
+) -> Vec<QfdrpRecord> {
     result
+        .iter()
+        .map(|(cpg, qfdrp)| QfdrpRecord {
+            chrom: bamutil::tid2chrom(cpg.tid, header),
+            start: cpg.pos,
+            end: cpg.pos + 2,
+            qfdrp: *qfdrp,
+        })
+        .collect()
+}
+
+pub fn compute(
+    input: &str,
+    output: &str,
+    format: OutputFormat,
+    header: bool,
+    params: &QfdrpParams,
+) -> Result<()> {
+    let header = header.then_some(HEADER);
+
+    if params.read_options.group_by_tag.is_some() {
+        let groups = compute_grouped_records(input, params)?;
+        return metric::write_grouped_records(input, output, format, header, &groups);
+    }
+
+    let records = compute_records(input, params)?;
+
+    metric::write_records(input, output, format, header, &records)
+}
+
+/// Computes qFDRP of the CpGs covered by the reads in `input`, sorted by position.
+pub fn compute_records(input: &str, params: &QfdrpParams) -> Result<Vec<QfdrpRecord>> {
+    metric::compute(
+        input,
+        params.min_qual,
+        &params.cpg_set,
+        &params.read_options,
+        || QfdrpMetric::new(params),
+    )
+}
+
+/// Computes qFDRP separately for each group of reads sharing the value of the aux tag given by
+/// `group_by_tag` of `read_options`, sorted by the name of the group.
+pub fn compute_grouped_records(
+    input: &str,
+    params: &QfdrpParams,
+) -> Result<Vec<(String, Vec<QfdrpRecord>)>> {
+    metric::compute_grouped(
+        input,
+        params.min_qual,
+        &params.cpg_set,
+        &params.read_options,
+        || QfdrpMetric::new(params),
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::super::bamutil;
     use super::*;
+    use rust_htslib::bam::Read;
 
     fn assert_approximately_equal(a: f32, b: f32) {
         assert!((a - b) < 1e-5);
@@ -271,12 +389,12 @@ mod tests {
         let input = "tests/test1.bam";
         let max_depth = 40;
 
-        let mut reader = bamutil::get_reader(input);
+        let mut reader = bamutil::get_reader(input).unwrap();
 
         let mut cpg2reads: BTreeMap<readutil::CpGPosition, AssociatedReads> = BTreeMap::new();
 
         for r in reader.records().map(|r| r.unwrap()) {
-            let br = readutil::BismarkRead::new(&r);
+            let br = readutil::BismarkRead::new(&r).unwrap();
 
             for cpg_position in br.get_cpg_positions().iter() {
                 let r = cpg2reads
@@ -310,12 +428,12 @@ mod tests {
         let input = "tests/test1.bam";
         let max_depth = 40;
 
-        let mut reader = bamutil::get_reader(input);
+        let mut reader = bamutil::get_reader(input).unwrap();
 
         let mut cpg2reads: BTreeMap<readutil::CpGPosition, AssociatedReads> = BTreeMap::new();
 
         for r in reader.records().map(|r| r.unwrap()) {
-            let br = readutil::BismarkRead::new(&r);
+            let br = readutil::BismarkRead::new(&r).unwrap();
 
             for cpg_position in br.get_cpg_positions().iter() {
                 let r = cpg2reads
@@ -335,12 +453,12 @@ mod tests {
         let input = "tests/test1.bam";
         let max_depth = 40;
 
-        let mut reader = bamutil::get_reader(input);
+        let mut reader = bamutil::get_reader(input).unwrap();
 
         let mut cpg2reads: BTreeMap<readutil::CpGPosition, AssociatedReads> = BTreeMap::new();
 
         for r in reader.records().map(|r| r.unwrap()) {
-            let br = readutil::BismarkRead::new(&r);
+            let br = readutil::BismarkRead::new(&r).unwrap();
 
             for cpg_position in br.get_cpg_positions().iter() {
                 let r = cpg2reads
@@ -358,83 +476,88 @@ mod tests {
     #[test]
     fn test1() {
         let input = "tests/test1.bam";
-        let min_qual = 0;
-        let min_depth = 2;
-        let max_depth = 40;
-        let min_overlap = 4;
-        let cpg_set = None;
+        let params = QfdrpParams {
+            min_qual: 0,
+            min_depth: 2,
+            min_overlap: 4,
+            ..Default::default()
+        };
 
         let cpg_positions = [0, 2, 4, 6];
 
-        let result = compute_helper(input, min_qual, min_depth, max_depth, min_overlap, &cpg_set);
-        for (i, (cpg, qfdrp)) in result.iter().enumerate() {
-            assert_eq!(cpg.pos, cpg_positions[i]);
-            assert_approximately_equal(*qfdrp, 8.0 / 15.0);
+        let result = compute_records(input, &params).unwrap();
+        for (i, record) in result.iter().enumerate() {
+            assert_eq!(record.start, cpg_positions[i]);
+            assert_approximately_equal(record.qfdrp, 8.0 / 15.0);
         }
     }
     #[test]
     fn test2() {
         let input = "tests/test2.bam";
-        let min_qual = 0;
-        let min_depth = 2;
-        let max_depth = 40;
-        let min_overlap = 4;
-        let cpg_set = None;
+        let params = QfdrpParams {
+            min_qual: 0,
+            min_depth: 2,
+            min_overlap: 4,
+            ..Default::default()
+        };
 
         let cpg_positions = [0, 2, 4, 6];
 
-        let result = compute_helper(input, min_qual, min_depth, max_depth, min_overlap, &cpg_set);
-        for (i, (cpg, qfdrp)) in result.iter().enumerate() {
-            assert_eq!(cpg.pos, cpg_positions[i]);
-            assert_eq!(*qfdrp, 8.0 / 15.0);
-            assert_approximately_equal(*qfdrp, 8.0 / 15.0);
+        let result = compute_records(input, &params).unwrap();
+        for (i, record) in result.iter().enumerate() {
+            assert_eq!(record.start, cpg_positions[i]);
+            assert_eq!(record.qfdrp, 8.0 / 15.0);
+            assert_approximately_equal(record.qfdrp, 8.0 / 15.0);
         }
     }
     #[test]
     fn test3() {
         let input = "tests/test3.bam";
-        let min_qual = 1;
-        let min_depth = 2;
-        let max_depth = 40;
-        let min_overlap = 4;
-        let cpg_set = None;
+        let params = QfdrpParams {
+            min_qual: 1,
+            min_depth: 2,
+            min_overlap: 4,
+            ..Default::default()
+        };
 
         let cpg_positions = [0, 2, 4, 6];
 
-        let result = compute_helper(input, min_qual, min_depth, max_depth, min_overlap, &cpg_set);
-        for (i, (cpg, qfdrp)) in result.iter().enumerate() {
-            assert_eq!(cpg.pos, cpg_positions[i]);
-            assert_eq!(*qfdrp, 1.0);
+        let result = compute_records(input, &params).unwrap();
+        for (i, record) in result.iter().enumerate() {
+            assert_eq!(record.start, cpg_positions[i]);
+            assert_eq!(record.qfdrp, 1.0);
         }
     }
     #[test]
     fn test4() {
         let input = "tests/test4.bam";
-        let min_qual = 1;
-        let min_depth = 2;
-        let max_depth = 40;
-        let min_overlap = 4;
-        let cpg_set = None;
+        let params = QfdrpParams {
+            min_qual: 1,
+            min_depth: 2,
+            min_overlap: 4,
+            ..Default::default()
+        };
 
         let cpg_positions = [0, 2, 4, 6, 13, 15, 17, 19];
 
-        let result = compute_helper(input, min_qual, min_depth, max_depth, min_overlap, &cpg_set);
-        for (i, (cpg, qfdrp)) in result.iter().enumerate() {
-            assert_eq!(cpg.pos, cpg_positions[i]);
-            assert_eq!(*qfdrp, 8.0 / 15.0);
+        let result = compute_records(input, &params).unwrap();
+        for (i, record) in result.iter().enumerate() {
+            assert_eq!(record.start, cpg_positions[i]);
+            assert_eq!(record.qfdrp, 8.0 / 15.0);
         }
     }
     #[test]
     fn test5() {
         // No reads pass quality cutoff.
         let input = "tests/test5.bam";
-        let min_qual = 1;
-        let min_depth = 2;
-        let max_depth = 40;
-        let min_overlap = 4;
-        let cpg_set = None;
+        let params = QfdrpParams {
+            min_qual: 1,
+            min_depth: 2,
+            min_overlap: 4,
+            ..Default::default()
+        };
 
-        let result = compute_helper(input, min_qual, min_depth, max_depth, min_overlap, &cpg_set);
+        let result = compute_records(input, &params).unwrap();
         assert_eq!(result.len(), 0);
     }
 }
@@ -1,54 +1,471 @@
+use clap::{Args, ValueEnum};
+use rust_htslib::faidx;
 use rust_htslib::{
     bam,
     bam::ext::BamRecordExtensions,
     bam::record::{Aux, Record},
 };
 use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt;
 use std::fs;
 
-use crate::bamutil;
+use crate::error::{Error, Result};
+use crate::{bamutil, filter};
 
 pub type QuartetPattern = usize;
 
+/// Source of per-CpG methylation calls.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CallerKind {
+    /// Bismark XM tag, falling back to MM/ML tags for reads without it.
+    Auto,
+    /// Bismark XM tag.
+    Bismark,
+    /// SAM MM/ML base modification tags.
+    Modbam,
+    /// Read sequence compared against the reference genome (bwa-meth, Biscuit, gemBS).
+    Reference,
+}
+
+/// Policy for a CpG called differently by the two mates of a fragment.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MateConflict {
+    /// Discard the CpG from the fragment.
+    Discard,
+    /// Keep the call of the first mate (read 1).
+    First,
+    /// Keep the call of the second mate (read 2).
+    Second,
+}
+
+/// Options controlling which alignment records are read and
+/// how methylation calls are extracted from them.
+#[derive(Args, Clone, Debug)]
+pub struct ReadOptions {
+    /// Source of methylation calls for each read.
+    #[clap(long, value_enum, default_value_t = CallerKind::Auto, display_order = 20)]
+    pub caller: CallerKind,
+
+    /// Path to reference genome fasta file, required for `--caller reference`.
+    #[clap(
+        long,
+        short = 'g',
+        required_if_eq("caller", "reference"),
+        display_order = 21
+    )]
+    pub genome: Option<String>,
+
+    /// Minimum probability for calling a CpG methylated (or unmethylated) from MM/ML tags.
+    /// Calls with lower confidence are regarded as ambiguous and ignored.
+    #[clap(long, default_value_t = 0.5, display_order = 22)]
+    pub mod_threshold: f32,
+
+    /// (Optional) Restrict the analysis to a region (e.g., chr1:10000-20000).
+    /// Can be specified multiple times. Requires an indexed input.
+    #[clap(long, display_order = 23)]
+    pub region: Vec<String>,
+
+    /// (Optional) Restrict the analysis to the regions in a BED file. Requires an indexed input.
+    #[clap(long, display_order = 24)]
+    pub regions: Option<String>,
+
+    /// Number of threads. Reference sequences are processed in parallel, which requires an indexed input.
+    #[clap(long, short = 't', default_value_t = 1, display_order = 25)]
+    pub threads: usize,
+
+    /// Path to reference genome fasta file used to decode CRAM input. Defaults to `--genome`.
+    #[clap(long, display_order = 26)]
+    pub reference: Option<String>,
+
+    /// Skip reads marked as PCR or optical duplicates (flag 0x400).
+    #[clap(long, display_order = 27)]
+    pub skip_duplicates: bool,
+
+    /// Skip secondary alignments (flag 0x100).
+    #[clap(long, display_order = 28)]
+    pub skip_secondary: bool,
+
+    /// Skip supplementary alignments (flag 0x800).
+    #[clap(long, display_order = 29)]
+    pub skip_supplementary: bool,
+
+    /// Skip reads failing platform/vendor quality checks (flag 0x200).
+    #[clap(long, display_order = 30)]
+    pub skip_qc_fail: bool,
+
+    /// Skip paired reads not mapped in a proper pair (flag 0x2 unset). Single-end reads are kept.
+    #[clap(long, display_order = 31)]
+    pub require_proper_pair: bool,
+
+    /// Only consider reads with all of these SAM flags set (decimal or hexadecimal, e.g., 0x40).
+    #[clap(long, default_value = "0", value_parser = filter::parse_flags, display_order = 32)]
+    pub include_flags: u16,
+
+    /// Skip reads with any of these SAM flags set (decimal or hexadecimal, e.g., 0x400).
+    #[clap(long, default_value = "0", value_parser = filter::parse_flags, display_order = 33)]
+    pub exclude_flags: u16,
+
+    /// Merge the CpG calls of overlapping mates of a read pair into a single fragment,
+    /// so that CpGs covered by both mates are counted once.
+    #[clap(long, display_order = 34)]
+    pub merge_mates: bool,
+
+    /// Policy for CpGs called differently by overlapping mates, used with `--merge-mates`.
+    #[clap(long, value_enum, default_value_t = MateConflict::Discard, display_order = 35)]
+    pub mate_conflict: MateConflict,
+
+    /// Minimum base quality for a CpG call to be considered.
+    #[clap(long, default_value_t = 0, display_order = 36)]
+    pub min_base_qual: u8,
+
+    /// Number of bases at the 5' end of single-end reads or read 1 whose CpG calls are ignored.
+    #[clap(long, default_value_t = 0, display_order = 37)]
+    pub ignore_5p: usize,
+
+    /// Number of bases at the 3' end of single-end reads or read 1 whose CpG calls are ignored.
+    #[clap(long, default_value_t = 0, display_order = 38)]
+    pub ignore_3p: usize,
+
+    /// Number of bases at the 5' end of read 2 whose CpG calls are ignored.
+    #[clap(long, default_value_t = 0, display_order = 39)]
+    pub ignore_5p_r2: usize,
+
+    /// Number of bases at the 3' end of read 2 whose CpG calls are ignored.
+    #[clap(long, default_value_t = 0, display_order = 40)]
+    pub ignore_3p_r2: usize,
+
+    /// (Optional) Compute the measure separately for each group of reads sharing the value of
+    /// this aux tag (e.g., CB for cell barcodes or RG for read groups). Reads without the tag are skipped.
+    #[clap(long, value_parser = parse_aux_tag, display_order = 41)]
+    pub group_by_tag: Option<String>,
+}
+
+/// Parses the name of an aux tag, which consists of two alphanumeric characters.
+fn parse_aux_tag(s: &str) -> std::result::Result<String, String> {
+    match s.len() == 2 && s.chars().all(|c| c.is_ascii_alphanumeric()) {
+        true => Ok(s.to_string()),
+        false => Err(format!("invalid aux tag: {}", s)),
+    }
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self {
+            caller: CallerKind::Auto,
+            genome: None,
+            mod_threshold: 0.5,
+            region: Vec::new(),
+            regions: None,
+            threads: 1,
+            reference: None,
+            skip_duplicates: false,
+            skip_secondary: false,
+            skip_supplementary: false,
+            skip_qc_fail: false,
+            require_proper_pair: false,
+            include_flags: 0,
+            exclude_flags: 0,
+            merge_mates: false,
+            mate_conflict: MateConflict::Discard,
+            min_base_qual: 0,
+            ignore_5p: 0,
+            ignore_3p: 0,
+            ignore_5p_r2: 0,
+            ignore_3p_r2: 0,
+            group_by_tag: None,
+        }
+    }
+}
+
+impl ReadOptions {
+    /// Reference genome used to decode CRAM input.
+    pub fn cram_reference(&self) -> &Option<String> {
+        match self.reference {
+            Some(_) => &self.reference,
+            None => &self.genome,
+        }
+    }
+}
+
+/// Strand of the original DNA molecule a bisulfite read was derived from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OriginalStrand {
+    /// Original top strand, where unmethylated cytosines read as C->T on the reference.
+    Top,
+    /// Original bottom strand, where unmethylated cytosines read as G->A on the reference.
+    Bottom,
+}
+
+/// Determines the original strand of a read from the conversion tags written by
+/// bisulfite aligners: `YD` (bwa-meth, Biscuit), `XB` (gemBS), `ZS` (BSMAP) and `XG` (Bismark).
+/// Falls back to the alignment flags of a directional library if none of them is present.
+pub fn get_original_strand(r: &Record) -> OriginalStrand {
+    let strand = match r.aux(b"YD") {
+        Ok(Aux::String("f")) | Ok(Aux::Char(b'f')) => Some(OriginalStrand::Top),
+        Ok(Aux::String("r")) | Ok(Aux::Char(b'r')) => Some(OriginalStrand::Bottom),
+        _ => None,
+    }
+    .or_else(|| match r.aux(b"XB") {
+        Ok(Aux::String("C")) | Ok(Aux::Char(b'C')) => Some(OriginalStrand::Top),
+        Ok(Aux::String("G")) | Ok(Aux::Char(b'G')) => Some(OriginalStrand::Bottom),
+        _ => None,
+    })
+    .or_else(|| match r.aux(b"ZS") {
+        Ok(Aux::String(zs)) if zs.starts_with('+') => Some(OriginalStrand::Top),
+        Ok(Aux::String(zs)) if zs.starts_with('-') => Some(OriginalStrand::Bottom),
+        _ => None,
+    })
+    .or_else(|| match r.aux(b"XG") {
+        Ok(Aux::String("CT")) => Some(OriginalStrand::Top),
+        Ok(Aux::String("GA")) => Some(OriginalStrand::Bottom),
+        _ => None,
+    });
+
+    match strand {
+        Some(strand) => strand,
+        None => {
+            let is_top = match r.is_paired() {
+                true => {
+                    (!r.is_reverse() && r.is_first_in_template())
+                        || (r.is_reverse() && r.is_last_in_template())
+                }
+                false => !r.is_reverse(),
+            };
+            match is_top {
+                true => OriginalStrand::Top,
+                false => OriginalStrand::Bottom,
+            }
+        }
+    }
+}
+
+/// Converts alignment records into `BismarkRead`s according to `ReadOptions`.
+pub struct MethylationCaller {
+    kind: CallerKind,
+    mod_threshold: f32,
+    genome: Option<faidx::Reader>,
+    genome_path: String,
+    contig_names: Vec<String>,
+    // Reference sequence of the contig the last read was aligned to.
+    contig: Option<(i32, Vec<u8>)>,
+    min_base_qual: u8,
+    // Number of bases ignored at the (5', 3') ends of read 1 and read 2.
+    ignore_r1: (usize, usize),
+    ignore_r2: (usize, usize),
+}
+
+impl MethylationCaller {
+    pub fn new(options: &ReadOptions, header: &bam::HeaderView) -> Result<Self> {
+        let genome = match &options.genome {
+            Some(genome) => {
+                Some(
+                    faidx::Reader::from_path(genome).map_err(|source| Error::Reference {
+                        path: genome.to_string(),
+                        source,
+                    })?,
+                )
+            }
+            None => None,
+        };
+        let contig_names = (0..header.target_count() as i32)
+            .map(|tid| bamutil::tid2chrom(tid, header))
+            .collect();
+
+        Ok(Self {
+            kind: options.caller,
+            mod_threshold: options.mod_threshold,
+            genome,
+            genome_path: options.genome.clone().unwrap_or_default(),
+            contig_names,
+            contig: None,
+            min_base_qual: options.min_base_qual,
+            ignore_r1: (options.ignore_5p, options.ignore_3p),
+            ignore_r2: (options.ignore_5p_r2, options.ignore_3p_r2),
+        })
+    }
+
+    /// Calls the methylation states of CpGs in `r`. Calls with base quality lower than
+    /// `min_base_qual` or within the ignored bases at the ends of the read are dropped.
+    pub fn call(&mut self, r: &Record) -> Result<BismarkRead> {
+        let mut br = self.call_all(r)?;
+
+        let (ignore_5p, ignore_3p) = match br.is_second {
+            true => self.ignore_r2,
+            false => self.ignore_r1,
+        };
+        if self.min_base_qual > 0 || ignore_5p > 0 || ignore_3p > 0 {
+            let qual = r.qual();
+            let read_len = r.seq_len() as i32;
+            br.cpgs.retain(|cpg| {
+                cpg.cycle >= ignore_5p as i32
+                    && cpg.cycle < read_len - ignore_3p as i32
+                    && qual.get(cpg.relpos as usize).copied().unwrap_or(u8::MAX)
+                        >= self.min_base_qual
+            });
+        }
+
+        Ok(br)
+    }
+
+    fn call_all(&mut self, r: &Record) -> Result<BismarkRead> {
+        match self.kind {
+            CallerKind::Auto => {
+                if r.aux(b"XM").is_err() && r.aux(b"MM").is_ok() {
+                    BismarkRead::from_mm_ml(r, self.mod_threshold)
+                } else {
+                    BismarkRead::new(r)
+                }
+            }
+            CallerKind::Bismark => BismarkRead::new(r),
+            CallerKind::Modbam => BismarkRead::from_mm_ml(r, self.mod_threshold),
+            CallerKind::Reference => {
+                let strand = get_original_strand(r);
+                Ok(BismarkRead::from_reference(
+                    r,
+                    self.get_contig(r.tid())?,
+                    strand,
+                ))
+            }
+        }
+    }
+
+    fn get_contig(&mut self, tid: i32) -> Result<&[u8]> {
+        if !matches!(self.contig, Some((cached, _)) if cached == tid) {
+            // Release the previous contig before loading the next one.
+            self.contig = None;
+
+            let genome = self.genome.as_ref().ok_or(Error::MissingReference)?;
+            let name = usize::try_from(tid)
+                .ok()
+                .and_then(|tid| self.contig_names.get(tid))
+                .ok_or_else(|| {
+                    Error::HeaderMismatch(format!("No reference sequence with id {}", tid))
+                })?;
+            let seq = genome
+                .fetch_seq(name, 0, genome.fetch_seq_len(name) as usize)
+                .map_err(|source| Error::Reference {
+                    path: self.genome_path.clone(),
+                    source,
+                })?
+                .to_ascii_uppercase();
+
+            self.contig = Some((tid, seq));
+        }
+
+        match &self.contig {
+            Some((_, seq)) => Ok(seq),
+            None => unreachable!(),
+        }
+    }
+}
+
 pub struct BismarkRead {
     start_pos: i32,
     end_pos: i32,
+    // Whether the read is the second mate of a read pair.
+    is_second: bool,
     // Read is defined as an array of CpG methylation states
     // and their relative/absolute positions.
     cpgs: Vec<CpG>,
 }
 
 impl BismarkRead {
-    pub fn new(r: &Record) -> Self {
-        let mut start_pos = -1;
-        let mut end_pos = -1;
+    pub fn new(r: &Record) -> Result<Self> {
+        let (start_pos, end_pos) = get_aligned_span(r);
 
-        for abspos in r.reference_positions_full().flatten() {
-            if start_pos == -1 {
-                start_pos = abspos as i32;
+        match r.aux(b"XM") {
+            Ok(Aux::String(xm)) => {
+                let cpgs = get_cpgs(r, xm);
+                Ok(Self {
+                    start_pos,
+                    end_pos,
+                    is_second: is_second_mate(r),
+                    cpgs,
+                })
             }
-            end_pos = abspos as i32;
+            _ => Err(Error::MissingXmTag(get_qname(r))),
         }
+    }
 
-        match r.aux(b"XM") {
-            Ok(value) => {
-                if let Aux::String(xm) = value {
-                    // if value is a type of Aux::String, run:
-                    let cpgs = get_cpgs(r, xm);
-                    Self {
-                        start_pos,
-                        end_pos,
-                        cpgs,
-                    }
-                } else {
-                    panic!("Error reading XM tag in BAM record. Make sure the reads are aligned using Bismark!");
+    /// Builds a read from SAM `MM`/`ML` base modification tags (e.g., nanopore or PacBio 5mC calls).
+    /// A CpG is called methylated if its modification probability is at least `threshold`,
+    /// unmethylated if its probability of being unmodified is at least `threshold`,
+    /// and ignored otherwise.
+    pub fn from_mm_ml(r: &Record, threshold: f32) -> Result<Self> {
+        let (start_pos, end_pos) = get_aligned_span(r);
+
+        let mm = match r.aux(b"MM").or_else(|_| r.aux(b"Mm")) {
+            Ok(Aux::String(mm)) => mm,
+            _ => return Err(Error::MissingModificationTags(get_qname(r))),
+        };
+        let ml: Vec<u8> = match r.aux(b"ML").or_else(|_| r.aux(b"Ml")) {
+            Ok(Aux::ArrayU8(ml)) => ml.iter().collect(),
+            _ => return Err(Error::MissingModificationTags(get_qname(r))),
+        };
+
+        let cpgs = get_cpgs_from_mm_ml(r, mm, &ml, threshold);
+        Ok(Self {
+            start_pos,
+            end_pos,
+            is_second: is_second_mate(r),
+            cpgs,
+        })
+    }
+
+    /// Builds a read by comparing its sequence against the reference sequence of the contig
+    /// it is aligned to, as required for aligners that do not write an XM tag (e.g., bwa-meth).
+    pub fn from_reference(r: &Record, refseq: &[u8], strand: OriginalStrand) -> Self {
+        let (start_pos, end_pos) = get_aligned_span(r);
+        let cpgs = get_cpgs_from_reference(r, refseq, strand);
+
+        Self {
+            start_pos,
+            end_pos,
+            is_second: is_second_mate(r),
+            cpgs,
+        }
+    }
+
+    /// Merges the mates of a read pair into a single fragment spanning both mates.
+    /// CpGs covered by both mates are kept once, and CpGs called differently by the mates are
+    /// resolved by `conflict`. Relative positions of CpGs are taken from the start of the fragment.
+    pub fn merge_mates(first: Self, second: Self, conflict: MateConflict) -> Self {
+        let start_pos = first.start_pos.min(second.start_pos);
+        let end_pos = first.end_pos.max(second.end_pos);
+
+        let mut calls: BTreeMap<CpGPosition, Option<CpG>> = BTreeMap::new();
+        for cpg in first.cpgs.iter() {
+            calls.insert(cpg.abspos, Some(*cpg));
+        }
+        for cpg in second.cpgs.iter() {
+            let call = calls.entry(cpg.abspos).or_insert(Some(*cpg));
+            if let Some(first_cpg) = *call {
+                if first_cpg.methylated != cpg.methylated {
+                    *call = match conflict {
+                        MateConflict::Discard => None,
+                        MateConflict::First => Some(first_cpg),
+                        MateConflict::Second => Some(*cpg),
+                    };
                 }
             }
-            Err(_) => {
-                panic!("Error reading XM tag in BAM record. Make sure the reads are aligned using Bismark!");
-            }
+        }
+
+        let cpgs = calls
+            .into_values()
+            .flatten()
+            .map(|cpg| CpG {
+                relpos: cpg.abspos.pos - start_pos,
+                ..cpg
+            })
+            .collect();
+
+        Self {
+            start_pos,
+            end_pos,
+            is_second: false,
+            cpgs,
         }
     }
 
@@ -59,6 +476,11 @@ impl BismarkRead {
         }
     }
 
+    /// Whether the read is the second mate of a read pair. Fragments of merged mates are not.
+    pub fn is_second_mate(&self) -> bool {
+        self.is_second
+    }
+
     pub fn get_start_pos(&self) -> i32 {
         self.start_pos
     }
@@ -84,6 +506,26 @@ impl BismarkRead {
         s
     }
 
+    pub fn filter_in_regions(&mut self, regions: &[bamutil::Region]) {
+        self.cpgs
+            .retain(|cpg| bamutil::in_regions(regions, cpg.abspos.tid, cpg.abspos.pos));
+    }
+
+    /// Copy of the read with only the CpGs in `region`.
+    pub fn in_region(&self, region: &bamutil::Region) -> Self {
+        Self {
+            start_pos: self.start_pos,
+            end_pos: self.end_pos,
+            is_second: self.is_second,
+            cpgs: self
+                .cpgs
+                .iter()
+                .filter(|cpg| region.contains(cpg.abspos.tid, cpg.abspos.pos))
+                .copied()
+                .collect(),
+        }
+    }
+
     pub fn filter_isin(&mut self, target_cpgs: &HashSet<CpGPosition>) {
         let mut new_cpgs: Vec<CpG> = Vec::new();
 
@@ -246,14 +688,17 @@ pub enum ReadConcordanceState {
 #[derive(Copy)]
 pub struct CpG {
     pub relpos: i32,
+    /// 0-based position of the call from the 5' end of the sequenced read.
+    pub cycle: i32,
     pub abspos: CpGPosition,
     pub methylated: bool,
 }
 
 impl CpG {
-    fn new(relpos: i32, abspos: CpGPosition, c: char) -> Self {
+    fn new(r: &Record, relpos: usize, abspos: CpGPosition, c: char) -> Self {
         Self {
-            relpos,
+            relpos: relpos as i32,
+            cycle: get_read_cycle(r, relpos),
             abspos,
             methylated: c == 'Z',
         }
@@ -276,7 +721,7 @@ impl fmt::Display for CpG {
     }
 }
 
-#[derive(Eq, PartialEq, Hash, Copy)]
+#[derive(Eq, PartialEq, Hash, Copy, Debug)]
 pub struct CpGPosition {
     pub tid: i32,
     pub pos: i32,
@@ -323,20 +768,217 @@ impl Clone for CpGPosition {
 fn get_cpgs(r: &Record, xm: &str) -> Vec<CpG> {
     let mut cpgs: Vec<CpG> = Vec::new();
 
+    // Calls from the original bottom strand are made at the G of the CpG.
+    let offset = match get_original_strand(r) {
+        OriginalStrand::Top => 0,
+        OriginalStrand::Bottom => 1,
+    };
+
     for (relpos, (abspos, c)) in r.reference_positions_full().zip(xm.chars()).enumerate() {
         if (c != 'z') && (c != 'Z') {
             continue;
         }
 
         if let Some(abspos) = abspos {
-            if (r.flags() == 0) || (r.flags() == 99) || (r.flags() == 147) {
-                // Forward
-                let cpgpos = CpGPosition::new(r.tid(), abspos as i32);
-                cpgs.push(CpG::new(relpos as i32, cpgpos, c));
-            } else {
-                // Reverse
-                let cpgpos = CpGPosition::new(r.tid(), (abspos - 1) as i32);
-                cpgs.push(CpG::new(relpos as i32, cpgpos, c));
+            let cpgpos = CpGPosition::new(r.tid(), abspos as i32 - offset);
+            cpgs.push(CpG::new(r, relpos, cpgpos, c));
+        }
+    }
+
+    cpgs
+}
+
+/// Position of the base at `qpos` of the stored sequence from the 5' end of the sequenced read,
+/// which is the other end of the stored sequence for reads aligned to the reverse strand.
+fn get_read_cycle(r: &Record, qpos: usize) -> i32 {
+    match r.is_reverse() {
+        true => (r.seq_len() - 1 - qpos) as i32,
+        false => qpos as i32,
+    }
+}
+
+fn is_second_mate(r: &Record) -> bool {
+    r.is_paired() && r.is_last_in_template()
+}
+
+fn get_qname(r: &Record) -> String {
+    String::from_utf8_lossy(r.qname()).into_owned()
+}
+
+fn get_aligned_span(r: &Record) -> (i32, i32) {
+    let mut start_pos = -1;
+    let mut end_pos = -1;
+
+    for abspos in r.reference_positions_full().flatten() {
+        if start_pos == -1 {
+            start_pos = abspos as i32;
+        }
+        end_pos = abspos as i32;
+    }
+
+    (start_pos, end_pos)
+}
+
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        _ => b'N',
+    }
+}
+
+fn get_cpgs_from_mm_ml(r: &Record, mm: &str, ml: &[u8], threshold: f32) -> Vec<CpG> {
+    // MM/ML tags describe modifications with respect to the originally sequenced strand,
+    // so the stored sequence of a reverse-mapped read is read backwards and complemented.
+    let stored_seq = r.seq().as_bytes().to_ascii_uppercase();
+    let n = stored_seq.len();
+    let is_reverse = r.is_reverse();
+    let original_base = |k: usize| match is_reverse {
+        true => complement(stored_seq[n - 1 - k]),
+        false => stored_seq[k],
+    };
+
+    let c_indices: Vec<usize> = (0..n).filter(|&k| original_base(k) == b'C').collect();
+
+    // Probability of 5mC (or 5hmC, as bisulfite conversion cannot tell them apart)
+    // for each cytosine in the original read, indexed as in `c_indices`.
+    let mut mod_probs: Vec<Option<f32>> = vec![None; c_indices.len()];
+
+    let mut ml_offset = 0;
+    for entry in mm.split(';').filter(|entry| !entry.is_empty()) {
+        let mut tokens = entry.split(',');
+        let spec = tokens.next().unwrap_or("").as_bytes();
+        let skips: Vec<usize> = tokens.filter_map(|t| t.parse::<usize>().ok()).collect();
+
+        if spec.len() < 3 {
+            continue;
+        }
+        let (base, strand) = (spec[0], spec[1]);
+        let (codes, implicit) = match spec[spec.len() - 1] {
+            b'?' => (&spec[2..spec.len() - 1], false),
+            b'.' => (&spec[2..spec.len() - 1], true),
+            _ => (&spec[2..], true),
+        };
+        // Modification codes are either single letters (possibly several of them)
+        // or a single ChEBI identifier.
+        let codes: Vec<&[u8]> = match codes.iter().all(|c| c.is_ascii_digit()) {
+            true => vec![codes],
+            false => codes.chunks(1).collect(),
+        };
+        let n_codes = codes.len();
+
+        if base != b'C' || strand != b'+' {
+            ml_offset += skips.len() * n_codes;
+            continue;
+        }
+
+        let is_cytosine_methylation: Vec<bool> = codes
+            .iter()
+            .map(|code| matches!(*code, b"m" | b"h" | b"27551" | b"76792"))
+            .collect();
+        if !is_cytosine_methylation.iter().any(|&x| x) {
+            ml_offset += skips.len() * n_codes;
+            continue;
+        }
+
+        // In implicit mode, cytosines that are not listed are regarded as unmodified.
+        if implicit {
+            for prob in mod_probs.iter_mut() {
+                prob.get_or_insert(0.0);
+            }
+        }
+
+        let mut c_idx = 0;
+        for skip in skips {
+            c_idx += skip;
+            for is_target in is_cytosine_methylation.iter() {
+                if *is_target && c_idx < mod_probs.len() && ml_offset < ml.len() {
+                    // ML values encode probabilities in 256 equally sized bins.
+                    let p = (ml[ml_offset] as f32 + 0.5) / 256.0;
+                    let prob = mod_probs[c_idx].get_or_insert(0.0);
+                    *prob = (*prob + p).min(1.0);
+                }
+                ml_offset += 1;
+            }
+            c_idx += 1;
+        }
+    }
+
+    let ref_positions: Vec<Option<i64>> = r.reference_positions_full().collect();
+
+    let mut cpgs: Vec<CpG> = Vec::new();
+    for (&k, prob) in c_indices.iter().zip(mod_probs.iter()) {
+        let prob = match prob {
+            Some(prob) => *prob,
+            None => continue,
+        };
+        if k + 1 >= n || original_base(k + 1) != b'G' {
+            continue;
+        } // Only cytosines in CpG context of the read are considered.
+
+        let c = if prob >= threshold {
+            'Z'
+        } else if 1.0 - prob >= threshold {
+            'z'
+        } else {
+            continue;
+        }; // Ambiguous calls are ignored.
+
+        let relpos = match is_reverse {
+            true => n - 1 - k,
+            false => k,
+        };
+        if let Some(abspos) = ref_positions[relpos] {
+            let abspos = match is_reverse {
+                true => abspos - 1,
+                false => abspos,
+            };
+            let cpgpos = CpGPosition::new(r.tid(), abspos as i32);
+            cpgs.push(CpG::new(r, relpos, cpgpos, c));
+        }
+    }
+    cpgs.sort_by_key(|cpg| cpg.relpos);
+
+    cpgs
+}
+
+fn get_cpgs_from_reference(r: &Record, refseq: &[u8], strand: OriginalStrand) -> Vec<CpG> {
+    let read_seq = r.seq().as_bytes().to_ascii_uppercase();
+    let mut cpgs: Vec<CpG> = Vec::new();
+
+    for [qpos, rpos] in r.aligned_pairs() {
+        let (qpos, rpos) = (qpos as usize, rpos as usize);
+        let base = read_seq[qpos];
+
+        match strand {
+            OriginalStrand::Top => {
+                // Cytosine of a CpG on the top strand; unmethylated C is converted to T.
+                if refseq.get(rpos) != Some(&b'C') || refseq.get(rpos + 1) != Some(&b'G') {
+                    continue;
+                }
+                let c = match base {
+                    b'C' => 'Z',
+                    b'T' => 'z',
+                    _ => continue,
+                };
+                let cpgpos = CpGPosition::new(r.tid(), rpos as i32);
+                cpgs.push(CpG::new(r, qpos, cpgpos, c));
+            }
+            OriginalStrand::Bottom => {
+                // Cytosine of a CpG on the bottom strand, which pairs with the G of a top-strand CpG.
+                // Unmethylated C is converted to T, hence read as G->A on the top strand.
+                if rpos == 0 || refseq.get(rpos) != Some(&b'G') || refseq[rpos - 1] != b'C' {
+                    continue;
+                }
+                let c = match base {
+                    b'G' => 'Z',
+                    b'A' => 'z',
+                    _ => continue,
+                };
+                let cpgpos = CpGPosition::new(r.tid(), (rpos - 1) as i32);
+                cpgs.push(CpG::new(r, qpos, cpgpos, c));
             }
         }
     }
@@ -347,29 +989,40 @@ fn get_cpgs(r: &Record, xm: &str) -> Vec<CpG> {
 pub fn get_target_cpgs(
     cpg_set: &Option<String>,
     header: &bam::HeaderView,
-) -> Option<HashSet<CpGPosition>> {
+) -> Result<Option<HashSet<CpGPosition>>> {
     match cpg_set {
         Some(cpg_set) => {
             eprint!("Processing target CpG set... ");
             let mut target_cpgs: HashSet<CpGPosition> = HashSet::new();
 
-            let contents = fs::read_to_string(cpg_set).expect("Could not read target CpG file.");
+            let contents = fs::read_to_string(cpg_set).map_err(|source| Error::Io {
+                path: cpg_set.to_string(),
+                source,
+            })?;
 
             for line in contents.lines() {
                 let tokens: Vec<&str> = line.split("\t").collect();
 
                 let chrom = tokens[0];
-                let pos = tokens[1].parse::<i32>().unwrap();
+                let pos = match tokens.get(1).map(|pos| pos.parse::<i32>()) {
+                    Some(Ok(pos)) => pos,
+                    _ => {
+                        return Err(Error::MalformedBed {
+                            path: cpg_set.to_string(),
+                            line: line.to_string(),
+                        })
+                    }
+                };
 
                 target_cpgs.insert(CpGPosition {
-                    tid: bamutil::chrom2tid(chrom.as_bytes(), header) as i32,
+                    tid: bamutil::chrom2tid(chrom.as_bytes(), header)? as i32,
                     pos,
                 });
             }
 
-            Some(target_cpgs)
+            Ok(Some(target_cpgs))
         }
-        None => None,
+        None => Ok(None),
     }
 }
 
@@ -382,20 +1035,20 @@ mod tests {
     #[test]
     fn test_bismarkread_constructor() {
         let input = "tests/test1.bam";
-        let mut reader = bamutil::get_reader(input);
+        let mut reader = bamutil::get_reader(input).unwrap();
         for r in reader.records() {
             let r = r.unwrap();
-            let _br = BismarkRead::new(&r);
+            let _br = BismarkRead::new(&r).unwrap();
         }
     }
 
     #[test]
     fn test_bismarkread_get_concordance_state() {
         let input = "tests/test1.bam";
-        let mut reader = bamutil::get_reader(input);
+        let mut reader = bamutil::get_reader(input).unwrap();
         for r in reader.records() {
             let r = r.unwrap();
-            let br = BismarkRead::new(&r);
+            let br = BismarkRead::new(&r).unwrap();
 
             br.get_concordance_state();
         }
@@ -407,11 +1060,11 @@ mod tests {
         let max_distance = 16;
 
         let input = "tests/test1.bam";
-        let mut reader = bamutil::get_reader(input);
+        let mut reader = bamutil::get_reader(input).unwrap();
         for r in reader.records() {
             let r = r.unwrap();
 
-            let br = BismarkRead::new(&r);
+            let br = BismarkRead::new(&r).unwrap();
 
             br.compute_pairwise_cpg_concordance_discordance(min_distance, max_distance);
         }
@@ -420,13 +1073,13 @@ mod tests {
     #[test]
     fn test_test1_pdr() {
         let input = "tests/test1.bam";
-        let mut reader = bamutil::get_reader(input);
+        let mut reader = bamutil::get_reader(input).unwrap();
         let mut n_read = 0;
         let mut n_discordant_read = 0;
         for r in reader.records() {
             let r = r.unwrap();
 
-            let br = BismarkRead::new(&r);
+            let br = BismarkRead::new(&r).unwrap();
 
             n_read += 1;
             match br.get_concordance_state() {
@@ -439,6 +1092,267 @@ mod tests {
         assert_eq!(n_discordant_read, 14);
     }
 
+    fn test_header() -> bam::HeaderView {
+        let mut header = bam::Header::new();
+        let mut sq = bam::header::HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", "chr1");
+        sq.push_tag(b"LN", 248956422);
+        header.push_record(&sq);
+
+        bam::HeaderView::from_header(&header)
+    }
+
+    fn record_from_sam(sam: &str) -> Record {
+        Record::from_sam(&test_header(), sam.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_mm_ml_forward_read() {
+        let r = record_from_sam(
+            "read\t0\tchr1\t1\t40\t8M\t*\t0\t0\tCGCGCGCG\tJJJJJJJJ\tMM:Z:C+m?,0,0,0,0;\tML:B:C,255,0,200,10",
+        );
+        let br = BismarkRead::from_mm_ml(&r, 0.5).unwrap();
+
+        let positions: Vec<i32> = br.get_cpgs().iter().map(|cpg| cpg.abspos.pos).collect();
+        let methylated: Vec<bool> = br.get_cpgs().iter().map(|cpg| cpg.methylated).collect();
+        assert_eq!(positions, vec![0, 2, 4, 6]);
+        assert_eq!(methylated, vec![true, false, true, false]);
+    }
+
+    #[test]
+    fn test_mm_ml_reverse_read() {
+        // Original read is the reverse complement of the stored sequence,
+        // so the first MM/ML call refers to the last CpG on the reference.
+        let r = record_from_sam(
+            "read\t16\tchr1\t1\t40\t8M\t*\t0\t0\tCGCGCGCG\tJJJJJJJJ\tMM:Z:C+m?,0,0,0,0;\tML:B:C,255,0,0,0",
+        );
+        let br = BismarkRead::from_mm_ml(&r, 0.5).unwrap();
+
+        let positions: Vec<i32> = br.get_cpgs().iter().map(|cpg| cpg.abspos.pos).collect();
+        let methylated: Vec<bool> = br.get_cpgs().iter().map(|cpg| cpg.methylated).collect();
+        assert_eq!(positions, vec![0, 2, 4, 6]);
+        assert_eq!(methylated, vec![false, false, false, true]);
+    }
+
+    #[test]
+    fn test_mm_ml_ambiguous_calls_are_ignored() {
+        let r = record_from_sam(
+            "read\t0\tchr1\t1\t40\t8M\t*\t0\t0\tCGCGCGCG\tJJJJJJJJ\tMM:Z:C+m?,0,0,0,0;\tML:B:C,250,128,100,5",
+        );
+        let br = BismarkRead::from_mm_ml(&r, 0.9).unwrap();
+
+        let positions: Vec<i32> = br.get_cpgs().iter().map(|cpg| cpg.abspos.pos).collect();
+        assert_eq!(positions, vec![0, 6]);
+    }
+
+    #[test]
+    fn test_mm_ml_implicit_mode_and_skips() {
+        // Implicit mode: cytosines skipped over are regarded as unmethylated.
+        let r = record_from_sam(
+            "read\t0\tchr1\t1\t40\t8M\t*\t0\t0\tCGCGCGCG\tJJJJJJJJ\tMM:Z:C+m,1,1;\tML:B:C,255,255",
+        );
+        let br = BismarkRead::from_mm_ml(&r, 0.5).unwrap();
+
+        let methylated: Vec<bool> = br.get_cpgs().iter().map(|cpg| cpg.methylated).collect();
+        assert_eq!(methylated, vec![false, true, false, true]);
+    }
+
+    #[test]
+    fn test_missing_methylation_tags() {
+        let r = record_from_sam("read\t0\tchr1\t1\t40\t8M\t*\t0\t0\tCGCGCGCG\tJJJJJJJJ");
+
+        assert!(matches!(
+            BismarkRead::new(&r),
+            Err(Error::MissingXmTag(qname)) if qname == "read"
+        ));
+        assert!(matches!(
+            BismarkRead::from_mm_ml(&r, 0.5),
+            Err(Error::MissingModificationTags(_))
+        ));
+
+        let mut caller = MethylationCaller::new(&ReadOptions::default(), &test_header()).unwrap();
+        assert!(matches!(caller.call(&r), Err(Error::MissingXmTag(_))));
+    }
+
+    #[test]
+    fn test_methylation_caller_prefers_xm() {
+        let mut caller = MethylationCaller::new(&ReadOptions::default(), &test_header()).unwrap();
+
+        let r =
+            record_from_sam("read\t0\tchr1\t1\t40\t8M\t*\t0\t0\tTGTGTGCG\tJJJJJJJJ\tXM:Z:z.z.z.Z.");
+        assert_eq!(caller.call(&r).unwrap().get_num_cpgs(), 4);
+
+        let r = record_from_sam(
+            "read\t0\tchr1\t1\t40\t8M\t*\t0\t0\tCGCGCGCG\tJJJJJJJJ\tMM:Z:C+m?,2;\tML:B:C,255",
+        );
+        assert_eq!(caller.call(&r).unwrap().get_num_cpgs(), 1);
+    }
+
+    #[test]
+    fn test_methylation_caller_drops_low_quality_and_trimmed_calls() {
+        // Base qualities: '+' is 10, 'J' is 41.
+        let r =
+            record_from_sam("read\t0\tchr1\t1\t40\t8M\t*\t0\t0\tTGTGTGCG\tJJ+JJJJJ\tXM:Z:z.z.z.Z.");
+        let positions = |options: &ReadOptions| -> Vec<i32> {
+            let mut caller = MethylationCaller::new(options, &test_header()).unwrap();
+            let br = caller.call(&r).unwrap();
+            br.get_cpgs().iter().map(|cpg| cpg.abspos.pos).collect()
+        };
+
+        assert_eq!(positions(&ReadOptions::default()), vec![0, 2, 4, 6]);
+        let options = ReadOptions {
+            min_base_qual: 20,
+            ..Default::default()
+        };
+        assert_eq!(positions(&options), vec![0, 4, 6]);
+        let options = ReadOptions {
+            ignore_5p: 1,
+            ignore_3p: 2,
+            ..Default::default()
+        };
+        assert_eq!(positions(&options), vec![2, 4]);
+        // Options for read 2 do not apply to single-end reads.
+        let options = ReadOptions {
+            ignore_5p_r2: 4,
+            ..Default::default()
+        };
+        assert_eq!(positions(&options), vec![0, 2, 4, 6]);
+    }
+
+    #[test]
+    fn test_read_cycle_of_reverse_read() {
+        let r = record_from_sam(
+            "read\t16\tchr1\t2\t40\t2S6M\t*\t0\t0\tTGTGTGTG\tJJJJJJJJ\tXM:Z:..z.z.Z.",
+        );
+        let br = BismarkRead::new(&r).unwrap();
+
+        let cycles: Vec<i32> = br.get_cpgs().iter().map(|cpg| cpg.cycle).collect();
+        assert_eq!(cycles, vec![5, 3, 1]);
+    }
+
+    #[test]
+    fn test_from_reference_top_strand() {
+        // bwa-meth read from the original top strand: C->T conversion at unmethylated CpGs.
+        let refseq = b"ACGTTCGAACGGTACG";
+        let r = record_from_sam(
+            "read\t0\tchr1\t1\t40\t16M\t*\t0\t0\tATGTTCGAATGGTACG\tJJJJJJJJJJJJJJJJ\tYD:Z:f",
+        );
+        let br = BismarkRead::from_reference(&r, refseq, get_original_strand(&r));
+
+        let positions: Vec<i32> = br.get_cpgs().iter().map(|cpg| cpg.abspos.pos).collect();
+        let methylated: Vec<bool> = br.get_cpgs().iter().map(|cpg| cpg.methylated).collect();
+        assert_eq!(positions, vec![1, 5, 9, 14]);
+        assert_eq!(methylated, vec![false, true, false, true]);
+    }
+
+    #[test]
+    fn test_from_reference_bottom_strand() {
+        // bwa-meth read from the original bottom strand: G->A conversion at unmethylated CpGs.
+        let refseq = b"ACGTTCGAACGGTACG";
+        let r = record_from_sam(
+            "read\t16\tchr1\t1\t40\t16M\t*\t0\t0\tACATTCGAACAGTACG\tJJJJJJJJJJJJJJJJ\tYD:Z:r",
+        );
+        let br = BismarkRead::from_reference(&r, refseq, get_original_strand(&r));
+
+        let positions: Vec<i32> = br.get_cpgs().iter().map(|cpg| cpg.abspos.pos).collect();
+        let methylated: Vec<bool> = br.get_cpgs().iter().map(|cpg| cpg.methylated).collect();
+        assert_eq!(positions, vec![1, 5, 9, 14]);
+        assert_eq!(methylated, vec![false, true, false, true]);
+    }
+
+    #[test]
+    fn test_original_strand_from_conversion_tags() {
+        let r = record_from_sam("read\t16\tchr1\t1\t40\t4M\t*\t0\t0\tACGT\tJJJJ\tYD:Z:f");
+        assert_eq!(get_original_strand(&r), OriginalStrand::Top);
+
+        let r = record_from_sam("read\t0\tchr1\t1\t40\t4M\t*\t0\t0\tACGT\tJJJJ\tXB:A:G");
+        assert_eq!(get_original_strand(&r), OriginalStrand::Bottom);
+
+        let r = record_from_sam("read\t0\tchr1\t1\t40\t4M\t*\t0\t0\tACGT\tJJJJ\tZS:Z:-+");
+        assert_eq!(get_original_strand(&r), OriginalStrand::Bottom);
+
+        let r = record_from_sam("read\t16\tchr1\t1\t40\t4M\t*\t0\t0\tACGT\tJJJJ");
+        assert_eq!(get_original_strand(&r), OriginalStrand::Bottom);
+    }
+
+    fn xm_cpg_positions(flag: u16, tags: &str) -> Vec<i32> {
+        let sam = format!(
+            "read\t{}\tchr1\t11\t40\t8M\t*\t0\t0\tTGTGTGCG\tJJJJJJJJ\tXM:Z:z.z.z.Z.{}",
+            flag, tags
+        );
+        let br = BismarkRead::new(&record_from_sam(&sam)).unwrap();
+
+        br.get_cpgs().iter().map(|cpg| cpg.abspos.pos).collect()
+    }
+
+    #[test]
+    fn test_xm_strand_from_flags() {
+        let top = vec![10, 12, 14, 16];
+        let bottom = vec![9, 11, 13, 15];
+
+        // (flag, original strand is top) for single-end reads and each mate of a pair,
+        // including mates with unmapped or unpaired partners.
+        let base_flags = [
+            (0, true),
+            (16, false),
+            (99, true),
+            (147, true),
+            (83, false),
+            (163, false),
+            (97, true),
+            (145, true),
+            (81, false),
+            (161, false),
+            (65, true),
+            (129, false),
+            (73, true),
+            (137, false),
+            (89, false),
+            (153, true),
+        ];
+        // Secondary, QC-fail, duplicate and supplementary flags do not change the strand.
+        let extra_flags = [0, 256, 512, 1024, 2048, 256 | 1024, 512 | 1024 | 2048];
+
+        for &(base, is_top) in base_flags.iter() {
+            for &extra in extra_flags.iter() {
+                let expected = match is_top {
+                    true => &top,
+                    false => &bottom,
+                };
+                assert_eq!(
+                    &xm_cpg_positions(base | extra, ""),
+                    expected,
+                    "flag {}",
+                    base | extra
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_xm_strand_from_bismark_tags() {
+        // XG gives the converted genome strand regardless of the flags,
+        // e.g., for the complementary strands of a non-directional library.
+        for flag in [0, 16, 99, 147, 83, 163, 1107] {
+            assert_eq!(
+                xm_cpg_positions(flag, "\tXR:Z:CT\tXG:Z:CT"),
+                vec![10, 12, 14, 16]
+            );
+            assert_eq!(
+                xm_cpg_positions(flag, "\tXR:Z:GA\tXG:Z:CT"),
+                vec![10, 12, 14, 16]
+            );
+            assert_eq!(
+                xm_cpg_positions(flag, "\tXR:Z:CT\tXG:Z:GA"),
+                vec![9, 11, 13, 15]
+            );
+            assert_eq!(
+                xm_cpg_positions(flag, "\tXR:Z:GA\tXG:Z:GA"),
+                vec![9, 11, 13, 15]
+            );
+        }
+    }
+
     #[test]
     fn test_cpgposition_eq() {
         let pos1 = CpGPosition { tid: 0, pos: 1 };
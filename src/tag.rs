@@ -1,63 +1,117 @@
-use rust_htslib::faidx;
+use clap::ValueEnum;
 use rust_htslib::{
     bam,
     bam::ext::BamRecordExtensions,
     bam::record::{Aux, Cigar, Record},
     bam::Read,
 };
-use std::cmp::{max, min};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use rust_htslib::{faidx, tpool};
+use std::cmp::{max, min, Ordering};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
 use std::str;
 
-use crate::bamutil;
+use crate::error::{Error, Result};
+use crate::{bamutil, manifest};
 
 fn need_reverse_complement(read: &Record) -> bool {
     !((!read.is_reverse() && read.is_first_in_template())
         || (read.is_reverse() && read.is_last_in_template()))
 }
 
-fn reverse_complement(seq: &str, rcmapping: &HashMap<char, char>) -> String {
-    let mut res: String = String::with_capacity(seq.len());
-    for base in seq.chars().rev() {
-        res.push(rcmapping[&base]);
+/// Bisulfite conversion of a read (Bismark `XR` tag) or of the genome it aligns to (`XG` tag).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Conversion {
+    /// C-to-T conversion (`CT`).
+    CtoT,
+    /// G-to-A conversion (`GA`).
+    GtoA,
+}
+
+impl Conversion {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Conversion::CtoT => "CT",
+            Conversion::GtoA => "GA",
+        }
     }
-    res
 }
 
-fn char_at(seq: &str, i: usize) -> char {
-    seq.chars().nth(i).unwrap()
+/// Determines the read conversion (`XR`) and the genome conversion (`XG`) of `r`, from the
+/// original bottom strand if `is_bottom_strand`, or the original top strand otherwise.
+///
+/// Reads from the original top strand align to the C-to-T converted genome, and those from the
+/// original bottom strand to the G-to-A converted genome. Reads sequenced from the original strand
+/// (C-to-T converted) align in the direction of the strand, i.e., forward for the top strand and
+/// reverse for the bottom strand, while reads from the complementary strands (G-to-A converted)
+/// align the other way.
+pub fn determine_conversions(r: &Record, is_bottom_strand: bool) -> (Conversion, Conversion) {
+    let read = match r.is_reverse() == is_bottom_strand {
+        true => Conversion::CtoT,
+        false => Conversion::GtoA,
+    };
+    let genome = match is_bottom_strand {
+        true => Conversion::GtoA,
+        false => Conversion::CtoT,
+    };
+    (read, genome)
 }
 
-fn is_chg_context(seq: &str) -> bool {
-    matches!(seq, "CAG" | "CTG" | "CCG")
+/// Complement of a base (IUPAC codes included), or `N` for unknown bases. Gaps are kept.
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        b'M' => b'K',
+        b'R' => b'Y',
+        b'W' => b'W',
+        b'S' => b'S',
+        b'Y' => b'R',
+        b'K' => b'M',
+        b'V' => b'B',
+        b'H' => b'D',
+        b'D' => b'H',
+        b'B' => b'V',
+        b'-' => b'-',
+        _ => b'N',
+    }
 }
 
-fn is_chh_context(seq: &str) -> bool {
-    matches!(
-        seq,
-        "CAA" | "CAT" | "CAC" | "CTA" | "CTT" | "CTC" | "CCA" | "CCT" | "CCC"
-    )
+fn reverse_complement(seq: &mut [u8]) {
+    seq.reverse();
+    for base in seq.iter_mut() {
+        *base = complement(*base);
+    }
 }
 
-fn is_unknown_context(seq: &str) -> bool {
-    let mut flag = false;
-    for base in seq.chars() {
-        if base == '-' || base == 'N' {
-            flag = true;
-            break;
-        }
+/// Uppercase XM call of a cytosine in the reference `context`: the cytosine followed by (up to)
+/// two reference bases. Contexts that are neither CpG, CHG, CHH nor unknown get no call.
+fn context_call(context: &[u8]) -> Option<u8> {
+    if context.get(1) == Some(&b'G') {
+        Some(b'Z')
+    } else if matches!(context, b"CAG" | b"CTG" | b"CCG") {
+        Some(b'X')
+    } else if matches!(
+        context,
+        b"CAA" | b"CAT" | b"CAC" | b"CTA" | b"CTT" | b"CTC" | b"CCA" | b"CCT" | b"CCC"
+    ) {
+        Some(b'H')
+    } else if context.iter().any(|&base| base == b'-' || base == b'N') {
+        Some(b'U')
+    } else {
+        None
     }
-    flag
 }
 
-pub fn get_header_template_from_bam(input: &str) -> bam::Header {
-    let bam = bam::Reader::from_path(input).unwrap();
-    bam::Header::from_template(bam.header())
+pub fn get_header_template_from_bam(input: &str) -> Result<bam::Header> {
+    let bam = bamutil::get_reader(input)?;
+    Ok(bam::Header::from_template(bam.header()))
 }
 
-pub fn get_tid2size_from_bam(input: &str) -> HashMap<usize, usize> {
-    let header_ = get_header_template_from_bam(input);
+pub fn get_tid2size_from_bam(input: &str) -> Result<HashMap<usize, usize>> {
+    let header_ = get_header_template_from_bam(input)?;
 
     let mut tid2size: HashMap<usize, usize> = HashMap::new();
     for (key, records) in header_.to_hashmap() {
@@ -70,115 +124,365 @@ pub fn get_tid2size_from_bam(input: &str) -> HashMap<usize, usize> {
         }
 
         for (tid, record) in records.iter().enumerate() {
-            tid2size.insert(tid, record["LN"].parse().unwrap());
+            let size = record.get("LN").and_then(|size| size.parse().ok());
+            match size {
+                Some(size) => tid2size.insert(tid, size),
+                None => {
+                    return Err(Error::HeaderMismatch(format!(
+                        "Invalid length of reference sequence with id {}",
+                        tid
+                    )))
+                }
+            };
+        }
+    }
+    Ok(tid2size)
+}
+
+/// Whether the alignments with `header` are sorted by coordinate (`@HD SO:coordinate`).
+pub fn is_coordinate_sorted(header: &bam::Header) -> bool {
+    header
+        .to_hashmap()
+        .get("HD")
+        .and_then(|records| records.first())
+        .and_then(|record| record.get("SO"))
+        .is_some_and(|order| order == "coordinate")
+}
+
+/// Reference genome from which the sequences aligned to the reads are fetched.
+///
+/// For coordinate-sorted input, only the contig of the current reads is kept in memory: it is
+/// loaded with the first read on it, and released when the reads move on to the next contig.
+/// Otherwise, the region aligned to each read is fetched from the indexed fasta file on demand,
+/// and kept until a region outside of it is requested.
+pub struct ReferenceGenome {
+    path: String,
+    reader: faidx::Reader,
+    /// Name and length of each reference sequence in the header of the alignments, by tid.
+    contigs: HashMap<usize, (String, usize)>,
+    sorted: bool,
+    /// Reference sequence id and 0-based start of the loaded contig or region in `seq`.
+    loaded: Option<(usize, usize)>,
+    seq: Vec<u8>,
+}
+
+impl ReferenceGenome {
+    /// Opens the reference genome at `path` (indexed with `samtools faidx`) for alignments with
+    /// `header`, sorted by coordinate if `sorted`.
+    pub fn from_path(path: &str, header: &bam::HeaderView, sorted: bool) -> Result<Self> {
+        let reader = faidx::Reader::from_path(path).map_err(|source| Error::Reference {
+            path: path.to_string(),
+            source,
+        })?;
+        let contigs = (0..header.target_count())
+            .map(|tid| {
+                let name = bamutil::tid2chrom(tid as i32, header);
+                let len = header.target_len(tid).unwrap_or(0) as usize;
+                (tid as usize, (name, len))
+            })
+            .collect();
+
+        Ok(Self {
+            path: path.to_string(),
+            reader,
+            contigs,
+            sorted,
+            loaded: None,
+            seq: Vec::new(),
+        })
+    }
+
+    /// Length of reference sequence `tid` in the header of the alignments.
+    pub fn contig_len(&self, tid: i32) -> Result<usize> {
+        let contig = match tid {
+            tid if tid >= 0 => self.contigs.get(&(tid as usize)),
+            _ => None,
+        };
+        contig
+            .map(|(_, len)| *len)
+            .ok_or_else(|| Error::HeaderMismatch(format!("No reference sequence with id {}", tid)))
+    }
+
+    /// Sequence of reference sequence `tid` from `start` to `end` (0-based, half-open).
+    pub fn fetch(&mut self, tid: i32, start: usize, end: usize) -> Result<&[u8]> {
+        self.contig_len(tid)?;
+        let tid = tid as usize;
+        let (name, len) = &self.contigs[&tid];
+        let path = &self.path;
+        let reference_error = |source| Error::Reference {
+            path: path.clone(),
+            source,
+        };
+
+        if self.sorted {
+            if self.loaded != Some((tid, 0)) {
+                // Release the previous contig before loading the next one.
+                self.seq = Vec::new();
+                self.loaded = None;
+                self.seq = self
+                    .reader
+                    .fetch_seq(name, 0, *len)
+                    .map_err(reference_error)?;
+                self.loaded = Some((tid, 0));
+            }
+        } else if !self.loaded.is_some_and(|(loaded_tid, offset)| {
+            loaded_tid == tid && offset <= start && end <= offset + self.seq.len()
+        }) {
+            self.seq = match end > start {
+                // The end of faidx regions is inclusive.
+                true => self
+                    .reader
+                    .fetch_seq(name, start, end - 1)
+                    .map_err(reference_error)?,
+                false => Vec::new(),
+            };
+            self.loaded = Some((tid, start));
+        }
+
+        let offset = self.loaded.map_or(0, |(_, offset)| offset);
+        self.seq.get(start - offset..end - offset).ok_or_else(|| {
+            Error::HeaderMismatch(format!(
+                "Reference sequence with id {} is shorter than its length in the BAM header",
+                tid
+            ))
+        })
+    }
+}
+
+/// Type of bisulfite sequencing library, which determines the strands the reads come from.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Library {
+    /// Single-end reads and read 1 from the original top (OT) or bottom (OB) strand.
+    #[default]
+    Directional,
+    /// Single-end reads and read 1 from the strands complementary to the original top (CTOT) or
+    /// bottom (CTOB) strand, as in post-bisulfite adaptor tagging (PBAT).
+    Pbat,
+    /// Reads from any of the four strands, as in scBS-seq or snmC-seq.
+    NonDirectional,
+}
+
+/// Determines the original strand of reads by the type of the library, and for non-directional
+/// libraries by the conversions observed in the reads.
+pub struct StrandCaller {
+    library: Library,
+    is_paired_end: bool,
+    /// Strands of the first mates of pairs whose second mate has not been seen, by read name, with
+    /// the position (tid and 0-based start) of the second mate.
+    mates: HashMap<Vec<u8>, (bool, (i32, i64))>,
+    /// Read names in `mates` by the position of the second mate, for coordinate-sorted input.
+    mate_positions: BTreeSet<((i32, i64), Vec<u8>)>,
+}
+
+impl StrandCaller {
+    pub fn new(library: Library, is_paired_end: bool) -> Self {
+        Self {
+            library,
+            is_paired_end,
+            mates: HashMap::new(),
+            mate_positions: BTreeSet::new(),
+        }
+    }
+
+    /// Forgets the first mates whose second mate should have been seen before the position `pos`
+    /// in coordinate-sorted input, e.g., as it is missing from the input.
+    fn release_mates(&mut self, pos: (i32, i64)) {
+        while self
+            .mate_positions
+            .first()
+            .is_some_and(|(mate_pos, _)| *mate_pos < pos)
+        {
+            let (mate_pos, qname) = self.mate_positions.pop_first().expect("checked above");
+            if self.mates.get(&qname).is_some_and(|(_, p)| *p == mate_pos) {
+                self.mates.remove(&qname);
+            }
+        }
+    }
+
+    /// Whether `r` originates from the original bottom strand, so that it is analyzed along the
+    /// reverse complement of the reference.
+    ///
+    /// For non-directional libraries, the strand is the one whose conversion (C-to-T for the top
+    /// strand, G-to-A for the bottom strand) is observed more often in the alignment of `r`
+    /// against `refgenome`, or that of a directional library on ties. Mates of a pair are assigned
+    /// the strand of the first mate.
+    pub fn is_bottom_strand(
+        &mut self,
+        r: &Record,
+        refgenome: &mut ReferenceGenome,
+    ) -> Result<bool> {
+        let directional = match self.is_paired_end {
+            true => need_reverse_complement(r),
+            false => r.is_reverse(),
+        };
+
+        match self.library {
+            Library::Directional => Ok(directional),
+            Library::Pbat => Ok(!directional),
+            Library::NonDirectional => {
+                let is_primary_pair = self.is_paired_end
+                    && r.is_paired()
+                    && !r.is_secondary()
+                    && !r.is_supplementary();
+                if refgenome.sorted {
+                    self.release_mates((r.tid(), r.pos()));
+                }
+                if is_primary_pair {
+                    if let Some((is_bottom_strand, mate_pos)) = self.mates.remove(r.qname()) {
+                        self.mate_positions.remove(&(mate_pos, r.qname().to_vec()));
+                        return Ok(is_bottom_strand);
+                    }
+                }
+
+                let (n_c_to_t, n_g_to_a) = count_conversions(r, refgenome)?;
+                let is_bottom_strand = match n_c_to_t.cmp(&n_g_to_a) {
+                    Ordering::Greater => false,
+                    Ordering::Less => true,
+                    Ordering::Equal => directional,
+                };
+
+                if is_primary_pair && !r.is_mate_unmapped() {
+                    let mate_pos = (r.mtid(), r.mpos());
+                    self.mates
+                        .insert(r.qname().to_vec(), (is_bottom_strand, mate_pos));
+                    if refgenome.sorted {
+                        self.mate_positions.insert((mate_pos, r.qname().to_vec()));
+                    }
+                }
+                Ok(is_bottom_strand)
+            }
         }
     }
-    tid2size
 }
 
-pub fn get_rcmapping() -> HashMap<char, char> {
-    // reverse-complement mapping
-    let mut rcmapping: HashMap<char, char> = HashMap::new();
-    rcmapping.insert('A', 'T');
-    rcmapping.insert('C', 'G');
-    rcmapping.insert('G', 'C');
-    rcmapping.insert('T', 'A');
-    rcmapping.insert('N', 'N');
-    rcmapping.insert('M', 'K');
-    rcmapping.insert('R', 'Y');
-    rcmapping.insert('W', 'W');
-    rcmapping.insert('S', 'S');
-    rcmapping.insert('Y', 'R');
-    rcmapping.insert('K', 'M');
-    rcmapping.insert('V', 'B');
-    rcmapping.insert('H', 'D');
-    rcmapping.insert('D', 'H');
-    rcmapping.insert('B', 'V');
-    rcmapping.insert('-', '-');
-
-    rcmapping
+/// Number of C-to-T (reference C, read T) and G-to-A (reference G, read A) mismatches in the
+/// alignment of `r`.
+fn count_conversions(r: &Record, refgenome: &mut ReferenceGenome) -> Result<(u32, u32)> {
+    // The flanked region is fetched as for the XM tag, so that it is fetched once for both.
+    let (flanked_start, flanked_end) = flanked_region(r, refgenome)?;
+    let flanked_ref = refgenome.fetch(r.tid(), flanked_start, flanked_end)?;
+    let start = max(r.reference_start(), 0) as usize;
+    let reference = flanked_ref.get(start - flanked_start..).unwrap_or(&[]);
+    let seq = r.seq();
+
+    let mut n_c_to_t = 0;
+    let mut n_g_to_a = 0;
+    let mut read_pos: usize = 0;
+    let mut ref_pos: usize = 0;
+
+    for cigar in r.cigar().iter() {
+        match cigar {
+            Cigar::Match(length) | Cigar::Equal(length) | Cigar::Diff(length) => {
+                let length = *length as usize;
+                for i in 0..length {
+                    let (read_base, ref_base) = match reference.get(ref_pos + i) {
+                        Some(ref_base) if read_pos + i < seq.len() => {
+                            (seq[read_pos + i], ref_base.to_ascii_uppercase())
+                        }
+                        _ => break,
+                    };
+                    match (ref_base, read_base) {
+                        (b'C', b'T') => n_c_to_t += 1,
+                        (b'G', b'A') => n_g_to_a += 1,
+                        _ => {}
+                    }
+                }
+                read_pos += length;
+                ref_pos += length;
+            }
+            Cigar::Ins(length) | Cigar::SoftClip(length) => read_pos += *length as usize,
+            Cigar::Del(length) | Cigar::RefSkip(length) => ref_pos += *length as usize,
+            _ => {}
+        }
+    }
+
+    Ok((n_c_to_t, n_g_to_a))
 }
 
-// fn process_match(
-//     tmp_read_seq: Vec<char>,
-//     tmp_ref_seq: Vec<char>,
-//     read_seq: String,
-//     ref_seq: String,
-//     used_read_len: &mut usize,
-//     used_ref_len: &mut usize,
-//     length: &mut u32,
-// ) -> () {
-//     tmp_read_seq.append(
-//         &mut read_seq
-//             .chars()
-//             .skip(used_read_len)
-//             .take(*length as usize)
-//             .collect(),
-//     );
-//     tmp_ref_seq.append(
-//         &mut ref_seq
-//             .chars()
-//             .skip(used_ref_len)
-//             .take(*length as usize)
-//             .collect(),
-//     );
-
-//     *used_read_len += *length as usize;
-//     *used_ref_len += *length as usize;
-// }
+/// Region of the reference (0-based, half-open) aligned to `r` with the upstream and downstream
+/// 2 bp, to determine the cytosine context near the left and right edges of the alignment, clipped
+/// to the reference sequence.
+fn flanked_region(r: &Record, refgenome: &ReferenceGenome) -> Result<(usize, usize)> {
+    let chromsize = refgenome.contig_len(r.tid())? as i64;
+    let clipped_start = max(r.reference_start() - 2, 0) as usize;
+    let clipped_end = min(r.reference_end() + 2, chromsize) as usize;
+    if clipped_start > clipped_end {
+        return Err(Error::HeaderMismatch(format!(
+            "Reference sequence with id {} is shorter than its length in the BAM header",
+            r.tid()
+        )));
+    }
 
+    Ok((clipped_start, clipped_end))
+}
+
+/// Buffers reused across reads, so that XM tags are determined without allocating for each read.
+#[derive(Default)]
+pub struct XmBuffers {
+    read: Vec<u8>,
+    reference: Vec<u8>,
+    xm: Vec<u8>,
+}
+
+/// Determines the Bismark XM tag of `r` by comparing the read sequence with the reference sequence.
+/// The read is analyzed along the reverse complement of the reference if `is_bottom_strand` (see
+/// [`StrandCaller`]).
 pub fn determine_xm_tag_string(
     r: &Record,
-    refgenome: &HashMap<usize, Vec<u8>>,
-    tid2size: &HashMap<usize, usize>,
-    rcmapping: &HashMap<char, char>,
-    is_paired_end: bool,
-) -> String {
+    refgenome: &mut ReferenceGenome,
+    is_bottom_strand: bool,
+) -> Result<String> {
+    let mut buffers = XmBuffers::default();
+    determine_xm_tag(r, refgenome, is_bottom_strand, &mut buffers).map(str::to_string)
+}
+
+/// Determines the Bismark XM tag of `r` as done by [`determine_xm_tag_string`], in `buffers`.
+pub fn determine_xm_tag<'a>(
+    r: &Record,
+    refgenome: &mut ReferenceGenome,
+    is_bottom_strand: bool,
+    buffers: &'a mut XmBuffers,
+) -> Result<&'a str> {
     let tid = r.tid();
     let start = r.reference_start();
     let end = r.reference_end();
 
-    let flag_reverse_complement = match is_paired_end {
-        true => need_reverse_complement(r),
-        false => r.is_reverse(),
-    };
-
-    // Extract read sequence from alignment record.
-    let read_seq = match str::from_utf8(&r.seq().as_bytes()) {
-        Ok(read_seq) => read_seq.to_string().to_uppercase(),
-        Err(error) => panic!("Error parsing alignment record: {}", error),
-    };
     // For reference sequence,
     // we should additionally consider upstream & downstream 2-bp positions,
     // to determine the cytosine context near the left & right edge of the alignment.
-    let chromsize = tid2size[&(tid as usize)] as i64;
-    let clipped_start = max(start - 2, 0) as usize;
-    let clipped_end = min(end + 2, chromsize) as usize;
-
-    let ref_seq_result =
-        str::from_utf8(&refgenome[&(tid as usize)].as_slice()[clipped_start..clipped_end]);
-    let ref_seq = match ref_seq_result {
-        Ok(ref_seq) => ref_seq.to_string().to_uppercase(),
-        Err(error) => panic!("Error extracting reference sequence: {}", error),
-    };
+    let chromsize = refgenome.contig_len(tid)? as i64;
+    let (clipped_start, clipped_end) = flanked_region(r, refgenome)?;
+
     // For reads aligned at the edge of the reference genome,
     // we may not be able to extract flanking 2bp. In that case, just pad with N as much as needed.
-    let padding = ["", "N", "NN"];
+    let flanked_ref = refgenome.fetch(tid, clipped_start, clipped_end)?;
     let pad_nbases_start = max(2 - start, 0) as usize;
     let pad_nbases_end = max(end - chromsize + 2, 0) as usize;
-    let ref_seq = format!(
-        "{}{}{}",
-        padding[pad_nbases_start], ref_seq, padding[pad_nbases_end]
-    );
+    let ref_len = pad_nbases_start + flanked_ref.len() + pad_nbases_end;
+    let ref_base = |i: usize| match i
+        .checked_sub(pad_nbases_start)
+        .and_then(|i| flanked_ref.get(i))
+    {
+        Some(base) => base.to_ascii_uppercase(),
+        None => b'N',
+    };
 
-    let mut tmp_read_seq: Vec<char> = Vec::new();
-    let mut tmp_ref_seq: Vec<char> = Vec::new();
+    let seq = r.seq();
+    let read_len = seq.len();
 
-    tmp_read_seq.push('-');
-    tmp_read_seq.push('-');
+    // Align the read and reference sequences base by base, with gaps ('-') for indels.
+    let XmBuffers {
+        read,
+        reference,
+        xm,
+    } = buffers;
+    read.clear();
+    reference.clear();
+    xm.clear();
 
-    tmp_ref_seq.push(ref_seq.chars().next().unwrap());
-    tmp_ref_seq.push(ref_seq.chars().nth(1).unwrap());
+    read.extend_from_slice(b"--");
+    reference.push(ref_base(0));
+    reference.push(ref_base(1));
 
     let mut used_read_len: usize = 0;
     let mut used_ref_len: usize = 2;
@@ -186,287 +490,470 @@ pub fn determine_xm_tag_string(
     for cigar in r.cigar().iter() {
         match cigar {
             Cigar::Match(length) => {
-                tmp_read_seq.append(
-                    &mut read_seq
-                        .chars()
-                        .skip(used_read_len)
-                        .take(*length as usize)
-                        .collect(),
-                );
-                tmp_ref_seq.append(
-                    &mut ref_seq
-                        .chars()
-                        .skip(used_ref_len)
-                        .take(*length as usize)
-                        .collect(),
-                );
-
-                used_read_len += *length as usize;
-                used_ref_len += *length as usize;
+                let length = *length as usize;
+                read.extend((used_read_len..min(used_read_len + length, read_len)).map(|i| seq[i]));
+                reference.extend((used_ref_len..min(used_ref_len + length, ref_len)).map(ref_base));
+
+                used_read_len += length;
+                used_ref_len += length;
             }
             Cigar::Ins(length) => {
-                tmp_read_seq.append(
-                    &mut read_seq
-                        .chars()
-                        .skip(used_read_len)
-                        .take(*length as usize)
-                        .collect(),
-                );
-                tmp_ref_seq.extend(std::iter::repeat_n('-', *length as usize));
-
-                used_read_len += *length as usize;
+                let length = *length as usize;
+                read.extend((used_read_len..min(used_read_len + length, read_len)).map(|i| seq[i]));
+                reference.extend(std::iter::repeat_n(b'-', length));
+
+                used_read_len += length;
             }
             Cigar::Del(length) => {
-                tmp_read_seq.extend(std::iter::repeat_n('-', *length as usize));
-                tmp_ref_seq.append(
-                    &mut ref_seq
-                        .chars()
-                        .skip(used_ref_len)
-                        .take(*length as usize)
-                        .collect(),
-                );
-
-                used_ref_len += *length as usize;
+                let length = *length as usize;
+                read.extend(std::iter::repeat_n(b'-', length));
+                reference.extend((used_ref_len..min(used_ref_len + length, ref_len)).map(ref_base));
+
+                used_ref_len += length;
             }
             _ => {}
         }
     }
 
-    tmp_read_seq.push('-');
-    tmp_read_seq.push('-');
-
-    tmp_ref_seq.push(ref_seq.chars().nth(ref_seq.len() - 2).unwrap());
-    tmp_ref_seq.push(ref_seq.chars().nth(ref_seq.len() - 1).unwrap());
-
-    let target_read_seq;
-    let target_ref_seq;
-
-    if flag_reverse_complement {
-        let read = tmp_read_seq
-            .iter()
-            .take(tmp_read_seq.len() - 2)
-            .collect::<String>();
-        let reference = tmp_ref_seq
-            .iter()
-            .take(tmp_ref_seq.len() - 2)
-            .collect::<String>();
-
-        target_read_seq = reverse_complement(&read, rcmapping);
-        target_ref_seq = reverse_complement(&reference, rcmapping);
+    read.extend_from_slice(b"--");
+    reference.push(ref_base(ref_len - 2));
+    reference.push(ref_base(ref_len - 1));
+
+    // Bases are read along the original strand, followed by two bases of context.
+    let (target_read_seq, target_ref_seq): (&[u8], &[u8]) = if is_bottom_strand {
+        read.truncate(read.len() - 2);
+        reference.truncate(reference.len() - 2);
+        reverse_complement(read);
+        reverse_complement(reference);
+        (&read[..], &reference[..])
     } else {
-        target_read_seq = tmp_read_seq.iter().skip(2).collect::<String>();
-        target_ref_seq = tmp_ref_seq.iter().skip(2).collect::<String>();
-    }
+        (&read[2..], &reference[2..])
+    };
 
-    let mut xm_tag: Vec<char> = Vec::new();
-    for idx in 0..target_read_seq.len() - 2 {
-        if char_at(&target_read_seq, idx) == '-' {
+    let len = target_read_seq.len();
+    for idx in 0..len - 2 {
+        let base = target_read_seq[idx];
+        if base == b'-' {
             continue;
-        } else if char_at(&target_read_seq, idx) == 'N' {
-            xm_tag.push('.');
-        } else if char_at(&target_ref_seq, idx) == 'C' {
-            if (char_at(&target_read_seq, idx + 1) == '-'
-                || char_at(&target_read_seq, idx + 2) == '-')
-                && ((idx != target_read_seq.len() - 3) && (idx != target_read_seq.len() - 4))
-            {
-                let mut tmp_target_read_seq: Vec<char> = Vec::new();
-                let mut tmp_target_ref_seq: Vec<char> = Vec::new();
-
-                tmp_target_read_seq.push(char_at(&target_read_seq, idx));
-                tmp_target_ref_seq.push(char_at(&target_ref_seq, idx));
-
-                let mut flag_tmp = 0;
-                let mut tmp_count = 1;
-
-                while flag_tmp != 2 {
-                    if idx + tmp_count > target_read_seq.len() - 1 {
-                        break;
-                    }
-                    if char_at(&target_read_seq, idx + tmp_count) != '-' {
-                        tmp_target_read_seq.push(char_at(&target_read_seq, idx + tmp_count));
-                        tmp_target_ref_seq.push(char_at(&target_ref_seq, idx + tmp_count));
-                        flag_tmp += 1;
-                    }
+        } else if base == b'N' || target_ref_seq[idx] != b'C' {
+            xm.push(b'.');
+            continue;
+        }
 
-                    tmp_count += 1;
+        let call = if (target_read_seq[idx + 1] == b'-' || target_read_seq[idx + 2] == b'-')
+            && idx + 3 != len
+            && idx + 4 != len
+        {
+            // Deletion right after the cytosine: the context is given by the reference bases
+            // aligned to the next two bases of the read.
+            let mut context = [b'C'; 3];
+            let mut n_context = 1;
+            let next_bases = target_read_seq[idx + 1..]
+                .iter()
+                .zip(&target_ref_seq[idx + 1..]);
+            for (&read_base, &reference_base) in next_bases {
+                if n_context == 3 {
+                    break;
                 }
-
-                let ref_context = tmp_target_ref_seq.iter().collect::<String>();
-
-                if (tmp_target_ref_seq[0] == 'C') && (tmp_target_ref_seq[1] == 'G') {
-                    if tmp_target_read_seq[0] == 'C' {
-                        xm_tag.push('Z');
-                    } else if tmp_target_read_seq[0] == 'T' {
-                        xm_tag.push('z');
-                    } else {
-                        xm_tag.push('.');
-                    }
-                } else if is_chg_context(&ref_context) {
-                    if tmp_target_read_seq[0] == 'C' {
-                        xm_tag.push('X');
-                    } else if tmp_target_read_seq[0] == 'T' {
-                        xm_tag.push('x');
-                    } else {
-                        xm_tag.push('.');
-                    }
-                } else if is_chh_context(&ref_context) {
-                    if tmp_target_read_seq[0] == 'C' {
-                        xm_tag.push('H');
-                    } else if tmp_target_read_seq[0] == 'T' {
-                        xm_tag.push('h');
-                    } else {
-                        xm_tag.push('.');
-                    }
-                } else if is_unknown_context(&ref_context) {
-                    if tmp_target_read_seq[0] == 'C' {
-                        xm_tag.push('U');
-                    } else if tmp_target_read_seq[0] == 'T' {
-                        xm_tag.push('u');
-                    } else {
-                        xm_tag.push('.');
-                    }
-                }
-            }
-            // No deletion
-            else {
-                let ref_context = target_ref_seq.chars().skip(idx).take(3).collect::<String>();
-
-                if (char_at(&target_ref_seq, idx) == 'C')
-                    && (char_at(&target_ref_seq, idx + 1) == 'G')
-                {
-                    // Reference context is CG, read 'C' -> 'methylated in CG context (Z)'
-                    if char_at(&target_read_seq, idx) == 'C' {
-                        xm_tag.push('Z');
-                    }
-                    // Reference context is CG, read 'T' -> 'unmethylated in CG context (z)'
-                    else if char_at(&target_read_seq, idx) == 'T' {
-                        xm_tag.push('z');
-                    }
-                    // Reference context is CG, read 'A or G' -> Nothing.
-                    else {
-                        xm_tag.push('.');
-                    }
-                } else if is_chg_context(&ref_context) {
-                    if char_at(&target_read_seq, idx) == 'C' {
-                        xm_tag.push('X');
-                    } else if char_at(&target_read_seq, idx) == 'T' {
-                        xm_tag.push('x');
-                    } else {
-                        xm_tag.push('.');
-                    }
-                } else if is_chh_context(&ref_context) {
-                    if char_at(&target_read_seq, idx) == 'C' {
-                        xm_tag.push('H');
-                    } else if char_at(&target_read_seq, idx) == 'T' {
-                        xm_tag.push('h');
-                    } else {
-                        xm_tag.push('.');
-                    }
-                } else if is_unknown_context(&ref_context) {
-                    if char_at(&target_read_seq, idx) == 'C' {
-                        xm_tag.push('U');
-                    } else if char_at(&target_read_seq, idx) == 'T' {
-                        xm_tag.push('u');
-                    } else {
-                        xm_tag.push('.');
-                    }
+                if read_base != b'-' {
+                    context[n_context] = reference_base;
+                    n_context += 1;
                 }
             }
+            context_call(&context[..n_context])
+        }
+        // No deletion
+        else {
+            context_call(&target_ref_seq[idx..idx + 3])
+        };
+
+        if let Some(call) = call {
+            xm.push(match base {
+                // Read 'C' -> methylated, read 'T' -> unmethylated, read 'A or G' -> Nothing.
+                b'C' => call,
+                b'T' => call.to_ascii_lowercase(),
+                _ => b'.',
+            });
+        }
+    }
+
+    if is_bottom_strand {
+        xm.reverse();
+    }
+
+    Ok(str::from_utf8(xm).expect("XM tags are ASCII"))
+}
+
+/// Format of the tagged alignments.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlignmentFormat {
+    Sam,
+    Bam,
+    Cram,
+}
+
+impl AlignmentFormat {
+    /// Format given by the extension of `output`: BAM for `.bam`, CRAM for `.cram`, SAM otherwise.
+    pub fn from_path(output: &str) -> Self {
+        let lower = output.to_lowercase();
+        if lower.ends_with(".bam") {
+            AlignmentFormat::Bam
+        } else if lower.ends_with(".cram") {
+            AlignmentFormat::Cram
         } else {
-            xm_tag.push('.');
+            AlignmentFormat::Sam
         }
     }
 
-    match flag_reverse_complement {
-        true => xm_tag.iter().rev().collect::<String>(),
-        false => xm_tag.iter().collect::<String>(),
+    fn htslib_format(self) -> bam::Format {
+        match self {
+            AlignmentFormat::Sam => bam::Format::Sam,
+            AlignmentFormat::Bam => bam::Format::Bam,
+            AlignmentFormat::Cram => bam::Format::Cram,
+        }
     }
 }
 
-pub fn run(input: &str, output: &str, genome: &str) {
-    let mut reader = bamutil::get_reader(input);
-    let is_paired_end = bamutil::is_paired_end(input);
-    let header = bamutil::get_header(&reader);
-    let tid2size: HashMap<usize, usize> = get_tid2size_from_bam(input);
+/// Appends a `@PG` header line of metheor with the command line to the program chain of `header`.
+///
+/// The line follows (`PP`) the last program of the chain, i.e., the last `@PG` line not followed by
+/// another, and its ID is made unique by a numeric suffix if needed.
+fn push_program_record(header: &mut bam::Header) {
+    let programs = header.to_hashmap().remove("PG").unwrap_or_default();
+    let ids: Vec<&String> = programs.iter().filter_map(|pg| pg.get("ID")).collect();
+    let previous = ids
+        .iter()
+        .rev()
+        .find(|id| !programs.iter().any(|pg| pg.get("PP") == Some(**id)));
+
+    let mut id = "metheor".to_string();
+    let mut suffix = 0;
+    while ids.iter().any(|other| **other == id) {
+        suffix += 1;
+        id = format!("metheor.{}", suffix);
+    }
 
-    let rcmapping = get_rcmapping();
+    let command_line: Vec<String> = std::env::args().collect();
+
+    let mut record = bam::header::HeaderRecord::new(b"PG");
+    record.push_tag(b"ID", id);
+    record.push_tag(b"PN", "metheor");
+    if let Some(previous) = previous {
+        record.push_tag(b"PP", previous);
+    }
+    record.push_tag(b"VN", env!("CARGO_PKG_VERSION"));
+    record.push_tag(b"CL", command_line.join(" "));
+    header.push_record(&record);
+}
+
+/// Parameters of XM tagging.
+#[derive(Clone)]
+pub struct TagParams {
+    /// Path to reference genome fasta file.
+    pub genome: String,
+    /// Path to reference genome fasta file used to decode CRAM input. Defaults to `genome`.
+    pub reference: Option<String>,
+    /// Number of threads of the htslib thread pool compressing and decompressing the alignments.
+    pub threads: usize,
+    /// Format of the output. Determined by the extension of the output path if not given.
+    pub output_format: Option<AlignmentFormat>,
+    /// Compression level (0-9) of BAM and CRAM output. Defaults to the level of htslib.
+    pub compression_level: Option<u32>,
+    /// Number of threads compressing the output, instead of sharing the thread pool of `threads`.
+    pub writer_threads: Option<usize>,
+    /// Type of the library, which determines the original strand of the reads.
+    pub library: Library,
+}
+
+impl TagParams {
+    pub fn new(genome: &str) -> Self {
+        Self {
+            genome: genome.to_string(),
+            reference: None,
+            threads: 1,
+            output_format: None,
+            compression_level: None,
+            writer_threads: None,
+            library: Library::Directional,
+        }
+    }
+}
+
+/// Writes the alignments in `input` to `output` with Bismark XM, XR and XG tags attached.
+pub fn run(input: &str, output: &str, params: &TagParams) -> Result<()> {
+    let genome = params.genome.as_str();
+    // CRAM input is decoded with the reference genome used for tagging, unless specified otherwise.
+    let reference = &Some(
+        params
+            .reference
+            .clone()
+            .unwrap_or_else(|| genome.to_string()),
+    );
+    let mut reader = bamutil::get_reader_with_reference(input, reference)?;
+    manifest::record_input(input, None);
+    let is_paired_end = bamutil::is_paired_end(input, reference)?;
+    let header = bamutil::get_header(&reader);
 
     // Assert if the output directory exists.
     let path = PathBuf::from(&output);
-    let dir = path.parent().unwrap();
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
 
-    if !dir.is_dir() {
-        panic!(
-            "No such directory for output alignment file: {}",
-            dir.to_str().unwrap()
-        )
+    if !dir.as_os_str().is_empty() && !dir.is_dir() {
+        return Err(Error::MissingOutputDirectory(
+            dir.to_string_lossy().into_owned(),
+        ));
     }
-    // Prepare output writer.
-    let header_tmpl = get_header_template_from_bam(input);
-    let mut writer = match bam::Writer::from_path(output, &header_tmpl, bam::Format::Sam) {
-        Ok(writer) => writer,
-        Err(error) => panic!("Error opening alignment file to write: {}", error),
-    };
     // Prepare reference genome.
-    let refgenome_reader = match faidx::Reader::from_path(genome) {
-        Ok(refgenome_reader) => refgenome_reader,
-        Err(error) => {
-            panic!("Error opening reference genome file: {}", error);
+    let mut header_tmpl = get_header_template_from_bam(input)?;
+    let mut refgenome =
+        ReferenceGenome::from_path(genome, &header, is_coordinate_sorted(&header_tmpl))?;
+    // Prepare output writer.
+    let format = params
+        .output_format
+        .unwrap_or_else(|| AlignmentFormat::from_path(output));
+    push_program_record(&mut header_tmpl);
+    let mut writer = bam::Writer::from_path(output, &header_tmpl, format.htslib_format())
+        .map_err(Error::WriteAlignment)?;
+    if format == AlignmentFormat::Cram {
+        writer
+            .set_reference(genome)
+            .map_err(|source| Error::Reference {
+                path: genome.to_string(),
+                source,
+            })?;
+    }
+    if let Some(level) = params.compression_level {
+        writer
+            .set_compression_level(bam::CompressionLevel::Level(level))
+            .map_err(Error::WriteAlignment)?;
+    }
+    // Decoding and encoding share a thread pool of htslib, kept alive until all reads are written,
+    // unless the number of threads for encoding is given separately.
+    let thread_pool = match params.threads {
+        threads if threads > 1 => {
+            Some(tpool::ThreadPool::new(threads as u32).map_err(Error::ReadAlignment)?)
         }
+        _ => None,
     };
-    println!("Parsing reference genome...");
-    let mut refgenome: HashMap<usize, Vec<u8>> = HashMap::new();
-    for (tid, _size) in tid2size.iter() {
-        let ref_array = refgenome_reader
-            .fetch_seq(bamutil::tid2chrom(*tid as i32, &header), 0, tid2size[tid])
-            .expect("Error fetching reference genome sequence.");
-
-        refgenome.insert(*tid, ref_array);
+    if let Some(thread_pool) = &thread_pool {
+        reader
+            .set_thread_pool(thread_pool)
+            .map_err(Error::ReadAlignment)?;
+    }
+    match (params.writer_threads, &thread_pool) {
+        (Some(threads), _) if threads > 1 => {
+            writer.set_threads(threads).map_err(Error::WriteAlignment)?
+        }
+        (None, Some(thread_pool)) => writer
+            .set_thread_pool(thread_pool)
+            .map_err(Error::WriteAlignment)?,
+        _ => {}
     }
-    println!("Done!");
-
     // Main loop
     // Iterate aligned reads and determine xm tag string.
-    for mut r in reader.records().map(|r| r.unwrap()) {
+    // The record and the buffers are reused, so that no allocation is made for each read.
+    let mut r = Record::new();
+    let mut buffers = XmBuffers::default();
+    let mut strand_caller = StrandCaller::new(params.library, is_paired_end);
+    while let Some(result) = reader.read(&mut r) {
+        result.map_err(Error::ReadAlignment)?;
+        // Unmapped reads have no alignment to call methylation from, and are written unchanged.
+        if r.is_unmapped() || r.tid() < 0 {
+            writer.write(&r).map_err(Error::WriteAlignment)?;
+            continue;
+        }
+        // Determine the original strand of the read by the type of the library.
+        let is_bottom_strand = strand_caller.is_bottom_strand(&r, &mut refgenome)?;
         // Determine XM tag string by comparing read sequence and reference sequence.
-        let xm_tag_string =
-            determine_xm_tag_string(&r, &refgenome, &tid2size, &rcmapping, is_paired_end);
-        // Attach XM tag to the record.
-        let add_result = r.push_aux("XM".as_bytes(), Aux::String(&xm_tag_string));
-        match add_result {
-            Ok(_) => (),
-            Err(e) => panic!("Error adding XM tag to alignment record. {}", e),
+        let xm_tag_string = determine_xm_tag(&r, &mut refgenome, is_bottom_strand, &mut buffers)?;
+        // Attach XM tag to the record, replacing the one it may already have.
+        let _ = r.remove_aux(b"XM");
+        r.push_aux("XM".as_bytes(), Aux::String(xm_tag_string))
+            .map_err(Error::ReadAlignment)?;
+        // Attach XR and XG tags, unless the record already has them.
+        let (read_conversion, genome_conversion) = determine_conversions(&r, is_bottom_strand);
+        for (tag, conversion) in [(b"XR", read_conversion), (b"XG", genome_conversion)] {
+            if r.aux(tag).is_err() {
+                r.push_aux(tag, Aux::String(conversion.as_str()))
+                    .map_err(Error::ReadAlignment)?;
+            }
         }
         // Write record to output.
-        writer.write(&r).expect("Error writing to output file.");
+        writer.write(&r).map_err(Error::WriteAlignment)?;
     }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn tinyref_header() -> bam::HeaderView {
+        let mut header = bam::Header::new();
+        let mut sq = bam::header::HeaderRecord::new(b"SQ");
+        sq.push_tag(b"SN", "ref");
+        sq.push_tag(b"LN", 51);
+        header.push_record(&sq);
+
+        bam::HeaderView::from_header(&header)
+    }
+
+    #[test]
+    fn test_reference_genome_sorted_and_unsorted() {
+        let header = tinyref_header();
+        let mut sorted = ReferenceGenome::from_path("tests/tinyref.fa", &header, true).unwrap();
+        let mut unsorted = ReferenceGenome::from_path("tests/tinyref.fa", &header, false).unwrap();
+
+        for (start, end) in [(0, 4), (10, 20), (47, 51)] {
+            let expected = sorted.fetch(0, start, end).unwrap().to_vec();
+            assert_eq!(expected.len(), end - start);
+            assert_eq!(expected, unsorted.fetch(0, start, end).unwrap());
+        }
+        assert!(matches!(
+            unsorted.fetch(1, 0, 4),
+            Err(Error::HeaderMismatch(_))
+        ));
+        assert!(matches!(
+            sorted.fetch(0, 40, 60),
+            Err(Error::HeaderMismatch(_))
+        ));
+    }
+    #[test]
+    fn test_conversions() {
+        let header = tinyref_header();
+        let mut refgenome = ReferenceGenome::from_path("tests/tinyref.fa", &header, true).unwrap();
+        let mut conversions = |library: Library, flag: u16, is_paired_end: bool| {
+            let sam = format!("r\t{}\tref\t1\t60\t4M\t*\t0\t0\tCGGG\tIIII", flag);
+            let r = Record::from_sam(&header, sam.as_bytes()).unwrap();
+            let is_bottom_strand = StrandCaller::new(library, is_paired_end)
+                .is_bottom_strand(&r, &mut refgenome)
+                .unwrap();
+            let (read, genome) = determine_conversions(&r, is_bottom_strand);
+            (read.as_str(), genome.as_str())
+        };
+
+        // Single-end reads from the original top (OT) and bottom (OB) strands.
+        assert_eq!(conversions(Library::Directional, 0, false), ("CT", "CT"));
+        assert_eq!(conversions(Library::Directional, 16, false), ("CT", "GA"));
+        // Read 1 and read 2 of pairs from OT (99/147) and OB (83/163).
+        assert_eq!(conversions(Library::Directional, 99, true), ("CT", "CT"));
+        assert_eq!(conversions(Library::Directional, 147, true), ("GA", "CT"));
+        assert_eq!(conversions(Library::Directional, 83, true), ("CT", "GA"));
+        assert_eq!(conversions(Library::Directional, 163, true), ("GA", "GA"));
+        // PBAT reads from the complementary to the original bottom (CTOB) and top (CTOT) strands.
+        assert_eq!(conversions(Library::Pbat, 0, false), ("GA", "GA"));
+        assert_eq!(conversions(Library::Pbat, 16, false), ("GA", "CT"));
+        assert_eq!(conversions(Library::Pbat, 99, true), ("GA", "GA"));
+        assert_eq!(conversions(Library::Pbat, 147, true), ("CT", "GA"));
+    }
+    #[test]
+    fn test_non_directional_strand() {
+        let header = tinyref_header();
+        let mut refgenome = ReferenceGenome::from_path("tests/tinyref.fa", &header, true).unwrap();
+        let reference = refgenome.fetch(0, 0, 20).unwrap().to_ascii_uppercase();
+        let mut caller = StrandCaller::new(Library::NonDirectional, false);
+
+        // Reads with C-to-T conversions are from the top strand, and reads with G-to-A conversions
+        // from the bottom strand, regardless of the direction of the alignment.
+        for (flag, from, to, expected) in [
+            (0, b'C', b'T', false),
+            (16, b'C', b'T', false),
+            (0, b'G', b'A', true),
+            (16, b'G', b'A', true),
+        ] {
+            let seq: String = reference
+                .iter()
+                .map(|&b| (if b == from { to } else { b }) as char)
+                .collect();
+            let sam = format!("r\t{}\tref\t1\t60\t20M\t*\t0\t0\t{}\t*", flag, seq);
+            let r = Record::from_sam(&header, sam.as_bytes()).unwrap();
+            assert_eq!(
+                caller.is_bottom_strand(&r, &mut refgenome).unwrap(),
+                expected
+            );
+        }
+    }
+    #[test]
+    fn test_non_directional_pairs() {
+        let header = tinyref_header();
+        let mut refgenome = ReferenceGenome::from_path("tests/tinyref.fa", &header, true).unwrap();
+        let reference = refgenome.fetch(0, 0, 20).unwrap().to_ascii_uppercase();
+        let converted = |from: u8, to: u8| -> String {
+            reference
+                .iter()
+                .map(|&b| (if b == from { to } else { b }) as char)
+                .collect()
+        };
+        let mut caller = StrandCaller::new(Library::NonDirectional, true);
+
+        // Read 1 of pairs from the original top (OT, 99/147) and the strand complementary to it
+        // (CTOT, 83/163) has C-to-T conversions. Read 2, seen second, is assigned the strand of
+        // read 1 even though it has G-to-A conversions.
+        for (qname, flag1, flag2, conversions1, conversions2) in [
+            ("ot", 99, 147, ("CT", "CT"), ("GA", "CT")),
+            ("ctot", 83, 163, ("GA", "CT"), ("CT", "CT")),
+        ] {
+            for (flag, seq, expected) in [
+                (flag1, converted(b'C', b'T'), conversions1),
+                (flag2, converted(b'G', b'A'), conversions2),
+            ] {
+                let sam = format!("{}\t{}\tref\t1\t60\t20M\t=\t1\t20\t{}\t*", qname, flag, seq);
+                let r = Record::from_sam(&header, sam.as_bytes()).unwrap();
+                let is_bottom_strand = caller.is_bottom_strand(&r, &mut refgenome).unwrap();
+                assert!(!is_bottom_strand);
+                let (read, genome) = determine_conversions(&r, is_bottom_strand);
+                assert_eq!((read.as_str(), genome.as_str()), expected);
+            }
+            assert!(caller.mates.is_empty());
+        }
+    }
+    #[test]
+    fn test_non_directional_missing_mates_released() {
+        let header = tinyref_header();
+        let mut refgenome = ReferenceGenome::from_path("tests/tinyref.fa", &header, true).unwrap();
+        let mut caller = StrandCaller::new(Library::NonDirectional, true);
+        let mut call = |caller: &mut StrandCaller, qname: &str, pos: i64, mpos: i64| {
+            let sam = format!(
+                "{}\t99\tref\t{}\t60\t4M\t=\t{}\t20\tCGGG\t*",
+                qname, pos, mpos
+            );
+            let r = Record::from_sam(&header, sam.as_bytes()).unwrap();
+            caller.is_bottom_strand(&r, &mut refgenome).unwrap();
+        };
+
+        // The mate of `a` at position 5 is not in the input, so `a` is released once reads after
+        // position 5 are seen.
+        call(&mut caller, "a", 1, 5);
+        call(&mut caller, "b", 5, 20);
+        assert!(caller.mates.contains_key(b"a".as_slice()));
+        call(&mut caller, "c", 6, 20);
+        assert!(!caller.mates.contains_key(b"a".as_slice()));
+        assert_eq!(caller.mates.len(), 2);
+        assert_eq!(caller.mate_positions.len(), 2);
+    }
     #[test]
     fn test_reverse_complement() {
-        let rcmapping = get_rcmapping();
-        assert_eq!("ATGC", reverse_complement("GCAT", &rcmapping));
+        let mut seq = *b"GCAT";
+        reverse_complement(&mut seq);
+        assert_eq!(b"ATGC", &seq);
     }
     #[test]
-    #[should_panic]
     fn error_when_output_directory_is_not_found() {
-        run(
+        let result = run(
             "tests/test1.bam",
             "tests/no_such_directory/out.bam",
-            "tests/tinyref.fa",
-        )
+            &TagParams::new("tests/tinyref.fa"),
+        );
+        assert!(matches!(result, Err(Error::MissingOutputDirectory(_))));
     }
     #[test]
-    #[should_panic]
     fn error_when_reference_genome_is_not_found() {
-        run(
+        let result = run(
             "tests/test1.bam",
             "tests/out.tagged.bam",
-            "tests/there_is_no_such.fa",
-        )
+            &TagParams::new("tests/there_is_no_such.fa"),
+        );
+        assert!(matches!(result, Err(Error::Reference { .. })));
     }
 }
@@ -0,0 +1,108 @@
+use assert_cmd::prelude::*; // Add methods on commands
+use predicates::prelude::*; // Used for writing assertions
+use std::process::Command; // Run programs
+
+#[test]
+fn aggregate_regions() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("aggregate")
+        .arg("-i")
+        .arg("tests/test4.bam")
+        .arg("-b")
+        .arg("tests/test4.named.bed")
+        .arg("-o")
+        .arg("tests/test4.aggregate.tsv")
+        .assert()
+        .success();
+
+    let result = std::fs::read_to_string("tests/test4.aggregate.tsv")?;
+    let lines: Vec<&str> = result.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(
+        lines[0],
+        "chrom\tstart\tend\tname\tn_cpgs\tdepth\tpdr\tpm\tme\tmhl\tlpmd"
+    );
+    assert!(lines[1].starts_with("chr1\t13\t21\tregion1\t"));
+    assert_eq!(lines[1].split('\t').count(), 11);
+    // Regions without reads are reported with NA measures.
+    assert_eq!(
+        lines[2],
+        "chr1\t100000\t100100\t.\t0\t0\tNA\tNA\tNA\tNA\tNA"
+    );
+
+    Ok(())
+}
+#[test]
+fn aggregate_bed_doesnt_exist() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("aggregate")
+        .arg("-i")
+        .arg("tests/test4.bam")
+        .arg("-b")
+        .arg("tests/doesnt_exist.bed")
+        .arg("-o")
+        .arg("tests/test4.aggregate.missing.tsv")
+        .assert()
+        .failure()
+        .code(3)
+        .stderr(predicate::str::contains("doesnt_exist.bed"));
+
+    Ok(())
+}
+#[test]
+fn aggregate_windows_to_bedgraph() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("aggregate")
+        .arg("-i")
+        .arg("tests/test4.bam")
+        .arg("--window-size")
+        .arg("1000")
+        .arg("--step")
+        .arg("500")
+        .arg("-o")
+        .arg("tests/test4.windows.tsv")
+        .arg("--bedgraph")
+        .arg("tests/test4.windows")
+        .assert()
+        .success();
+
+    let result = std::fs::read_to_string("tests/test4.windows.tsv")?;
+    let lines: Vec<&str> = result.lines().collect();
+    // Only windows with reads are reported.
+    assert!(lines.len() >= 2);
+    for line in lines[1..].iter() {
+        let tokens: Vec<&str> = line.split('\t').collect();
+        let start: u64 = tokens[1].parse()?;
+        assert_eq!(start % 500, 0);
+        assert_ne!(tokens[4], "0");
+    }
+
+    let result = std::fs::read_to_string("tests/test4.windows.pdr.bedGraph")?;
+    for line in result.lines() {
+        assert_eq!(line.split('\t').count(), 4);
+    }
+
+    Ok(())
+}
+#[test]
+fn aggregate_bed_and_windows_conflict() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("aggregate")
+        .arg("-i")
+        .arg("tests/test4.bam")
+        .arg("-b")
+        .arg("tests/test4.named.bed")
+        .arg("-w")
+        .arg("1000")
+        .arg("-o")
+        .arg("tests/test4.conflict.tsv")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use assert_cmd::prelude::*; // Add methods on commands
+use predicates::prelude::*; // Used for writing assertions
+use std::process::Command; // Run programs
+
+#[test]
+fn same_as_individual_measures() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("all")
+        .arg("-i")
+        .arg("tests/test9.bam")
+        .arg("--pdr")
+        .arg("tests/test9.all.pdr.tsv")
+        .arg("--me")
+        .arg("tests/test9.all.me.tsv")
+        .arg("--lpmd")
+        .arg("tests/test9.all.lpmd.tsv")
+        .arg("--lpmd-pairs")
+        .arg("tests/test9.all.lpmd.pairs.tsv")
+        .arg("-d")
+        .arg("1")
+        .arg("--threads")
+        .arg("2")
+        .assert()
+        .success();
+
+    for (measure, output) in [
+        ("pdr", "tests/test9.single.pdr.tsv"),
+        ("me", "tests/test9.single.me.tsv"),
+    ] {
+        let mut cmd = Command::cargo_bin("metheor")?;
+
+        cmd.arg(measure)
+            .arg("-i")
+            .arg("tests/test9.bam")
+            .arg("-o")
+            .arg(output)
+            .arg("-d")
+            .arg("1")
+            .assert()
+            .success();
+    }
+    let mut cmd = Command::cargo_bin("metheor")?;
+    cmd.arg("lpmd")
+        .arg("-i")
+        .arg("tests/test9.bam")
+        .arg("-o")
+        .arg("tests/test9.single.lpmd.tsv")
+        .arg("-p")
+        .arg("tests/test9.single.lpmd.pairs.tsv")
+        .assert()
+        .success();
+
+    for (all, single) in [
+        ("tests/test9.all.pdr.tsv", "tests/test9.single.pdr.tsv"),
+        ("tests/test9.all.me.tsv", "tests/test9.single.me.tsv"),
+        ("tests/test9.all.lpmd.tsv", "tests/test9.single.lpmd.tsv"),
+        (
+            "tests/test9.all.lpmd.pairs.tsv",
+            "tests/test9.single.lpmd.pairs.tsv",
+        ),
+    ] {
+        let all = std::fs::read(all)?;
+        assert!(!all.is_empty());
+        assert_eq!(all, std::fs::read(single)?);
+    }
+
+    Ok(())
+}
+#[test]
+fn no_output_specified() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("all")
+        .arg("-i")
+        .arg("tests/test1.bam")
+        .assert()
+        .failure()
+        .code(2)
+        .stderr(predicate::str::contains("required"));
+
+    Ok(())
+}
+#[test]
+fn lpmd_pairs_requires_lpmd() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("all")
+        .arg("-i")
+        .arg("tests/test1.bam")
+        .arg("--pdr")
+        .arg("tests/test1.all.pdr.tsv")
+        .arg("--lpmd-pairs")
+        .arg("tests/test1.all.lpmd.pairs.tsv")
+        .assert()
+        .failure()
+        .code(2);
+
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use assert_cmd::prelude::*; // Add methods on commands
+use predicates::prelude::*; // Used for writing assertions
+use std::fs;
+use std::process::Command; // Run programs
+
+#[test]
+fn reference_caller_matches_xm_tags() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test1.bam")
+        .arg("-o")
+        .arg("tests/test1.caller.pdr.tsv")
+        .arg("-d")
+        .arg("1")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test8.sam")
+        .arg("-o")
+        .arg("tests/test8.caller.pdr.tsv")
+        .arg("-d")
+        .arg("1")
+        .arg("--caller")
+        .arg("reference")
+        .arg("--genome")
+        .arg("tests/test8.fa")
+        .assert()
+        .success();
+
+    let expected = fs::read_to_string("tests/test1.caller.pdr.tsv")?;
+    let generated = fs::read_to_string("tests/test8.caller.pdr.tsv")?;
+    assert_eq!(expected, generated);
+
+    Ok(())
+}
+#[test]
+fn reference_caller_requires_genome() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test8.sam")
+        .arg("-o")
+        .arg("tests/test8.caller.pdr.tsv")
+        .arg("--caller")
+        .arg("reference")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--genome"));
+
+    Ok(())
+}
@@ -210,6 +210,27 @@ mod cli_error_tests {
         Ok(())
     }
 
+    #[test]
+    fn test_region_with_unknown_contig() -> Result<(), Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("metheor")?;
+        cmd.arg("pdr")
+            .arg("--input")
+            .arg("tests/test4.bam")
+            .arg("--output")
+            .arg("test_output.tsv")
+            .arg("--region")
+            .arg("chrZ:1-10");
+
+        cmd.assert()
+            .failure()
+            .code(13)
+            .stderr(predicate::str::contains(
+                "Error: Contig not found in BAM header: chrZ",
+            ));
+
+        Ok(())
+    }
+
     #[test]
     fn test_invalid_bam_file_format() -> Result<(), Box<dyn std::error::Error>> {
         let mut cmd = Command::cargo_bin("metheor")?;
@@ -221,7 +242,8 @@ mod cli_error_tests {
 
         cmd.assert()
             .failure()
-            .stderr(predicate::str::contains("Error opening BAM file"));
+            .code(4)
+            .stderr(predicate::str::starts_with("Error: Cannot open BAM file"));
 
         Ok(())
     }
@@ -237,7 +259,10 @@ mod cli_error_tests {
             .arg("--cpg-set")
             .arg("nonexistent.bed");
 
-        cmd.assert().failure();
+        cmd.assert()
+            .failure()
+            .code(3)
+            .stderr(predicate::str::contains("nonexistent.bed"));
 
         Ok(())
     }
@@ -0,0 +1,94 @@
+use assert_cmd::prelude::*; // Add methods on commands
+use std::fs;
+use std::process::Command; // Run programs
+
+#[test]
+fn cram_input_with_reference() -> Result<(), Box<dyn std::error::Error>> {
+    for (input, output) in [
+        ("tests/test8.sam", "tests/test8.sam.pdr.tsv"),
+        ("tests/test8.cram", "tests/test8.cram.pdr.tsv"),
+    ] {
+        let mut cmd = Command::cargo_bin("metheor")?;
+
+        cmd.arg("pdr")
+            .arg("-i")
+            .arg(input)
+            .arg("-o")
+            .arg(output)
+            .arg("-d")
+            .arg("1")
+            .arg("--caller")
+            .arg("reference")
+            .arg("--genome")
+            .arg("tests/test8.fa")
+            .arg("--reference")
+            .arg("tests/test8.fa")
+            .assert()
+            .success();
+    }
+
+    let expected = fs::read_to_string("tests/test8.sam.pdr.tsv")?;
+    let generated = fs::read_to_string("tests/test8.cram.pdr.tsv")?;
+    assert!(!expected.is_empty());
+    assert_eq!(expected, generated);
+
+    Ok(())
+}
+#[test]
+fn cram_input_with_genome_as_reference() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    // The reference genome given by --genome is also used for decoding CRAM records.
+    cmd.arg("mhl")
+        .arg("-i")
+        .arg("tests/test8.cram")
+        .arg("-o")
+        .arg("tests/test8.cram.mhl.tsv")
+        .arg("-d")
+        .arg("1")
+        .arg("--caller")
+        .arg("reference")
+        .arg("--genome")
+        .arg("tests/test8.fa")
+        .assert()
+        .success();
+
+    let generated = fs::read_to_string("tests/test8.cram.mhl.tsv")?;
+    assert!(!generated.is_empty());
+
+    Ok(())
+}
+#[test]
+fn tag_cram_input() -> Result<(), Box<dyn std::error::Error>> {
+    for (input, output) in [
+        ("tests/test8.sam", "tests/test8.sam.tagged.sam"),
+        ("tests/test8.cram", "tests/test8.cram.tagged.sam"),
+    ] {
+        let mut cmd = Command::cargo_bin("metheor")?;
+
+        cmd.arg("tag")
+            .arg("-i")
+            .arg(input)
+            .arg("-o")
+            .arg(output)
+            .arg("-g")
+            .arg("tests/test8.fa")
+            .assert()
+            .success();
+    }
+
+    // Records decoded from CRAM additionally carry MD/NM tags, so only XM tags are compared.
+    let xm_tags = |path: &str| -> Result<Vec<String>, std::io::Error> {
+        Ok(fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.starts_with('@'))
+            .filter_map(|line| line.split('\t').find(|field| field.starts_with("XM:Z:")))
+            .map(|field| field.to_string())
+            .collect())
+    };
+    let expected = xm_tags("tests/test8.sam.tagged.sam")?;
+    assert_eq!(expected.len(), 16);
+    assert_eq!(expected, xm_tags("tests/test8.cram.tagged.sam")?);
+
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use assert_cmd::prelude::*; // Add methods on commands
+use predicates::prelude::*; // Used for writing assertions
+use std::process::Command; // Run programs
+
+#[test]
+fn diff_pdr_with_regions() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("diff")
+        .arg("-a")
+        .arg("tests/test1.bam")
+        .arg("tests/test4.bam")
+        .arg("-b")
+        .arg("tests/test2.bam")
+        .arg("-o")
+        .arg("tests/test1.diff.tsv")
+        .arg("--dhr")
+        .arg("tests/test1.dhr.tsv")
+        .assert()
+        .success();
+
+    let result = std::fs::read_to_string("tests/test1.diff.tsv")?;
+    let lines: Vec<&str> = result.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[1].starts_with("chr1\t0\t2\t4\t28\t16\t0\t0.875\t0\t-0.875\t"));
+
+    let result = std::fs::read_to_string("tests/test1.dhr.tsv")?;
+    let lines: Vec<&str> = result.lines().collect();
+    assert_eq!(
+        lines[0],
+        "chrom\tstart\tend\tn_sites\tmean_diff\tmin_qvalue"
+    );
+    assert!(lines[1].starts_with("chr1\t0\t8\t4\t-0.875\t"));
+
+    Ok(())
+}
+#[test]
+fn diff_pdr_tables() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test2.bam")
+        .arg("-o")
+        .arg("tests/test2.diff.pdr.tsv")
+        .arg("-d")
+        .arg("1")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("metheor")?;
+    cmd.arg("diff")
+        .arg("-a")
+        .arg("tests/test1.bam")
+        .arg("-b")
+        .arg("tests/test2.diff.pdr.tsv")
+        .arg("-o")
+        .arg("tests/test2.diff.tsv")
+        .arg("--test")
+        .arg("quasi-binomial")
+        .assert()
+        .success();
+
+    let result = std::fs::read_to_string("tests/test2.diff.tsv")?;
+    let lines: Vec<&str> = result.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[1].starts_with("chr1\t0\t2\t2\t14\t16\t0\t0.875\t0\t-0.875\t"));
+
+    Ok(())
+}
+#[test]
+fn diff_pdr_compressed_tables() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test2.bam")
+        .arg("-o")
+        .arg("tests/test2.diff.pdr.tsv.gz")
+        .arg("-d")
+        .arg("1")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("metheor")?;
+    cmd.arg("diff")
+        .arg("-a")
+        .arg("tests/test1.bam")
+        .arg("-b")
+        .arg("tests/test2.diff.pdr.tsv.gz")
+        .arg("-o")
+        .arg("tests/test2.diff.gz.tsv")
+        .arg("--test")
+        .arg("quasi-binomial")
+        .assert()
+        .success();
+
+    let result = std::fs::read_to_string("tests/test2.diff.gz.tsv")?;
+    let lines: Vec<&str> = result.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[1].starts_with("chr1\t0\t2\t2\t14\t16\t0\t0.875\t0\t-0.875\t"));
+
+    Ok(())
+}
+#[test]
+fn diff_malformed_table() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("diff")
+        .arg("-a")
+        .arg("tests/test1.bam")
+        .arg("-b")
+        .arg("tests/test2.malformed.pdr.tsv")
+        .arg("-o")
+        .arg("tests/test2.malformed.diff.tsv")
+        .assert()
+        .failure()
+        .code(18)
+        .stderr(predicate::str::contains("Malformed line in table"));
+
+    Ok(())
+}
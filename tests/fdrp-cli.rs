@@ -32,3 +32,31 @@ fn input_bam_doesnt_exist() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+#[test]
+fn run_with_threads() -> Result<(), Box<dyn std::error::Error>> {
+    for (threads, output) in [
+        ("1", "tests/test9.fdrp.tsv"),
+        ("2", "tests/test9.threads.fdrp.tsv"),
+    ] {
+        let mut cmd = Command::cargo_bin("metheor")?;
+
+        cmd.arg("fdrp")
+            .arg("-i")
+            .arg("tests/test9.bam")
+            .arg("-o")
+            .arg(output)
+            .arg("-d")
+            .arg("1")
+            .arg("--threads")
+            .arg(threads)
+            .assert()
+            .success();
+    }
+
+    let serial = std::fs::read("tests/test9.fdrp.tsv")?;
+    let threaded = std::fs::read("tests/test9.threads.fdrp.tsv")?;
+    assert!(!serial.is_empty());
+    assert_eq!(serial, threaded);
+
+    Ok(())
+}
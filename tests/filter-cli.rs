@@ -0,0 +1,105 @@
+use assert_cmd::prelude::*; // Add methods on commands
+use predicates::prelude::*; // Used for writing assertions
+use std::process::Command; // Run programs
+
+#[test]
+fn no_filters_by_default() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test10.sam")
+        .arg("-o")
+        .arg("tests/test10.unfiltered.pdr.tsv")
+        .arg("-d")
+        .arg("1")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Processed 10 reads, 10 passed the read filters.",
+        ));
+
+    let result = std::fs::read_to_string("tests/test10.unfiltered.pdr.tsv")?;
+    assert_eq!(result.lines().next(), Some("chr1\t0\t2\t0.6\t4\t6"));
+
+    Ok(())
+}
+#[test]
+fn skip_flagged_reads() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test10.sam")
+        .arg("-o")
+        .arg("tests/test10.filtered.pdr.tsv")
+        .arg("-d")
+        .arg("1")
+        .arg("--skip-duplicates")
+        .arg("--skip-secondary")
+        .arg("--skip-supplementary")
+        .arg("--skip-qc-fail")
+        .arg("--require-proper-pair")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Processed 10 reads, 4 passed the read filters.",
+        ))
+        .stderr(predicate::str::contains("Filtered 2 duplicates"))
+        .stderr(predicate::str::contains("Filtered 1 secondary alignments"))
+        .stderr(predicate::str::contains(
+            "Filtered 1 supplementary alignments",
+        ))
+        .stderr(predicate::str::contains(
+            "Filtered 1 reads failing quality checks",
+        ))
+        .stderr(predicate::str::contains(
+            "Filtered 1 reads not mapped in a proper pair",
+        ));
+
+    let result = std::fs::read_to_string("tests/test10.filtered.pdr.tsv")?;
+    assert_eq!(result.lines().next(), Some("chr1\t0\t2\t0\t4\t0"));
+
+    Ok(())
+}
+#[test]
+fn exclude_flags_in_lpmd() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("lpmd")
+        .arg("-i")
+        .arg("tests/test10.sam")
+        .arg("-o")
+        .arg("tests/test10.lpmd.tsv")
+        .arg("--exclude-flags")
+        .arg("0xF00")
+        .arg("--require-proper-pair")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Filtered 5 reads with --exclude-flags",
+        ));
+
+    let result = std::fs::read_to_string("tests/test10.lpmd.tsv")?;
+    assert_eq!(result.lines().nth(1), Some("tests/test10.sam\t0"));
+
+    Ok(())
+}
+#[test]
+fn invalid_flags() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test10.sam")
+        .arg("-o")
+        .arg("tests/test10.pdr.tsv")
+        .arg("--include-flags")
+        .arg("paired")
+        .assert()
+        .failure()
+        .code(2)
+        .stderr(predicate::str::contains("invalid SAM flags"));
+
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use assert_cmd::prelude::*; // Add methods on commands
+use std::process::Command; // Run programs
+
+#[test]
+fn mates_counted_separately_by_default() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test11.sam")
+        .arg("-o")
+        .arg("tests/test11.pdr.tsv")
+        .arg("-d")
+        .arg("1")
+        .assert()
+        .success();
+
+    let result = std::fs::read_to_string("tests/test11.pdr.tsv")?;
+    let lines: Vec<&str> = result.lines().collect();
+    assert_eq!(lines[2], "chr1\t4\t6\t0.16666667\t5\t1");
+
+    Ok(())
+}
+#[test]
+fn merge_mates() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test11.sam")
+        .arg("-o")
+        .arg("tests/test11.merged.pdr.tsv")
+        .arg("-d")
+        .arg("1")
+        .arg("--merge-mates")
+        .assert()
+        .success();
+
+    // The CpG at 6 called differently by the mates of pair_2 is discarded from the fragment.
+    let result = std::fs::read_to_string("tests/test11.merged.pdr.tsv")?;
+    let lines: Vec<&str> = result.lines().collect();
+    assert_eq!(lines[2], "chr1\t4\t6\t0\t3\t0");
+    assert_eq!(lines[3], "chr1\t6\t8\t0\t2\t0");
+
+    Ok(())
+}
+#[test]
+fn merge_mates_keeping_second_call() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test11.sam")
+        .arg("-o")
+        .arg("tests/test11.merged.second.pdr.tsv")
+        .arg("-d")
+        .arg("1")
+        .arg("--merge-mates")
+        .arg("--mate-conflict")
+        .arg("second")
+        .assert()
+        .success();
+
+    let result = std::fs::read_to_string("tests/test11.merged.second.pdr.tsv")?;
+    let lines: Vec<&str> = result.lines().collect();
+    assert_eq!(lines[2], "chr1\t4\t6\t0.33333334\t2\t1");
+
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use assert_cmd::prelude::*; // Add methods on commands
+use predicates::prelude::*; // Used for writing assertions
+use std::process::Command; // Run programs
+
+#[test]
+fn pdr_by_cell_barcode() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test12.sam")
+        .arg("-o")
+        .arg("tests/test12.pdr.tsv")
+        .arg("-d")
+        .arg("1")
+        .arg("--group-by-tag")
+        .arg("CB")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Filtered 1 reads without --group-by-tag",
+        ));
+
+    let result = std::fs::read_to_string("tests/test12.pdr.tsv")?;
+    let lines: Vec<&str> = result.lines().collect();
+    assert_eq!(lines.len(), 8);
+    assert_eq!(lines[0], "AAAC\tchr1\t0\t2\t0\t3\t0");
+    assert_eq!(lines[4], "AAAG\tchr1\t0\t2\t0.6666667\t1\t2");
+
+    Ok(())
+}
+#[test]
+fn lpmd_by_cell_barcode() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("lpmd")
+        .arg("-i")
+        .arg("tests/test12.sam")
+        .arg("-o")
+        .arg("tests/test12.lpmd.tsv")
+        .arg("-p")
+        .arg("tests/test12.lpmd.pairs.tsv")
+        .arg("--group-by-tag")
+        .arg("CB")
+        .assert()
+        .success();
+
+    let result = std::fs::read_to_string("tests/test12.lpmd.tsv")?;
+    let lines: Vec<&str> = result.lines().collect();
+    assert_eq!(
+        lines,
+        vec![
+            "group\tname\tlpmd",
+            "AAAC\ttests/test12.sam\t0",
+            "AAAG\ttests/test12.sam\t0.44444445",
+        ]
+    );
+
+    let pairs = std::fs::read_to_string("tests/test12.lpmd.pairs.tsv")?;
+    assert!(pairs.starts_with("group\tchrom\tcpg1\tcpg2\tlpmd\tn_concordant\tn_discordant\nAAAC\t"));
+
+    Ok(())
+}
+#[test]
+fn invalid_tag() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test12.sam")
+        .arg("-o")
+        .arg("tests/test12.pdr.tsv")
+        .arg("--group-by-tag")
+        .arg("cell")
+        .assert()
+        .failure()
+        .code(2)
+        .stderr(predicate::str::contains("invalid aux tag"));
+
+    Ok(())
+}
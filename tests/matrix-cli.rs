@@ -0,0 +1,88 @@
+use assert_cmd::prelude::*; // Add methods on commands
+use predicates::prelude::*; // Used for writing assertions
+use std::process::Command; // Run programs
+
+#[test]
+fn pdr_matrix() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("matrix")
+        .arg("-s")
+        .arg("tests/samples.tsv")
+        .arg("-o")
+        .arg("tests/samples.pdr.tsv")
+        .arg("-d")
+        .arg("1")
+        .arg("-t")
+        .arg("2")
+        .assert()
+        .success();
+
+    let result = std::fs::read_to_string("tests/samples.pdr.tsv")?;
+    let lines: Vec<&str> = result.lines().collect();
+    assert_eq!(lines.len(), 9);
+    assert_eq!(lines[0], "chrom\tstart\tend\tA\tB\tC");
+    assert_eq!(lines[1], "chr1\t0\t2\t0.875\t0\t0.875");
+
+    Ok(())
+}
+#[test]
+fn pdr_matrix_with_zero_threads() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("matrix")
+        .arg("-s")
+        .arg("tests/samples.tsv")
+        .arg("-o")
+        .arg("tests/samples.pdr.zero_threads.tsv")
+        .arg("-d")
+        .arg("1")
+        .arg("-t")
+        .arg("0")
+        .assert()
+        .success();
+
+    let result = std::fs::read_to_string("tests/samples.pdr.zero_threads.tsv")?;
+    assert_eq!(result.lines().count(), 9);
+
+    Ok(())
+}
+#[test]
+fn pm_matrix_with_missing_values() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("matrix")
+        .arg("-s")
+        .arg("tests/samples.tsv")
+        .arg("-o")
+        .arg("tests/samples.pm.tsv")
+        .arg("--metric")
+        .arg("pm")
+        .arg("-d")
+        .arg("1")
+        .assert()
+        .success();
+
+    let result = std::fs::read_to_string("tests/samples.pm.tsv")?;
+    let lines: Vec<&str> = result.lines().collect();
+    assert_eq!(lines[0], "chrom\tcpg1\tcpg2\tcpg3\tcpg4\tA\tB\tC");
+    assert_eq!(lines[2], "chr1\t13\t15\t17\t19\tNA\tNA\t0.9375");
+
+    Ok(())
+}
+#[test]
+fn malformed_sample_sheet() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("matrix")
+        .arg("-s")
+        .arg("tests/samples.malformed.tsv")
+        .arg("-o")
+        .arg("tests/samples.malformed.out.tsv")
+        .assert()
+        .failure()
+        .code(17)
+        .stderr(predicate::str::contains("Malformed line in sample sheet"));
+
+    Ok(())
+}
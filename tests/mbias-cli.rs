@@ -0,0 +1,49 @@
+use assert_cmd::prelude::*; // Add methods on commands
+use std::process::Command; // Run programs
+
+#[test]
+fn simple_run() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("mbias")
+        .arg("-i")
+        .arg("tests/test11.sam")
+        .arg("-o")
+        .arg("tests/test11.mbias.tsv")
+        .assert()
+        .success();
+
+    let result = std::fs::read_to_string("tests/test11.mbias.tsv")?;
+    let lines: Vec<&str> = result.lines().collect();
+    assert_eq!(
+        lines[0],
+        "read\tposition\tn_methylated\tn_unmethylated\tbeta"
+    );
+    assert_eq!(lines[1], "R1\t1\t3\t0\t1");
+    assert_eq!(lines[7], "R2\t6\t2\t1\t0.6666667");
+
+    Ok(())
+}
+#[test]
+fn ignore_ends_of_reads() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("mbias")
+        .arg("-i")
+        .arg("tests/test11.sam")
+        .arg("-o")
+        .arg("tests/test11.mbias.ignored.tsv")
+        .arg("--ignore-5p")
+        .arg("1")
+        .arg("--ignore-5p-r2")
+        .arg("2")
+        .assert()
+        .success();
+
+    let result = std::fs::read_to_string("tests/test11.mbias.ignored.tsv")?;
+    let lines: Vec<&str> = result.lines().collect();
+    assert_eq!(lines[1], "R1\t3\t3\t0\t1");
+    assert_eq!(lines[4], "R2\t4\t3\t0\t1");
+
+    Ok(())
+}
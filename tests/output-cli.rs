@@ -0,0 +1,353 @@
+use assert_cmd::prelude::*; // Add methods on commands
+use rust_htslib::tbx::{self, Read};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::process::Command; // Run programs
+
+#[test]
+fn compressed_and_indexed_output() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test1.bam")
+        .arg("-o")
+        .arg("tests/test1.output.pdr.tsv")
+        .arg("-d")
+        .arg("1")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("metheor")?;
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test1.bam")
+        .arg("-o")
+        .arg("tests/test1.output.pdr.tsv.gz")
+        .arg("-d")
+        .arg("1")
+        .assert()
+        .success();
+
+    assert!(std::path::Path::new("tests/test1.output.pdr.tsv.gz.tbi").exists());
+
+    let expected = std::fs::read_to_string("tests/test1.output.pdr.tsv")?;
+    let expected: Vec<&str> = expected.lines().collect();
+    assert!(!expected.is_empty());
+
+    // Query the whole chromosome through the tabix index.
+    let mut reader = tbx::Reader::from_path("tests/test1.output.pdr.tsv.gz")?;
+    let tid = reader.tid("chr1")?;
+    reader.fetch(tid, 0, i64::MAX as u64)?;
+    let mut lines: Vec<String> = Vec::new();
+    for record in reader.records() {
+        lines.push(String::from_utf8(record?)?);
+    }
+    assert_eq!(lines, expected);
+
+    Ok(())
+}
+#[test]
+fn compressed_table_with_header() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+    cmd.arg("aggregate")
+        .arg("-i")
+        .arg("tests/test4.bam")
+        .arg("-b")
+        .arg("tests/test4.named.bed")
+        .arg("-o")
+        .arg("tests/test4.output.aggregate.tsv.gz")
+        .assert()
+        .success();
+
+    let mut reader = tbx::Reader::from_path("tests/test4.output.aggregate.tsv.gz")?;
+    let tid = reader.tid("chr1")?;
+    reader.fetch(tid, 13, 14)?;
+    let lines: Vec<Vec<u8>> = reader.records().collect::<Result<_, _>>()?;
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with(b"chr1\t13\t21\tregion1\t"));
+
+    Ok(())
+}
+#[test]
+fn bigwig_output() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test1.bam")
+        .arg("-o")
+        .arg("tests/test1.output.bigwig.tsv")
+        .arg("-d")
+        .arg("1")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("metheor")?;
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test1.bam")
+        .arg("-o")
+        .arg("tests/test1.output.pdr.bw")
+        .arg("-d")
+        .arg("1")
+        .assert()
+        .success();
+
+    let bw = std::fs::read("tests/test1.output.pdr.bw")?;
+    assert_eq!(u32_at(&bw, 0), 0x888F_FC26);
+    assert_eq!(u32_at(&bw, bw.len() - 4), 0x888F_FC26);
+    let n_zoom_levels = u16_at(&bw, 6) as usize;
+    assert!(n_zoom_levels > 0);
+
+    let mut chroms: Vec<(String, u32)> = Vec::new();
+    read_chrom_tree(
+        &bw,
+        u64_at(&bw, 8) + 32,
+        u32_at(&bw, u64_at(&bw, 8) + 8) as usize,
+        &mut chroms,
+    );
+    assert!(chroms.iter().any(|(chrom, _)| chrom == "chr1"));
+
+    let table = std::fs::read_to_string("tests/test1.output.bigwig.tsv")?;
+    let expected: Vec<(u32, u32, u32, f32)> = table
+        .lines()
+        .map(|line| {
+            let tokens: Vec<&str> = line.split('\t').collect();
+            let (_, chrom_id) = chroms.iter().find(|(chrom, _)| chrom == tokens[0]).unwrap();
+            (
+                *chrom_id,
+                tokens[1].parse().unwrap(),
+                tokens[2].parse().unwrap(),
+                tokens[3].parse().unwrap(),
+            )
+        })
+        .collect();
+
+    // Read the intervals back from the (uncompressed) data sections indexed by the R-tree.
+    let mut intervals: Vec<(u32, u32, u32, f32)> = Vec::new();
+    for (offset, _) in read_r_tree(&bw, u64_at(&bw, 24)) {
+        let chrom_id = u32_at(&bw, offset);
+        assert_eq!(bw[offset + 20], 1); // bedGraph section.
+        let n_items = u16_at(&bw, offset + 22) as usize;
+        for item in 0..n_items {
+            let item_offset = offset + 24 + item * 12;
+            intervals.push((
+                chrom_id,
+                u32_at(&bw, item_offset),
+                u32_at(&bw, item_offset + 4),
+                f32_at(&bw, item_offset + 8),
+            ));
+        }
+    }
+    assert_eq!(intervals, expected);
+
+    // Read back the records of the first zoom level and compare them with the intervals
+    // summarized in bins of the reduction level.
+    let reduction = u32_at(&bw, 64);
+    let mut records: Vec<ZoomRecord> = Vec::new();
+    for (offset, size) in read_r_tree(&bw, u64_at(&bw, 64 + 16)) {
+        for record_offset in (offset..offset + size).step_by(32) {
+            records.push((
+                u32_at(&bw, record_offset),
+                u32_at(&bw, record_offset + 4),
+                u32_at(&bw, record_offset + 8),
+                u32_at(&bw, record_offset + 12),
+                f32_at(&bw, record_offset + 16),
+                f32_at(&bw, record_offset + 20),
+                f32_at(&bw, record_offset + 24),
+            ));
+        }
+    }
+    assert_eq!(u32_at(&bw, u64_at(&bw, 64 + 8)) as usize, records.len());
+
+    let mut bins: BTreeMap<(u32, u32), ZoomRecord> = BTreeMap::new();
+    for (chrom_id, start, end, value) in expected.iter().copied() {
+        for bin in start / reduction..=(end - 1) / reduction {
+            let bin_start = (bin * reduction).max(start);
+            let bin_end = ((bin + 1) * reduction).min(end);
+            let sum = value * (bin_end - bin_start) as f32;
+            bins.entry((chrom_id, bin))
+                .and_modify(|r| {
+                    r.1 = r.1.min(bin_start);
+                    r.2 = r.2.max(bin_end);
+                    r.3 += bin_end - bin_start;
+                    r.4 = r.4.min(value);
+                    r.5 = r.5.max(value);
+                    r.6 += sum;
+                })
+                .or_insert((
+                    chrom_id,
+                    bin_start,
+                    bin_end,
+                    bin_end - bin_start,
+                    value,
+                    value,
+                    sum,
+                ));
+        }
+    }
+    let expected_records: Vec<_> = bins.into_values().collect();
+    assert_eq!(records.len(), expected_records.len());
+    for (record, expected) in records.iter().zip(expected_records.iter()) {
+        let (chrom_id, start, end, valid_count, min, max, sum) = *record;
+        assert_eq!(
+            (chrom_id, start, end, valid_count, min, max),
+            (expected.0, expected.1, expected.2, expected.3, expected.4, expected.5)
+        );
+        assert!((sum - expected.6).abs() < 1e-3);
+    }
+
+    Ok(())
+}
+
+/// Chromosome index, start, end, number of bases, minimum, maximum and sum of a zoom record.
+type ZoomRecord = (u32, u32, u32, u32, f32, f32, f32);
+
+fn u16_at(bw: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes(bw[i..i + 2].try_into().unwrap())
+}
+
+fn u32_at(bw: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes(bw[i..i + 4].try_into().unwrap())
+}
+
+fn u64_at(bw: &[u8], i: usize) -> usize {
+    u64::from_le_bytes(bw[i..i + 8].try_into().unwrap()) as usize
+}
+
+fn f32_at(bw: &[u8], i: usize) -> f32 {
+    f32::from_le_bytes(bw[i..i + 4].try_into().unwrap())
+}
+
+/// Walks the node of the chromosome B+ tree at `offset`, and collects the names and indices of
+/// the chromosomes.
+fn read_chrom_tree(bw: &[u8], offset: usize, key_size: usize, chroms: &mut Vec<(String, u32)>) {
+    let is_leaf = bw[offset] == 1;
+    let count = u16_at(bw, offset + 2) as usize;
+    for item in 0..count {
+        let item_offset = offset + 4 + item * (key_size + 8);
+        let key_offset = item_offset + key_size;
+        if is_leaf {
+            let key = &bw[item_offset..key_offset];
+            let name = String::from_utf8(key.iter().copied().take_while(|&b| b != 0).collect());
+            chroms.push((name.unwrap(), u32_at(bw, key_offset)));
+        } else {
+            read_chrom_tree(bw, u64_at(bw, key_offset), key_size, chroms);
+        }
+    }
+}
+
+/// Walks the R-tree at `offset`, and returns the offsets and sizes of the blocks it indexes.
+fn read_r_tree(bw: &[u8], offset: usize) -> Vec<(usize, usize)> {
+    assert_eq!(u32_at(bw, offset), 0x2468_ACE0);
+    let mut blocks = Vec::new();
+    read_r_tree_node(bw, offset + 48, &mut blocks);
+    assert_eq!(u64_at(bw, offset + 8), blocks.len());
+
+    blocks
+}
+
+fn read_r_tree_node(bw: &[u8], offset: usize, blocks: &mut Vec<(usize, usize)>) {
+    let is_leaf = bw[offset] == 1;
+    let count = u16_at(bw, offset + 2) as usize;
+    for item in 0..count {
+        if is_leaf {
+            let item_offset = offset + 4 + item * 32;
+            blocks.push((u64_at(bw, item_offset + 16), u64_at(bw, item_offset + 24)));
+        } else {
+            let item_offset = offset + 4 + item * 24;
+            read_r_tree_node(bw, u64_at(bw, item_offset + 16), blocks);
+        }
+    }
+}
+#[test]
+fn table_with_header() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test1.bam")
+        .arg("-o")
+        .arg("tests/test1.output.header.pdr.tsv")
+        .arg("-d")
+        .arg("1")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("metheor")?;
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test1.bam")
+        .arg("-o")
+        .arg("tests/test1.output.header.pdr.header.tsv")
+        .arg("-d")
+        .arg("1")
+        .arg("--header")
+        .assert()
+        .success();
+
+    let table = std::fs::read_to_string("tests/test1.output.header.pdr.tsv")?;
+    let with_header = std::fs::read_to_string("tests/test1.output.header.pdr.header.tsv")?;
+    assert_eq!(
+        with_header,
+        format!(
+            "chrom\tstart\tend\tpdr\tn_concordant\tn_discordant\n{}",
+            table
+        )
+    );
+
+    let mut cmd = Command::cargo_bin("metheor")?;
+    cmd.arg("pm")
+        .arg("-i")
+        .arg("tests/test1.bam")
+        .arg("-o")
+        .arg("tests/test1.output.header.pm.tsv")
+        .arg("-d")
+        .arg("1")
+        .arg("--header")
+        .assert()
+        .success();
+
+    let table = std::fs::read_to_string("tests/test1.output.header.pm.tsv")?;
+    assert_eq!(
+        table.lines().next(),
+        Some("chrom\tcpg1\tcpg2\tcpg3\tcpg4\tpm")
+    );
+
+    Ok(())
+}
+#[test]
+fn run_manifest() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test1.bam")
+        .arg("-o")
+        .arg("tests/test1.output.manifest.pdr.tsv")
+        .arg("-d")
+        .arg("1")
+        .arg("--manifest")
+        .arg("tests/test1.output.manifest.json")
+        .assert()
+        .success();
+
+    let manifest = std::fs::read_to_string("tests/test1.output.manifest.json")?;
+    assert!(manifest.contains(&format!(
+        "\"metheor_version\": \"{}\"",
+        env!("CARGO_PKG_VERSION")
+    )));
+    assert!(manifest.contains("\"command\": \"pdr\""));
+    assert!(manifest.contains("\"min_depth\": 1,"));
+    assert!(manifest.contains("\"min_cpgs\": 4,"));
+    assert!(manifest.contains("\"header\": false"));
+    assert!(manifest.contains("\"min_qual\": 10,"));
+    assert!(manifest.contains("\"cpg_set\": null"));
+    assert!(manifest.contains("\"output\": \"tests/test1.output.manifest.pdr.tsv\""));
+    assert!(manifest.contains("\"path\": \"tests/test1.bam\""));
+    assert!(manifest.contains(&format!(
+        "\"size\": {}",
+        std::fs::metadata("tests/test1.bam")?.len()
+    )));
+    assert!(manifest.contains("\"reads\": {\"total\": "));
+    assert!(manifest.contains("\"wall_time_seconds\": "));
+
+    Ok(())
+}
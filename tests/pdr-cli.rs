@@ -32,3 +32,53 @@ fn input_bam_doesnt_exist() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+#[test]
+fn run_with_regions_bed() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("pdr")
+        .arg("-i")
+        .arg("tests/test4.bam")
+        .arg("-o")
+        .arg("tests/test4.region.pdr.tsv")
+        .arg("-d")
+        .arg("1")
+        .arg("--regions")
+        .arg("tests/test4.regions.bed")
+        .assert()
+        .success();
+
+    let generated = std::fs::read_to_string("tests/test4.region.pdr.tsv")?;
+    assert_eq!(generated.lines().count(), 4);
+    assert!(generated.lines().all(|line| line.starts_with("chr1\t1")));
+
+    Ok(())
+}
+#[test]
+fn run_with_threads() -> Result<(), Box<dyn std::error::Error>> {
+    for (threads, output) in [
+        ("1", "tests/test9.pdr.tsv"),
+        ("2", "tests/test9.threads.pdr.tsv"),
+    ] {
+        let mut cmd = Command::cargo_bin("metheor")?;
+
+        cmd.arg("pdr")
+            .arg("-i")
+            .arg("tests/test9.bam")
+            .arg("-o")
+            .arg(output)
+            .arg("-d")
+            .arg("1")
+            .arg("--threads")
+            .arg(threads)
+            .assert()
+            .success();
+    }
+
+    let serial = std::fs::read("tests/test9.pdr.tsv")?;
+    let threaded = std::fs::read("tests/test9.threads.pdr.tsv")?;
+    assert!(!serial.is_empty());
+    assert_eq!(serial, threaded);
+
+    Ok(())
+}
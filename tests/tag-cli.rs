@@ -1,8 +1,29 @@
 use assert_cmd::prelude::*; // Add methods on commands
 use predicates::prelude::*; // Used for writing assertions
+use rust_htslib::bam::{self, record::Aux, Read};
 use std::fs;
 use std::process::Command; // Run programs
 
+/// Values of the string aux tag `tag` of the alignments in `path`.
+fn string_tags(path: &str, tag: &[u8]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut reader = bam::Reader::from_path(path)?;
+    let mut tags = Vec::new();
+    for r in reader.records() {
+        match r?.aux(tag)? {
+            Aux::String(value) => tags.push(value.to_string()),
+            _ => return Err("aux tag is not a string".into()),
+        }
+    }
+    Ok(tags)
+}
+
+/// Lines of a SAM file, except the `@PG` line added by metheor.
+fn without_metheor_program(sam: &str) -> Vec<&str> {
+    sam.lines()
+        .filter(|line| !line.starts_with("@PG\tID:metheor"))
+        .collect()
+}
+
 #[test]
 fn input_file_doesnt_exist() -> Result<(), Box<dyn std::error::Error>> {
     let mut cmd = Command::cargo_bin("metheor")?;
@@ -74,7 +95,208 @@ fn test_whether_xmtag_generated_correctly_for_1000_chr19_reads(
 
     let original = fs::read_to_string("tests/test.chr19.XM.sam")?;
     let generated = fs::read_to_string("tests/test.chr19.metheor_tag_out.sam")?;
-    assert_eq!(original, generated);
+    assert_eq!(
+        original.lines().collect::<Vec<_>>(),
+        without_metheor_program(&generated)
+    );
+    assert!(generated.contains("@PG\tID:metheor\tPN:metheor\tPP:samtools\t"));
+
+    Ok(())
+}
+#[test]
+fn test_whether_xmtag_generated_correctly_with_multiple_threads(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("tag")
+        .arg("-i")
+        .arg("tests/test.chr19.noXM.sam")
+        .arg("-o")
+        .arg("tests/test.chr19.metheor_tag_out.threads.sam")
+        .arg("-g")
+        .arg("tests/hg38.chr19.fa")
+        .arg("-t")
+        .arg("2")
+        .assert()
+        .success();
+
+    let original = fs::read_to_string("tests/test.chr19.XM.sam")?;
+    let generated = fs::read_to_string("tests/test.chr19.metheor_tag_out.threads.sam")?;
+    assert_eq!(
+        original.lines().collect::<Vec<_>>(),
+        without_metheor_program(&generated)
+    );
+
+    Ok(())
+}
+#[test]
+fn xr_and_xg_tags_same_as_bismark() -> Result<(), Box<dyn std::error::Error>> {
+    // Records already tagged by Bismark get their XM tag replaced rather than duplicated.
+    for (input, output) in [
+        (
+            "tests/test.chr19.noXMXRXG.sam",
+            "tests/test.chr19.metheor_tag_out.XRXG.sam",
+        ),
+        (
+            "tests/test.chr19.XM.sam",
+            "tests/test.chr19.metheor_tag_out.retag.sam",
+        ),
+    ] {
+        let mut cmd = Command::cargo_bin("metheor")?;
+
+        cmd.arg("tag")
+            .arg("-i")
+            .arg(input)
+            .arg("-o")
+            .arg(output)
+            .arg("-g")
+            .arg("tests/hg38.chr19.fa")
+            .assert()
+            .success();
+
+        for tag in [b"XM", b"XR", b"XG"] {
+            assert_eq!(
+                string_tags("tests/test.chr19.XM.sam", tag)?,
+                string_tags(output, tag)?
+            );
+        }
+        let sam = fs::read_to_string(output)?;
+        for line in sam.lines().filter(|line| !line.starts_with('@')) {
+            assert_eq!(line.matches("\tXM:Z:").count(), 1);
+        }
+    }
+
+    Ok(())
+}
+#[test]
+fn bam_output_by_extension() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("tag")
+        .arg("-i")
+        .arg("tests/test.chr19.noXM.sam")
+        .arg("-o")
+        .arg("tests/test.chr19.metheor_tag_out.bam")
+        .arg("-g")
+        .arg("tests/hg38.chr19.fa")
+        .arg("--compression-level")
+        .arg("1")
+        .assert()
+        .success();
+
+    // BGZF-compressed, not SAM text.
+    let generated = fs::read("tests/test.chr19.metheor_tag_out.bam")?;
+    assert_eq!(&generated[..2], &[0x1f, 0x8b]);
+
+    assert_eq!(
+        string_tags("tests/test.chr19.XM.sam", b"XM")?,
+        string_tags("tests/test.chr19.metheor_tag_out.bam", b"XM")?
+    );
+
+    Ok(())
+}
+#[test]
+fn invalid_compression_level() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("tag")
+        .arg("-i")
+        .arg("tests/test.chr19.noXM.sam")
+        .arg("-o")
+        .arg("tests/out.bam")
+        .arg("-g")
+        .arg("tests/hg38.chr19.fa")
+        .arg("--compression-level")
+        .arg("10")
+        .assert()
+        .failure()
+        .code(2);
+
+    Ok(())
+}
+#[test]
+fn invalid_library() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("tag")
+        .arg("-i")
+        .arg("tests/test.chr19.noXM.sam")
+        .arg("-o")
+        .arg("tests/out.bam")
+        .arg("-g")
+        .arg("tests/hg38.chr19.fa")
+        .arg("--library")
+        .arg("undirectional")
+        .assert()
+        .failure()
+        .code(2);
+
+    Ok(())
+}
+#[test]
+fn unmapped_reads_written_unchanged() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("tag")
+        .arg("-i")
+        .arg("tests/tinyref.sam")
+        .arg("-o")
+        .arg("tests/tinyref.metheor_tag_out.sam")
+        .arg("-g")
+        .arg("tests/tinyref.fa")
+        .assert()
+        .success();
+
+    let mut reader = bam::Reader::from_path("tests/tinyref.metheor_tag_out.sam")?;
+    let records = reader.records().collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(records.len(), 3);
+    for r in &records[..2] {
+        for tag in [b"XM", b"XR", b"XG"] {
+            assert!(r.aux(tag).is_ok());
+        }
+    }
+    let unmapped = &records[2];
+    assert_eq!(unmapped.qname(), b"r3");
+    assert!(unmapped.is_unmapped());
+    assert_eq!(unmapped.seq().as_bytes(), b"ACGTACGT");
+    for tag in [b"XM", b"XR", b"XG"] {
+        assert!(unmapped.aux(tag).is_err());
+    }
+
+    Ok(())
+}
+#[test]
+fn non_directional_library() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("metheor")?;
+
+    cmd.arg("tag")
+        .arg("-i")
+        .arg("tests/tinyref.sam")
+        .arg("-o")
+        .arg("tests/tinyref.metheor_tag_out.non_directional.sam")
+        .arg("-g")
+        .arg("tests/tinyref.fa")
+        .arg("--library")
+        .arg("non-directional")
+        .assert()
+        .success();
+
+    // The reverse read with C-to-T conversions is from the strand complementary to the original
+    // top strand, and the forward read with G-to-A conversions from that complementary to the
+    // original bottom strand.
+    let mut reader = bam::Reader::from_path("tests/tinyref.metheor_tag_out.non_directional.sam")?;
+    let records = reader.records().collect::<Result<Vec<_>, _>>()?;
+    let string_tag = |r: &bam::Record, tag: &[u8]| match r.aux(tag) {
+        Ok(Aux::String(value)) => value.to_string(),
+        _ => String::new(),
+    };
+    assert_eq!(string_tag(&records[0], b"XM"), "z....z....z.z.z.....");
+    for (r, expected) in records.iter().zip([("GA", "CT"), ("GA", "GA")]) {
+        assert_eq!(
+            (string_tag(r, b"XR"), string_tag(r, b"XG")),
+            (expected.0.to_string(), expected.1.to_string())
+        );
+    }
 
     Ok(())
 }
@@ -0,0 +1,132 @@
+use metheor::{fdrp, lpmd, me, mhl, pdr, pm, qfdrp, readutil};
+
+#[cfg(test)]
+mod api_tests {
+    use super::*;
+
+    #[test]
+    fn test_pdr_records() {
+        let params = pdr::PdrParams {
+            min_depth: 0,
+            min_cpgs: 0,
+            ..Default::default()
+        };
+        let records = pdr::compute_records("tests/test1.bam", &params).unwrap();
+
+        assert_eq!(records.len(), 4);
+        assert_eq!(
+            records[0],
+            pdr::PdrRecord {
+                chrom: "chr1".to_string(),
+                start: 0,
+                end: 2,
+                pdr: 14.0 / 16.0,
+                n_concordant: 2,
+                n_discordant: 14,
+            }
+        );
+        assert_eq!(records[0].to_string(), "chr1\t0\t2\t0.875\t2\t14");
+    }
+
+    #[test]
+    fn test_default_params_filter_low_depth() {
+        // test1.bam has 16 reads, test3.bam has only 2.
+        let params = pdr::PdrParams::default();
+        assert_eq!(
+            pdr::compute_records("tests/test1.bam", &params)
+                .unwrap()
+                .len(),
+            4
+        );
+        assert!(pdr::compute_records("tests/test3.bam", &params)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_quartet_records() {
+        let pm_records = pm::compute_records("tests/test4.bam", &pm::PmParams::default()).unwrap();
+        let me_records = me::compute_records("tests/test4.bam", &me::MeParams::default()).unwrap();
+
+        // Quartets are sorted by position.
+        let cpg1: Vec<i32> = pm_records.iter().map(|record| record.cpg1).collect();
+        assert_eq!(cpg1, vec![0, 13]);
+        assert_eq!(pm_records[0].depth, 16);
+        assert_eq!(me_records[1].cpg4, 19);
+        assert_eq!(me_records[1].me, 1.0);
+    }
+
+    #[test]
+    fn test_read_pair_records() {
+        let fdrp_params = fdrp::FdrpParams {
+            min_depth: 2,
+            min_overlap: 4,
+            ..Default::default()
+        };
+        let qfdrp_params = qfdrp::QfdrpParams {
+            min_depth: 2,
+            min_overlap: 4,
+            ..Default::default()
+        };
+        let fdrp_records = fdrp::compute_records("tests/test1.bam", &fdrp_params).unwrap();
+        let qfdrp_records = qfdrp::compute_records("tests/test1.bam", &qfdrp_params).unwrap();
+
+        assert_eq!(fdrp_records.len(), 4);
+        assert_eq!(qfdrp_records.len(), 4);
+        assert_eq!(fdrp_records[3].start, qfdrp_records[3].start);
+        assert_eq!(fdrp_records[3].fdrp, 1.0);
+    }
+
+    #[test]
+    fn test_mhl_records_with_threads() {
+        let params = mhl::MhlParams {
+            min_depth: 0,
+            min_cpgs: 0,
+            read_options: readutil::ReadOptions {
+                threads: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let records = mhl::compute_records("tests/test9.bam", &params).unwrap();
+
+        assert_eq!(records.len(), 16);
+        assert_eq!(records[0].chrom, "chr1");
+        assert_eq!(records[15].chrom, "chr2");
+    }
+
+    #[test]
+    fn test_grouped_records() {
+        let params = me::MeParams {
+            min_depth: 0,
+            read_options: readutil::ReadOptions {
+                group_by_tag: Some("CB".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let groups = me::compute_grouped_records("tests/test12.sam", &params).unwrap();
+
+        let names: Vec<&str> = groups.iter().map(|(group, _)| group.as_str()).collect();
+        assert_eq!(names, vec!["AAAC", "AAAG"]);
+        assert_eq!(groups[0].1[0].depth, 3);
+        assert_eq!(groups[1].1[0].depth, 3);
+
+        // Without the tag, all reads are in a single group.
+        let groups =
+            me::compute_grouped_records("tests/test12.sam", &me::MeParams::default()).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].0, "");
+    }
+
+    #[test]
+    fn test_lpmd_record() {
+        let record =
+            lpmd::compute_records("tests/test4.bam", &lpmd::LpmdParams::default()).unwrap();
+
+        assert_eq!(record.name, "tests/test4.bam");
+        assert_eq!(record.lpmd, 0.5);
+        assert_eq!(record.pairs.len(), 12);
+        assert_eq!(record.to_string(), "tests/test4.bam\t0.5");
+    }
+}
@@ -101,4 +101,21 @@ mod progressbar_tests {
 
         // If we get here without panic, the method worked
     }
+
+    #[test]
+    fn test_add_reads_from_threads() {
+        let bar = ProgressBar::new();
+
+        // Test that shards processed in parallel can share a single bar
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    bar.add_reads(10000, 9000);
+                    bar.add_reads(42, 0);
+                });
+            }
+        });
+
+        // If we get here without panic, the method worked
+    }
 }